@@ -1,13 +1,27 @@
-use crate::{bail, message_proto::*, ResultType};
-use std::path::{Path, PathBuf};
+use crate::{bail, message_proto::*, protobuf::ProtobufEnumOrUnknown, ResultType};
+use std::{
+    collections::HashMap,
+    path::{Component, Path, PathBuf},
+    time::{Duration, Instant},
+};
 // https://doc.rust-lang.org/std/os/windows/fs/trait.MetadataExt.html
 use crate::{
     compress::{compress, decompress},
     config::{Config, COMPRESS_LEVEL},
 };
+use sha2::{Digest, Sha256};
+#[cfg(unix)]
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
 #[cfg(windows)]
 use std::os::windows::prelude::*;
-use tokio::{fs::File, io::*};
+use tokio::{
+    fs::{File, OpenOptions},
+    io::*,
+};
+
+const BUF_SIZE: usize = 128 * 1024;
+// a send slower than this means the stream is congested, e.g. the socket buffer is full
+const SEND_BACKOFF_THRESHOLD: Duration = Duration::from_millis(10);
 
 pub fn read_dir(path: &PathBuf, include_hidden: bool) -> ResultType<FileDirectory> {
     let mut dir = FileDirectory {
@@ -77,22 +91,17 @@ pub fn read_dir(path: &PathBuf, include_hidden: bool) -> ResultType<FileDirector
                     }
                 }
             };
-            let modified_time = meta
-                .modified()
-                .map(|x| {
-                    x.duration_since(std::time::SystemTime::UNIX_EPOCH)
-                        .map(|x| x.as_secs())
-                        .unwrap_or(0)
-                })
-                .unwrap_or(0) as u64;
-            dir.entries.push(FileEntry {
+            let modified_time = get_modified_time(&meta);
+            let mut entry = FileEntry {
                 name: get_file_name(&p),
                 entry_type,
                 is_hidden,
                 size,
                 modified_time,
                 ..Default::default()
-            });
+            };
+            set_unix_meta(&mut entry, &meta);
+            dir.entries.push(entry);
         }
     }
     Ok(dir)
@@ -106,6 +115,17 @@ pub fn get_file_name(p: &PathBuf) -> String {
         .to_owned()
 }
 
+#[inline]
+pub fn get_modified_time(meta: &std::fs::Metadata) -> u64 {
+    meta.modified()
+        .map(|x| {
+            x.duration_since(std::time::SystemTime::UNIX_EPOCH)
+                .map(|x| x.as_secs())
+                .unwrap_or(0)
+        })
+        .unwrap_or(0) as u64
+}
+
 #[inline]
 pub fn get_string(path: &PathBuf) -> String {
     path.to_str().unwrap_or("").to_owned()
@@ -128,8 +148,6 @@ fn read_dir_recursive(
 ) -> ResultType<Vec<FileEntry>> {
     let mut files = Vec::new();
     if path.is_dir() {
-        // to-do: symbol link handling, cp the link rather than the content
-        // to-do: file mode, for unix
         let fd = read_dir(&path, include_hidden)?;
         for entry in fd.entries.iter() {
             match entry.entry_type.enum_value() {
@@ -138,6 +156,15 @@ fn read_dir_recursive(
                     entry.name = get_string(&prefix.join(entry.name));
                     files.push(entry);
                 }
+                // cp the link rather than the content
+                Ok(FileType::FileLink) | Ok(FileType::DirLink) => {
+                    let mut entry = entry.clone();
+                    entry.link_target = std::fs::read_link(path.join(&entry.name))
+                        .map(|x| get_string(&x))
+                        .unwrap_or_default();
+                    entry.name = get_string(&prefix.join(entry.name));
+                    files.push(entry);
+                }
                 Ok(FileType::Dir) => {
                     if let Ok(mut tmp) = read_dir_recursive(
                         &path.join(&entry.name),
@@ -154,36 +181,197 @@ fn read_dir_recursive(
         }
         Ok(files)
     } else if path.is_file() {
-        let (size, modified_time) = if let Ok(meta) = std::fs::metadata(&path) {
-            (
-                meta.len(),
-                meta.modified()
-                    .map(|x| {
-                        x.duration_since(std::time::SystemTime::UNIX_EPOCH)
-                            .map(|x| x.as_secs())
-                            .unwrap_or(0)
-                    })
-                    .unwrap_or(0) as u64,
-            )
-        } else {
-            (0, 0)
-        };
-        files.push(FileEntry {
+        let mut entry = FileEntry {
             entry_type: FileType::File.into(),
-            size,
-            modified_time,
             ..Default::default()
-        });
+        };
+        if let Ok(meta) = std::fs::metadata(&path) {
+            entry.size = meta.len();
+            entry.modified_time = get_modified_time(&meta);
+            set_unix_meta(&mut entry, &meta);
+        }
+        files.push(entry);
         Ok(files)
     } else {
         bail!("Not exists");
     }
 }
 
+#[inline]
+fn is_link(entry: &FileEntry) -> bool {
+    match entry.entry_type.enum_value() {
+        Ok(FileType::FileLink) | Ok(FileType::DirLink) => true,
+        _ => false,
+    }
+}
+
+// no setuid, setgid or sticky bits
+#[cfg(unix)]
+fn set_unix_meta(entry: &mut FileEntry, meta: &std::fs::Metadata) {
+    entry.mode = meta.mode() & 0o777;
+    entry.uid = meta.uid();
+    entry.gid = meta.gid();
+}
+
+#[cfg(not(unix))]
+fn set_unix_meta(_entry: &mut FileEntry, _meta: &std::fs::Metadata) {}
+
+// chown only if asked and we are root, before chmod which may be reset by chown
+#[cfg(unix)]
+fn apply_unix_meta(path: &PathBuf, entry: &FileEntry, preserve_owner: bool) -> ResultType<()> {
+    use std::os::unix::ffi::OsStrExt;
+    if entry.mode == 0 {
+        return Ok(());
+    }
+    let is_link = is_link(entry);
+    if preserve_owner && unsafe { libc::geteuid() } == 0 {
+        let c_path = std::ffi::CString::new(path.as_os_str().as_bytes())?;
+        let ret = unsafe {
+            if is_link {
+                libc::lchown(c_path.as_ptr(), entry.uid, entry.gid)
+            } else {
+                libc::chown(c_path.as_ptr(), entry.uid, entry.gid)
+            }
+        };
+        if ret != 0 {
+            bail!(
+                "Failed to change the owner of {}: {}",
+                get_string(path),
+                std::io::Error::last_os_error()
+            );
+        }
+    }
+    if !is_link {
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(entry.mode & 0o777))?;
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn apply_unix_meta(_path: &PathBuf, _entry: &FileEntry, _preserve_owner: bool) -> ResultType<()> {
+    Ok(())
+}
+
+/// The sender must not write out of the job path, the name must be relative
+/// without "..", and none of its parents under the job path a symbolic link.
+fn check_entry_path(base: &PathBuf, name: &str) -> ResultType<()> {
+    let name = Path::new(name);
+    if name
+        .components()
+        .any(|x| !matches!(x, Component::Normal(_)))
+    {
+        bail!("Invalid file name {}", name.display());
+    }
+    let mut path = base.clone();
+    if let Some(parent) = name.parent() {
+        for x in parent.components() {
+            path.push(x);
+            if let Ok(meta) = std::fs::symlink_metadata(&path) {
+                if meta.file_type().is_symlink() {
+                    bail!("{} is a symbolic link", get_string(&path));
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Only links within the copied tree, i.e. relative without "..".
+fn check_link_target(target: &str) -> ResultType<()> {
+    let path = Path::new(target);
+    if target.is_empty()
+        || path
+            .components()
+            .any(|x| !matches!(x, Component::Normal(_) | Component::CurDir))
+    {
+        bail!("Refuse the link target {}", target);
+    }
+    Ok(())
+}
+
+/// A link given as the path is listed itself, e.g. to remove the link rather than its target.
 pub fn get_recursive_files(path: &str, include_hidden: bool) -> ResultType<Vec<FileEntry>> {
-    read_dir_recursive(&get_path(path), &get_path(""), include_hidden)
+    let path = get_path(path);
+    if let Ok(meta) = std::fs::symlink_metadata(&path) {
+        if meta.file_type().is_symlink() {
+            let mut entry = FileEntry {
+                entry_type: if path.is_dir() {
+                    FileType::DirLink
+                } else {
+                    FileType::FileLink
+                }
+                .into(),
+                modified_time: get_modified_time(&meta),
+                link_target: std::fs::read_link(&path)
+                    .map(|x| get_string(&x))
+                    .unwrap_or_default(),
+                ..Default::default()
+            };
+            set_unix_meta(&mut entry, &meta);
+            return Ok(vec![entry]);
+        }
+    }
+    read_dir_recursive(&path, &get_path(""), include_hidden)
+}
+
+/// The file transfer features of this version, announced in LoginRequest and PeerInfo.
+pub fn features() -> Vec<ProtobufEnumOrUnknown<FileTransferFeature>> {
+    vec![
+        FileTransferFeature::Resume.into(),
+        FileTransferFeature::PerFileDone.into(),
+        FileTransferFeature::OverwritePolicy.into(),
+    ]
 }
 
+/// The features announced by the peer, none if it is an old version.
+pub fn peer_features(
+    features: &[ProtobufEnumOrUnknown<FileTransferFeature>],
+) -> Vec<FileTransferFeature> {
+    features
+        .iter()
+        .filter_map(|x| x.enum_value().ok())
+        .collect()
+}
+
+/// Existing .download files under the destination path, with offset set to
+/// their current sizes, reported by the receiver before a job starts.
+pub async fn get_download_offsets(path: String) -> Vec<FileEntry> {
+    // hashing the partial files may take a while
+    tokio::task::spawn_blocking(move || {
+        let path = get_path(&path);
+        let download_path = format!("{}.download", get_string(&path));
+        if let Ok(meta) = std::fs::metadata(&download_path) {
+            if meta.is_file() {
+                return vec![FileEntry {
+                    offset: meta.len(),
+                    offset_digest: digest_prefix(&download_path, meta.len()).unwrap_or_default(),
+                    ..Default::default()
+                }];
+            }
+        }
+        if !path.is_dir() {
+            return Vec::new();
+        }
+        read_dir_recursive(&path, &get_path(""), true)
+            .unwrap_or_default()
+            .drain(..)
+            .filter_map(|mut entry| {
+                let download_path = get_string(&path.join(&entry.name));
+                let name = entry.name.strip_suffix(".download")?.to_owned();
+                entry.offset = entry.size;
+                entry.offset_digest = digest_prefix(&download_path, entry.size).ok()?;
+                entry.name = name;
+                Some(entry)
+            })
+            .collect()
+    })
+    .await
+    .unwrap_or_default()
+}
+
+/// The sha256 states after the resume offsets, by file_num.
+pub type PrefixHashers = HashMap<usize, Sha256>;
+
 #[derive(Default)]
 pub struct TransferJob {
     id: i32,
@@ -191,6 +379,16 @@ pub struct TransferJob {
     files: Vec<FileEntry>,
     file_num: i32,
     file: Option<File>,
+    hasher: Option<Sha256>,
+    resume_hashers: PrefixHashers,
+    // position in the current file, and the end of its data region if sparse
+    pos: u64,
+    data_end: u64,
+    policy: FileOverwritePolicy,
+    per_file_done: bool,
+    preserve_owner: bool,
+    // links created after all regular files are written, not to be written through
+    links: Vec<usize>,
     total_size: u64,
     finished_size: u64,
     transferred: u64,
@@ -231,7 +429,8 @@ impl TransferJob {
     }
 
     pub fn new_read(id: i32, path: String, include_hidden: bool) -> ResultType<Self> {
-        let files = get_recursive_files(&path, include_hidden)?;
+        // a link given as the path is copied as what it points to
+        let files = read_dir_recursive(&get_path(&path), &get_path(""), include_hidden)?;
         let total_size = files.iter().map(|x| x.size as u64).sum();
         Ok(Self {
             id,
@@ -252,6 +451,254 @@ impl TransferJob {
         self.files = files;
     }
 
+    #[inline]
+    pub fn policy(&self) -> FileOverwritePolicy {
+        self.policy
+    }
+
+    #[inline]
+    pub fn set_policy(&mut self, policy: FileOverwritePolicy) {
+        self.policy = policy;
+    }
+
+    /// Whether the peer handles the per-file done with the digest, an old one takes
+    /// any done as the end of the job, and its files are complete on the next one.
+    #[inline]
+    pub fn set_per_file_done(&mut self, per_file_done: bool) {
+        self.per_file_done = per_file_done;
+    }
+
+    #[inline]
+    pub fn set_preserve_owner(&mut self, preserve_owner: bool) {
+        self.preserve_owner = preserve_owner;
+    }
+
+    /// Set the resume offsets of the .download files reported with the send request,
+    /// offsets larger than the file are ignored, e.g. the source has changed.
+    pub fn set_download_offsets(&mut self, entries: &[FileEntry]) {
+        for file in self.files.iter_mut() {
+            if let Some(entry) = entries.iter().filter(|x| x.name == file.name).next() {
+                if entry.offset <= file.size {
+                    file.offset = entry.offset;
+                    file.offset_digest = entry.offset_digest.clone();
+                }
+            }
+        }
+    }
+
+    /// Set the resume offsets and the skipped files replied by the receiver,
+    /// by file_num since it may have renamed the files.
+    pub fn set_offsets(&mut self, entries: &[FileEntry]) {
+        for entry in entries.iter() {
+            if let Some(file) = self.files.get_mut(entry.file_num as usize) {
+                if entry.offset <= file.size {
+                    file.offset = entry.offset;
+                    file.offset_digest = entry.offset_digest.clone();
+                }
+                file.skip = entry.skip;
+            }
+        }
+        self.update_total_size();
+    }
+
+    /// The hashing of what the receiver already has of the resumed files,
+    /// to run in a blocking thread before reading, see set_prefix_hashers.
+    pub fn hash_prefixes(&self) -> impl FnOnce() -> PrefixHashers + Send + 'static {
+        let files: Vec<_> = self
+            .files
+            .iter()
+            .enumerate()
+            .filter(|(_, x)| x.offset > 0 && !x.skip && !is_link(x))
+            .map(|(i, x)| {
+                let path = get_string(&self.join(&x.name));
+                (i, path, x.offset, x.offset_digest.clone())
+            })
+            .collect();
+        move || {
+            files
+                .into_iter()
+                .filter_map(|(i, path, offset, offset_digest)| {
+                    let hasher = hash_prefix(&path, offset).ok()?;
+                    if offset_digest.is_empty()
+                        || &hasher.clone().finalize()[..] == &offset_digest[..]
+                    {
+                        Some((i, hasher))
+                    } else {
+                        None
+                    }
+                })
+                .collect()
+        }
+    }
+
+    /// Continue the resumed files from their hashed prefixes, the others have changed
+    /// since the interrupted job and start over, the receiver rewinds on the offset.
+    pub fn set_prefix_hashers(&mut self, hashers: PrefixHashers) {
+        for (i, file) in self.files.iter_mut().enumerate() {
+            if file.offset > 0 && !file.skip && !is_link(file) && !hashers.contains_key(&i) {
+                log::info!("{} has changed, transfer it from the start", file.name);
+                file.offset = 0;
+                file.offset_digest = Vec::new();
+            }
+        }
+        self.resume_hashers = hashers;
+    }
+
+    pub async fn load_prefix_digests(&mut self) {
+        let hashers = tokio::task::spawn_blocking(self.hash_prefixes())
+            .await
+            .unwrap_or_default();
+        self.set_prefix_hashers(hashers);
+    }
+
+    /// The files numbered, to reply to the sender.
+    pub fn reply_files(&self) -> Vec<FileEntry> {
+        self.files
+            .iter()
+            .enumerate()
+            .map(|(i, x)| FileEntry {
+                file_num: i as _,
+                ..x.clone()
+            })
+            .collect()
+    }
+
+    /// The digests of the source files for SkipSameContent, to run in a blocking thread,
+    /// hashing a large folder takes a while, see set_digests.
+    pub fn hash_files(&self) -> impl FnOnce() -> Vec<Vec<u8>> + Send + 'static {
+        let paths: Vec<_> = self
+            .files
+            .iter()
+            .map(|x| get_string(&self.join(&x.name)))
+            .collect();
+        move || {
+            paths
+                .iter()
+                .map(|x| digest_file(x).unwrap_or_default())
+                .collect()
+        }
+    }
+
+    pub fn set_digests(&mut self, digests: Vec<Vec<u8>>) {
+        for (file, digest) in self.files.iter_mut().zip(digests.into_iter()) {
+            file.digest = digest;
+        }
+    }
+
+    /// The files with the same content as the existing destination ones for SkipSameContent,
+    /// to run in a blocking thread before apply_policy.
+    pub fn same_contents(&self) -> impl FnOnce() -> Vec<usize> + Send + 'static {
+        let files: Vec<_> = if self.policy == FileOverwritePolicy::SkipSameContent {
+            self.files
+                .iter()
+                .enumerate()
+                .filter(|(_, x)| !x.digest.is_empty())
+                .map(|(i, x)| (i, get_string(&self.join(&x.name)), x.size, x.digest.clone()))
+                .collect()
+        } else {
+            Vec::new()
+        };
+        move || {
+            files
+                .into_iter()
+                .filter(|(_, path, size, digest)| {
+                    std::fs::metadata(path)
+                        .map(|x| x.is_file() && x.len() == *size)
+                        .unwrap_or(false)
+                        && digest_file(path).map(|x| &x == digest).unwrap_or(false)
+                })
+                .map(|(i, ..)| i)
+                .collect()
+        }
+    }
+
+    /// Apply the overwrite policy against the existing destination files,
+    /// returns the entries to reply to the sender.
+    pub fn apply_policy(&mut self, same_contents: &[usize]) -> Vec<FileEntry> {
+        for i in 0..self.files.len() {
+            let path = self.join(&self.files[i].name);
+            let file = &mut self.files[i];
+            let meta = match std::fs::metadata(&path) {
+                Ok(meta) if meta.is_file() => meta,
+                _ => continue,
+            };
+            match self.policy {
+                FileOverwritePolicy::Overwrite => {}
+                FileOverwritePolicy::SkipSameSizeTime => {
+                    file.skip =
+                        meta.len() == file.size && get_modified_time(&meta) == file.modified_time;
+                }
+                FileOverwritePolicy::SkipSameContent => {
+                    file.skip = same_contents.contains(&i);
+                }
+                FileOverwritePolicy::Rename => {
+                    file.offset = 0;
+                }
+                FileOverwritePolicy::Ask => {
+                    // confirmed by the user on the client side
+                    file.skip = true;
+                }
+            }
+            if self.policy == FileOverwritePolicy::Rename {
+                let path = get_free_path(&path);
+                if file.name.is_empty() {
+                    self.path = path;
+                } else if let Ok(name) = path.strip_prefix(&self.path) {
+                    file.name = get_string(&name.to_path_buf());
+                }
+            }
+        }
+        self.update_total_size();
+        self.reply_files()
+    }
+
+    /// Overwrite the files skipped for confirmation, for Ask.
+    pub fn overwrite_skipped(&mut self) {
+        for file in self.files.iter_mut() {
+            file.skip = false;
+        }
+        self.update_total_size();
+    }
+
+    fn update_total_size(&mut self) {
+        self.total_size = self
+            .files
+            .iter()
+            .filter(|x| !x.skip)
+            .map(|x| x.size as u64)
+            .sum();
+    }
+
+    /// The resume offsets from the .download files left by an interrupted job, with
+    /// the digests of what they hold for the sender to check against its files,
+    /// to run in a blocking thread before set_download_offsets.
+    pub fn download_offsets(&self) -> impl FnOnce() -> Vec<FileEntry> + Send + 'static {
+        let files: Vec<_> = self
+            .files
+            .iter()
+            .map(|x| (x.name.clone(), get_string(&self.join(&x.name)), x.size))
+            .collect();
+        move || {
+            files
+                .into_iter()
+                .filter_map(|(name, path, size)| {
+                    let download_path = format!("{}.download", path);
+                    let offset = std::fs::metadata(&download_path).ok()?.len();
+                    // same rule as set_offsets on the reader side
+                    if offset == 0 || offset > size {
+                        return None;
+                    }
+                    Some(FileEntry {
+                        name,
+                        offset,
+                        offset_digest: digest_prefix(&download_path, offset).ok()?,
+                        ..Default::default()
+                    })
+                })
+                .collect()
+        }
+    }
+
     #[inline]
     pub fn id(&self) -> i32 {
         self.id
@@ -277,19 +724,95 @@ impl TransferJob {
         self.file_num
     }
 
-    pub fn modify_time(&self) {
-        let file_num = self.file_num as usize;
-        if file_num < self.files.len() {
-            let entry = &self.files[file_num];
-            let path = self.join(&entry.name);
-            let download_path = format!("{}.download", get_string(&path));
-            std::fs::rename(&download_path, &path).ok();
-            filetime::set_file_mtime(
-                &path,
-                filetime::FileTime::from_unix_time(entry.modified_time as _, 0),
-            )
-            .ok();
+    /// Called on the per-file done, verify the digest of the .download file
+    /// and rename it, the .download file is kept if mismatched.
+    pub async fn modify_time(&mut self, file_num: i32, digest: &[u8]) -> ResultType<()> {
+        if file_num as usize >= self.files.len() {
+            bail!("Wrong file number");
+        }
+        if is_link(&self.files[file_num as usize]) {
+            self.file_num = file_num;
+            self.links.push(file_num as usize);
+            return Ok(());
+        }
+        if file_num != self.file_num || self.file.is_none() {
+            // no block received, e.g. empty file, or fully downloaded before resuming
+            self.open_download_file(file_num).await?;
+        }
+        let entry = &self.files[file_num as usize];
+        if let Some(file) = self.file.take() {
+            if self.pos < entry.size {
+                // trailing hole skipped by the sender
+                file.set_len(entry.size).await?;
+                self.finished_size += entry.size - self.pos;
+                self.pos = entry.size;
+            }
+            file.sync_all().await?;
+        }
+        let path = self.join(&entry.name);
+        let download_path = format!("{}.download", get_string(&path));
+        if !digest.is_empty() {
+            // hashed in a blocking thread, not to stall the other sessions on the runtime
+            let p = download_path.clone();
+            if tokio::task::spawn_blocking(move || digest_file(&p)).await?? != digest {
+                bail!("Digest mismatch, {} is kept for retry", download_path);
+            }
+        }
+        std::fs::rename(&download_path, &path)?;
+        if let Err(err) = apply_unix_meta(&path, entry, self.preserve_owner) {
+            log::warn!("{}", err);
+        }
+        filetime::set_file_mtime(
+            &path,
+            filetime::FileTime::from_unix_time(entry.modified_time as _, 0),
+        )
+        .ok();
+        Ok(())
+    }
+
+    fn create_link(&self, file_num: usize) -> ResultType<()> {
+        let entry = &self.files[file_num];
+        check_entry_path(&self.path, &entry.name)?;
+        check_link_target(&entry.link_target)?;
+        let path = self.join(&entry.name);
+        if let Some(p) = path.parent() {
+            std::fs::create_dir_all(p).ok();
+        }
+        if std::fs::symlink_metadata(&path).is_ok() {
+            // kept as is, e.g. copied again
+            if std::fs::read_link(&path).ok() == Some(get_path(&entry.link_target)) {
+                return Ok(());
+            }
+            bail!("{} already exists", get_string(&path));
+        }
+        #[cfg(unix)]
+        std::os::unix::fs::symlink(&entry.link_target, &path)?;
+        #[cfg(windows)]
+        if entry.entry_type.enum_value() == Ok(FileType::DirLink) {
+            std::os::windows::fs::symlink_dir(&entry.link_target, &path)?;
+        } else {
+            std::os::windows::fs::symlink_file(&entry.link_target, &path)?;
+        }
+        if let Err(err) = apply_unix_meta(&path, entry, self.preserve_owner) {
+            log::warn!("{}", err);
+        }
+        Ok(())
+    }
+
+    /// Called on the done of the job, an old sender sends no done for the last file,
+    /// the links are created here.
+    pub async fn finish(&mut self) -> ResultType<()> {
+        if !self.per_file_done && self.file.is_some() {
+            self.modify_time(self.file_num, &[]).await?;
+        }
+        let mut res = Ok(());
+        for file_num in std::mem::take(&mut self.links) {
+            if let Err(err) = self.create_link(file_num) {
+                log::error!("{}", err);
+                res = Err(err);
+            }
         }
+        res
     }
 
     pub fn remove_download_file(&self) {
@@ -311,18 +834,31 @@ impl TransferJob {
             bail!("Wrong file number");
         }
         if file_num != self.file_num as usize || self.file.is_none() {
-            self.modify_time();
-            if let Some(file) = self.file.as_mut() {
+            if !self.per_file_done && self.file.is_some() {
+                // an old sender moves to the next file without done
+                self.modify_time(self.file_num, &[]).await?;
+            } else if let Some(file) = self.file.take() {
                 file.sync_all().await?;
             }
-            self.file_num = block.file_num;
-            let entry = &self.files[file_num];
-            let path = self.join(&entry.name);
-            if let Some(p) = path.parent() {
-                std::fs::create_dir_all(p).ok();
-            }
-            let path = format!("{}.download", get_string(&path));
-            self.file = Some(File::create(&path).await?);
+            self.open_download_file(block.file_num).await?;
+        }
+        if block.offset < self.pos {
+            // the sender starts over, its file has changed since the interrupted job
+            let file = self.file.as_mut().unwrap();
+            file.set_len(0).await?;
+            file.seek(SeekFrom::Start(0)).await?;
+            self.finished_size = self.finished_size.saturating_sub(self.pos);
+            self.pos = 0;
+        }
+        if block.offset > self.pos {
+            // a hole skipped by the sender
+            self.file
+                .as_mut()
+                .unwrap()
+                .seek(SeekFrom::Start(block.offset))
+                .await?;
+            self.finished_size += block.offset - self.pos;
+            self.pos = block.offset;
         }
         let data = if let Some(data) = raw {
             data
@@ -333,14 +869,43 @@ impl TransferJob {
             let tmp = decompress(data);
             self.file.as_mut().unwrap().write_all(&tmp).await?;
             self.finished_size += tmp.len() as u64;
+            self.pos += tmp.len() as u64;
         } else {
             self.file.as_mut().unwrap().write_all(data).await?;
             self.finished_size += data.len() as u64;
+            self.pos += data.len() as u64;
         }
         self.transferred += data.len() as u64;
         Ok(())
     }
 
+    async fn open_download_file(&mut self, file_num: i32) -> ResultType<()> {
+        self.file_num = file_num;
+        let entry = &self.files[file_num as usize];
+        check_entry_path(&self.path, &entry.name)?;
+        let path = self.join(&entry.name);
+        if let Some(p) = path.parent() {
+            std::fs::create_dir_all(p).ok();
+        }
+        let path = format!("{}.download", get_string(&path));
+        let offset = entry.offset;
+        self.pos = offset;
+        let mut options = OpenOptions::new();
+        options.write(true);
+        #[cfg(unix)]
+        options.custom_flags(libc::O_NOFOLLOW);
+        if offset > 0 {
+            let mut file = options.open(&path).await?;
+            file.set_len(offset).await?;
+            file.seek(SeekFrom::Start(offset)).await?;
+            self.file = Some(file);
+            self.finished_size += offset;
+        } else {
+            self.file = Some(options.create(true).truncate(true).open(&path).await?);
+        }
+        Ok(())
+    }
+
     #[inline]
     fn join(&self, name: &str) -> PathBuf {
         if name.is_empty() {
@@ -350,25 +915,80 @@ impl TransferJob {
         }
     }
 
-    pub async fn read(&mut self) -> ResultType<Option<FileTransferBlock>> {
+    /// Read the next block of the current file, or the per-file done with
+    /// the digest at the end of the file, None if all files are read.
+    pub async fn read(&mut self) -> ResultType<Option<Message>> {
+        if self.file.is_none() {
+            // an old receiver can not recreate the links
+            while (self.file_num as usize) < self.files.len()
+                && (self.files[self.file_num as usize].skip
+                    || (!self.per_file_done && is_link(&self.files[self.file_num as usize])))
+            {
+                self.file_num += 1;
+            }
+        }
         let file_num = self.file_num as usize;
         if file_num >= self.files.len() {
             self.file.take();
             return Ok(None);
         }
+        if self.file.is_none() && is_link(&self.files[file_num]) {
+            // recreated from link_target by the receiver, no content
+            self.file_num += 1;
+            return Ok(Some(new_file_done(self.id, file_num as _, Vec::new())));
+        }
         let name = &self.files[file_num].name;
         if self.file.is_none() {
-            match File::open(self.join(&name)).await {
+            // the digest covers the whole file, continue from the hashed prefix
+            let mut offset = self.files[file_num].offset;
+            let hasher = match self.resume_hashers.remove(&file_num) {
+                Some(hasher) => hasher,
+                None => {
+                    // not checked by set_prefix_hashers, the receiver rewinds on the offset
+                    offset = 0;
+                    Sha256::new()
+                }
+            };
+            let res = match File::open(self.join(&name)).await {
+                Ok(mut file) => file
+                    .seek(SeekFrom::Start(offset))
+                    .await
+                    .map(|_| file)
+                    .map_err(|err| err.into()),
+                Err(err) => Err(err.into()),
+            };
+            match res {
                 Ok(file) => {
+                    self.finished_size += offset;
+                    self.pos = offset;
+                    // an old receiver ignores the offsets, no hole skipped
+                    self.data_end = if self.per_file_done && is_sparse(&file).await {
+                        0
+                    } else {
+                        u64::MAX
+                    };
                     self.file = Some(file);
+                    self.hasher = if self.per_file_done {
+                        Some(hasher)
+                    } else {
+                        None
+                    };
                 }
                 Err(err) => {
                     self.file_num += 1;
-                    return Err(err.into());
+                    return Err(err);
                 }
             }
         }
-        const BUF_SIZE: usize = 128 * 1024;
+        if self.pos >= self.data_end {
+            if let Err(err) = self.skip_hole().await {
+                self.file_num += 1;
+                self.file = None;
+                return Err(err);
+            }
+        }
+        let pos = self.pos;
+        let limit = std::cmp::min(BUF_SIZE as u64, self.data_end - pos) as usize;
         let mut buf: Vec<u8> = Vec::with_capacity(BUF_SIZE);
         unsafe {
             buf.set_len(BUF_SIZE);
@@ -376,7 +996,13 @@ impl TransferJob {
         let mut compressed = false;
         let mut offset: usize = 0;
         loop {
-            match self.file.as_mut().unwrap().read(&mut buf[offset..]).await {
+            match self
+                .file
+                .as_mut()
+                .unwrap()
+                .read(&mut buf[offset..limit])
+                .await
+            {
                 Err(err) => {
                     self.file_num += 1;
                     self.file = None;
@@ -384,7 +1010,7 @@ impl TransferJob {
                 }
                 Ok(n) => {
                     offset += n;
-                    if n == 0 || offset == BUF_SIZE {
+                    if n == 0 || offset == limit {
                         break;
                     }
                 }
@@ -394,25 +1020,157 @@ impl TransferJob {
         if offset == 0 {
             self.file_num += 1;
             self.file = None;
-        } else {
-            self.finished_size += offset as u64;
-            if !is_compressed_file(name) {
-                let tmp = compress(&buf, COMPRESS_LEVEL);
-                if tmp.len() < buf.len() {
-                    buf = tmp;
-                    compressed = true;
-                }
+            if !self.per_file_done {
+                // an empty block instead, on which an old receiver creates an empty file
+                return Ok(Some(new_block(FileTransferBlock {
+                    id: self.id,
+                    file_num: file_num as _,
+                    offset: pos,
+                    ..Default::default()
+                })));
             }
-            self.transferred += buf.len() as u64;
+            let digest = self
+                .hasher
+                .take()
+                .map(|x| x.finalize().to_vec())
+                .unwrap_or_default();
+            return Ok(Some(new_file_done(self.id, file_num as _, digest)));
         }
-        Ok(Some(FileTransferBlock {
+        self.finished_size += offset as u64;
+        self.pos += offset as u64;
+        if let Some(hasher) = self.hasher.as_mut() {
+            hasher.update(&buf);
+        }
+        if !is_compressed_file(&self.files[file_num].name) {
+            let tmp = compress(&buf, COMPRESS_LEVEL);
+            if tmp.len() < buf.len() {
+                buf = tmp;
+                compressed = true;
+            }
+        }
+        self.transferred += buf.len() as u64;
+        Ok(Some(new_block(FileTransferBlock {
             id: self.id,
             file_num: file_num as _,
             data: buf.into(),
             compressed,
+            offset: pos,
             ..Default::default()
-        }))
+        })))
     }
+
+    // move to the next data region of a sparse file, the hole is not sent
+    // but still counted in the digest and the progress
+    async fn skip_hole(&mut self) -> ResultType<()> {
+        let file = self.file.as_mut().unwrap();
+        let (start, end) = match next_data(file, self.pos)? {
+            Some(x) => x,
+            None => {
+                let len = file.metadata().await?.len();
+                (len, len)
+            }
+        };
+        let start = std::cmp::max(start, self.pos);
+        if let Some(hasher) = self.hasher.as_mut() {
+            hash_zeros(hasher, start - self.pos);
+        }
+        self.finished_size += start - self.pos;
+        file.seek(SeekFrom::Start(start)).await?;
+        self.pos = start;
+        self.data_end = end;
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "linux")]
+async fn is_sparse(file: &File) -> bool {
+    file.metadata()
+        .await
+        .map(|x| x.blocks() * 512 < x.len())
+        .unwrap_or(false)
+}
+
+#[cfg(not(target_os = "linux"))]
+async fn is_sparse(_file: &File) -> bool {
+    false
+}
+
+// the data region at or after pos, None if there is only a hole left
+#[cfg(target_os = "linux")]
+fn next_data(file: &File, pos: u64) -> ResultType<Option<(u64, u64)>> {
+    use std::os::unix::io::AsRawFd;
+    let fd = file.as_raw_fd();
+    let start = unsafe { libc::lseek(fd, pos as _, libc::SEEK_DATA) };
+    if start < 0 {
+        let err = std::io::Error::last_os_error();
+        if err.raw_os_error() == Some(libc::ENXIO) {
+            return Ok(None);
+        }
+        return Err(err.into());
+    }
+    let end = unsafe { libc::lseek(fd, start, libc::SEEK_HOLE) };
+    if end < 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(Some((start as _, end as _)))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn next_data(_file: &File, pos: u64) -> ResultType<Option<(u64, u64)>> {
+    Ok(Some((pos, u64::MAX)))
+}
+
+fn hash_zeros(hasher: &mut Sha256, mut len: u64) {
+    let buf = [0u8; 4096];
+    while len > 0 {
+        let n = std::cmp::min(len, buf.len() as u64) as usize;
+        hasher.update(&buf[..n]);
+        len -= n as u64;
+    }
+}
+
+// "name (1).ext" which does not exist yet
+fn get_free_path(path: &PathBuf) -> PathBuf {
+    let stem = path
+        .file_stem()
+        .map(|x| x.to_str().unwrap_or(""))
+        .unwrap_or("");
+    let ext = path.extension().map(|x| x.to_str().unwrap_or(""));
+    let mut i = 1;
+    loop {
+        let name = if let Some(ext) = ext {
+            format!("{} ({}).{}", stem, i, ext)
+        } else {
+            format!("{} ({})", stem, i)
+        };
+        let p = path.with_file_name(name);
+        if !p.exists() {
+            return p;
+        }
+        i += 1;
+    }
+}
+
+// sha256 state after the first len bytes of the file
+fn hash_prefix(path: &str, len: u64) -> ResultType<Sha256> {
+    let file = std::fs::File::open(path)?;
+    let mut hasher = Sha256::new();
+    if std::io::copy(&mut std::io::Read::take(file, len), &mut hasher)? < len {
+        bail!("File is shorter than the resume offset");
+    }
+    Ok(hasher)
+}
+
+#[inline]
+fn digest_prefix(path: &str, len: u64) -> ResultType<Vec<u8>> {
+    Ok(hash_prefix(path, len)?.finalize().to_vec())
+}
+
+fn digest_file(path: &str) -> ResultType<Vec<u8>> {
+    let mut file = std::fs::File::open(path)?;
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut file, &mut hasher)?;
+    Ok(hasher.finalize().to_vec())
 }
 
 #[inline]
@@ -452,12 +1210,20 @@ pub fn new_block(block: FileTransferBlock) -> Message {
 }
 
 #[inline]
-pub fn new_receive(id: i32, path: String, files: Vec<FileEntry>) -> Message {
+pub fn new_receive(
+    id: i32,
+    path: String,
+    files: Vec<FileEntry>,
+    policy: FileOverwritePolicy,
+    preserve_owner: bool,
+) -> Message {
     let mut action = FileAction::new();
     action.set_receive(FileTransferReceiveRequest {
         id,
         path,
         files: files.into(),
+        policy: policy.into(),
+        preserve_owner,
         ..Default::default()
     });
     let mut msg_out = Message::new();
@@ -466,12 +1232,33 @@ pub fn new_receive(id: i32, path: String, files: Vec<FileEntry>) -> Message {
 }
 
 #[inline]
-pub fn new_send(id: i32, path: String, include_hidden: bool) -> Message {
+pub fn new_send(
+    id: i32,
+    path: String,
+    include_hidden: bool,
+    files: Vec<FileEntry>,
+    policy: FileOverwritePolicy,
+) -> Message {
     let mut action = FileAction::new();
     action.set_send(FileTransferSendRequest {
         id,
         path,
         include_hidden,
+        files: files.into(),
+        policy: policy.into(),
+        ..Default::default()
+    });
+    let mut msg_out = Message::new();
+    msg_out.set_file_action(action);
+    msg_out
+}
+
+#[inline]
+pub fn new_send_confirm(id: i32, files: Vec<FileEntry>) -> Message {
+    let mut action = FileAction::new();
+    action.set_send_confirm(FileTransferSendConfirmRequest {
+        id,
+        files: files.into(),
         ..Default::default()
     });
     let mut msg_out = Message::new();
@@ -479,6 +1266,20 @@ pub fn new_send(id: i32, path: String, include_hidden: bool) -> Message {
     msg_out
 }
 
+#[inline]
+pub fn new_file_done(id: i32, file_num: i32, digest: Vec<u8>) -> Message {
+    let mut resp = FileResponse::new();
+    resp.set_done(FileTransferDone {
+        id,
+        file_num,
+        digest: digest.into(),
+        ..Default::default()
+    });
+    let mut msg_out = Message::new();
+    msg_out.set_file_response(resp);
+    msg_out
+}
+
 #[inline]
 pub fn new_done(id: i32, file_num: i32) -> Message {
     let mut resp = FileResponse::new();
@@ -502,20 +1303,93 @@ pub fn get_job(id: i32, jobs: &mut Vec<TransferJob>) -> Option<&mut TransferJob>
     jobs.iter_mut().filter(|x| x.id() == id).next()
 }
 
+/// Schedules the read jobs of a session: blocks are taken from the jobs in turn,
+/// capped by the speed limit, and paused while the stream is slow to accept data,
+/// so that file copies do not starve the video sharing the stream.
+#[derive(Default)]
+pub struct JobScheduler {
+    limit: u64, // bytes per second, 0 for unlimited
+    budget: i64,
+    last_refill: Option<Instant>,
+    backoff_until: Option<Instant>,
+    next: usize,
+}
+
+impl JobScheduler {
+    pub fn new(limit_kbps: u32) -> Self {
+        let mut scheduler = Self::default();
+        scheduler.set_limit(limit_kbps);
+        scheduler
+    }
+
+    pub fn set_limit(&mut self, limit_kbps: u32) {
+        self.limit = limit_kbps as u64 * 1024;
+        self.budget = 0;
+        self.last_refill = None;
+    }
+
+    fn ready(&mut self) -> bool {
+        let now = Instant::now();
+        if let Some(t) = self.backoff_until {
+            if now < t {
+                return false;
+            }
+            self.backoff_until = None;
+        }
+        if self.limit == 0 {
+            return true;
+        }
+        let elapsed = self
+            .last_refill
+            .map(|x| now - x)
+            .unwrap_or(Duration::from_secs(1));
+        self.last_refill = Some(now);
+        // burst of one second at most
+        let refill = (self.limit as f64 * elapsed.as_secs_f64()) as i64;
+        self.budget = std::cmp::min(self.budget + refill, self.limit as i64);
+        self.budget > 0
+    }
+
+    // the job indices in turn, each round starts from the job after the last start
+    fn round(&mut self, n: usize) -> Vec<usize> {
+        if n == 0 {
+            return Vec::new();
+        }
+        let start = self.next;
+        self.next = (self.next + 1) % n;
+        (0..n).map(|i| (start + i) % n).collect()
+    }
+
+    fn on_sent(&mut self, size: u64, elapsed: Duration) {
+        self.budget -= size as i64;
+        if elapsed > SEND_BACKOFF_THRESHOLD {
+            self.backoff_until = Some(Instant::now() + elapsed);
+        }
+    }
+}
+
 pub async fn handle_read_jobs(
     jobs: &mut Vec<TransferJob>,
     stream: &mut crate::Stream,
+    scheduler: &mut JobScheduler,
 ) -> ResultType<()> {
     let mut finished = Vec::new();
-    for job in jobs.iter_mut() {
+    for i in scheduler.round(jobs.len()) {
+        if !scheduler.ready() {
+            break;
+        }
+        let job = &mut jobs[i];
+        let transferred = job.transferred();
         match job.read().await {
             Err(err) => {
                 stream
                     .send(&new_error(job.id(), err, job.file_num()))
                     .await?;
             }
-            Ok(Some(block)) => {
-                stream.send(&new_block(block)).await?;
+            Ok(Some(msg)) => {
+                let tm = Instant::now();
+                stream.send(&msg).await?;
+                scheduler.on_sent(job.transferred() - transferred, tm.elapsed());
             }
             Ok(None) => {
                 finished.push(job.id());
@@ -530,6 +1404,12 @@ pub async fn handle_read_jobs(
 }
 
 pub fn remove_all_empty_dir(path: &PathBuf) -> ResultType<()> {
+    if let Ok(meta) = std::fs::symlink_metadata(path) {
+        if meta.file_type().is_symlink() {
+            // not into the target
+            return remove_file(&get_string(path));
+        }
+    }
     let fd = read_dir(path, true)?;
     for entry in fd.entries.iter() {
         match entry.entry_type.enum_value() {
@@ -548,7 +1428,18 @@ pub fn remove_all_empty_dir(path: &PathBuf) -> ResultType<()> {
 
 #[inline]
 pub fn remove_file(file: &str) -> ResultType<()> {
-    std::fs::remove_file(get_path(file))?;
+    let path = get_path(file);
+    // a directory link is removed as a directory on Windows
+    #[cfg(windows)]
+    if std::fs::symlink_metadata(&path)
+        .map(|x| x.file_type().is_symlink())
+        .unwrap_or(false)
+        && path.is_dir()
+    {
+        std::fs::remove_dir(&path)?;
+        return Ok(());
+    }
+    std::fs::remove_file(&path)?;
     Ok(())
 }
 
@@ -557,3 +1448,270 @@ pub fn create_dir(dir: &str) -> ResultType<()> {
     std::fs::create_dir_all(get_path(dir))?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("hbb_fs_{}_{}", name, std::process::id()));
+        std::fs::remove_dir_all(&dir).ok();
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn sha256(data: &[u8]) -> Vec<u8> {
+        Sha256::digest(data).to_vec()
+    }
+
+    // read all of the job into the writer, returns the offset of the first block and the count
+    async fn transfer(reader: &mut TransferJob, writer: &mut TransferJob) -> (Option<u64>, usize) {
+        let mut first = None;
+        let mut blocks = 0;
+        while let Some(msg) = reader.read().await.unwrap() {
+            match msg.union {
+                Some(message::Union::file_response(fr)) => match fr.union {
+                    Some(file_response::Union::block(block)) => {
+                        first.get_or_insert(block.offset);
+                        blocks += 1;
+                        writer.write(block, None).await.unwrap();
+                    }
+                    Some(file_response::Union::done(d)) => {
+                        writer.modify_time(d.file_num, &d.digest).await.unwrap();
+                    }
+                    _ => panic!("unexpected file response"),
+                },
+                _ => panic!("unexpected message"),
+            }
+        }
+        (first, blocks)
+    }
+
+    // a partial .download of dst, and the jobs with the offsets exchanged
+    async fn resume_jobs(src: &PathBuf, dst: &PathBuf) -> (TransferJob, TransferJob) {
+        let mut reader = TransferJob::new_read(1, get_string(src), false).unwrap();
+        reader.set_per_file_done(true);
+        let mut writer = TransferJob::new_write(1, get_string(dst), reader.files().to_vec());
+        writer.set_per_file_done(true);
+        let offsets = writer.download_offsets()();
+        writer.set_download_offsets(&offsets);
+        reader.set_offsets(&writer.reply_files());
+        reader.load_prefix_digests().await;
+        (reader, writer)
+    }
+
+    #[tokio::test]
+    async fn test_resume() {
+        let dir = temp_dir("resume");
+        let data: Vec<u8> = (0..300_000).map(|i| (i % 251) as u8).collect();
+        let src = dir.join("src.bin");
+        let dst = dir.join("dst.bin");
+        let download = dir.join("dst.bin.download");
+        std::fs::write(&src, &data).unwrap();
+
+        std::fs::write(&download, &data[..100_000]).unwrap();
+        let (mut reader, mut writer) = resume_jobs(&src, &dst).await;
+        assert_eq!(reader.files()[0].offset, 100_000);
+        assert_eq!(transfer(&mut reader, &mut writer).await.0, Some(100_000));
+        assert_eq!(std::fs::read(&dst).unwrap(), data);
+        assert!(!download.exists());
+
+        // changed since the interrupted job, the writer rewinds
+        std::fs::remove_file(&dst).unwrap();
+        let mut changed = data[..100_000].to_vec();
+        changed[0] ^= 0xff;
+        std::fs::write(&download, &changed).unwrap();
+        let (mut reader, mut writer) = resume_jobs(&src, &dst).await;
+        assert_eq!(reader.files()[0].offset, 0);
+        assert_eq!(transfer(&mut reader, &mut writer).await.0, Some(0));
+        assert_eq!(std::fs::read(&dst).unwrap(), data);
+
+        // larger than the source, ignored
+        std::fs::remove_file(&dst).unwrap();
+        std::fs::write(&download, &[data.clone(), data.clone()].concat()).unwrap();
+        let (reader, _) = resume_jobs(&src, &dst).await;
+        assert_eq!(reader.files()[0].offset, 0);
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[tokio::test]
+    async fn test_write_rewind() {
+        let dir = temp_dir("rewind");
+        let dst = dir.join("a.txt");
+        let files = vec![FileEntry {
+            size: 2,
+            ..Default::default()
+        }];
+        let mut writer = TransferJob::new_write(1, get_string(&dst), files);
+        writer.set_per_file_done(true);
+        for data in [&b"abcdef"[..], &b"xy"[..]] {
+            let block = FileTransferBlock {
+                id: 1,
+                data: data.to_vec().into(),
+                ..Default::default()
+            };
+            writer.write(block, None).await.unwrap();
+        }
+        assert_eq!(writer.finished_size(), 2);
+        writer.modify_time(0, &sha256(b"xy")).await.unwrap();
+        assert_eq!(std::fs::read(&dst).unwrap(), b"xy");
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_apply_policy() {
+        let dir = temp_dir("policy");
+        std::fs::write(dir.join("same.txt"), b"abc").unwrap();
+        std::fs::write(dir.join("diff.txt"), b"xyz").unwrap();
+        let modified_time = get_modified_time(&std::fs::metadata(dir.join("diff.txt")).unwrap());
+        let files = vec![
+            FileEntry {
+                name: "same.txt".to_owned(),
+                size: 3,
+                digest: sha256(b"abc"),
+                ..Default::default()
+            },
+            FileEntry {
+                name: "diff.txt".to_owned(),
+                size: 3,
+                digest: sha256(b"abd"),
+                modified_time,
+                ..Default::default()
+            },
+            FileEntry {
+                name: "new.txt".to_owned(),
+                size: 3,
+                ..Default::default()
+            },
+        ];
+        let apply = |policy| {
+            let mut job = TransferJob::new_write(1, get_string(&dir), files.clone());
+            job.set_policy(policy);
+            let same_contents = job.same_contents()();
+            job.apply_policy(&same_contents)
+        };
+        let skips = |files: Vec<FileEntry>| files.iter().map(|x| x.skip).collect::<Vec<_>>();
+
+        assert_eq!(
+            skips(apply(FileOverwritePolicy::Overwrite)),
+            [false, false, false]
+        );
+        assert_eq!(
+            skips(apply(FileOverwritePolicy::SkipSameContent)),
+            [true, false, false]
+        );
+        assert_eq!(
+            skips(apply(FileOverwritePolicy::SkipSameSizeTime)),
+            [false, true, false]
+        );
+        // confirmed on the client side
+        assert_eq!(skips(apply(FileOverwritePolicy::Ask)), [true, true, false]);
+        let names: Vec<_> = apply(FileOverwritePolicy::Rename)
+            .into_iter()
+            .map(|x| x.name)
+            .collect();
+        assert_eq!(names, ["same (1).txt", "diff (1).txt", "new.txt"]);
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_check_path() {
+        let dir = temp_dir("check");
+        assert!(check_entry_path(&dir, "a/b.txt").is_ok());
+        assert!(check_entry_path(&dir, "../b.txt").is_err());
+        assert!(check_entry_path(&dir, "a/../../b.txt").is_err());
+        assert!(check_entry_path(&dir, "/etc/passwd").is_err());
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink(std::env::temp_dir(), dir.join("link")).unwrap();
+            assert!(check_entry_path(&dir, "link/b.txt").is_err());
+            // the link itself may be replaced
+            assert!(check_entry_path(&dir, "link").is_ok());
+        }
+
+        assert!(check_link_target("a/b").is_ok());
+        assert!(check_link_target("./b").is_ok());
+        assert!(check_link_target("").is_err());
+        assert!(check_link_target("../b").is_err());
+        assert!(check_link_target("/etc/passwd").is_err());
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_job_scheduler() {
+        let mut scheduler = JobScheduler::new(0);
+        assert_eq!(scheduler.round(3), [0, 1, 2]);
+        assert_eq!(scheduler.round(3), [1, 2, 0]);
+        // a job finished
+        assert_eq!(scheduler.round(2), [0, 1]);
+        assert!(scheduler.round(0).is_empty());
+        assert!(scheduler.ready());
+
+        // one second of burst at first
+        let mut scheduler = JobScheduler::new(1);
+        assert!(scheduler.ready());
+        scheduler.on_sent(1024, Duration::ZERO);
+        assert!(!scheduler.ready());
+        scheduler.on_sent(1024, Duration::ZERO);
+        std::thread::sleep(Duration::from_millis(100));
+        assert!(!scheduler.ready());
+
+        // a slow send pauses the jobs for as long
+        let mut scheduler = JobScheduler::new(0);
+        scheduler.on_sent(1024, SEND_BACKOFF_THRESHOLD * 5);
+        assert!(!scheduler.ready());
+        std::thread::sleep(SEND_BACKOFF_THRESHOLD * 6);
+        assert!(scheduler.ready());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_sparse() {
+        let dir = temp_dir("sparse");
+        let src = dir.join("src.bin");
+        let dst = dir.join("dst.bin");
+        let len = 4 << 20;
+        {
+            use std::io::{Seek, Write};
+            let mut file = std::fs::File::create(&src).unwrap();
+            file.set_len(len).unwrap();
+            file.seek(std::io::SeekFrom::Start(2 << 20)).unwrap();
+            file.write_all(&[b'x'; 1000]).unwrap();
+        }
+        let mut reader = TransferJob::new_read(1, get_string(&src), false).unwrap();
+        reader.set_per_file_done(true);
+        let mut writer = TransferJob::new_write(1, get_string(&dst), reader.files().to_vec());
+        writer.set_per_file_done(true);
+        let (first, blocks) = transfer(&mut reader, &mut writer).await;
+        assert_eq!(std::fs::read(&dst).unwrap(), std::fs::read(&src).unwrap());
+        assert_eq!(writer.finished_size(), len);
+        let meta = std::fs::metadata(&src).unwrap();
+        if meta.blocks() * 512 < meta.len() {
+            // only the data region is sent, the digest still covers the holes
+            assert_eq!(first, Some(2 << 20));
+            assert_eq!(blocks, 1);
+            let meta = std::fs::metadata(&dst).unwrap();
+            assert!(meta.blocks() * 512 < meta.len());
+        }
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_remove_link() {
+        let dir = temp_dir("link");
+        let target = dir.join("target");
+        let link = dir.join("link");
+        std::fs::create_dir_all(&target).unwrap();
+        std::fs::write(target.join("a.txt"), b"abc").unwrap();
+        std::os::unix::fs::symlink(&target, &link).unwrap();
+        let files = get_recursive_files(&get_string(&link), true).unwrap();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].entry_type.enum_value(), Ok(FileType::DirLink));
+        assert_eq!(files[0].link_target, get_string(&target));
+        remove_all_empty_dir(&link).unwrap();
+        assert!(std::fs::symlink_metadata(&link).is_err());
+        assert!(target.join("a.txt").exists());
+        std::fs::remove_dir_all(&dir).ok();
+    }
+}
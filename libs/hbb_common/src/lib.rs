@@ -9,7 +9,7 @@ pub use protobuf;
 use std::{
     fs::File,
     io::{self, BufRead},
-    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6},
     path::Path,
     time::{self, SystemTime, UNIX_EPOCH},
 };
@@ -21,7 +21,6 @@ pub mod udp;
 pub use env_logger;
 pub use log;
 pub mod bytes_codec;
-#[cfg(feature = "quic")]
 pub mod quic;
 pub use anyhow::{self, bail};
 pub use futures_util;
@@ -31,14 +30,13 @@ pub use lazy_static;
 pub use mac_address;
 pub use rand;
 pub use regex;
+pub use socket2;
 pub use sodiumoxide;
 pub use tokio_socks;
 pub use tokio_socks::IntoTargetAddr;
 pub use tokio_socks::TargetAddr;
 
-#[cfg(feature = "quic")]
-pub type Stream = quic::Connection;
-#[cfg(not(feature = "quic"))]
+/// Over TCP, a socks5 proxy or QUIC, chosen per connection.
 pub type Stream = tcp::FramedStream;
 
 #[inline]
@@ -97,13 +95,25 @@ impl AddrMangle {
                 }
                 bytes[..(16 - n_padding)].to_vec()
             }
-            _ => {
-                panic!("Only support ipv4");
+            // 18 bytes, longer than any ipv4 one
+            SocketAddr::V6(addr_v6) => {
+                let mut bytes = addr_v6.ip().octets().to_vec();
+                bytes.extend_from_slice(&addr_v6.port().to_le_bytes());
+                bytes
             }
         }
     }
 
     pub fn decode(bytes: &[u8]) -> SocketAddr {
+        if bytes.len() > 16 {
+            if bytes.len() != 18 {
+                return config::Config::get_any_listen_addr(false);
+            }
+            let mut ip = [0u8; 16];
+            ip.copy_from_slice(&bytes[..16]);
+            let port = u16::from_le_bytes([bytes[16], bytes[17]]);
+            return SocketAddr::V6(SocketAddrV6::new(Ipv6Addr::from(ip), port, 0, 0));
+        }
         let mut padded = [0u8; 16];
         padded[..bytes.len()].copy_from_slice(&bytes);
         let number = u128::from_le_bytes(padded);
@@ -117,6 +127,18 @@ impl AddrMangle {
     }
 }
 
+/// The IPv4 addresses seen as IPv4-mapped IPv6 ones on the dual-stack sockets, unmapped.
+pub fn to_canonical(addr: SocketAddr) -> SocketAddr {
+    if let SocketAddr::V6(addr_v6) = addr {
+        if let [0, 0, 0, 0, 0, 0xffff, ..] = addr_v6.ip().segments() {
+            if let Some(ip) = addr_v6.ip().to_ipv4() {
+                return SocketAddr::V4(SocketAddrV4::new(ip, addr.port()));
+            }
+        }
+    }
+    addr
+}
+
 pub fn get_version_from_url(url: &str) -> String {
     let n = url.chars().count();
     let a = url
@@ -194,5 +216,7 @@ mod tests {
     fn test_mangle() {
         let addr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(192, 168, 16, 32), 21116));
         assert_eq!(addr, AddrMangle::decode(&AddrMangle::encode(addr)));
+        let addr = "[2001:db8::1]:21116".parse().unwrap();
+        assert_eq!(addr, AddrMangle::decode(&AddrMangle::encode(addr)));
     }
 }
@@ -1,135 +1,154 @@
-use crate::{allow_err, anyhow::anyhow, ResultType};
-use protobuf::Message;
-use std::{net::SocketAddr, sync::Arc};
-use tokio::{self, stream::StreamExt, sync::mpsc};
+//! QUIC through the hole punched for TCP, the UDP socket bound to the same local port.
+//! A connection carries a single bidirectional stream, framed and encrypted as the TCP ones,
+//! which the host opens because it speaks first.
+use crate::{anyhow::anyhow, tcp::FramedStream, udp::new_socket, ResultType};
+use futures::StreamExt;
+use std::{
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::SystemTime,
+};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
 
-const QUIC_HBB: &[&[u8]] = &[b"hbb"];
+const ALPN: &[u8] = b"hbb";
 const SERVER_NAME: &str = "hbb";
 
-type Sender = mpsc::UnboundedSender<Value>;
-type Receiver = mpsc::UnboundedReceiver<Value>;
+struct QuicStream {
+    send: quinn::SendStream,
+    recv: quinn::RecvStream,
+    _endpoint: quinn::Endpoint,
+}
 
-pub fn new_server(socket: std::net::UdpSocket) -> ResultType<(Server, SocketAddr)> {
-    let mut transport_config = quinn::TransportConfig::default();
-    transport_config.stream_window_uni(0);
-    let mut server_config = quinn::ServerConfig::default();
-    server_config.transport = Arc::new(transport_config);
-    let mut server_config = quinn::ServerConfigBuilder::new(server_config);
-    server_config.protocols(QUIC_HBB);
-    // server_config.enable_keylog();
-    // server_config.use_stateless_retry(true);
-    let mut endpoint = quinn::Endpoint::builder();
-    endpoint.listen(server_config.build());
-    let (end, incoming) = endpoint.with_socket(socket)?;
-    Ok((Server { incoming }, end.local_addr()?))
+/// Connects to the host accepting on the other side of the hole.
+pub async fn connect(
+    peer: SocketAddr,
+    local_addr: SocketAddr,
+    ms_timeout: u64,
+) -> ResultType<FramedStream> {
+    let socket = new_socket(local_addr, true)?.into_udp_socket();
+    let (endpoint, _) = quinn::Endpoint::new(Default::default(), None, socket)?;
+    let local_addr = endpoint.local_addr()?;
+    let stream = super::timeout(ms_timeout, connect_(endpoint, peer)).await??;
+    Ok(FramedStream::from(stream, local_addr))
 }
 
-pub async fn new_client(local_addr: &SocketAddr, peer: &SocketAddr) -> ResultType<Connection> {
-    let mut endpoint = quinn::Endpoint::builder();
-    let mut client_config = quinn::ClientConfigBuilder::default();
-    client_config.protocols(QUIC_HBB);
-    //client_config.enable_keylog();
-    endpoint.default_client_config(client_config.build());
-    let (endpoint, _) = endpoint.bind(local_addr)?;
-    let new_conn = endpoint.connect(peer, SERVER_NAME)?.await?;
-    Connection::new_for_client(new_conn.connection).await
+async fn connect_(endpoint: quinn::Endpoint, peer: SocketAddr) -> ResultType<QuicStream> {
+    let quinn::NewConnection { mut bi_streams, .. } = endpoint
+        .connect_with(client_config(), peer, SERVER_NAME)?
+        .await?;
+    let (send, recv) = bi_streams
+        .next()
+        .await
+        .ok_or_else(|| anyhow!("Reset by the peer"))??;
+    Ok(QuicStream {
+        send,
+        recv,
+        _endpoint: endpoint,
+    })
 }
 
-pub struct Server {
-    incoming: quinn::Incoming,
+/// Accepts the connection of `peer` on `local_addr`, sending a packet first to open our NAT.
+pub async fn accept(
+    peer: SocketAddr,
+    local_addr: SocketAddr,
+    ms_timeout: u64,
+) -> ResultType<(FramedStream, SocketAddr)> {
+    let socket = new_socket(local_addr, true)?.into_udp_socket();
+    socket.send_to(&[], peer).ok();
+    let (endpoint, incoming) =
+        quinn::Endpoint::new(Default::default(), Some(server_config()?), socket)?;
+    let local_addr = endpoint.local_addr()?;
+    let (stream, addr) = super::timeout(ms_timeout, accept_(endpoint, incoming)).await??;
+    Ok((FramedStream::from(stream, local_addr), addr))
 }
 
-impl Server {
-    #[inline]
-    pub async fn next(&mut self) -> ResultType<Option<Connection>> {
-        Connection::new_for_server(&mut self.incoming).await
-    }
+async fn accept_(
+    endpoint: quinn::Endpoint,
+    mut incoming: quinn::Incoming,
+) -> ResultType<(QuicStream, SocketAddr)> {
+    let connecting = incoming
+        .next()
+        .await
+        .ok_or_else(|| anyhow!("Endpoint closed"))?;
+    let addr = connecting.remote_address();
+    let quinn::NewConnection { connection, .. } = connecting.await?;
+    let (send, recv) = connection.open_bi().await?;
+    Ok((
+        QuicStream {
+            send,
+            recv,
+            _endpoint: endpoint,
+        },
+        addr,
+    ))
 }
 
-pub struct Connection {
-    conn: quinn::Connection,
-    tx: quinn::SendStream,
-    rx: Receiver,
+// the peers are authenticated with their signed keys afterwards, as on TCP,
+// so the certificate is a throwaway one and not verified
+fn server_config() -> ResultType<quinn::ServerConfig> {
+    let cert = rcgen::generate_simple_self_signed(vec![SERVER_NAME.to_owned()])?;
+    let key = rustls::PrivateKey(cert.serialize_private_key_der());
+    let cert = rustls::Certificate(cert.serialize_der()?);
+    let mut crypto = rustls::ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(vec![cert], key)?;
+    crypto.alpn_protocols = vec![ALPN.to_vec()];
+    Ok(quinn::ServerConfig::with_crypto(Arc::new(crypto)))
 }
 
-type Value = ResultType<Vec<u8>>;
+fn client_config() -> quinn::ClientConfig {
+    let mut crypto = rustls::ClientConfig::builder()
+        .with_safe_defaults()
+        .with_custom_certificate_verifier(Arc::new(SkipServerVerification))
+        .with_no_client_auth();
+    crypto.alpn_protocols = vec![ALPN.to_vec()];
+    quinn::ClientConfig::new(Arc::new(crypto))
+}
 
-impl Connection {
-    async fn new_for_server(incoming: &mut quinn::Incoming) -> ResultType<Option<Self>> {
-        if let Some(conn) = incoming.next().await {
-            let quinn::NewConnection {
-                connection: conn,
-                // uni_streams,
-                mut bi_streams,
-                ..
-            } = conn.await?;
-            let (tx, rx) = mpsc::unbounded_channel::<Value>();
-            tokio::spawn(async move {
-                loop {
-                    let stream = bi_streams.next().await;
-                    if let Some(stream) = stream {
-                        let stream = match stream {
-                            Err(e) => {
-                                tx.send(Err(e.into())).ok();
-                                break;
-                            }
-                            Ok(s) => s,
-                        };
-                        let cloned = tx.clone();
-                        tokio::spawn(async move {
-                            allow_err!(handle_request(stream.1, cloned).await);
-                        });
-                    } else {
-                        tx.send(Err(anyhow!("Reset by the peer"))).ok();
-                        break;
-                    }
-                }
-                log::info!("Exit connection outer loop");
-            });
-            let tx = conn.open_uni().await?;
-            Ok(Some(Self { conn, tx, rx }))
-        } else {
-            Ok(None)
-        }
-    }
+struct SkipServerVerification;
 
-    async fn new_for_client(conn: quinn::Connection) -> ResultType<Self> {
-        let (tx, rx_quic) = conn.open_bi().await?;
-        let (tx_mpsc, rx) = mpsc::unbounded_channel::<Value>();
-        tokio::spawn(async move {
-            allow_err!(handle_request(rx_quic, tx_mpsc).await);
-        });
-        Ok(Self { conn, tx, rx })
+impl rustls::client::ServerCertVerifier for SkipServerVerification {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &rustls::Certificate,
+        _intermediates: &[rustls::Certificate],
+        _server_name: &rustls::ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<rustls::client::ServerCertVerified, rustls::Error> {
+        Ok(rustls::client::ServerCertVerified::assertion())
     }
+}
 
-    #[inline]
-    pub async fn next(&mut self) -> Option<Value> {
-        // None is returned when all Sender halves have dropped,
-        // indicating that no further values can be sent on the channel.
-        self.rx.recv().await
+impl AsyncRead for QuicStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        AsyncRead::poll_read(Pin::new(&mut self.recv), cx, buf)
     }
+}
 
-    #[inline]
-    pub fn remote_address(&self) -> SocketAddr {
-        self.conn.remote_address()
+impl AsyncWrite for QuicStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        AsyncWrite::poll_write(Pin::new(&mut self.send), cx, buf)
     }
 
-    #[inline]
-    pub async fn send_raw(&mut self, bytes: &[u8]) -> ResultType<()> {
-        self.tx.write_all(bytes).await?;
-        Ok(())
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        AsyncWrite::poll_flush(Pin::new(&mut self.send), cx)
     }
 
-    #[inline]
-    pub async fn send(&mut self, msg: &dyn Message) -> ResultType<()> {
-        match msg.write_to_bytes() {
-            Ok(bytes) => self.send_raw(&bytes).await?,
-            err => allow_err!(err),
-        }
-        Ok(())
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        AsyncWrite::poll_shutdown(Pin::new(&mut self.send), cx)
     }
 }
-
-async fn handle_request(rx: quinn::RecvStream, tx: Sender) -> ResultType<()> {
-    Ok(())
-}
@@ -1,5 +1,6 @@
 use crate::{
-    config::{Config, NetworkType},
+    bail,
+    config::{Config, NetworkType, Socks5Server},
     tcp::FramedStream,
     udp::FramedSocket,
     ResultType,
@@ -17,7 +18,7 @@ fn to_socket_addr(host: &str) -> ResultType<SocketAddr> {
 pub fn get_target_addr(host: &str) -> ResultType<TargetAddr<'static>> {
     let addr = match Config::get_network_type() {
         NetworkType::Direct => to_socket_addr(&host)?.into_target_addr()?,
-        NetworkType::ProxySocks => host.into_target_addr()?,
+        NetworkType::ProxySocks | NetworkType::HttpConnect => host.into_target_addr()?,
     }
     .to_owned();
     Ok(addr)
@@ -25,7 +26,10 @@ pub fn get_target_addr(host: &str) -> ResultType<TargetAddr<'static>> {
 
 pub fn test_if_valid_server(host: &str) -> String {
     let mut host = host.to_owned();
-    if !host.contains(":") {
+    if host.parse::<std::net::Ipv6Addr>().is_ok() {
+        host = format!("[{}]", host);
+    }
+    if !host.contains(":") || host.ends_with(']') {
         host = format!("{}:{}", host, 0);
     }
 
@@ -34,7 +38,7 @@ pub fn test_if_valid_server(host: &str) -> String {
             Err(err) => err.to_string(),
             Ok(_) => "".to_owned(),
         },
-        NetworkType::ProxySocks => match &host.into_target_addr() {
+        NetworkType::ProxySocks | NetworkType::HttpConnect => match &host.into_target_addr() {
             Err(err) => err.to_string(),
             Ok(_) => "".to_owned(),
         },
@@ -48,30 +52,77 @@ pub async fn connect_tcp<'t, T: IntoTargetAddr<'t>>(
 ) -> ResultType<FramedStream> {
     let target_addr = target.into_target_addr()?;
 
-    if let Some(conf) = Config::get_socks() {
-        FramedStream::connect(
-            conf.proxy.as_str(),
-            target_addr,
-            local,
-            conf.username.as_str(),
-            conf.password.as_str(),
-            ms_timeout,
-        )
-        .await
-    } else {
-        let addr = std::net::ToSocketAddrs::to_socket_addrs(&target_addr)?
-            .next()
-            .context("Invalid target addr")?;
-        Ok(FramedStream::new(addr, local, ms_timeout).await?)
+    match get_proxy(&target_addr) {
+        Some(conf) if conf.get_network_type() == NetworkType::HttpConnect => {
+            FramedStream::connect_http(
+                conf.get_address(),
+                target_addr,
+                local,
+                conf.username.as_str(),
+                conf.password.as_str(),
+                ms_timeout,
+            )
+            .await
+        }
+        Some(conf) => {
+            FramedStream::connect(
+                conf.get_address(),
+                target_addr,
+                local,
+                conf.username.as_str(),
+                conf.password.as_str(),
+                ms_timeout,
+            )
+            .await
+        }
+        None => {
+            let addr = std::net::ToSocketAddrs::to_socket_addrs(&target_addr)?
+                .next()
+                .context("Invalid target addr")?;
+            Ok(FramedStream::new(addr, local, ms_timeout).await?)
+        }
+    }
+}
+
+fn get_proxy(target: &TargetAddr<'_>) -> Option<Socks5Server> {
+    let host = match target {
+        TargetAddr::Ip(addr) => addr.ip().to_string(),
+        TargetAddr::Domain(host, _) => host.to_string(),
+    };
+    Config::get_proxy_for(&host)
+}
+
+/// QUIC goes around the proxy, so it is off with one.
+pub fn is_quic_available() -> bool {
+    Config::get_network_type() == NetworkType::Direct
+}
+
+/// QUIC through the hole punched for [`connect_tcp`], unavailable behind a proxy.
+pub async fn connect_quic(
+    target: SocketAddr,
+    local: SocketAddr,
+    ms_timeout: u64,
+) -> ResultType<FramedStream> {
+    if !is_quic_available() {
+        bail!("QUIC is not available through the proxy");
+    }
+    crate::quic::connect(target, local, ms_timeout).await
+}
+
+/// The family of the local address to reach the target from, ipv4 through a proxy.
+pub fn is_ipv4(target: &TargetAddr<'_>) -> bool {
+    match target {
+        TargetAddr::Ip(addr) => addr.is_ipv4(),
+        _ => true,
     }
 }
 
 pub async fn new_udp<T: ToSocketAddrs>(local: T, ms_timeout: u64) -> ResultType<FramedSocket> {
-    match Config::get_socks() {
-        None => Ok(FramedSocket::new(local).await?),
-        Some(conf) => {
+    match Config::get_proxy() {
+        // HTTP CONNECT can not carry udp
+        Some(conf) if conf.get_network_type() == NetworkType::ProxySocks => {
             let socket = FramedSocket::new_proxy(
-                conf.proxy.as_str(),
+                conf.get_address(),
                 local,
                 conf.username.as_str(),
                 conf.password.as_str(),
@@ -80,12 +131,13 @@ pub async fn new_udp<T: ToSocketAddrs>(local: T, ms_timeout: u64) -> ResultType<
             .await?;
             Ok(socket)
         }
+        _ => Ok(FramedSocket::new(local).await?),
     }
 }
 
 pub async fn rebind_udp<T: ToSocketAddrs>(local: T) -> ResultType<Option<FramedSocket>> {
     match Config::get_network_type() {
-        NetworkType::Direct => Ok(Some(FramedSocket::new(local).await?)),
-        _ => Ok(None),
+        NetworkType::ProxySocks => Ok(None),
+        _ => Ok(Some(FramedSocket::new(local).await?)),
     }
 }
@@ -1,20 +1,23 @@
-use crate::{bail, bytes_codec::BytesCodec, ResultType};
+use crate::{bail, bytes_codec::BytesCodec, config::Config, ResultType};
 use bytes::{BufMut, Bytes, BytesMut};
 use futures::{SinkExt, StreamExt};
 use protobuf::Message;
-use sodiumoxide::crypto::secretbox::{self, Key, Nonce};
+use sodiumoxide::{
+    base64,
+    crypto::secretbox::{self, Key, Nonce},
+};
 use std::{
     io::{self, Error, ErrorKind},
-    net::SocketAddr,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
     ops::{Deref, DerefMut},
     pin::Pin,
     task::{Context, Poll},
 };
 use tokio::{
-    io::{AsyncRead, AsyncWrite, ReadBuf},
-    net::{lookup_host, TcpListener, TcpSocket, ToSocketAddrs},
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf},
+    net::{lookup_host, TcpListener, TcpSocket, TcpStream, ToSocketAddrs},
 };
-use tokio_socks::{tcp::Socks5Stream, IntoTargetAddr, ToProxyAddrs};
+use tokio_socks::{tcp::Socks5Stream, IntoTargetAddr, TargetAddr, ToProxyAddrs};
 use tokio_util::codec::Framed;
 
 pub trait TcpStreamTrait: AsyncRead + AsyncWrite + Unpin {}
@@ -55,6 +58,28 @@ impl DerefMut for DynTcpStream {
     }
 }
 
+// the status code of the response to CONNECT, leaving the stream right after its headers
+async fn read_http_status(stream: &mut TcpStream) -> ResultType<u16> {
+    const MAX_HEADER_SIZE: usize = 8192;
+    let mut header = Vec::new();
+    while !header.ends_with(b"\r\n\r\n") {
+        if header.len() >= MAX_HEADER_SIZE {
+            bail!("HTTP proxy response too large");
+        }
+        header.push(stream.read_u8().await?);
+    }
+    let header = String::from_utf8_lossy(&header);
+    let status = header
+        .lines()
+        .next()
+        .and_then(|line| line.split_whitespace().nth(1))
+        .and_then(|code| code.parse().ok());
+    match status {
+        Some(status) => Ok(status),
+        None => bail!("Invalid HTTP proxy response"),
+    }
+}
+
 fn new_socket(addr: std::net::SocketAddr, reuse: bool) -> Result<TcpSocket, std::io::Error> {
     let socket = match addr {
         std::net::SocketAddr::V4(..) => TcpSocket::new_v4()?,
@@ -72,6 +97,17 @@ fn new_socket(addr: std::net::SocketAddr, reuse: bool) -> Result<TcpSocket, std:
     Ok(socket)
 }
 
+// the unspecified local address takes the family of the remote one, the port kept
+fn fit_family(local_addr: SocketAddr, remote_addr: &SocketAddr) -> SocketAddr {
+    if local_addr.ip().is_unspecified() && local_addr.is_ipv4() != remote_addr.is_ipv4() {
+        let mut addr = Config::get_any_listen_addr(remote_addr.is_ipv4());
+        addr.set_port(local_addr.port());
+        addr
+    } else {
+        local_addr
+    }
+}
+
 impl FramedStream {
     pub async fn new<T1: ToSocketAddrs, T2: ToSocketAddrs>(
         remote_addr: T1,
@@ -82,7 +118,7 @@ impl FramedStream {
             for remote_addr in lookup_host(&remote_addr).await? {
                 let stream = super::timeout(
                     ms_timeout,
-                    new_socket(local_addr, true)?.connect(remote_addr),
+                    new_socket(fit_family(local_addr, &remote_addr), true)?.connect(remote_addr),
                 )
                 .await??;
                 stream.set_nodelay(true).ok();
@@ -113,8 +149,12 @@ impl FramedStream {
     {
         if let Some(local) = lookup_host(&local).await?.next() {
             if let Some(proxy) = proxy.to_proxy_addrs().next().await {
-                let stream =
-                    super::timeout(ms_timeout, new_socket(local, true)?.connect(proxy?)).await??;
+                let proxy = proxy?;
+                let stream = super::timeout(
+                    ms_timeout,
+                    new_socket(fit_family(local, &proxy), true)?.connect(proxy),
+                )
+                .await??;
                 stream.set_nodelay(true).ok();
                 let stream = if username.trim().is_empty() {
                     super::timeout(
@@ -143,6 +183,54 @@ impl FramedStream {
         bail!("could not resolve to any address");
     }
 
+    /// Tunnels to the target through an HTTP proxy with CONNECT, basic auth if a username is given.
+    pub async fn connect_http<'t, T1, T2>(
+        proxy: &str,
+        target: T1,
+        local: T2,
+        username: &str,
+        password: &str,
+        ms_timeout: u64,
+    ) -> ResultType<Self>
+    where
+        T1: IntoTargetAddr<'t>,
+        T2: ToSocketAddrs,
+    {
+        let target = match target.into_target_addr()? {
+            TargetAddr::Ip(addr) => addr.to_string(),
+            TargetAddr::Domain(host, port) => format!("{}:{}", host, port),
+        };
+        if let Some(local) = lookup_host(&local).await?.next() {
+            if let Some(proxy) = lookup_host(proxy).await?.next() {
+                let mut stream = super::timeout(
+                    ms_timeout,
+                    new_socket(fit_family(local, &proxy), true)?.connect(proxy),
+                )
+                .await??;
+                stream.set_nodelay(true).ok();
+                let mut request = format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n", target);
+                if !username.trim().is_empty() {
+                    let auth = base64::encode(
+                        format!("{}:{}", username, password),
+                        base64::Variant::Original,
+                    );
+                    request += &format!("Proxy-Authorization: Basic {}\r\n", auth);
+                }
+                request += "\r\n";
+                super::timeout(ms_timeout, stream.write_all(request.as_bytes())).await??;
+                let status = super::timeout(ms_timeout, read_http_status(&mut stream)).await??;
+                match status {
+                    200..=299 => {}
+                    407 => bail!("HTTP proxy authentication required"),
+                    _ => bail!("HTTP proxy refused to connect: {}", status),
+                }
+                let addr = stream.local_addr()?;
+                return Ok(Self::from(stream, addr));
+            };
+        };
+        bail!("could not resolve to any address");
+    }
+
     pub fn local_addr(&self) -> SocketAddr {
         self.1
     }
@@ -252,6 +340,32 @@ pub async fn new_listener<T: ToSocketAddrs>(addr: T, reuse: bool) -> ResultType<
     }
 }
 
+/// Listens on both IPv6 and IPv4 on the port, or IPv4 only without IPv6 on the system.
+pub async fn listen_any(port: u16, reuse: bool) -> ResultType<TcpListener> {
+    match new_dual_stack_listener(port, reuse) {
+        Ok(listener) => Ok(TcpListener::from_std(listener)?),
+        Err(err) => {
+            log::debug!("Failed to listen on ipv6: {}", err);
+            new_listener((Ipv4Addr::UNSPECIFIED, port), reuse).await
+        }
+    }
+}
+
+fn new_dual_stack_listener(port: u16, reuse: bool) -> io::Result<std::net::TcpListener> {
+    let socket = socket2::Socket::new(socket2::Domain::ipv6(), socket2::Type::stream(), None)?;
+    socket.set_only_v6(false)?;
+    if reuse {
+        #[cfg(unix)]
+        socket.set_reuse_port(true)?;
+        socket.set_reuse_address(true)?;
+    }
+    socket.bind(&SocketAddr::from((Ipv6Addr::UNSPECIFIED, port)).into())?;
+    socket.listen(DEFAULT_BACKLOG as _)?;
+    let listener = socket.into_tcp_listener();
+    listener.set_nonblocking(true)?;
+    Ok(listener)
+}
+
 impl Unpin for DynTcpStream {}
 
 impl AsyncRead for DynTcpStream {
@@ -283,3 +397,67 @@ impl AsyncWrite for DynTcpStream {
 }
 
 impl<R: AsyncRead + AsyncWrite + Unpin> TcpStreamTrait for R {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a proxy answering CONNECT with `response`, returns the request it got
+    async fn http_proxy(response: &'static str) -> (SocketAddr, tokio::task::JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let proxy = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            while !request.ends_with(b"\r\n\r\n") {
+                request.push(stream.read_u8().await.unwrap());
+            }
+            stream.write_all(response.as_bytes()).await.unwrap();
+            String::from_utf8(request).unwrap()
+        });
+        (addr, proxy)
+    }
+
+    #[tokio::test]
+    async fn test_read_http_status() {
+        let (addr, _) = http_proxy("HTTP/1.1 200 Connection established\r\n\r\nhello").await;
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(b"CONNECT\r\n\r\n").await.unwrap();
+        assert_eq!(read_http_status(&mut stream).await.unwrap(), 200);
+        // the data of the target right after the headers
+        let mut rest = String::new();
+        stream.read_to_string(&mut rest).await.unwrap();
+        assert_eq!(rest, "hello");
+    }
+
+    #[tokio::test]
+    async fn test_connect_http() {
+        let local = "0.0.0.0:0";
+        let target = "rs.example.com:21116";
+        let (addr, proxy) = http_proxy("HTTP/1.1 200 OK\r\nServer: test\r\n\r\n").await;
+        let proxy_addr = addr.to_string();
+        FramedStream::connect_http(&proxy_addr, target, local, "user", "pass", 1000)
+            .await
+            .unwrap();
+        let request = proxy.await.unwrap();
+        assert!(request.starts_with("CONNECT rs.example.com:21116 HTTP/1.1\r\n"));
+        assert!(request.contains("Proxy-Authorization: Basic dXNlcjpwYXNz\r\n"));
+
+        let (addr, proxy) = http_proxy("HTTP/1.1 407 Proxy Authentication Required\r\n\r\n").await;
+        let proxy_addr = addr.to_string();
+        let err = FramedStream::connect_http(&proxy_addr, target, local, "", "", 1000)
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(err.to_string(), "HTTP proxy authentication required");
+        assert!(!proxy.await.unwrap().contains("Proxy-Authorization"));
+
+        let (addr, _) = http_proxy("SSH-2.0-OpenSSH_8.9\r\n\r\n").await;
+        let proxy_addr = addr.to_string();
+        let err = FramedStream::connect_http(&proxy_addr, target, local, "", "", 1000)
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(err.to_string(), "Invalid HTTP proxy response");
+    }
+}
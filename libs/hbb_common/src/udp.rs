@@ -5,7 +5,7 @@ use futures::{SinkExt, StreamExt};
 use protobuf::Message;
 use socket2::{Domain, Socket, Type};
 use std::net::SocketAddr;
-use tokio::net::{ToSocketAddrs, UdpSocket};
+use tokio::net::{lookup_host, ToSocketAddrs, UdpSocket};
 use tokio_socks::{udp::Socks5UdpFramed, IntoTargetAddr, TargetAddr, ToProxyAddrs};
 use tokio_util::{codec::BytesCodec, udp::UdpFramed};
 
@@ -14,7 +14,7 @@ pub enum FramedSocket {
     ProxySocks(Socks5UdpFramed),
 }
 
-fn new_socket(addr: SocketAddr, reuse: bool) -> Result<Socket, std::io::Error> {
+pub(crate) fn new_socket(addr: SocketAddr, reuse: bool) -> Result<Socket, std::io::Error> {
     let socket = match addr {
         SocketAddr::V4(..) => Socket::new(Domain::ipv4(), Type::dgram(), None),
         SocketAddr::V6(..) => Socket::new(Domain::ipv6(), Type::dgram(), None),
@@ -31,6 +31,22 @@ fn new_socket(addr: SocketAddr, reuse: bool) -> Result<Socket, std::io::Error> {
     Ok(socket)
 }
 
+// domains are left to the proxy, but not to an HTTP one which carries no udp
+async fn resolve(
+    framed: &UdpFramed<BytesCodec>,
+    addr: TargetAddr<'_>,
+) -> ResultType<Option<SocketAddr>> {
+    match addr {
+        TargetAddr::Ip(addr) => Ok(Some(addr)),
+        TargetAddr::Domain(host, port) => {
+            let is_ipv4 = framed.get_ref().local_addr()?.is_ipv4();
+            Ok(lookup_host((host.as_ref(), port))
+                .await?
+                .find(|x| x.is_ipv4() == is_ipv4))
+        }
+    }
+}
+
 impl FramedSocket {
     pub async fn new<T: ToSocketAddrs>(addr: T) -> ResultType<Self> {
         let socket = UdpSocket::bind(addr).await?;
@@ -82,10 +98,11 @@ impl FramedSocket {
         let addr = addr.into_target_addr()?.to_owned();
         let send_data = Bytes::from(msg.write_to_bytes()?);
         let _ = match self {
-            Self::Direct(f) => match addr {
-                TargetAddr::Ip(addr) => f.send((send_data, addr)).await?,
-                _ => {}
-            },
+            Self::Direct(f) => {
+                if let Some(addr) = resolve(f, addr).await? {
+                    f.send((send_data, addr)).await?
+                }
+            }
             Self::ProxySocks(f) => f.send((send_data, addr)).await?,
         };
         Ok(())
@@ -101,10 +118,11 @@ impl FramedSocket {
         let addr = addr.into_target_addr()?.to_owned();
 
         let _ = match self {
-            Self::Direct(f) => match addr {
-                TargetAddr::Ip(addr) => f.send((Bytes::from(msg), addr)).await?,
-                _ => {}
-            },
+            Self::Direct(f) => {
+                if let Some(addr) = resolve(f, addr).await? {
+                    f.send((Bytes::from(msg), addr)).await?
+                }
+            }
             Self::ProxySocks(f) => f.send((Bytes::from(msg), addr)).await?,
         };
         Ok(())
@@ -78,11 +78,47 @@ extern "C" {
         width: c_int,
         height: c_int,
     ) -> c_int;
+
+    pub fn I420Scale(
+        src_y: *const u8,
+        src_stride_y: c_int,
+        src_u: *const u8,
+        src_stride_u: c_int,
+        src_v: *const u8,
+        src_stride_v: c_int,
+        src_width: c_int,
+        src_height: c_int,
+        dst_y: *mut u8,
+        dst_stride_y: c_int,
+        dst_u: *mut u8,
+        dst_stride_u: c_int,
+        dst_v: *mut u8,
+        dst_stride_v: c_int,
+        dst_width: c_int,
+        dst_height: c_int,
+        filtering: c_int,
+    ) -> c_int;
+
+    pub fn ARGBScale(
+        src_argb: *const u8,
+        src_stride_argb: c_int,
+        src_width: c_int,
+        src_height: c_int,
+        dst_argb: *mut u8,
+        dst_stride_argb: c_int,
+        dst_width: c_int,
+        dst_height: c_int,
+        filtering: c_int,
+    ) -> c_int;
 }
 
+// libyuv FilterMode
+const FILTER_BOX: c_int = 3;
+const FILTER_BILINEAR: c_int = 2;
+
 // https://github.com/webmproject/libvpx/blob/master/vpx/src/vpx_image.c
 #[inline]
-fn get_vpx_i420_stride(
+pub(crate) fn get_vpx_i420_stride(
     width: usize,
     height: usize,
     stride_align: usize,
@@ -155,6 +191,72 @@ pub fn bgra_to_i420(width: usize, height: usize, src: &[u8], dst: &mut Vec<u8>)
     }
 }
 
+/// Scale an I420 image with the layout of `bgra_to_i420`, box filtered for downscaling.
+pub fn i420_scale(
+    width: usize,
+    height: usize,
+    src: &[u8],
+    dst_width: usize,
+    dst_height: usize,
+    dst: &mut Vec<u8>,
+) {
+    let (_, _, src_stride_y, src_stride_uv, src_u, src_v) =
+        get_vpx_i420_stride(width, height, super::STRIDE_ALIGN);
+    let (_, h, dst_stride_y, dst_stride_uv, u, v) =
+        get_vpx_i420_stride(dst_width, dst_height, super::STRIDE_ALIGN);
+    let bps = 12;
+    dst.resize(h * dst_stride_y * bps / 8, 0);
+    let dst_y = dst.as_mut_ptr();
+    let dst_u = dst[u..].as_mut_ptr();
+    let dst_v = dst[v..].as_mut_ptr();
+    unsafe {
+        I420Scale(
+            src.as_ptr(),
+            src_stride_y as _,
+            src[src_u..].as_ptr(),
+            src_stride_uv as _,
+            src[src_v..].as_ptr(),
+            src_stride_uv as _,
+            width as _,
+            height as _,
+            dst_y,
+            dst_stride_y as _,
+            dst_u,
+            dst_stride_uv as _,
+            dst_v,
+            dst_stride_uv as _,
+            dst_width as _,
+            dst_height as _,
+            FILTER_BOX,
+        );
+    }
+}
+
+/// Scale a packed BGRA image, e.g. a downscaled frame back to the size of the display.
+pub fn bgra_scale(
+    width: usize,
+    height: usize,
+    src: &[u8],
+    dst_width: usize,
+    dst_height: usize,
+    dst: &mut Vec<u8>,
+) {
+    dst.resize(dst_width * dst_height * 4, 0);
+    unsafe {
+        ARGBScale(
+            src.as_ptr(),
+            (width * 4) as _,
+            width as _,
+            height as _,
+            dst.as_mut_ptr(),
+            (dst_width * 4) as _,
+            dst_width as _,
+            dst_height as _,
+            FILTER_BILINEAR,
+        );
+    }
+}
+
 pub unsafe fn nv12_to_i420(
     src_y: *const u8,
     src_stride_y: c_int,
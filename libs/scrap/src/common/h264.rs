@@ -0,0 +1,214 @@
+// https://github.com/ralfbiedert/openh264-rs
+
+use super::codec::{CodecFormat, DecoderApi, EncodedFrame, EncoderApi, Error, Result};
+use super::vpxcodec::Config;
+use openh264::{
+    decoder::Decoder,
+    encoder::{Encoder, EncoderConfig, FrameType},
+    formats::YUVSource,
+};
+use std::time::{Duration, Instant};
+
+// recreating the encoder costs a key frame, only for a large bitrate change and not too often
+const RECREATE_BITRATE_CHANGE: f32 = 0.25;
+const RECREATE_INTERVAL: Duration = Duration::from_secs(5);
+
+fn map_err(err: openh264::Error) -> Error {
+    Error::FailedCall(err.to_string())
+}
+
+fn need_recreate(bitrate: u32, new_bitrate: u32, since_created: Duration) -> bool {
+    let change = (new_bitrate as f32 - bitrate as f32).abs();
+    change > bitrate as f32 * RECREATE_BITRATE_CHANGE && since_created >= RECREATE_INTERVAL
+}
+
+pub struct H264Encoder {
+    encoder: Encoder,
+    config: Config,
+    created: Instant,
+}
+
+impl H264Encoder {
+    pub fn new(config: &Config) -> Result<Self> {
+        Ok(Self {
+            encoder: Self::create(config)?,
+            config: *config,
+            created: Instant::now(),
+        })
+    }
+
+    fn recreate(&mut self) -> Result<()> {
+        self.encoder = Self::create(&self.config)?;
+        self.created = Instant::now();
+        Ok(())
+    }
+
+    fn create(config: &Config) -> Result<Encoder> {
+        let cfg = EncoderConfig::new(config.width, config.height)
+            .set_bitrate_bps(config.bitrate * 1000)
+            .enable_skip_frame(true);
+        Encoder::with_config(cfg).map_err(map_err)
+    }
+}
+
+// the planes of an I420 image laid out with STRIDE_ALIGN, see bgra_to_i420
+struct I420<'a> {
+    width: usize,
+    height: usize,
+    stride_y: usize,
+    stride_uv: usize,
+    y: &'a [u8],
+    u: &'a [u8],
+    v: &'a [u8],
+}
+
+impl<'a> I420<'a> {
+    fn new(width: usize, height: usize, data: &'a [u8]) -> Result<Self> {
+        let (_, _, stride_y, stride_uv, u, v) =
+            super::get_vpx_i420_stride(width, height, super::STRIDE_ALIGN);
+        let n_uv = stride_uv * ((height + 1) / 2);
+        if data.len() < v + n_uv {
+            return Err(Error::BadPtr(format!(
+                "I420 data too short: {} < {}",
+                data.len(),
+                v + n_uv
+            )));
+        }
+        Ok(Self {
+            width,
+            height,
+            stride_y,
+            stride_uv,
+            y: &data[..u],
+            u: &data[u..v],
+            v: &data[v..v + n_uv],
+        })
+    }
+}
+
+impl<'a> YUVSource for I420<'a> {
+    fn width(&self) -> i32 {
+        self.width as _
+    }
+
+    fn height(&self) -> i32 {
+        self.height as _
+    }
+
+    fn y(&self) -> &[u8] {
+        self.y
+    }
+
+    fn u(&self) -> &[u8] {
+        self.u
+    }
+
+    fn v(&self) -> &[u8] {
+        self.v
+    }
+
+    fn y_stride(&self) -> i32 {
+        self.stride_y as _
+    }
+
+    fn u_stride(&self) -> i32 {
+        self.stride_uv as _
+    }
+
+    fn v_stride(&self) -> i32 {
+        self.stride_uv as _
+    }
+}
+
+impl EncoderApi for H264Encoder {
+    fn format(&self) -> CodecFormat {
+        CodecFormat::H264
+    }
+
+    fn encode(&mut self, pts: i64, data: &[u8]) -> Result<Vec<EncodedFrame>> {
+        let yuv = I420::new(self.config.width as _, self.config.height as _, data)?;
+        let stream = self.encoder.encode(&yuv).map_err(map_err)?;
+        let key = match stream.frame_type() {
+            FrameType::Skip | FrameType::Invalid => return Ok(Vec::new()),
+            FrameType::IDR | FrameType::I => true,
+            _ => false,
+        };
+        Ok(vec![EncodedFrame {
+            data: stream.to_vec(),
+            key,
+            pts,
+        }])
+    }
+
+    // openh264-rs can not reconfigure a running encoder, the new one starts with a key frame,
+    // a small change is ignored until the drift from the encoder bitrate adds up
+    fn set_quality(&mut self, bitrate: u32, _: u32, _: u32) -> Result<()> {
+        if need_recreate(self.config.bitrate, bitrate, self.created.elapsed()) {
+            self.config.bitrate = bitrate;
+            self.recreate()?;
+        }
+        Ok(())
+    }
+
+    fn force_key_frame(&mut self) -> Result<()> {
+        self.recreate()
+    }
+}
+
+pub struct H264Decoder {
+    decoder: Decoder,
+}
+
+impl H264Decoder {
+    pub fn new() -> Result<Self> {
+        Ok(Self {
+            decoder: Decoder::new().map_err(map_err)?,
+        })
+    }
+}
+
+impl DecoderApi for H264Decoder {
+    fn decode(&mut self, frames: &[&[u8]], rgb: &mut Vec<u8>) -> Result<bool> {
+        let mut got = false;
+        for data in frames {
+            if let Some(yuv) = self.decoder.decode(data).map_err(map_err)? {
+                let (w, h) = yuv.dimension_rgb();
+                let (stride_y, stride_u, stride_v) = yuv.strides_yuv();
+                rgb.resize(w * h * 4, 0);
+                unsafe {
+                    super::I420ToARGB(
+                        yuv.y_with_stride().as_ptr(),
+                        stride_y as _,
+                        yuv.u_with_stride().as_ptr(),
+                        stride_u as _,
+                        yuv.v_with_stride().as_ptr(),
+                        stride_v as _,
+                        rgb.as_mut_ptr(),
+                        (w * 4) as _,
+                        w as _,
+                        h as _,
+                    );
+                }
+                got = true;
+            }
+        }
+        Ok(got)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_need_recreate() {
+        let interval = RECREATE_INTERVAL;
+        assert!(!need_recreate(1000, 1000, interval));
+        assert!(!need_recreate(1000, 1200, interval));
+        assert!(!need_recreate(1000, 800, interval));
+        assert!(need_recreate(1000, 1300, interval));
+        assert!(need_recreate(1000, 700, interval));
+        // at most once per interval
+        assert!(!need_recreate(1000, 2000, interval / 2));
+    }
+}
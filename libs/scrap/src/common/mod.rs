@@ -1,4 +1,5 @@
 pub use self::codec::*;
+pub use self::vpxcodec::*;
 
 cfg_if! {
     if #[cfg(quartz)] {
@@ -26,7 +27,19 @@ cfg_if! {
 
 pub mod codec;
 mod convert;
+#[cfg(feature = "h264")]
+mod h264;
+pub mod vpxcodec;
 pub use self::convert::*;
 pub const STRIDE_ALIGN: usize = 16; // commonly used in libvpx vpx_img_alloc caller
 
+/// A changed area of a frame, in pixels relative to the frame.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DirtyRect {
+    pub x: usize,
+    pub y: usize,
+    pub w: usize,
+    pub h: usize,
+}
+
 mod vpx;
@@ -10,7 +10,11 @@ fn map_err<E: ToString>(err: E) -> io::Error {
 
 impl Capturer {
     pub fn new(display: Display, yuv: bool) -> io::Result<Capturer> {
-        let r = display.0.recorder(false).map_err(map_err)?;
+        let r = display.0.recorder(false).map_err(|err| {
+            // the kept portal session may be closed, ask again next time
+            pipewire::reset_capturables();
+            map_err(err)
+        })?;
         Ok(Capturer(display, r, yuv, Default::default()))
     }
 
@@ -24,12 +28,15 @@ impl Capturer {
 
     pub fn frame<'a>(&'a mut self, timeout_ms: u32) -> io::Result<Frame<'a>> {
         match self.1.capture(timeout_ms as _).map_err(map_err)? {
-            PixelProvider::BGR0(w, h, x) => Ok(Frame(if self.2 {
-                crate::common::bgra_to_i420(w as _, h as _, &x, &mut self.3);
-                &self.3[..]
-            } else {
-                x
-            })),
+            PixelProvider::BGR0(w, h, x) => Ok(Frame(
+                if self.2 {
+                    crate::common::bgra_to_i420(w as _, h as _, &x, &mut self.3);
+                    &self.3[..]
+                } else {
+                    x
+                },
+                &[],
+            )),
             PixelProvider::NONE => Err(std::io::ErrorKind::WouldBlock.into()),
             _ => Err(map_err("Invalid data")),
         }
@@ -1,5 +1,5 @@
-use crate::x11;
-use std::{io, ops};
+use crate::{x11, DirtyRect};
+use std::{io, ops, time::Duration};
 
 pub struct Capturer(x11::Capturer);
 
@@ -16,12 +16,26 @@ impl Capturer {
         self.0.display().rect().h as usize
     }
 
-    pub fn frame<'a>(&'a mut self, _timeout_ms: u32) -> io::Result<Frame<'a>> {
-        Ok(Frame(self.0.frame()))
+    pub fn frame<'a>(&'a mut self, timeout_ms: u32) -> io::Result<Frame<'a>> {
+        match self.0.frame() {
+            Some((data, dirty_rects)) => Ok(Frame(data, dirty_rects)),
+            None => {
+                // nothing changed, wait as the other capturers do for a new frame
+                std::thread::sleep(Duration::from_millis(timeout_ms as _));
+                Err(io::ErrorKind::WouldBlock.into())
+            }
+        }
     }
 }
 
-pub struct Frame<'a>(pub(crate) &'a [u8]);
+pub struct Frame<'a>(pub(crate) &'a [u8], pub(crate) &'a [DirtyRect]);
+
+impl<'a> Frame<'a> {
+    /// The areas changed since the last frame, empty if unknown.
+    pub fn dirty_rects(&self) -> &[DirtyRect] {
+        self.1
+    }
+}
 
 impl<'a> ops::Deref for Frame<'a> {
     type Target = [u8];
@@ -19,7 +19,17 @@ use gstreamer_app::AppSink;
 use super::capturable::PixelProvider;
 use super::capturable::{Capturable, Recorder};
 
-use super::pipewire_dbus::{OrgFreedesktopPortalRequestResponse, OrgFreedesktopPortalScreenCast};
+use super::pipewire_dbus::{
+    OrgFreedesktopPortalRemoteDesktop, OrgFreedesktopPortalRequestResponse,
+    OrgFreedesktopPortalScreenCast,
+};
+
+lazy_static::lazy_static! {
+    // the user is asked by the portal only once, the session is kept for later captures
+    static ref CAPTURABLES: Mutex<Option<Vec<PipeWireCapturable>>> = Default::default();
+    // held across the portal dialog instead of CAPTURABLES, which the input needs meanwhile
+    static ref REQUESTING: Mutex<()> = Default::default();
+}
 
 #[derive(Debug, Clone, Copy)]
 struct PwStreamInfo {
@@ -58,6 +68,8 @@ pub struct PipeWireCapturable {
     // connection needs to be kept alive for recording
     dbus_conn: Arc<SyncConnection>,
     fd: OwnedFd,
+    // RemoteDesktop session for input, None if not supported by the portal
+    session: Option<dbus::Path<'static>>,
     path: u64,
     source_type: u64,
     pub position: (i32, i32),
@@ -65,10 +77,16 @@ pub struct PipeWireCapturable {
 }
 
 impl PipeWireCapturable {
-    fn new(conn: Arc<SyncConnection>, fd: OwnedFd, stream: PwStreamInfo) -> Self {
+    fn new(
+        conn: Arc<SyncConnection>,
+        fd: OwnedFd,
+        session: Option<dbus::Path<'static>>,
+        stream: PwStreamInfo,
+    ) -> Self {
         Self {
             dbus_conn: conn,
             fd,
+            session,
             path: stream.path,
             source_type: stream.source_type,
             position: stream.position,
@@ -385,10 +403,17 @@ fn streams_from_response(response: OrgFreedesktopPortalRequestResponse) -> Vec<P
 
 static mut INIT: bool = false;
 
+type ScreenCast = (
+    SyncConnection,
+    OwnedFd,
+    Vec<PwStreamInfo>,
+    Option<dbus::Path<'static>>,
+);
+
 // mostly inspired by https://gitlab.gnome.org/snippets/19
-fn request_screen_cast(
-    capture_cursor: bool,
-) -> Result<(SyncConnection, OwnedFd, Vec<PwStreamInfo>), Box<dyn Error>> {
+// the screen cast is started with a RemoteDesktop session if the portal supports it,
+// so that input can be injected with the same permission
+fn request_screen_cast(capture_cursor: bool) -> Result<ScreenCast, Box<dyn Error>> {
     unsafe {
         if !INIT {
             gstreamer::init()?;
@@ -397,11 +422,17 @@ fn request_screen_cast(
     }
     let conn = SyncConnection::new_session()?;
     let portal = get_portal(&conn);
+    // keyboard | pointer
+    let remote_desktop = portal
+        .available_device_types()
+        .map_or(false, |x| x & 3 == 3);
     let mut args: PropMap = HashMap::new();
     let fd: Arc<Mutex<Option<OwnedFd>>> = Arc::new(Mutex::new(None));
     let fd_res = fd.clone();
     let streams: Arc<Mutex<Vec<PwStreamInfo>>> = Arc::new(Mutex::new(Vec::new()));
     let streams_res = streams.clone();
+    let session: Arc<Mutex<Option<dbus::Path<'static>>>> = Arc::new(Mutex::new(None));
+    let session_res = session.clone();
     let failure = Arc::new(AtomicBool::new(false));
     let failure_res = failure.clone();
     args.insert(
@@ -412,33 +443,16 @@ fn request_screen_cast(
         "handle_token".to_string(),
         Variant(Box::new("u1".to_string())),
     );
-    let path = portal.create_session(args)?;
+    let path = if remote_desktop {
+        OrgFreedesktopPortalRemoteDesktop::create_session(&portal, args)?
+    } else {
+        OrgFreedesktopPortalScreenCast::create_session(&portal, args)?
+    };
     handle_response(
         &conn,
         path,
         move |r: OrgFreedesktopPortalRequestResponse, c, _| {
-            let portal = get_portal(c);
-            let mut args: PropMap = HashMap::new();
-            args.insert(
-                "handle_token".to_string(),
-                Variant(Box::new("u2".to_string())),
-            );
-            // https://flatpak.github.io/xdg-desktop-portal/portal-docs.html#gdbus-method-org-freedesktop-portal-ScreenCast.SelectSources
-            args.insert("multiple".into(), Variant(Box::new(true)));
-            args.insert("types".into(), Variant(Box::new(1u32))); //| 2u32)));
-
-            let cursor_mode = if capture_cursor { 2u32 } else { 1u32 };
-            let plasma = std::env::var("DESKTOP_SESSION").map_or(false, |s| s.contains("plasma"));
-            if plasma && capture_cursor {
-                // Warn the user if capturing the cursor is tried on kde as this can crash
-                // kwin_wayland and tear down the plasma desktop, see:
-                // https://bugs.kde.org/show_bug.cgi?id=435042
-                warn!("You are attempting to capture the cursor under KDE Plasma, this may crash your \
-                    desktop, see https://bugs.kde.org/show_bug.cgi?id=435042 for details! \
-                    You have been warned.");
-            }
-            args.insert("cursor_mode".into(), Variant(Box::new(cursor_mode)));
-            let session: dbus::Path = r
+            let handle: dbus::Path<'static> = r
                 .results
                 .get("session_handle")
                 .ok_or_else(|| {
@@ -451,7 +465,27 @@ fn request_screen_cast(
                 .ok_or_else(|| DBusError("Failed to convert session_handle to string.".into()))?
                 .to_string()
                 .into();
-            let path = portal.select_sources(session.clone(), args)?;
+            if !remote_desktop {
+                return select_sources(
+                    c,
+                    handle,
+                    capture_cursor,
+                    false,
+                    fd.clone(),
+                    streams.clone(),
+                    failure.clone(),
+                );
+            }
+            session.lock().unwrap().replace(handle.clone());
+            let portal = get_portal(c);
+            let mut args: PropMap = HashMap::new();
+            args.insert(
+                "handle_token".to_string(),
+                Variant(Box::new("u4".to_string())),
+            );
+            // keyboard | pointer
+            args.insert("types".into(), Variant(Box::new(3u32)));
+            let path = portal.select_devices(handle.clone(), args)?;
             let fd = fd.clone();
             let streams = streams.clone();
             let failure = failure.clone();
@@ -460,36 +494,15 @@ fn request_screen_cast(
                 c,
                 path,
                 move |_: OrgFreedesktopPortalRequestResponse, c, _| {
-                    let portal = get_portal(c);
-                    let mut args: PropMap = HashMap::new();
-                    args.insert(
-                        "handle_token".to_string(),
-                        Variant(Box::new("u3".to_string())),
-                    );
-                    let path = portal.start(session.clone(), "", args)?;
-                    let session = session.clone();
-                    let fd = fd.clone();
-                    let streams = streams.clone();
-                    let failure = failure.clone();
-                    let failure_out = failure.clone();
-                    handle_response(
+                    select_sources(
                         c,
-                        path,
-                        move |r: OrgFreedesktopPortalRequestResponse, c, _| {
-                            streams
-                                .clone()
-                                .lock()
-                                .unwrap()
-                                .append(&mut streams_from_response(r));
-                            let portal = get_portal(c);
-                            fd.clone().lock().unwrap().replace(
-                                portal.open_pipe_wire_remote(session.clone(), HashMap::new())?,
-                            );
-                            Ok(())
-                        },
-                        failure_out,
-                    )?;
-                    Ok(())
+                        handle.clone(),
+                        capture_cursor,
+                        true,
+                        fd.clone(),
+                        streams.clone(),
+                        failure.clone(),
+                    )
                 },
                 failure_out,
             )?;
@@ -512,7 +525,8 @@ fn request_screen_cast(
     let fd_res = fd_res.lock().unwrap();
     let streams_res = streams_res.lock().unwrap();
     if fd_res.is_some() && !streams_res.is_empty() {
-        Ok((conn, fd_res.clone().unwrap(), streams_res.clone()))
+        let session = session_res.lock().unwrap().clone();
+        Ok((conn, fd_res.clone().unwrap(), streams_res.clone(), session))
     } else {
         Err(Box::new(DBusError(
             "Failed to obtain screen capture.".into(),
@@ -520,11 +534,166 @@ fn request_screen_cast(
     }
 }
 
+fn select_sources(
+    c: &SyncConnection,
+    session: dbus::Path<'static>,
+    capture_cursor: bool,
+    remote_desktop: bool,
+    fd: Arc<Mutex<Option<OwnedFd>>>,
+    streams: Arc<Mutex<Vec<PwStreamInfo>>>,
+    failure: Arc<AtomicBool>,
+) -> Result<(), Box<dyn Error>> {
+    let portal = get_portal(c);
+    let mut args: PropMap = HashMap::new();
+    args.insert(
+        "handle_token".to_string(),
+        Variant(Box::new("u2".to_string())),
+    );
+    // https://flatpak.github.io/xdg-desktop-portal/portal-docs.html#gdbus-method-org-freedesktop-portal-ScreenCast.SelectSources
+    args.insert("multiple".into(), Variant(Box::new(true)));
+    args.insert("types".into(), Variant(Box::new(1u32))); //| 2u32)));
+
+    let cursor_mode = if capture_cursor { 2u32 } else { 1u32 };
+    let plasma = std::env::var("DESKTOP_SESSION").map_or(false, |s| s.contains("plasma"));
+    if plasma && capture_cursor {
+        // Warn the user if capturing the cursor is tried on kde as this can crash
+        // kwin_wayland and tear down the plasma desktop, see:
+        // https://bugs.kde.org/show_bug.cgi?id=435042
+        warn!(
+            "You are attempting to capture the cursor under KDE Plasma, this may crash your \
+            desktop, see https://bugs.kde.org/show_bug.cgi?id=435042 for details! \
+            You have been warned."
+        );
+    }
+    args.insert("cursor_mode".into(), Variant(Box::new(cursor_mode)));
+    let path = portal.select_sources(session.clone(), args)?;
+    let failure_out = failure.clone();
+    handle_response(
+        c,
+        path,
+        move |_: OrgFreedesktopPortalRequestResponse, c, _| {
+            let portal = get_portal(c);
+            let mut args: PropMap = HashMap::new();
+            args.insert(
+                "handle_token".to_string(),
+                Variant(Box::new("u3".to_string())),
+            );
+            let path = if remote_desktop {
+                OrgFreedesktopPortalRemoteDesktop::start(&portal, session.clone(), "", args)?
+            } else {
+                OrgFreedesktopPortalScreenCast::start(&portal, session.clone(), "", args)?
+            };
+            let session = session.clone();
+            let fd = fd.clone();
+            let streams = streams.clone();
+            let failure_out = failure.clone();
+            handle_response(
+                c,
+                path,
+                move |r: OrgFreedesktopPortalRequestResponse, c, _| {
+                    streams
+                        .clone()
+                        .lock()
+                        .unwrap()
+                        .append(&mut streams_from_response(r));
+                    let portal = get_portal(c);
+                    fd.clone()
+                        .lock()
+                        .unwrap()
+                        .replace(portal.open_pipe_wire_remote(session.clone(), HashMap::new())?);
+                    Ok(())
+                },
+                failure_out,
+            )?;
+            Ok(())
+        },
+        failure_out,
+    )?;
+    Ok(())
+}
+
 pub fn get_capturables(capture_cursor: bool) -> Result<Vec<PipeWireCapturable>, Box<dyn Error>> {
-    let (conn, fd, streams) = request_screen_cast(capture_cursor)?;
+    let _requesting = REQUESTING.lock().unwrap();
+    if let Some(capturables) = CAPTURABLES.lock().unwrap().as_ref() {
+        return Ok(capturables.clone());
+    }
+    let (conn, fd, streams, session) = request_screen_cast(capture_cursor)?;
     let conn = Arc::new(conn);
-    Ok(streams
+    let capturables: Vec<PipeWireCapturable> = streams
         .into_iter()
-        .map(|s| PipeWireCapturable::new(conn.clone(), fd.clone(), s))
-        .collect())
+        .map(|s| PipeWireCapturable::new(conn.clone(), fd.clone(), session.clone(), s))
+        .collect();
+    CAPTURABLES.lock().unwrap().replace(capturables.clone());
+    Ok(capturables)
+}
+
+/// Drop the kept session, e.g. closed by the user, the portal asks again on the next capture.
+pub fn reset_capturables() {
+    CAPTURABLES.lock().unwrap().take();
+}
+
+fn with_remote_desktop<F>(f: F) -> Result<(), Box<dyn Error>>
+where
+    F: FnOnce(
+        &Proxy<&SyncConnection>,
+        dbus::Path<'static>,
+        &[PipeWireCapturable],
+    ) -> Result<(), dbus::Error>,
+{
+    let lock = CAPTURABLES.lock().unwrap();
+    let capturables = lock
+        .as_ref()
+        .ok_or_else(|| DBusError("No screen cast session".into()))?;
+    let first = capturables
+        .first()
+        .ok_or_else(|| DBusError("No screen cast session".into()))?;
+    let session = first
+        .session
+        .clone()
+        .ok_or_else(|| DBusError("RemoteDesktop is not supported by the portal".into()))?;
+    let portal = get_portal(&first.dbus_conn);
+    f(&portal, session, capturables)?;
+    Ok(())
+}
+
+/// Move the pointer to x, y of the desktop, relative to the stream containing it.
+pub fn mouse_move_to(x: i32, y: i32) -> Result<(), Box<dyn Error>> {
+    with_remote_desktop(|portal, session, capturables| {
+        let c = capturables
+            .iter()
+            .find(|c| {
+                x >= c.position.0
+                    && y >= c.position.1
+                    && x < c.position.0 + c.size.0 as i32
+                    && y < c.position.1 + c.size.1 as i32
+            })
+            .unwrap_or(&capturables[0]);
+        portal.notify_pointer_motion_absolute(
+            session,
+            HashMap::new(),
+            c.path as _,
+            (x - c.position.0) as _,
+            (y - c.position.1) as _,
+        )
+    })
+}
+
+/// button is a linux input event code, e.g. BTN_LEFT
+pub fn mouse_button(button: i32, down: bool) -> Result<(), Box<dyn Error>> {
+    with_remote_desktop(|portal, session, _| {
+        portal.notify_pointer_button(session, HashMap::new(), button, down as _)
+    })
+}
+
+/// axis 0 is vertical and 1 is horizontal
+pub fn mouse_scroll(axis: u32, steps: i32) -> Result<(), Box<dyn Error>> {
+    with_remote_desktop(|portal, session, _| {
+        portal.notify_pointer_axis_discrete(session, HashMap::new(), axis, steps)
+    })
+}
+
+pub fn key_keysym(keysym: i32, down: bool) -> Result<(), Box<dyn Error>> {
+    with_remote_desktop(|portal, session, _| {
+        portal.notify_keyboard_keysym(session, HashMap::new(), keysym, down as _)
+    })
 }
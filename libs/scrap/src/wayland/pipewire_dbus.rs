@@ -104,6 +104,156 @@ impl<'a, T: blocking::BlockingSender, C: ::std::ops::Deref<Target = T>>
     }
 }
 
+pub trait OrgFreedesktopPortalRemoteDesktop {
+    fn create_session(&self, options: arg::PropMap) -> Result<dbus::Path<'static>, dbus::Error>;
+    fn select_devices(
+        &self,
+        session_handle: dbus::Path,
+        options: arg::PropMap,
+    ) -> Result<dbus::Path<'static>, dbus::Error>;
+    fn start(
+        &self,
+        session_handle: dbus::Path,
+        parent_window: &str,
+        options: arg::PropMap,
+    ) -> Result<dbus::Path<'static>, dbus::Error>;
+    fn notify_pointer_motion_absolute(
+        &self,
+        session_handle: dbus::Path,
+        options: arg::PropMap,
+        stream: u32,
+        x: f64,
+        y: f64,
+    ) -> Result<(), dbus::Error>;
+    fn notify_pointer_button(
+        &self,
+        session_handle: dbus::Path,
+        options: arg::PropMap,
+        button: i32,
+        state: u32,
+    ) -> Result<(), dbus::Error>;
+    fn notify_pointer_axis_discrete(
+        &self,
+        session_handle: dbus::Path,
+        options: arg::PropMap,
+        axis: u32,
+        steps: i32,
+    ) -> Result<(), dbus::Error>;
+    fn notify_keyboard_keysym(
+        &self,
+        session_handle: dbus::Path,
+        options: arg::PropMap,
+        keysym: i32,
+        state: u32,
+    ) -> Result<(), dbus::Error>;
+    fn available_device_types(&self) -> Result<u32, dbus::Error>;
+}
+
+impl<'a, T: blocking::BlockingSender, C: ::std::ops::Deref<Target = T>>
+    OrgFreedesktopPortalRemoteDesktop for blocking::Proxy<'a, C>
+{
+    fn create_session(&self, options: arg::PropMap) -> Result<dbus::Path<'static>, dbus::Error> {
+        self.method_call(
+            "org.freedesktop.portal.RemoteDesktop",
+            "CreateSession",
+            (options,),
+        )
+        .map(|r: (dbus::Path<'static>,)| r.0)
+    }
+
+    fn select_devices(
+        &self,
+        session_handle: dbus::Path,
+        options: arg::PropMap,
+    ) -> Result<dbus::Path<'static>, dbus::Error> {
+        self.method_call(
+            "org.freedesktop.portal.RemoteDesktop",
+            "SelectDevices",
+            (session_handle, options),
+        )
+        .map(|r: (dbus::Path<'static>,)| r.0)
+    }
+
+    fn start(
+        &self,
+        session_handle: dbus::Path,
+        parent_window: &str,
+        options: arg::PropMap,
+    ) -> Result<dbus::Path<'static>, dbus::Error> {
+        self.method_call(
+            "org.freedesktop.portal.RemoteDesktop",
+            "Start",
+            (session_handle, parent_window, options),
+        )
+        .map(|r: (dbus::Path<'static>,)| r.0)
+    }
+
+    fn notify_pointer_motion_absolute(
+        &self,
+        session_handle: dbus::Path,
+        options: arg::PropMap,
+        stream: u32,
+        x: f64,
+        y: f64,
+    ) -> Result<(), dbus::Error> {
+        self.method_call(
+            "org.freedesktop.portal.RemoteDesktop",
+            "NotifyPointerMotionAbsolute",
+            (session_handle, options, stream, x, y),
+        )
+    }
+
+    fn notify_pointer_button(
+        &self,
+        session_handle: dbus::Path,
+        options: arg::PropMap,
+        button: i32,
+        state: u32,
+    ) -> Result<(), dbus::Error> {
+        self.method_call(
+            "org.freedesktop.portal.RemoteDesktop",
+            "NotifyPointerButton",
+            (session_handle, options, button, state),
+        )
+    }
+
+    fn notify_pointer_axis_discrete(
+        &self,
+        session_handle: dbus::Path,
+        options: arg::PropMap,
+        axis: u32,
+        steps: i32,
+    ) -> Result<(), dbus::Error> {
+        self.method_call(
+            "org.freedesktop.portal.RemoteDesktop",
+            "NotifyPointerAxisDiscrete",
+            (session_handle, options, axis, steps),
+        )
+    }
+
+    fn notify_keyboard_keysym(
+        &self,
+        session_handle: dbus::Path,
+        options: arg::PropMap,
+        keysym: i32,
+        state: u32,
+    ) -> Result<(), dbus::Error> {
+        self.method_call(
+            "org.freedesktop.portal.RemoteDesktop",
+            "NotifyKeyboardKeysym",
+            (session_handle, options, keysym, state),
+        )
+    }
+
+    fn available_device_types(&self) -> Result<u32, dbus::Error> {
+        <Self as blocking::stdintf::org_freedesktop_dbus::Properties>::get(
+            &self,
+            "org.freedesktop.portal.RemoteDesktop",
+            "AvailableDeviceTypes",
+        )
+    }
+}
+
 pub trait OrgFreedesktopPortalRequest {
     fn close(&self) -> Result<(), dbus::Error>;
 }
@@ -4,6 +4,7 @@ use libc;
 
 use super::ffi::*;
 use super::Display;
+use crate::DirtyRect;
 
 pub struct Capturer {
     display: Display,
@@ -14,6 +15,18 @@ pub struct Capturer {
     size: usize,
     use_yuv: bool,
     yuv: Vec<u8>,
+
+    // None if the server has no DAMAGE extension, the frames are compared then
+    damage: Option<Damage>,
+    last: Vec<u8>,
+    dirty_rects: Vec<DirtyRect>,
+}
+
+struct Damage {
+    id: xcb_damage_damage_t,
+    event_base: u8,
+    // the whole screen is changed before the first frame
+    first: bool,
 }
 
 impl Capturer {
@@ -60,6 +73,8 @@ impl Capturer {
             );
         }
 
+        let damage = unsafe { Damage::new(server, display.root()) };
+
         let c = Capturer {
             display,
             shmid,
@@ -68,6 +83,9 @@ impl Capturer {
             size,
             use_yuv,
             yuv: Vec::new(),
+            damage,
+            last: Vec::new(),
+            dirty_rects: Vec::new(),
         };
         Ok(c)
     }
@@ -97,21 +115,130 @@ impl Capturer {
         }
     }
 
-    pub fn frame<'b>(&'b mut self) -> &'b [u8] {
-        self.get_image();
+    /// Returns the image and the areas changed, None if nothing is changed since the last frame.
+    pub fn frame<'b>(&'b mut self) -> Option<(&'b [u8], &'b [DirtyRect])> {
+        if let Some(damage) = self.damage.as_mut() {
+            let server = self.display.server().raw();
+            let rect = self.display.rect();
+            self.dirty_rects = unsafe { damage.take(server, rect) };
+            if self.dirty_rects.is_empty() {
+                return None;
+            }
+            self.get_image();
+        } else {
+            self.get_image();
+            let w = self.display.w();
+            let data = unsafe { slice::from_raw_parts(self.buffer, self.size) };
+            match changed_rows(&self.last, data, w * 4) {
+                Some((y0, y1)) => {
+                    self.dirty_rects = vec![DirtyRect {
+                        x: 0,
+                        y: y0,
+                        w,
+                        h: y1 - y0,
+                    }];
+                    self.last.clear();
+                    self.last.extend_from_slice(data);
+                }
+                None => return None,
+            }
+        }
         let result = unsafe { slice::from_raw_parts(self.buffer, self.size) };
         if self.use_yuv {
             crate::common::bgra_to_i420(self.display.w(), self.display.h(), &result, &mut self.yuv);
-            &self.yuv[..]
+            Some((&self.yuv[..], &self.dirty_rects[..]))
         } else {
-            result
+            Some((result, &self.dirty_rects[..]))
+        }
+    }
+}
+
+// the range of the rows changed, all if the size is changed
+fn changed_rows(last: &[u8], cur: &[u8], stride: usize) -> Option<(usize, usize)> {
+    let rows = cur.len() / stride;
+    if last.len() != cur.len() {
+        return Some((0, rows));
+    }
+    let row = |i: usize| i * stride..(i + 1) * stride;
+    let y0 = (0..rows).find(|&i| last[row(i)] != cur[row(i)])?;
+    let y1 = (y0..rows).rev().find(|&i| last[row(i)] != cur[row(i)])?;
+    Some((y0, y1 + 1))
+}
+
+impl Damage {
+    unsafe fn new(server: *mut xcb_connection_t, root: xcb_window_t) -> Option<Damage> {
+        let ext = xcb_get_extension_data(server, &mut xcb_damage_id);
+        if ext.is_null() || (*ext).present == 0 {
+            return None;
+        }
+        let cookie = xcb_damage_query_version(server, 1, 1);
+        let reply = xcb_damage_query_version_reply(server, cookie, ptr::null_mut());
+        if reply.is_null() {
+            return None;
+        }
+        libc::free(reply as *mut _);
+        let id = xcb_generate_id(server);
+        xcb_damage_create(server, id, root, XCB_DAMAGE_REPORT_LEVEL_BOUNDING_BOX);
+        xcb_flush(server);
+        Some(Damage {
+            id,
+            event_base: (*ext).first_event,
+            first: true,
+        })
+    }
+
+    // the damaged areas inside rect since the last call, relative to rect
+    unsafe fn take(&mut self, server: *mut xcb_connection_t, rect: super::Rect) -> Vec<DirtyRect> {
+        let mut rects = Vec::new();
+        if self.first {
+            self.first = false;
+            rects.push(DirtyRect {
+                x: 0,
+                y: 0,
+                w: rect.w as _,
+                h: rect.h as _,
+            });
+        }
+        let mut damaged = false;
+        loop {
+            let event = xcb_poll_for_event(server);
+            if event.is_null() {
+                break;
+            }
+            if (*event).response_type & 0x7f == self.event_base + XCB_DAMAGE_NOTIFY {
+                damaged = true;
+                let area = (*(event as *const xcb_damage_notify_event_t)).area;
+                let x0 = (area.x as i32).max(rect.x as i32);
+                let y0 = (area.y as i32).max(rect.y as i32);
+                let x1 = (area.x as i32 + area.width as i32).min(rect.x as i32 + rect.w as i32);
+                let y1 = (area.y as i32 + area.height as i32).min(rect.y as i32 + rect.h as i32);
+                if x1 > x0 && y1 > y0 {
+                    rects.push(DirtyRect {
+                        x: (x0 - rect.x as i32) as _,
+                        y: (y0 - rect.y as i32) as _,
+                        w: (x1 - x0) as _,
+                        h: (y1 - y0) as _,
+                    });
+                }
+            }
+            libc::free(event as *mut _);
+        }
+        if damaged {
+            // repair all, otherwise no more events inside the bounding box,
+            // the image is got right after
+            xcb_damage_subtract(server, self.id, 0, 0);
+            xcb_flush(server);
         }
+        rects
     }
 }
 
 impl Drop for Capturer {
     fn drop(&mut self) {
         unsafe {
+            if let Some(damage) = self.damage.as_ref() {
+                xcb_damage_destroy(self.display.server().raw(), damage.id);
+            }
             // Detach segment from XCB.
             xcb_shm_detach(self.display.server().raw(), self.xcbid);
             // Detach segment from our space.
@@ -5,7 +5,10 @@ use libc::c_void;
 #[link(name = "xcb")]
 #[link(name = "xcb-shm")]
 #[link(name = "xcb-randr")]
+#[link(name = "xcb-damage")]
 extern "C" {
+    pub static mut xcb_damage_id: xcb_extension_t;
+
     pub fn xcb_connect(displayname: *const i8, screenp: *mut i32) -> *mut xcb_connection_t;
 
     pub fn xcb_disconnect(c: *mut xcb_connection_t);
@@ -65,9 +68,51 @@ extern "C" {
     ) -> xcb_randr_monitor_info_iterator_t;
 
     pub fn xcb_randr_monitor_info_next(i: *mut xcb_randr_monitor_info_iterator_t);
+
+    pub fn xcb_flush(c: *mut xcb_connection_t) -> i32;
+
+    pub fn xcb_poll_for_event(c: *mut xcb_connection_t) -> *mut xcb_generic_event_t;
+
+    pub fn xcb_get_extension_data(
+        c: *mut xcb_connection_t,
+        ext: *mut xcb_extension_t,
+    ) -> *const xcb_query_extension_reply_t;
+
+    pub fn xcb_damage_query_version(
+        c: *mut xcb_connection_t,
+        client_major_version: u32,
+        client_minor_version: u32,
+    ) -> xcb_damage_query_version_cookie_t;
+
+    pub fn xcb_damage_query_version_reply(
+        c: *mut xcb_connection_t,
+        cookie: xcb_damage_query_version_cookie_t,
+        e: *mut *mut xcb_generic_error_t,
+    ) -> *mut xcb_damage_query_version_reply_t;
+
+    pub fn xcb_damage_create(
+        c: *mut xcb_connection_t,
+        damage: xcb_damage_damage_t,
+        drawable: xcb_drawable_t,
+        level: u8,
+    ) -> xcb_void_cookie_t;
+
+    pub fn xcb_damage_destroy(
+        c: *mut xcb_connection_t,
+        damage: xcb_damage_damage_t,
+    ) -> xcb_void_cookie_t;
+
+    pub fn xcb_damage_subtract(
+        c: *mut xcb_connection_t,
+        damage: xcb_damage_damage_t,
+        repair: u32,
+        parts: u32,
+    ) -> xcb_void_cookie_t;
 }
 
 pub const XCB_IMAGE_FORMAT_Z_PIXMAP: u8 = 2;
+pub const XCB_DAMAGE_REPORT_LEVEL_BOUNDING_BOX: u8 = 2;
+pub const XCB_DAMAGE_NOTIFY: u8 = 0;
 
 pub type xcb_atom_t = u32;
 pub type xcb_connection_t = c_void;
@@ -78,6 +123,72 @@ pub type xcb_timestamp_t = u32;
 pub type xcb_colormap_t = u32;
 pub type xcb_shm_seg_t = u32;
 pub type xcb_drawable_t = u32;
+pub type xcb_damage_damage_t = u32;
+
+#[repr(C)]
+pub struct xcb_extension_t {
+    pub name: *const i8,
+    pub global_id: i32,
+}
+
+#[repr(C)]
+pub struct xcb_query_extension_reply_t {
+    pub response_type: u8,
+    pub pad0: u8,
+    pub sequence: u16,
+    pub length: u32,
+    pub present: u8,
+    pub major_opcode: u8,
+    pub first_event: u8,
+    pub first_error: u8,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct xcb_damage_query_version_cookie_t {
+    pub sequence: u32,
+}
+
+#[repr(C)]
+pub struct xcb_damage_query_version_reply_t {
+    pub response_type: u8,
+    pub pad0: u8,
+    pub sequence: u16,
+    pub length: u32,
+    pub major_version: u32,
+    pub minor_version: u32,
+    pub pad1: [u8; 16],
+}
+
+#[repr(C)]
+pub struct xcb_generic_event_t {
+    pub response_type: u8,
+    pub pad0: u8,
+    pub sequence: u16,
+    pub pad: [u32; 7],
+    pub full_sequence: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct xcb_rectangle_t {
+    pub x: i16,
+    pub y: i16,
+    pub width: u16,
+    pub height: u16,
+}
+
+#[repr(C)]
+pub struct xcb_damage_notify_event_t {
+    pub response_type: u8,
+    pub level: u8,
+    pub sequence: u16,
+    pub drawable: xcb_drawable_t,
+    pub damage: xcb_damage_damage_t,
+    pub timestamp: xcb_timestamp_t,
+    pub area: xcb_rectangle_t,
+    pub geometry: xcb_rectangle_t,
+}
 
 #[repr(C)]
 pub struct xcb_setup_t {
@@ -1,13 +1,35 @@
 use crate::client::*;
 use hbb_common::{
-    config::PeerConfig,
+    bail,
+    config::{PeerConfig, CONNECT_TIMEOUT},
+    fs,
+    futures::StreamExt,
     log,
     message_proto::*,
     protobuf::Message as _,
-    tokio::{self, sync::mpsc},
-    Stream,
+    rendezvous_proto::ConnType,
+    timeout,
+    tokio::{
+        self,
+        sync::mpsc,
+        time::{self, Duration, Instant},
+    },
+    ResultType, Stream,
 };
-use std::sync::{Arc, RwLock};
+use serde_json::{json, Value};
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc, RwLock,
+};
+
+const MILLI1: Duration = Duration::from_millis(1);
+const SEC1: Duration = Duration::from_secs(1);
+const SEC30: Duration = Duration::from_secs(30);
+
+/// Exit codes of `rustdesk cp`, `rustdesk record` and the remote file commands
+pub const EXIT_FAILED: i32 = 1;
+pub const EXIT_USAGE: i32 = 2;
+pub const EXIT_CONNECT_FAILED: i32 = 3;
 
 #[derive(Clone)]
 pub struct Session {
@@ -15,25 +37,59 @@ pub struct Session {
     lc: Arc<RwLock<LoginConfigHandler>>,
     sender: mpsc::UnboundedSender<Data>,
     password: String,
+    // the password is given on the command line, never read from the tty
+    no_prompt: bool,
+    password_sent: Arc<AtomicBool>,
+    conn_type: ConnType,
 }
 
 impl Session {
+    // valid after login
+    fn path_sep(&self) -> &'static str {
+        if self.lc.read().unwrap().info.platform == "Windows" {
+            "\\"
+        } else {
+            "/"
+        }
+    }
+
     pub fn new(id: &str, sender: mpsc::UnboundedSender<Data>) -> Self {
-        let mut password = "".to_owned();
-        if PeerConfig::load(id).password.is_empty() {
+        Self::new_with(id, sender, ConnType::PORT_FORWARD, None)
+    }
+
+    pub fn new_file_transfer(
+        id: &str,
+        sender: mpsc::UnboundedSender<Data>,
+        password: Option<String>,
+    ) -> Self {
+        Self::new_with(id, sender, ConnType::FILE_TRANSFER, password)
+    }
+
+    fn new_with(
+        id: &str,
+        sender: mpsc::UnboundedSender<Data>,
+        conn_type: ConnType,
+        password: Option<String>,
+    ) -> Self {
+        let no_prompt = password.is_some();
+        let mut password = password.unwrap_or_default();
+        if !no_prompt && PeerConfig::load(id).password.is_empty() {
             password = rpassword::read_password_from_tty(Some("Enter password: ")).unwrap();
         }
         let session = Self {
             id: id.to_owned(),
             sender,
             password,
+            no_prompt,
+            password_sent: Default::default(),
             lc: Default::default(),
+            conn_type,
         };
-        session
-            .lc
-            .write()
-            .unwrap()
-            .initialize(id.to_owned(), false, true);
+        session.lc.write().unwrap().initialize(
+            id.to_owned(),
+            conn_type == ConnType::FILE_TRANSFER,
+            conn_type == ConnType::PORT_FORWARD,
+        );
         session
     }
 }
@@ -42,10 +98,23 @@ impl Session {
 impl Interface for Session {
     fn msgbox(&self, msgtype: &str, title: &str, text: &str) {
         if msgtype == "input-password" {
+            self.password_sent.store(true, Ordering::SeqCst);
             self.sender
                 .send(Data::Login((self.password.clone(), true)))
                 .ok();
         } else if msgtype == "re-input-password" {
+            if self.no_prompt {
+                // the stored password is tried first, then the given one
+                if self.password_sent.swap(true, Ordering::SeqCst) {
+                    log::error!("{}", title);
+                    self.sender.send(Data::Close).ok();
+                } else {
+                    self.sender
+                        .send(Data::Login((self.password.clone(), true)))
+                        .ok();
+                }
+                return;
+            }
             log::error!("{}: {}", title, text);
             let pass = rpassword::read_password_from_tty(Some("Enter password: ")).unwrap();
             self.sender.send(Data::Login((pass, true))).ok();
@@ -92,3 +161,696 @@ pub async fn start_one_port_forward(id: String, port: i32, remote_host: String,
     }
     log::info!("port forward (:{}) exit", port);
 }
+
+// "id:path" on the peer, a single letter before ':' is a windows drive
+fn parse_remote(path: &str) -> Option<(String, String)> {
+    let i = path.find(':')?;
+    let id = &path[..i];
+    if id.len() < 2 || id.contains('/') || id.contains('\\') {
+        return None;
+    }
+    Some((id.to_owned(), path[i + 1..].to_owned()))
+}
+
+#[inline]
+fn base_name(path: &str) -> &str {
+    path.trim_end_matches(|c| c == '/' || c == '\\')
+        .rsplit(|c| c == '/' || c == '\\')
+        .next()
+        .unwrap_or("")
+}
+
+/// `rustdesk cp [id:]src [id:]dst`, exactly one of src and dst is on the peer.
+/// Like scp, the source is copied into the destination if it is an existing directory.
+#[tokio::main(flavor = "current_thread")]
+pub async fn copy(
+    src: String,
+    dst: String,
+    recursive: bool,
+    preserve_owner: bool,
+    password: Option<String>,
+) -> i32 {
+    let (id, is_download) = match (parse_remote(&src), parse_remote(&dst)) {
+        (Some((id, _)), None) => (id, true),
+        (None, Some((id, _))) => (id, false),
+        _ => {
+            log::error!("Exactly one of the source and the destination must be id:path");
+            return EXIT_USAGE;
+        }
+    };
+    let (session, mut stream) = match connect(&id, password).await {
+        Ok(x) => x,
+        Err(err) => {
+            log::error!("Failed to connect to {}: {}", id, err);
+            return EXIT_CONNECT_FAILED;
+        }
+    };
+    let sep = session.path_sep();
+    let res = if is_download {
+        let (_, path) = parse_remote(&src).unwrap_or_default();
+        download(&session, &mut stream, path, dst, recursive, preserve_owner).await
+    } else {
+        let (_, path) = parse_remote(&dst).unwrap_or_default();
+        upload(
+            &session,
+            &mut stream,
+            src,
+            path,
+            recursive,
+            preserve_owner,
+            sep,
+        )
+        .await
+    };
+    match res {
+        Ok(()) => 0,
+        Err(err) => {
+            log::error!("{}", err);
+            EXIT_FAILED
+        }
+    }
+}
+
+/// Remote file commands, the result is printed as json.
+pub enum FileCommand {
+    List,
+    MakeDir,
+    Remove { recursive: bool },
+    Stat,
+}
+
+/// Run a file command on `id:path`, `{"error": "..."}` is printed if failed.
+#[tokio::main(flavor = "current_thread")]
+pub async fn file_command(cmd: FileCommand, target: String, password: Option<String>) -> i32 {
+    let (id, path) = match parse_remote(&target) {
+        Some(x) => x,
+        None => {
+            log::error!("{} is not id:path", target);
+            return EXIT_USAGE;
+        }
+    };
+    let (session, mut stream) = match connect(&id, password).await {
+        Ok(x) => x,
+        Err(err) => {
+            log::error!("Failed to connect to {}: {}", id, err);
+            return EXIT_CONNECT_FAILED;
+        }
+    };
+    let res = match cmd {
+        FileCommand::List => list(&mut stream, &path).await,
+        FileCommand::MakeDir => make_dir(&mut stream, &path).await,
+        FileCommand::Remove { recursive } => {
+            remove(&mut stream, &path, recursive, session.path_sep()).await
+        }
+        FileCommand::Stat => stat(&mut stream, &path).await,
+    };
+    match res {
+        Ok(v) => {
+            println!("{}", v);
+            0
+        }
+        Err(err) => {
+            println!("{}", json!({ "error": err.to_string() }));
+            EXIT_FAILED
+        }
+    }
+}
+
+async fn connect(id: &str, password: Option<String>) -> ResultType<(Session, Stream)> {
+    connect_with(id, ConnType::FILE_TRANSFER, password).await
+}
+
+async fn connect_with(
+    id: &str,
+    conn_type: ConnType,
+    password: Option<String>,
+) -> ResultType<(Session, Stream)> {
+    crate::common::test_rendezvous_server();
+    crate::common::test_nat_type();
+    let (sender, mut receiver) = mpsc::unbounded_channel::<Data>();
+    let mut session = Session::new_with(id, sender, conn_type, password);
+    let stream = connect_and_login(&mut session, &mut receiver).await?;
+    Ok((session, stream))
+}
+
+async fn connect_and_login(
+    session: &mut Session,
+    receiver: &mut mpsc::UnboundedReceiver<Data>,
+) -> ResultType<Stream> {
+    let (mut stream, _, _) = Client::start(&session.id, session.conn_type).await?;
+    loop {
+        tokio::select! {
+            res = timeout(CONNECT_TIMEOUT, stream.next()) => match res {
+                Err(_) => {
+                    bail!("Timeout");
+                }
+                Ok(Some(Ok(bytes))) => {
+                    let msg_in = Message::parse_from_bytes(&bytes)?;
+                    match msg_in.union {
+                        Some(message::Union::hash(hash)) => {
+                            session.handle_hash(hash, &mut stream).await;
+                        }
+                        Some(message::Union::login_response(lr)) => match lr.union {
+                            Some(login_response::Union::error(err)) => {
+                                if !session.handle_login_error(&err) {
+                                    bail!(err);
+                                }
+                            }
+                            Some(login_response::Union::peer_info(pi)) => {
+                                session.handle_peer_info(pi);
+                                return Ok(stream);
+                            }
+                            _ => {}
+                        }
+                        Some(message::Union::test_delay(t)) => {
+                            session.handle_test_delay(t, &mut stream).await;
+                        }
+                        _ => {}
+                    }
+                }
+                _ => {
+                    bail!("Reset by the peer");
+                }
+            },
+            d = receiver.recv() => {
+                match d {
+                    Some(Data::Login((password, remember))) => {
+                        session.handle_login_from_ui(password, remember, &mut stream).await;
+                    }
+                    Some(Data::Close) => {
+                        bail!("Wrong Password");
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+}
+
+/// `rustdesk record <id> <file>`, the screen of the peer is recorded into a WebM file
+/// until Ctrl-C, the end of the duration or the end of the session.
+#[tokio::main(flavor = "current_thread")]
+pub async fn record(
+    id: String,
+    path: String,
+    duration: Option<u64>,
+    password: Option<String>,
+) -> i32 {
+    let (_session, mut stream) = match connect_with(&id, ConnType::DEFAULT_CONN, password).await {
+        Ok(x) => x,
+        Err(err) => {
+            log::error!("Failed to connect to {}: {}", id, err);
+            return EXIT_CONNECT_FAILED;
+        }
+    };
+    match record_stream(&mut stream, path.into(), duration).await {
+        Ok(()) => 0,
+        Err(err) => {
+            log::error!("{}", err);
+            EXIT_FAILED
+        }
+    }
+}
+
+async fn record_stream(
+    stream: &mut Stream,
+    path: std::path::PathBuf,
+    duration: Option<u64>,
+) -> ResultType<()> {
+    // only vp9 can be muxed without re-encoding
+    stream
+        .send(&LoginConfigHandler::set_decodings(vec![
+            scrap::CodecFormat::VP9,
+        ]))
+        .await?;
+    // only the current display is streamed
+    let mut recorder = crate::recorder::Recorder::new(path);
+    let end = duration.map(|x| Instant::now() + Duration::from_secs(x));
+    let mut timer = time::interval(SEC1);
+    let ctrl_c = tokio::signal::ctrl_c();
+    tokio::pin!(ctrl_c);
+    loop {
+        tokio::select! {
+            res = stream.next() => {
+                let bytes = match res {
+                    Some(Ok(bytes)) => bytes,
+                    Some(Err(err)) => {
+                        log::info!("Connection closed: {}", err);
+                        break;
+                    }
+                    None => {
+                        log::info!("Reset by the peer");
+                        break;
+                    }
+                };
+                let msg_in = Message::parse_from_bytes(&bytes)?;
+                match msg_in.union {
+                    Some(message::Union::test_delay(t)) => {
+                        handle_test_delay(t, stream).await;
+                    }
+                    Some(message::Union::video_frame(vf)) => {
+                        recorder.write_video(&vf)?;
+                    }
+                    Some(message::Union::audio_frame(frame)) => {
+                        recorder.write_audio(&frame);
+                    }
+                    Some(message::Union::misc(misc)) => match misc.union {
+                        Some(misc::Union::audio_format(f)) => {
+                            recorder.set_audio_format(f);
+                        }
+                        Some(misc::Union::close_reason(c)) => {
+                            log::info!("Connection closed: {}", c);
+                            break;
+                        }
+                        _ => {}
+                    },
+                    _ => {}
+                }
+            }
+            _ = &mut ctrl_c => {
+                break;
+            }
+            _ = timer.tick() => {
+                if end.map(|x| Instant::now() >= x).unwrap_or(false) {
+                    break;
+                }
+            }
+        }
+    }
+    log::info!("Recorded to {}", recorder.path().display());
+    Ok(())
+}
+
+// the next file response, test delay is answered to keep the connection alive
+async fn next_file_response(stream: &mut Stream) -> ResultType<file_response::Union> {
+    loop {
+        match timeout(SEC30.as_millis() as _, stream.next()).await {
+            Err(_) => bail!("Timeout"),
+            Ok(Some(Ok(bytes))) => {
+                let msg_in = Message::parse_from_bytes(&bytes)?;
+                match msg_in.union {
+                    Some(message::Union::test_delay(t)) => {
+                        handle_test_delay(t, stream).await;
+                    }
+                    Some(message::Union::file_response(FileResponse {
+                        union: Some(fr), ..
+                    })) => {
+                        return Ok(fr);
+                    }
+                    _ => {}
+                }
+            }
+            Ok(Some(Err(err))) => bail!("Connection closed: {}", err),
+            Ok(None) => bail!("Reset by the peer"),
+        }
+    }
+}
+
+// all files under the path on the peer, a single file has an empty name
+async fn remote_files(stream: &mut Stream, id: i32, path: &str) -> ResultType<Vec<FileEntry>> {
+    let mut msg_out = Message::new();
+    let mut action = FileAction::new();
+    action.set_all_files(ReadAllFiles {
+        id,
+        path: path.to_owned(),
+        include_hidden: true,
+        ..Default::default()
+    });
+    msg_out.set_file_action(action);
+    stream.send(&msg_out).await?;
+    loop {
+        match next_file_response(stream).await? {
+            file_response::Union::dir(fd) if fd.id == id => return Ok(fd.entries.to_vec()),
+            file_response::Union::error(e) if e.id == id => bail!(e.error),
+            _ => {}
+        }
+    }
+}
+
+#[inline]
+// a link to a directory is a directory to copy
+fn is_single_file(files: &[FileEntry]) -> bool {
+    is_single_entry(files) && files[0].entry_type.enum_value() != Ok(FileType::DirLink)
+}
+
+// the path itself is a file or a link
+fn is_single_entry(files: &[FileEntry]) -> bool {
+    files.len() == 1 && files[0].name.is_empty()
+}
+
+struct Progress {
+    last: Instant,
+    last_transferred: u64,
+}
+
+impl Progress {
+    fn new() -> Self {
+        Self {
+            last: Instant::now(),
+            last_transferred: 0,
+        }
+    }
+
+    fn update(&mut self, job: &fs::TransferJob, force: bool) {
+        let elapsed = self.last.elapsed();
+        if elapsed < SEC1 && !force {
+            return;
+        }
+        let total = std::cmp::max(job.total_size(), 1);
+        let speed = (job.transferred() - self.last_transferred) as f64 / elapsed.as_secs_f64();
+        eprint!(
+            "\r{:>3}% {}/{} files {:.1} KB/s   ",
+            job.finished_size() * 100 / total,
+            std::cmp::min(job.file_num() + 1, job.files().len() as i32),
+            job.files().len(),
+            speed / 1024.
+        );
+        self.last = Instant::now();
+        self.last_transferred = job.transferred();
+    }
+}
+
+async fn download(
+    session: &Session,
+    stream: &mut Stream,
+    path: String,
+    to: String,
+    recursive: bool,
+    preserve_owner: bool,
+) -> ResultType<()> {
+    const ID: i32 = 1;
+    let files = remote_files(stream, ID, &path).await?;
+    if !is_single_file(&files) && !recursive {
+        bail!("{} is a directory, use -r", path);
+    }
+    let mut to = fs::get_path(&to);
+    if to.is_dir() {
+        to = to.join(base_name(&path));
+    }
+    let to = fs::get_string(&to);
+    log::info!("Copy {}:{} to {}", session.id, path, to);
+    let features = session.lc.read().unwrap().file_transfer_features.clone();
+    let offsets = if features.contains(&FileTransferFeature::Resume) {
+        fs::get_download_offsets(to.clone()).await
+    } else {
+        Vec::new()
+    };
+    let policy = FileOverwritePolicy::Overwrite;
+    let mut job = fs::TransferJob::new_write(ID, to, Vec::new());
+    job.set_per_file_done(features.contains(&FileTransferFeature::PerFileDone));
+    job.set_preserve_owner(preserve_owner);
+    stream
+        .send(&fs::new_send(ID, path, true, offsets, policy))
+        .await?;
+    let mut progress = Progress::new();
+    let mut failed = 0;
+    loop {
+        match next_file_response(stream).await? {
+            file_response::Union::dir(fd) if fd.id == ID => {
+                job.set_files(fd.entries.to_vec());
+            }
+            file_response::Union::block(block) if block.id == ID => {
+                job.write(block, None).await?;
+                progress.update(&job, false);
+            }
+            file_response::Union::done(d) if d.id == ID => {
+                if (d.file_num as usize) < job.files().len() {
+                    if let Err(err) = job.modify_time(d.file_num, &d.digest).await {
+                        eprintln!();
+                        log::error!("{}", err);
+                        failed += 1;
+                    }
+                    continue;
+                }
+                job.finish().await?;
+                break;
+            }
+            file_response::Union::error(e) if e.id == ID => {
+                if job.files().is_empty() {
+                    bail!(e.error);
+                }
+                eprintln!();
+                log::error!("{}", e.error);
+                failed += 1;
+            }
+            _ => {}
+        }
+    }
+    progress.update(&job, true);
+    eprintln!();
+    if failed > 0 {
+        bail!("{} of {} files failed", failed, job.files().len());
+    }
+    Ok(())
+}
+
+async fn upload(
+    session: &Session,
+    stream: &mut Stream,
+    path: String,
+    to: String,
+    recursive: bool,
+    preserve_owner: bool,
+    sep: &str,
+) -> ResultType<()> {
+    const ID: i32 = 1;
+    if fs::get_path(&path).is_dir() && !recursive {
+        bail!("{} is a directory, use -r", path);
+    }
+    let mut to = to;
+    if let Ok(files) = remote_files(stream, ID, &to).await {
+        if !is_single_file(&files) {
+            to = format!(
+                "{}{}{}",
+                to.trim_end_matches(|c| c == '/' || c == '\\'),
+                sep,
+                base_name(&path)
+            );
+        }
+    }
+    log::info!("Copy {} to {}:{}", path, session.id, to);
+    let policy = FileOverwritePolicy::Overwrite;
+    let features = session.lc.read().unwrap().file_transfer_features.clone();
+    let mut job = fs::TransferJob::new_read(ID, path, true)?;
+    job.set_policy(policy);
+    job.set_per_file_done(features.contains(&FileTransferFeature::PerFileDone));
+    stream
+        .send(&fs::new_receive(
+            ID,
+            to,
+            job.files().clone(),
+            policy,
+            preserve_owner,
+        ))
+        .await?;
+    // the peer replies the existing .download sizes to resume from, an old one does not
+    let resume = features.contains(&FileTransferFeature::Resume);
+    while resume {
+        match next_file_response(stream).await? {
+            file_response::Union::dir(fd) if fd.id == ID => {
+                job.set_offsets(&fd.entries);
+                break;
+            }
+            file_response::Union::error(e) if e.id == ID => bail!(e.error),
+            _ => {}
+        }
+    }
+    job.load_prefix_digests().await;
+    let n = job.files().len();
+    let mut jobs = vec![job];
+    let mut scheduler = fs::JobScheduler::new(session.lc.read().unwrap().get_speed_limit());
+    let mut progress = Progress::new();
+    let mut timer = time::interval(MILLI1);
+    let mut last_recv_time = Instant::now();
+    let mut failed = 0;
+    loop {
+        tokio::select! {
+            res = stream.next() => {
+                last_recv_time = Instant::now();
+                let bytes = match res {
+                    Some(Ok(bytes)) => bytes,
+                    Some(Err(err)) => bail!("Connection closed: {}", err),
+                    None => bail!("Reset by the peer"),
+                };
+                let msg_in = Message::parse_from_bytes(&bytes)?;
+                match msg_in.union {
+                    Some(message::Union::test_delay(t)) => {
+                        handle_test_delay(t, stream).await;
+                    }
+                    Some(message::Union::file_response(fr)) => match fr.union {
+                        Some(file_response::Union::done(d)) if d.id == ID => {
+                            // the peer has written all files
+                            break;
+                        }
+                        Some(file_response::Union::error(e)) if e.id == ID => {
+                            eprintln!();
+                            log::error!("{}", e.error);
+                            failed += 1;
+                        }
+                        _ => {}
+                    },
+                    _ => {}
+                }
+            }
+            _ = timer.tick() => {
+                if last_recv_time.elapsed() >= SEC30 {
+                    bail!("Timeout");
+                }
+                if let Some(job) = jobs.first() {
+                    progress.update(job, false);
+                    fs::handle_read_jobs(&mut jobs, stream, &mut scheduler).await?;
+                    if jobs.is_empty() {
+                        timer = time::interval(SEC1);
+                    }
+                }
+            }
+        }
+    }
+    eprintln!();
+    if failed > 0 {
+        bail!("{} of {} files failed", failed, n);
+    }
+    Ok(())
+}
+
+#[inline]
+fn new_file_action(action: file_action::Union) -> Message {
+    let mut msg_out = Message::new();
+    let mut file_action = FileAction::new();
+    file_action.union = Some(action);
+    msg_out.set_file_action(file_action);
+    msg_out
+}
+
+// wait for the done of a command, or its error
+async fn wait_done(stream: &mut Stream, id: i32) -> ResultType<()> {
+    loop {
+        match next_file_response(stream).await? {
+            file_response::Union::done(d) if d.id == id => return Ok(()),
+            file_response::Union::error(e) if e.id == id => bail!(e.error),
+            _ => {}
+        }
+    }
+}
+
+fn entry_to_json(entry: &FileEntry) -> Value {
+    let entry_type = match entry.entry_type.enum_value() {
+        Ok(FileType::Dir) => "dir",
+        Ok(FileType::DirLink) => "dir_link",
+        Ok(FileType::DirDrive) => "drive",
+        Ok(FileType::FileLink) => "file_link",
+        _ => "file",
+    };
+    json!({
+        "name": entry.name,
+        "type": entry_type,
+        "size": entry.size,
+        "modified_time": entry.modified_time,
+        "is_hidden": entry.is_hidden,
+        "mode": entry.mode,
+    })
+}
+
+async fn list(stream: &mut Stream, path: &str) -> ResultType<Value> {
+    const ID: i32 = 1;
+    stream
+        .send(&new_file_action(file_action::Union::read_dir(ReadDir {
+            id: ID,
+            path: path.to_owned(),
+            include_hidden: true,
+            ..Default::default()
+        })))
+        .await?;
+    loop {
+        match next_file_response(stream).await? {
+            file_response::Union::dir(fd) if fd.id == ID => {
+                let entries: Vec<Value> = fd.entries.iter().map(entry_to_json).collect();
+                return Ok(json!({ "path": fd.path, "entries": entries }));
+            }
+            file_response::Union::error(e) if e.id == ID => bail!(e.error),
+            _ => {}
+        }
+    }
+}
+
+async fn make_dir(stream: &mut Stream, path: &str) -> ResultType<Value> {
+    const ID: i32 = 1;
+    stream
+        .send(&new_file_action(file_action::Union::create(
+            FileDirCreate {
+                id: ID,
+                path: path.to_owned(),
+                ..Default::default()
+            },
+        )))
+        .await?;
+    wait_done(stream, ID).await?;
+    Ok(json!({ "path": path }))
+}
+
+// like the remote ui, remove the files one by one, then the empty directories
+async fn remove(stream: &mut Stream, path: &str, recursive: bool, sep: &str) -> ResultType<Value> {
+    const ID: i32 = 1;
+    let files = remote_files(stream, ID, path).await?;
+    // a link is removed itself, not what it points to
+    if is_single_entry(&files) {
+        stream
+            .send(&new_file_action(file_action::Union::remove_file(
+                FileRemoveFile {
+                    id: ID,
+                    path: path.to_owned(),
+                    file_num: 0,
+                    ..Default::default()
+                },
+            )))
+            .await?;
+        wait_done(stream, ID).await?;
+        return Ok(json!({ "path": path, "removed": 1 }));
+    }
+    if !recursive && !files.is_empty() {
+        bail!("{} is not empty, use -r", path);
+    }
+    let dir = path.trim_end_matches(|c| c == '/' || c == '\\');
+    for (i, entry) in files.iter().enumerate() {
+        stream
+            .send(&new_file_action(file_action::Union::remove_file(
+                FileRemoveFile {
+                    id: ID,
+                    path: format!("{}{}{}", dir, sep, entry.name),
+                    file_num: i as _,
+                    ..Default::default()
+                },
+            )))
+            .await?;
+        wait_done(stream, ID).await?;
+    }
+    stream
+        .send(&new_file_action(file_action::Union::remove_dir(
+            FileRemoveDir {
+                id: ID,
+                path: path.to_owned(),
+                recursive,
+                ..Default::default()
+            },
+        )))
+        .await?;
+    wait_done(stream, ID).await?;
+    Ok(json!({ "path": path, "removed": files.len() }))
+}
+
+// a directory is reported with the number and the total size of its files
+async fn stat(stream: &mut Stream, path: &str) -> ResultType<Value> {
+    const ID: i32 = 1;
+    let files = remote_files(stream, ID, path).await?;
+    if is_single_entry(&files) {
+        let mut v = entry_to_json(&files[0]);
+        v["name"] = json!(path);
+        return Ok(v);
+    }
+    Ok(json!({
+        "name": path,
+        "type": "dir",
+        "files": files.len(),
+        "size": files.iter().map(|x| x.size).sum::<u64>(),
+    }))
+}
@@ -9,7 +9,7 @@ use hbb_common::{
     anyhow::{anyhow, Context},
     bail,
     config::{Config, PeerConfig, PeerInfoSerde, CONNECT_TIMEOUT, RELAY_PORT, RENDEZVOUS_TIMEOUT},
-    log,
+    fs, log,
     message_proto::{option_message::BoolOption, *},
     protobuf::Message as _,
     rendezvous_proto::*,
@@ -19,8 +19,10 @@ use hbb_common::{
     tokio::time::Duration,
     AddrMangle, ResultType, Stream,
 };
+#[cfg(not(any(target_os = "android")))]
+use magnum_opus::Encoder as AudioEncoder;
 use magnum_opus::{Channels::*, Decoder as AudioDecoder};
-use scrap::{Decoder, Image, VideoCodecId};
+use scrap::{codec::DecoderApi, CodecFormat};
 use sha2::{Digest, Sha256};
 use std::{
     collections::HashMap,
@@ -31,6 +33,12 @@ use std::{
 use uuid::Uuid;
 
 pub const SEC30: Duration = Duration::from_secs(30);
+// a key frame requested is not requested again before
+const KEY_FRAME_REQUEST_INTERVAL: Duration = Duration::from_secs(1);
+// the width and height limit of the frames of a display not in PeerInfo
+const MAX_FRAME_SIDE: usize = 16384;
+// QUIC is given up for TCP after, in ms, e.g. if udp is blocked on the way
+const QUIC_CONNECT_TIMEOUT: u64 = 1_000;
 
 pub struct Client;
 
@@ -102,7 +110,8 @@ impl Drop for OboePlayer {
 }
 
 impl Client {
-    pub async fn start(peer: &str, conn_type: ConnType) -> ResultType<(Stream, bool)> {
+    /// Returns the stream, whether it is direct and whether it is over QUIC.
+    pub async fn start(peer: &str, conn_type: ConnType) -> ResultType<(Stream, bool, bool)> {
         match Self::_start(peer, conn_type).await {
             Err(err) => {
                 let err_str = err.to_string();
@@ -116,19 +125,18 @@ impl Client {
         }
     }
 
-    async fn _start(peer: &str, conn_type: ConnType) -> ResultType<(Stream, bool)> {
+    async fn _start(peer: &str, conn_type: ConnType) -> ResultType<(Stream, bool, bool)> {
         // to-do: remember the port for each peer, so that we can retry easier
-        let any_addr = Config::get_any_listen_addr();
+        let any_addr = Config::get_any_listen_addr(true);
         if crate::is_ip(peer) {
-            return Ok((
-                socket_client::connect_tcp(
-                    crate::check_port(peer, RELAY_PORT + 1),
-                    any_addr,
-                    RENDEZVOUS_TIMEOUT,
-                )
-                .await?,
-                true,
-            ));
+            let mut conn = socket_client::connect_tcp(
+                crate::check_port(peer, RELAY_PORT + 1),
+                any_addr,
+                RENDEZVOUS_TIMEOUT,
+            )
+            .await?;
+            Self::secure_direct_connection(peer, &mut conn).await?;
+            return Ok((conn, true, false));
         }
         let rendezvous_server = crate::get_rendezvous_server(1_000).await;
         log::info!("rendezvous server: {}", rendezvous_server);
@@ -144,6 +152,7 @@ impl Client {
         let mut peer_nat_type = NatType::UNKNOWN_NAT;
         let my_nat_type = crate::get_nat_type(100).await;
         let mut is_local = false;
+        let mut peer_quic = false;
         for i in 1..=3 {
             log::info!("#{} punch attempt with {}, id: {}", i, my_addr, peer);
             let mut msg_out = RendezvousMessage::new();
@@ -181,6 +190,7 @@ impl Client {
                             } else {
                                 peer_nat_type = ph.get_nat_type();
                                 is_local = ph.get_is_local();
+                                peer_quic = ph.quic;
                                 signed_id_pk = ph.pk;
                                 relay_server = ph.relay_server;
                                 peer_addr = AddrMangle::decode(&ph.socket_addr);
@@ -198,8 +208,14 @@ impl Client {
                             let mut conn =
                                 Self::create_relay(peer, rr.uuid, rr.relay_server, conn_type)
                                     .await?;
-                            Self::secure_connection(peer, signed_id_pk, &mut conn).await?;
-                            return Ok((conn, false));
+                            Self::secure_connection(
+                                peer,
+                                signed_id_pk,
+                                &rendezvous_server,
+                                &mut conn,
+                            )
+                            .await?;
+                            return Ok((conn, false, false));
                         }
                         _ => {
                             log::error!("Unexpected protobuf msg received: {:?}", msg_in);
@@ -236,6 +252,7 @@ impl Client {
             peer_nat_type,
             my_nat_type,
             is_local,
+            peer_quic,
             conn_type,
         )
         .await
@@ -252,8 +269,9 @@ impl Client {
         peer_nat_type: NatType,
         my_nat_type: i32,
         is_local: bool,
+        peer_quic: bool,
         conn_type: ConnType,
-    ) -> ResultType<(Stream, bool)> {
+    ) -> ResultType<(Stream, bool, bool)> {
         let direct_failures = PeerConfig::load(peer_id).direct_failures;
         let mut connect_timeout = 0;
         const MIN: u64 = 1000;
@@ -288,8 +306,17 @@ impl Client {
         }
         log::info!("peer address: {}, timeout: {}", peer, connect_timeout);
         let start = std::time::Instant::now();
-        // NOTICE: Socks5 is be used event in intranet. Which may be not a good way.
-        let mut conn = socket_client::connect_tcp(peer, local_addr, connect_timeout).await;
+        // QUIC over the udp hole punched on the same port first
+        let mut conn = match get_quic_timeout(peer_quic, connect_timeout) {
+            Some(ms_timeout) => socket_client::connect_quic(peer, local_addr, ms_timeout).await,
+            None => Err(anyhow!("QUIC is not supported by the peer")),
+        };
+        let quic = conn.is_ok();
+        if let Err(err) = &conn {
+            log::info!("Failed to connect with QUIC: {}", err);
+            // NOTICE: Socks5 is be used event in intranet. Which may be not a good way.
+            conn = socket_client::connect_tcp(peer, local_addr, connect_timeout).await;
+        }
         let direct = !conn.is_err();
         if conn.is_err() {
             if !relay_server.is_empty() {
@@ -318,18 +345,34 @@ impl Client {
             config.store(peer_id);
         }
         let mut conn = conn?;
-        log::info!("{:?} used to establish connection", start.elapsed());
-        Self::secure_connection(peer_id, signed_id_pk, &mut conn).await?;
-        Ok((conn, direct))
+        log::info!(
+            "{:?} used to establish {} connection",
+            start.elapsed(),
+            if quic { "QUIC" } else { "TCP" }
+        );
+        Self::secure_connection(peer_id, signed_id_pk, rendezvous_server, &mut conn).await?;
+        Ok((conn, direct, quic))
     }
 
-    async fn secure_connection(peer_id: &str, signed_id_pk: Vec<u8>, conn: &mut Stream) -> ResultType<()> {
-        let rs_pk = get_rs_pk("OeVuKk5nlHiXp+APNn0Y3pC1Iwpwn44JGqrQCsWqmBw=");
+    async fn secure_connection(
+        peer_id: &str,
+        signed_id_pk: Vec<u8>,
+        rendezvous_server: &str,
+        conn: &mut Stream,
+    ) -> ResultType<()> {
+        let rs_pks: Vec<sign::PublicKey> = Config::get_rendezvous_server_keys(rendezvous_server)
+            .iter()
+            .filter_map(|x| get_rs_pk(x))
+            .collect();
+        let require_encryption = !Config::get_option("require-encryption").is_empty();
         let mut sign_pk = None;
-        if !signed_id_pk.is_empty() && rs_pk.is_some() {
-            if let Ok((id, pk)) = decode_id_pk(&signed_id_pk, &rs_pk.unwrap()) {
-                if id == peer_id {
-                    sign_pk = Some(sign::PublicKey(pk));
+        if !signed_id_pk.is_empty() && !rs_pks.is_empty() {
+            for rs_pk in rs_pks.iter() {
+                if let Ok((id, pk)) = decode_id_pk(&signed_id_pk, rs_pk) {
+                    if id == peer_id {
+                        sign_pk = Some(sign::PublicKey(pk));
+                    }
+                    break;
                 }
             }
             if sign_pk.is_none() {
@@ -341,6 +384,9 @@ impl Client {
             None => {
                 // send an empty message out in case server is setting up secure and waiting for first message
                 conn.send(&Message::new()).await?;
+                if require_encryption {
+                    bail!("Handshake failed: the public key of the peer can not be verified, please check the key of your ID server");
+                }
                 return Ok(());
             }
         };
@@ -351,43 +397,114 @@ impl Client {
                     if let Some(message::Union::signed_id(si)) = msg_in.union {
                         if let Ok((id, their_pk_b)) = decode_id_pk(&si.id, &sign_pk) {
                             if id == peer_id {
-                                let their_pk_b = box_::PublicKey(their_pk_b);
-                                let (our_pk_b, out_sk_b) = box_::gen_keypair();
-                                let key = secretbox::gen_key();
-                                let nonce = box_::Nonce([0u8; box_::NONCEBYTES]);
-                                let sealed_key = box_::seal(&key.0, &nonce, &their_pk_b, &out_sk_b);
-                                let mut msg_out = Message::new();
-                                msg_out.set_public_key(PublicKey {
-                                    asymmetric_value: our_pk_b.0.into(),
-                                    symmetric_value: sealed_key,
-                                    ..Default::default()
-                                });
-                                timeout(CONNECT_TIMEOUT, conn.send(&msg_out)).await??;
-                                conn.set_key(key);
+                                Self::send_symmetric_key(box_::PublicKey(their_pk_b), conn)
+                                    .await?;
                             } else {
                                 log::error!("Handshake failed: sign failure");
                                 conn.send(&Message::new()).await?;
+                                if require_encryption {
+                                    bail!("Handshake failed: sign failure");
+                                }
                             }
                         } else {
-                            // fall back to non-secure connection in case pk mismatch
-                            log::info!("pk mismatch, fall back to non-secure");
                             let mut msg_out = Message::new();
                             msg_out.set_public_key(PublicKey::new());
                             conn.send(&msg_out).await?;
+                            if require_encryption {
+                                bail!("Handshake failed: pk mismatch");
+                            }
+                            // fall back to non-secure connection in case pk mismatch
+                            log::info!("pk mismatch, fall back to non-secure");
                         }
                     } else {
                         log::error!("Handshake failed: invalid message type");
                         conn.send(&Message::new()).await?;
+                        if require_encryption {
+                            bail!("Handshake failed: invalid message type");
+                        }
                     }
                 } else {
                     log::error!("Handshake failed: invalid message format");
                     conn.send(&Message::new()).await?;
+                    if require_encryption {
+                        bail!("Handshake failed: invalid message format");
+                    }
+                }
+            }
+            None => {
+                bail!("Reset by the peer");
+            }
+        }
+        Ok(())
+    }
+
+    // direct ip has no rendezvous server to vouch for the peer, so pin its sign pk on first use
+    async fn secure_direct_connection(peer: &str, conn: &mut Stream) -> ResultType<()> {
+        let mut si = None;
+        match timeout(CONNECT_TIMEOUT, conn.next()).await? {
+            Some(res) => {
+                let bytes = res?;
+                if let Ok(msg_in) = Message::parse_from_bytes(&bytes) {
+                    if let Some(message::Union::signed_id(tmp)) = msg_in.union {
+                        si = Some(tmp);
+                    }
                 }
             }
             None => {
                 bail!("Reset by the peer");
             }
         }
+        let si = match si {
+            Some(si) => si,
+            None => {
+                conn.send(&Message::new()).await?;
+                bail!("Handshake failed: the peer does not support secure direct connection");
+            }
+        };
+        let sign_pk = match get_pk(&si.pk) {
+            Some(pk) => sign::PublicKey(pk),
+            None => {
+                conn.send(&Message::new()).await?;
+                bail!("Handshake failed: invalid public key from the peer");
+            }
+        };
+        let mut config = PeerConfig::load(peer);
+        if !config.host_key.is_empty() && config.host_key != si.pk {
+            conn.send(&Message::new()).await?;
+            bail!(
+                "Handshake failed: the key of {} mismatches the trusted one, remove it from your peers if the change is expected",
+                peer
+            );
+        }
+        let their_pk_b = match decode_id_pk(&si.id, &sign_pk) {
+            Ok((_, pk)) => pk,
+            Err(err) => {
+                conn.send(&Message::new()).await?;
+                bail!("Handshake failed: {}", err);
+            }
+        };
+        Self::send_symmetric_key(box_::PublicKey(their_pk_b), conn).await?;
+        if config.host_key.is_empty() {
+            log::info!("trust key of {} on first use", peer);
+            config.host_key = si.pk;
+            config.store(peer);
+        }
+        Ok(())
+    }
+
+    async fn send_symmetric_key(their_pk_b: box_::PublicKey, conn: &mut Stream) -> ResultType<()> {
+        let (our_pk_b, out_sk_b) = box_::gen_keypair();
+        let key = secretbox::gen_key();
+        let nonce = box_::Nonce([0u8; box_::NONCEBYTES]);
+        let sealed_key = box_::seal(&key.0, &nonce, &their_pk_b, &out_sk_b);
+        let mut msg_out = Message::new();
+        msg_out.set_public_key(PublicKey {
+            asymmetric_value: our_pk_b.0.into(),
+            symmetric_value: sealed_key,
+            ..Default::default()
+        });
+        timeout(CONNECT_TIMEOUT, conn.send(&msg_out)).await??;
+        conn.set_key(key);
         Ok(())
     }
 
@@ -398,7 +515,7 @@ impl Client {
         secure: bool,
         conn_type: ConnType,
     ) -> ResultType<Stream> {
-        let any_addr = Config::get_any_listen_addr();
+        let any_addr = Config::get_any_listen_addr(true);
         let mut succeed = false;
         let mut uuid = "".to_owned();
         for i in 1..=3 {
@@ -452,7 +569,7 @@ impl Client {
     ) -> ResultType<Stream> {
         let mut conn = socket_client::connect_tcp(
             crate::check_port(relay_server, RELAY_PORT),
-            Config::get_any_listen_addr(),
+            Config::get_any_listen_addr(true),
             CONNECT_TIMEOUT,
         )
         .await
@@ -469,20 +586,134 @@ impl Client {
     }
 }
 
+// the target depth of the jitter buffer follows the measured jitter between these, in ms
+const MIN_AUDIO_DELAY: i64 = 20;
+const MAX_AUDIO_DELAY: i64 = 300;
+// the longer gaps in the timestamps are the silence not sent by the peer, not concealed
+const MAX_CONCEALED_GAP: i64 = 100;
+// the audio is moved to the video if more than SYNC_THRESHOLD ms off, and delayed MAX_SYNC_DELAY at most
+#[cfg(not(any(target_os = "android")))]
+const SYNC_THRESHOLD: i64 = 40;
+#[cfg(not(any(target_os = "android")))]
+const MAX_SYNC_DELAY: i64 = 500;
+// no video frame shown since, e.g. a static screen, the audio plays on its own
+#[cfg(not(any(target_os = "android")))]
+const VIDEO_CLOCK_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// The pts of the video frame shown last and when, the audio is played in sync with it.
+pub type VideoClock = Arc<Mutex<Option<(i64, std::time::Instant)>>>;
+
+/// The state of the audio jitter buffer, durations in ms.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct AudioStats {
+    pub depth: i64,
+    pub target: i64,
+    pub jitter: i64,
+    /// The times the buffer ran dry, the pauses of the peer included.
+    pub underruns: u64,
+    /// The lost frames rebuilt by the decoder.
+    pub concealed: u64,
+    /// Positive if the audio is ahead of the video.
+    pub av_offset: i64,
+}
+
+// the samples consumed by the output stream
+#[cfg(not(any(target_os = "android")))]
+struct AudioBuffer {
+    samples: std::collections::VecDeque<f32>,
+    // silent until `target` samples are buffered, at the start and after an underrun
+    buffering: bool,
+    target: usize,
+    underruns: u64,
+}
+
+#[cfg(not(any(target_os = "android")))]
+impl Default for AudioBuffer {
+    fn default() -> Self {
+        Self {
+            samples: Default::default(),
+            buffering: true,
+            target: 0,
+            underruns: 0,
+        }
+    }
+}
+
+// the arrival jitter of the frames as in RFC 3550, and the timestamp expected next
+#[derive(Default)]
+struct AudioJitter {
+    start: Option<std::time::Instant>,
+    last_transit: Option<i64>,
+    jitter: f64,
+    next_timestamp: Option<i64>,
+}
+
+impl AudioJitter {
+    // the 10ms frames lost right before the frame
+    fn lost_frames(&self, timestamp: i64) -> usize {
+        match self.next_timestamp {
+            Some(next) if timestamp > 0 && timestamp - next <= MAX_CONCEALED_GAP => {
+                ((timestamp - next).max(0) / 10) as _
+            }
+            _ => 0,
+        }
+    }
+
+    fn update(&mut self, timestamp: i64, duration: i64) {
+        // the peers of older versions send no timestamp
+        if timestamp <= 0 {
+            return;
+        }
+        let start = *self.start.get_or_insert_with(std::time::Instant::now);
+        let transit = start.elapsed().as_millis() as i64 - timestamp;
+        if let Some(last) = self.last_transit {
+            self.jitter += ((transit - last).abs() as f64 - self.jitter) / 16.;
+        }
+        self.last_transit = Some(transit);
+        self.next_timestamp = Some(timestamp + duration);
+    }
+
+    fn target(&self) -> i64 {
+        (self.jitter as i64 * 3 + MIN_AUDIO_DELAY).min(MAX_AUDIO_DELAY)
+    }
+}
+
 #[derive(Default)]
 pub struct AudioHandler {
     audio_decoder: Option<(AudioDecoder, Vec<f32>)>,
     #[cfg(any(target_os = "android"))]
     oboe: RefCell<OboePlayer>,
     #[cfg(not(any(target_os = "android")))]
-    audio_buffer: Arc<Mutex<std::collections::vec_deque::VecDeque<f32>>>,
+    audio_buffer: Arc<Mutex<AudioBuffer>>,
     sample_rate: (u32, u32),
     #[cfg(not(any(target_os = "android")))]
     audio_stream: Option<Box<dyn StreamTrait>>,
     channels: u16,
+    jitter: AudioJitter,
+    // added to the target depth to hold the audio for the video
+    #[cfg(not(any(target_os = "android")))]
+    sync_delay: i64,
+    #[cfg(not(any(target_os = "android")))]
+    video_clock: Option<VideoClock>,
+    stats: Arc<Mutex<AudioStats>>,
 }
 
 impl AudioHandler {
+    /// The audio is synced to `video_clock` if any.
+    pub fn new(video_clock: Option<VideoClock>) -> Self {
+        #[cfg(any(target_os = "android"))]
+        let _ = video_clock;
+        Self {
+            #[cfg(not(any(target_os = "android")))]
+            video_clock,
+            ..Default::default()
+        }
+    }
+
+    pub fn get_stats(&self) -> Arc<Mutex<AudioStats>> {
+        self.stats.clone()
+    }
+
     #[cfg(any(target_os = "android"))]
     fn start_audio(&mut self, format0: AudioFormat) -> ResultType<()> {
         self.sample_rate = (format0.sample_rate, format0.sample_rate);
@@ -519,6 +750,14 @@ impl AudioHandler {
                 let buffer = vec![0.; f.sample_rate as usize * f.channels as usize];
                 self.audio_decoder = Some((d, buffer));
                 self.channels = f.channels as _;
+                self.jitter = Default::default();
+                #[cfg(not(any(target_os = "android")))]
+                {
+                    self.sync_delay = 0;
+                    let mut buffer = self.audio_buffer.lock().unwrap();
+                    buffer.samples.clear();
+                    buffer.buffering = true;
+                }
                 allow_err!(self.start_audio(f));
             }
             Err(err) => {
@@ -533,51 +772,98 @@ impl AudioHandler {
             return;
         }
         let sample_rate0 = self.sample_rate.0;
-        let sample_rate = self.sample_rate.1;
-        let channels = self.channels;
-        cfg_if::cfg_if! {
-        if #[cfg(not(target_os = "android"))] {
-        let audio_buffer = self.audio_buffer.clone();
-        // avoiding memory overflow if audio_buffer consumer side has problem
-        if audio_buffer.lock().unwrap().len() as u32 > sample_rate * 120 {
-            *audio_buffer.lock().unwrap() = Default::default();
-        }
-        } else {
-        if self.oboe.borrow().is_null() {
-            self.oboe = RefCell::new(OboePlayer::new(
-                channels as _,
-                sample_rate0 as _,
-            ));
+        let channels = self.channels as usize;
+        let lost = self.jitter.lost_frames(frame.timestamp);
+        let (decoder, buffer) = match self.audio_decoder.as_mut() {
+            Some(x) => x,
+            None => return,
+        };
+        let mut samples = Vec::new();
+        // the lost frames are concealed, the last one from the fec data of this frame if the
+        // peer sends some, otherwise opus falls back to its plc
+        let size = sample_rate0 as usize / 100 * channels;
+        for i in 0..lost {
+            let fec = i + 1 == lost;
+            let data: &[u8] = if fec { &frame.data } else { &[] };
+            if let Ok(n) = decoder.decode_float(data, &mut buffer[..size], fec) {
+                samples.extend_from_slice(&buffer[..n * channels]);
+            }
         }
-        let mut oboe = self.oboe.borrow_mut();
+        // a frame failing to decode is concealed with the next one
+        let mut duration = 0;
+        if let Ok(n) = decoder.decode_float(&frame.data, buffer, false) {
+            samples.extend_from_slice(&buffer[..n * channels]);
+            duration = n as i64 * 1000 / sample_rate0 as i64;
         }
+        self.jitter.update(frame.timestamp, duration);
+        self.stats.lock().unwrap().concealed += lost as u64;
+        self.play(samples);
+    }
+
+    #[cfg(any(target_os = "android"))]
+    fn play(&mut self, samples: Vec<f32>) {
+        if self.oboe.borrow().is_null() {
+            self.oboe = RefCell::new(OboePlayer::new(self.channels as _, self.sample_rate.0 as _));
         }
-        self.audio_decoder.as_mut().map(|(d, buffer)| {
-            if let Ok(n) = d.decode_float(&frame.data, buffer, false) {
-                let n = n * (channels as usize);
-                #[cfg(not(any(target_os = "android")))]
-                {
-                    if sample_rate != sample_rate0 {
-                        let buffer = crate::resample_channels(
-                            &buffer[0..n],
-                            sample_rate0,
-                            sample_rate,
-                            channels,
-                        );
-                        audio_buffer.lock().unwrap().extend(buffer);
-                    } else {
-                        audio_buffer
-                            .lock()
-                            .unwrap()
-                            .extend(buffer[0..n].iter().cloned());
-                    }
+        self.oboe.borrow_mut().push(&samples);
+        self.stats.lock().unwrap().jitter = self.jitter.jitter as _;
+    }
+
+    #[cfg(not(any(target_os = "android")))]
+    fn play(&mut self, samples: Vec<f32>) {
+        let (sample_rate0, sample_rate) = self.sample_rate;
+        let samples = if sample_rate != sample_rate0 {
+            crate::resample_channels(&samples, sample_rate0, sample_rate, self.channels)
+        } else {
+            samples
+        };
+        // the samples per ms
+        let unit = (sample_rate as usize / 1000).max(1) * self.channels as usize;
+        let mut buffer = self.audio_buffer.lock().unwrap();
+        buffer.samples.extend(samples);
+        let mut depth = (buffer.samples.len() / unit) as i64;
+        let av_offset = self.get_av_offset(depth);
+        if !buffer.buffering {
+            match av_offset {
+                Some(offset) if offset > SYNC_THRESHOLD => {
+                    // held until the video catches up
+                    self.sync_delay = (self.sync_delay + offset).min(MAX_SYNC_DELAY);
+                    buffer.buffering = true;
                 }
-                #[cfg(any(target_os = "android"))]
-                {
-                    oboe.push(&buffer[0..n]);
+                Some(offset) if offset < -SYNC_THRESHOLD => {
+                    // skipped to the video
+                    self.sync_delay = (self.sync_delay + offset).max(0);
+                    let skipped = (-offset).min(depth - MIN_AUDIO_DELAY).max(0);
+                    buffer.samples.drain(..skipped as usize * unit);
+                    depth -= skipped;
                 }
+                _ => {}
             }
-        });
+        }
+        let target = self.jitter.target() + self.sync_delay;
+        // the latency is bounded, e.g. after a burst of frames held up on the way
+        if depth > target * 2 + MIN_AUDIO_DELAY {
+            buffer.samples.drain(..(depth - target) as usize * unit);
+            depth = target;
+        }
+        buffer.target = target as usize * unit;
+        let mut stats = self.stats.lock().unwrap();
+        stats.depth = depth;
+        stats.target = target;
+        stats.jitter = self.jitter.jitter as _;
+        stats.underruns = buffer.underruns;
+        stats.av_offset = av_offset.unwrap_or_default();
+    }
+
+    // the timestamp of the audio playing now minus the pts of the video shown now
+    #[cfg(not(any(target_os = "android")))]
+    fn get_av_offset(&self, depth: i64) -> Option<i64> {
+        let (pts, shown) = (*self.video_clock.as_ref()?.lock().unwrap())?;
+        let elapsed = shown.elapsed();
+        if elapsed > VIDEO_CLOCK_TIMEOUT {
+            return None;
+        }
+        Some(self.jitter.next_timestamp? - depth - pts - elapsed.as_millis() as i64)
     }
 
     #[cfg(not(any(target_os = "android")))]
@@ -595,11 +881,18 @@ impl AudioHandler {
             config,
             move |data: &mut [T], _: &_| {
                 let mut lock = audio_buffer.lock().unwrap();
-                let mut n = data.len();
-                if lock.len() < n {
-                    n = lock.len();
+                if lock.buffering && lock.samples.len() >= lock.target.max(1) {
+                    lock.buffering = false;
+                }
+                let mut n = 0;
+                if !lock.buffering {
+                    n = data.len().min(lock.samples.len());
+                    if n < data.len() {
+                        lock.underruns += 1;
+                        lock.buffering = true;
+                    }
                 }
-                let mut input = lock.drain(0..n);
+                let mut input = lock.samples.drain(0..n);
                 for sample in data.iter_mut() {
                     *sample = match input.next() {
                         Some(x) => T::from(&x),
@@ -615,41 +908,321 @@ impl AudioHandler {
     }
 }
 
+/// The default microphone encoded with opus, captured until dropped.
+#[cfg(not(any(target_os = "android")))]
+pub struct Microphone {
+    _stream: Box<dyn StreamTrait>,
+}
+
+#[cfg(not(any(target_os = "android")))]
+impl Microphone {
+    /// `on_frame` is called on the audio thread with every 10ms encoded.
+    pub fn new<F>(on_frame: F) -> ResultType<(Self, AudioFormat)>
+    where
+        F: 'static + FnMut(AudioFrame) + Send,
+    {
+        let device = AUDIO_HOST
+            .default_input_device()
+            .with_context(|| "Failed to get default input device")?;
+        log::info!(
+            "Using default input device: \"{}\"",
+            device.name().unwrap_or("".to_owned())
+        );
+        let config = device.default_input_config().map_err(|e| anyhow!(e))?;
+        log::info!("Default input format: {:?}", config);
+        let sample_rate = crate::common::get_opus_sample_rate(config.sample_rate().0);
+        // opus encodes mono or stereo, the other channels are dropped
+        let channels = config.channels().min(2);
+        let mut encoder = MicrophoneEncoder {
+            encoder: crate::common::new_opus_encoder(
+                sample_rate,
+                if channels > 1 { Stereo } else { Mono },
+            )?,
+            on_frame: Box::new(on_frame),
+            buffer: Vec::new(),
+            sample_rate0: config.sample_rate().0,
+            sample_rate,
+            channels0: config.channels(),
+            channels,
+            timestamp: 1,
+        };
+        let err_fn = |err| log::trace!("an error occurred on the input stream: {}", err);
+        let stream_config: StreamConfig = config.clone().into();
+        let stream = match config.sample_format() {
+            cpal::SampleFormat::F32 => device.build_input_stream(
+                &stream_config,
+                move |data: &[f32], _: &_| encoder.push(data),
+                err_fn,
+            )?,
+            cpal::SampleFormat::I16 => device.build_input_stream(
+                &stream_config,
+                move |data: &[i16], _: &_| {
+                    let data: Vec<f32> = data.iter().map(cpal::Sample::to_f32).collect();
+                    encoder.push(&data);
+                },
+                err_fn,
+            )?,
+            cpal::SampleFormat::U16 => device.build_input_stream(
+                &stream_config,
+                move |data: &[u16], _: &_| {
+                    let data: Vec<f32> = data.iter().map(cpal::Sample::to_f32).collect();
+                    encoder.push(&data);
+                },
+                err_fn,
+            )?,
+        };
+        stream.play()?;
+        let format = AudioFormat {
+            sample_rate,
+            channels: channels as _,
+            ..Default::default()
+        };
+        Ok((
+            Self {
+                _stream: Box::new(stream),
+            },
+            format,
+        ))
+    }
+}
+
+#[cfg(not(any(target_os = "android")))]
+struct MicrophoneEncoder {
+    encoder: AudioEncoder,
+    on_frame: Box<dyn FnMut(AudioFrame) + Send>,
+    // the samples less than a frame
+    buffer: Vec<f32>,
+    sample_rate0: u32,
+    sample_rate: u32,
+    channels0: u16,
+    channels: u16,
+    // in ms counted from the start of the capture, not synced to any video
+    timestamp: i64,
+}
+
+#[cfg(not(any(target_os = "android")))]
+impl MicrophoneEncoder {
+    fn push(&mut self, data: &[f32]) {
+        let channels = self.channels as usize;
+        let data: Vec<f32> = if self.channels0 != self.channels {
+            data.chunks_exact(self.channels0 as usize)
+                .flat_map(|x| x[..channels].iter().cloned())
+                .collect()
+        } else {
+            data.to_vec()
+        };
+        if self.sample_rate0 != self.sample_rate {
+            self.buffer.extend(crate::common::resample_channels(
+                &data,
+                self.sample_rate0,
+                self.sample_rate,
+                self.channels,
+            ));
+        } else {
+            self.buffer.extend(data);
+        }
+        let n = self.sample_rate as usize / 100 * channels;
+        while self.buffer.len() >= n {
+            let samples: Vec<f32> = self.buffer.drain(..n).collect();
+            let timestamp = self.timestamp;
+            self.timestamp += 10;
+            match self.encoder.encode_vec_float(&samples, n * 6) {
+                Ok(data) => (self.on_frame)(AudioFrame {
+                    data,
+                    timestamp,
+                    ..Default::default()
+                }),
+                Err(err) => log::trace!("Failed to encode the microphone: {}", err),
+            }
+        }
+    }
+}
+
 pub struct VideoHandler {
-    decoder: Decoder,
+    // created with the format of the first frame, the peer may switch codec
+    decoder: Option<(CodecFormat, Box<dyn DecoderApi>)>,
     pub rgb: Vec<u8>,
+    // the frames scaled down by the peer are scaled back to the size of the display
+    scaled: Vec<u8>,
+    last_seq: i64,
+    // the time a key frame was requested, None once received
+    key_frame_requested: Option<std::time::Instant>,
+    key_frame_request: bool,
+    // from PeerInfo, the frames of the display are not larger
+    display_size: Option<(usize, usize)>,
 }
 
 impl VideoHandler {
     pub fn new() -> Self {
         VideoHandler {
-            decoder: Decoder::new(VideoCodecId::VP9, (num_cpus::get() / 2) as _).unwrap(),
+            decoder: None,
             rgb: Default::default(),
+            scaled: Default::default(),
+            last_seq: 0,
+            key_frame_requested: None,
+            key_frame_request: false,
+            display_size: None,
+        }
+    }
+
+    pub fn set_display_size(&mut self, width: usize, height: usize) {
+        self.display_size = Some((width, height));
+    }
+
+    // the width and height of a frame, None if larger than the display
+    fn frame_size(&self, width: i32, height: i32) -> Option<(usize, usize)> {
+        let (w, h) = (usize::try_from(width).ok()?, usize::try_from(height).ok()?);
+        let (dw, dh) = self
+            .display_size
+            .unwrap_or((MAX_FRAME_SIDE, MAX_FRAME_SIDE));
+        if w > dw || h > dh {
+            return None;
         }
+        w.checked_mul(h)?.checked_mul(4)?;
+        Some((w, h))
     }
 
-    pub fn handle_vp9s(&mut self, vp9s: &VP9s) -> ResultType<bool> {
-        let mut last_frame = Image::new();
-        for vp9 in vp9s.frames.iter() {
-            for frame in self.decoder.decode(&vp9.data)? {
-                drop(last_frame);
-                last_frame = frame;
+    /// True once if the decoder lost its reference and the peer should send a key frame.
+    pub fn take_key_frame_request(&mut self) -> bool {
+        std::mem::replace(&mut self.key_frame_request, false)
+    }
+
+    fn request_key_frame(&mut self, reason: &str) {
+        if let Some(tm) = self.key_frame_requested {
+            if tm.elapsed() < KEY_FRAME_REQUEST_INTERVAL {
+                return;
             }
         }
-        for frame in self.decoder.flush()? {
-            drop(last_frame);
-            last_frame = frame;
+        log::info!("Request a key frame: {}", reason);
+        self.key_frame_requested = Some(std::time::Instant::now());
+        self.key_frame_request = true;
+    }
+
+    pub fn handle_frame(&mut self, vf: &VideoFrame) -> ResultType<bool> {
+        let (format, frames) = match &vf.union {
+            Some(video_frame::Union::vp9s(vp9s)) => (CodecFormat::VP9, vp9s),
+            Some(video_frame::Union::h264s(h264s)) => (CodecFormat::H264, h264s),
+            Some(video_frame::Union::rgb(rgb)) => return Ok(self.handle_rgb(rgb)),
+            _ => return Ok(false),
+        };
+        let mut created = false;
+        if self.decoder.as_ref().map(|x| x.0) != Some(format) {
+            log::info!("Create video decoder: {:?}", format);
+            let decoder = scrap::codec::new_decoder(format, (num_cpus::get() / 2) as _)?;
+            self.decoder = Some((format, decoder));
+            created = true;
         }
-        if last_frame.is_null() {
-            Ok(false)
-        } else {
-            last_frame.rgb(1, true, &mut self.rgb);
-            Ok(true)
+        // the frames not decodable are still decoded until the key frame, with artifacts
+        for (i, frame) in frames.frames.iter().enumerate() {
+            if frame.key {
+                self.key_frame_requested = None;
+            } else if created && i == 0 {
+                self.request_key_frame("no key frame for the new decoder");
+            } else if frame.seq > 0 && self.last_seq > 0 && frame.seq != self.last_seq + 1 {
+                // seq is 0 from the peers of older versions
+                self.request_key_frame("frames lost");
+            }
+            self.last_seq = frame.seq;
+        }
+        let frames: Vec<&[u8]> = frames.frames.iter().map(|x| &x.data[..]).collect();
+        let decoded = match self.decoder.as_mut() {
+            Some((_, decoder)) => match decoder.decode(&frames, &mut self.rgb) {
+                Ok(decoded) => decoded,
+                Err(err) => {
+                    self.request_key_frame(&format!("failed to decode: {}", err));
+                    return Err(err.into());
+                }
+            },
+            None => false,
+        };
+        if decoded {
+            if let Some(s) = vf.scaling.as_ref() {
+                // scaled back to the display, not to any size the peer tells
+                let size = self.frame_size(s.width, s.height);
+                let display_size = self.frame_size(s.display_width, s.display_height);
+                match (size, display_size) {
+                    (Some((w, h)), Some((dw, dh)))
+                        if self.display_size.map(|x| x == (dw, dh)).unwrap_or(true) =>
+                    {
+                        if self.rgb.len() == w * h * 4 {
+                            scrap::bgra_scale(w, h, &self.rgb, dw, dh, &mut self.scaled);
+                            std::mem::swap(&mut self.rgb, &mut self.scaled);
+                        }
+                    }
+                    _ => {
+                        log::error!(
+                            "Invalid scaling: {}x{} to {}x{}",
+                            s.width,
+                            s.height,
+                            s.display_width,
+                            s.display_height
+                        );
+                        return Ok(false);
+                    }
+                }
+            }
         }
+        Ok(decoded)
+    }
+
+    // the changed tiles of a lossless frame, copied into the last image,
+    // the frame is dropped if any tile is invalid
+    fn handle_rgb(&mut self, rgb: &RGB) -> bool {
+        let (w, h) = match self.frame_size(rgb.width, rgb.height) {
+            Some(size) => size,
+            None => {
+                log::error!("Invalid rgb frame: {}x{}", rgb.width, rgb.height);
+                return false;
+            }
+        };
+        let stride = w * 4;
+        let mut tiles = Vec::new();
+        for tile in rgb.tiles.iter() {
+            let rect = (|| {
+                let x = usize::try_from(tile.x).ok()?;
+                let y = usize::try_from(tile.y).ok()?;
+                let (tw, th) = self.frame_size(tile.width, tile.height)?;
+                if x.checked_add(tw)? > w || y.checked_add(th)? > h {
+                    return None;
+                }
+                Some((x, y, tw, th))
+            })();
+            let data = if rgb.compress {
+                hbb_common::compress::decompress(&tile.data)
+            } else {
+                tile.data.clone()
+            };
+            match rect {
+                Some((x, y, tw, th)) if data.len() >= tw * th * 4 => {
+                    tiles.push((x, y, tw, th, data));
+                }
+                _ => {
+                    log::error!(
+                        "Invalid rgb tile: {}x{} at ({}, {})",
+                        tile.width,
+                        tile.height,
+                        tile.x,
+                        tile.y
+                    );
+                    return false;
+                }
+            }
+        }
+        if self.rgb.len() != stride * h {
+            self.rgb = vec![0; stride * h];
+        }
+        for (x, y, tw, th, data) in tiles {
+            for row in 0..th {
+                let dst = (y + row) * stride + x * 4;
+                self.rgb[dst..dst + tw * 4]
+                    .copy_from_slice(&data[row * tw * 4..(row + 1) * tw * 4]);
+            }
+        }
+        true
     }
 
     pub fn reset(&mut self) {
-        self.decoder = Decoder::new(VideoCodecId::VP9, 1).unwrap();
+        self.decoder = None;
     }
 }
 
@@ -664,6 +1237,7 @@ pub struct LoginConfigHandler {
     config: PeerConfig,
     pub port_forward: (String, i32),
     pub version: i64,
+    pub file_transfer_features: Vec<FileTransferFeature>,
 }
 
 impl Deref for LoginConfigHandler {
@@ -843,6 +1417,8 @@ impl LoginConfigHandler {
             Some(ImageQuality::Low)
         } else if q == "best" {
             Some(ImageQuality::Best)
+        } else if q == "lossless" {
+            Some(ImageQuality::Lossless)
         } else if q == "balanced" {
             if ignore_default {
                 None
@@ -872,6 +1448,18 @@ impl LoginConfigHandler {
         }
     }
 
+    /// Ask the peer to encode the next frame of the display as a key frame.
+    pub fn request_key_frame(display: usize) -> Message {
+        let mut misc = Misc::new();
+        misc.set_key_frame_request(KeyFrameRequest {
+            display: display as _,
+            ..Default::default()
+        });
+        let mut msg_out = Message::new();
+        msg_out.set_misc(misc);
+        msg_out
+    }
+
     pub fn refresh() -> Message {
         let mut misc = Misc::new();
         misc.set_refresh_video(true);
@@ -880,6 +1468,22 @@ impl LoginConfigHandler {
         msg_out
     }
 
+    /// Restrict the codecs of the video, the peer switches its encoder if needed.
+    pub fn set_decodings(decodings: Vec<CodecFormat>) -> Message {
+        let mut misc = Misc::new();
+        misc.set_option(OptionMessage {
+            supported_decodings: decodings
+                .into_iter()
+                .map(|x| crate::common::codec_format_to_proto(x).into())
+                .collect::<Vec<_>>()
+                .into(),
+            ..Default::default()
+        });
+        let mut msg_out = Message::new();
+        msg_out.set_misc(misc);
+        msg_out
+    }
+
     pub fn save_custom_image_quality(&mut self, bitrate: i32, quantizer: i32) -> Message {
         let mut misc = Misc::new();
         misc.set_option(OptionMessage {
@@ -913,6 +1517,27 @@ impl LoginConfigHandler {
         res
     }
 
+    /// The peer's file transfer speed limit in KB/s, 0 for the global one.
+    pub fn get_peer_speed_limit(&self) -> u32 {
+        self.config.file_transfer_speed_limit
+    }
+
+    pub fn save_peer_speed_limit(&mut self, value: u32) {
+        let mut config = self.load_config();
+        config.file_transfer_speed_limit = value;
+        self.save_config(config);
+    }
+
+    /// File transfer speed limit in KB/s, the peer's one or the global one.
+    pub fn get_speed_limit(&self) -> u32 {
+        if self.config.file_transfer_speed_limit > 0 {
+            return self.config.file_transfer_speed_limit;
+        }
+        Config::get_option("file-transfer-speed-limit")
+            .parse()
+            .unwrap_or(0)
+    }
+
     pub fn get_option(&self, k: &str) -> String {
         if let Some(v) = self.config.options.get(k) {
             v.clone()
@@ -944,6 +1569,7 @@ impl LoginConfigHandler {
         if !pi.version.is_empty() {
             self.version = hbb_common::get_version_number(&pi.version);
         }
+        self.file_transfer_features = fs::peer_features(&pi.file_transfer_features);
         let serde = PeerInfoSerde {
             username,
             hostname: pi.hostname.clone(),
@@ -980,12 +1606,15 @@ impl LoginConfigHandler {
             my_id,
             my_name: crate::username(),
             option: self.get_option_message(true).into(),
+            supported_decodings: crate::common::supported_codecs().into(),
+            file_transfer_features: fs::features().into(),
             ..Default::default()
         };
         if self.is_file_transfer {
             lr.set_file_transfer(FileTransfer {
                 dir: self.get_option("remote_dir"),
                 show_hidden: !self.get_option("remote_show_hidden").is_empty(),
+                speed_limit: self.get_speed_limit(),
                 ..Default::default()
             });
         } else if self.is_port_forward {
@@ -1005,33 +1634,83 @@ pub enum MediaData {
     VideoFrame(VideoFrame),
     AudioFrame(AudioFrame),
     AudioFormat(AudioFormat),
-    Reset,
+    // the decoder of the display
+    Reset(usize),
+    // the display and its width and height
+    DisplaySize(usize, usize, usize),
 }
 
 pub type MediaSender = mpsc::Sender<MediaData>;
 
-pub fn start_video_audio_threads<F>(video_callback: F) -> (MediaSender, MediaSender)
+/// `video_callback` receives the index of the display and its image.
+/// `key_frame_callback` is called with the display the decoder of which needs a key frame.
+/// The audio is played in sync with the video shown.
+pub fn start_video_audio_threads<F, K>(
+    video_callback: F,
+    key_frame_callback: K,
+) -> (MediaSender, MediaSender, Arc<Mutex<AudioStats>>)
 where
-    F: 'static + FnMut(&[u8]) + Send,
+    F: 'static + FnMut(usize, &[u8]) + Send,
+    K: 'static + FnMut(usize) + Send,
 {
     let (video_sender, video_receiver) = mpsc::channel::<MediaData>();
-    let (audio_sender, audio_receiver) = mpsc::channel::<MediaData>();
     let mut video_callback = video_callback;
+    let mut key_frame_callback = key_frame_callback;
+    let video_clock: VideoClock = Default::default();
+    let clock = video_clock.clone();
 
     std::thread::spawn(move || {
-        let mut video_handler = VideoHandler::new();
+        // one decoder per captured display
+        let mut video_handlers: HashMap<usize, VideoHandler> = HashMap::new();
+        let mut display_sizes: HashMap<usize, (usize, usize)> = HashMap::new();
+        // the peers of older versions tag the frames of any display as 0,
+        // they are of the display switched to
+        let mut tagged = false;
+        let mut untagged_display = 0;
         loop {
             if let Ok(data) = video_receiver.recv() {
                 match data {
                     MediaData::VideoFrame(vf) => {
-                        if let Some(video_frame::Union::vp9s(vp9s)) = &vf.union {
-                            if let Ok(true) = video_handler.handle_vp9s(vp9s) {
-                                video_callback(&video_handler.rgb);
+                        let display = vf.display as usize;
+                        tagged |= display > 0;
+                        let size = if tagged { display } else { untagged_display };
+                        let video_handler = video_handlers.entry(display).or_insert_with(|| {
+                            let mut handler = VideoHandler::new();
+                            if let Some((w, h)) = display_sizes.get(&size) {
+                                handler.set_display_size(*w, *h);
                             }
+                            handler
+                        });
+                        let res = video_handler.handle_frame(&vf);
+                        if video_handler.take_key_frame_request() {
+                            key_frame_callback(display);
+                        }
+                        if let Ok(true) = res {
+                            video_callback(display, &video_handler.rgb);
+                            if let Some(pts) = get_pts(&vf) {
+                                *clock.lock().unwrap() = Some((pts, std::time::Instant::now()));
+                            }
+                        }
+                    }
+                    MediaData::Reset(display) => {
+                        video_handlers.remove(&display);
+                        if !tagged {
+                            video_handlers.remove(&0);
+                            untagged_display = display;
                         }
                     }
-                    MediaData::Reset => {
-                        video_handler.reset();
+                    MediaData::DisplaySize(display, w, h) => {
+                        display_sizes.insert(display, (w, h));
+                        let handler = if tagged {
+                            video_handlers.get_mut(&display)
+                        } else if display == untagged_display {
+                            video_handlers.get_mut(&0)
+                        } else {
+                            None
+                        };
+                        if let Some(handler) = handler {
+                            handler.set_display_size(w, h);
+                        }
                     }
                     _ => {}
                 }
@@ -1041,8 +1720,28 @@ where
         }
         log::info!("Video decoder loop exits");
     });
+    let (audio_sender, audio_stats) = start_audio_thread(Some(video_clock));
+    return (video_sender, audio_sender, audio_stats);
+}
+
+fn get_pts(vf: &VideoFrame) -> Option<i64> {
+    match &vf.union {
+        Some(video_frame::Union::vp9s(f)) | Some(video_frame::Union::h264s(f)) => {
+            f.frames.last().map(|f| f.pts)
+        }
+        _ => None,
+    }
+}
+
+/// The audio is played on the default output device until the sender is dropped,
+/// in sync with `video_clock` if any.
+pub fn start_audio_thread(
+    video_clock: Option<VideoClock>,
+) -> (MediaSender, Arc<Mutex<AudioStats>>) {
+    let (audio_sender, audio_receiver) = mpsc::channel::<MediaData>();
+    let mut audio_handler = AudioHandler::new(video_clock);
+    let audio_stats = audio_handler.get_stats();
     std::thread::spawn(move || {
-        let mut audio_handler = AudioHandler::default();
         loop {
             if let Ok(data) = audio_receiver.recv() {
                 match data {
@@ -1060,7 +1759,7 @@ where
         }
         log::info!("Audio decoder loop exits");
     });
-    return (video_sender, audio_sender);
+    (audio_sender, audio_stats)
 }
 
 pub async fn handle_test_delay(t: TestDelay, peer: &mut Stream) {
@@ -1136,6 +1835,8 @@ pub enum Data {
     RemoveDirAll((i32, String, bool)),
     ConfirmDeleteFiles((i32, i32)),
     SetNoConfirm(i32),
+    ConfirmOverwrite((i32, bool)),
+    UpdateSpeedLimit,
     RemoveDir((i32, String)),
     RemoveFile((i32, String, i32, bool)),
     CreateDir((i32, String, bool)),
@@ -1144,6 +1845,12 @@ pub enum Data {
     AddPortForward((i32, String, i32)),
     ToggleClipboardFile,
     NewRDP,
+    ToggleRecording,
+    ToggleMicrophone,
+    DownloadOffsets((i32, String, bool, Vec<FileEntry>)),
+    FileDigests((i32, String, Vec<Vec<u8>>)),
+    SameContents((i32, Vec<usize>)),
+    PrefixHashed((i32, fs::PrefixHashers)),
 }
 
 #[derive(Clone)]
@@ -1291,6 +1998,15 @@ pub fn check_if_retry(msgtype: &str, title: &str, text: &str) -> bool {
 }
 
 #[inline]
+// the hosts of older versions or behind a proxy do not announce QUIC, no waiting for it then
+fn get_quic_timeout(peer_quic: bool, connect_timeout: u64) -> Option<u64> {
+    if peer_quic {
+        Some(connect_timeout.min(QUIC_CONNECT_TIMEOUT))
+    } else {
+        None
+    }
+}
+
 fn get_pk(pk: &[u8]) -> Option<[u8; 32]> {
     if pk.len() == 32 {
         let mut tmp = [0u8; 32];
@@ -1320,3 +2036,172 @@ fn decode_id_pk(signed: &[u8], key: &sign::PublicKey) -> ResultType<(String, [u8
         bail!("Wrong public length");
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hbb_common::tokio::{self, net::TcpListener};
+
+    // a peer sending its signed id signed with `sign_sk`, returns what the client answers
+    async fn direct_handshake(
+        peer: &str,
+        sign_pk: &sign::PublicKey,
+        sign_sk: &sign::SecretKey,
+    ) -> ResultType<Message> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let (sign_pk, sign_sk) = (sign_pk.clone(), sign_sk.clone());
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await?;
+            let mut stream = Stream::from(stream, addr);
+            let (our_pk_b, _) = box_::gen_keypair();
+            let mut msg_out = Message::new();
+            msg_out.set_signed_id(SignedId {
+                id: sign::sign(
+                    &IdPk {
+                        id: "123456789".to_owned(),
+                        pk: our_pk_b.0.to_vec(),
+                        ..Default::default()
+                    }
+                    .write_to_bytes()?,
+                    &sign_sk,
+                ),
+                pk: sign_pk.0.to_vec(),
+                ..Default::default()
+            });
+            stream.send(&msg_out).await?;
+            let bytes = match stream.next().await {
+                Some(bytes) => bytes?,
+                None => bail!("Reset by the client"),
+            };
+            ResultType::<Message>::Ok(Message::parse_from_bytes(&bytes)?)
+        });
+        let mut conn =
+            socket_client::connect_tcp(addr, Config::get_any_listen_addr(true), CONNECT_TIMEOUT)
+                .await?;
+        let res = Client::secure_direct_connection(peer, &mut conn).await;
+        let msg = server.await??;
+        res.map(|_| msg)
+    }
+
+    #[tokio::test]
+    async fn test_direct_key_pinning() {
+        let peer = format!("test_direct_key_pinning_{}", std::process::id());
+        PeerConfig::remove(&peer);
+        let (sign_pk, sign_sk) = sign::gen_keypair();
+
+        // trusted on first use
+        let msg = direct_handshake(&peer, &sign_pk, &sign_sk).await.unwrap();
+        assert!(matches!(msg.union, Some(message::Union::public_key(_))));
+        assert_eq!(PeerConfig::load(&peer).host_key, sign_pk.0.to_vec());
+
+        // the same key again
+        let msg = direct_handshake(&peer, &sign_pk, &sign_sk).await.unwrap();
+        assert!(matches!(msg.union, Some(message::Union::public_key(_))));
+
+        // another key is rejected, and the trusted one kept
+        let (other_pk, other_sk) = sign::gen_keypair();
+        let err = direct_handshake(&peer, &other_pk, &other_sk)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("mismatches the trusted one"));
+        assert_eq!(PeerConfig::load(&peer).host_key, sign_pk.0.to_vec());
+
+        PeerConfig::remove(&peer);
+    }
+
+    #[test]
+    fn test_quic_timeout() {
+        assert_eq!(get_quic_timeout(false, CONNECT_TIMEOUT), None);
+        assert_eq!(
+            get_quic_timeout(true, CONNECT_TIMEOUT),
+            Some(QUIC_CONNECT_TIMEOUT)
+        );
+        assert_eq!(get_quic_timeout(true, 500), Some(500));
+    }
+
+    #[test]
+    fn test_audio_lost_frames() {
+        let mut jitter = AudioJitter::default();
+        assert_eq!(jitter.lost_frames(1000), 0);
+        // no timestamp from older peers
+        jitter.update(0, 10);
+        assert_eq!(jitter.next_timestamp, None);
+        jitter.update(1000, 10);
+        assert_eq!(jitter.next_timestamp, Some(1010));
+        assert_eq!(jitter.lost_frames(1010), 0);
+        assert_eq!(jitter.lost_frames(1040), 3);
+        assert_eq!(jitter.lost_frames(1010 + MAX_CONCEALED_GAP), 10);
+        // a pause of the peer, or a frame out of order
+        assert_eq!(jitter.lost_frames(1020 + MAX_CONCEALED_GAP), 0);
+        assert_eq!(jitter.lost_frames(990), 0);
+        assert_eq!(jitter.lost_frames(0), 0);
+    }
+
+    #[test]
+    fn test_audio_jitter() {
+        let mut jitter = AudioJitter::default();
+        assert_eq!(jitter.target(), MIN_AUDIO_DELAY);
+        jitter.update(1000, 10);
+        assert_eq!(jitter.jitter, 0.);
+        // arrived right after the previous one, 200ms early
+        jitter.update(1200, 10);
+        assert!(jitter.jitter > 11. && jitter.jitter <= 12.5);
+        assert_eq!(jitter.target(), jitter.jitter as i64 * 3 + MIN_AUDIO_DELAY);
+        jitter.jitter = 1000.;
+        assert_eq!(jitter.target(), MAX_AUDIO_DELAY);
+    }
+
+    // one sample per ms, the audio plays the timestamp `next_timestamp - samples`
+    #[cfg(not(any(target_os = "android")))]
+    fn new_audio_handler(next_timestamp: i64, pts: i64, shown: std::time::Instant) -> AudioHandler {
+        let clock: VideoClock = Arc::new(Mutex::new(Some((pts, shown))));
+        let mut handler = AudioHandler::new(Some(clock));
+        handler.sample_rate = (1000, 1000);
+        handler.channels = 1;
+        handler.jitter.next_timestamp = Some(next_timestamp);
+        handler.audio_buffer.lock().unwrap().buffering = false;
+        handler
+    }
+
+    #[cfg(not(any(target_os = "android")))]
+    #[test]
+    fn test_audio_sync() {
+        let now = std::time::Instant::now();
+        // in sync
+        let mut handler = new_audio_handler(1000, 950, now);
+        handler.play(vec![0.; 50]);
+        assert_eq!(handler.sync_delay, 0);
+        assert!(!handler.audio_buffer.lock().unwrap().buffering);
+        assert_eq!(handler.get_stats().lock().unwrap().depth, 50);
+
+        // ahead of the video, held, less the ms passed since `now`
+        let mut handler = new_audio_handler(1000, 850, now);
+        handler.play(vec![0.; 50]);
+        assert!(handler.sync_delay > 90 && handler.sync_delay <= 100);
+        assert!(handler.audio_buffer.lock().unwrap().buffering);
+        let stats = *handler.get_stats().lock().unwrap();
+        assert_eq!(stats.av_offset, handler.sync_delay);
+        assert_eq!(stats.target, MIN_AUDIO_DELAY + handler.sync_delay);
+
+        // too far ahead, held at most MAX_SYNC_DELAY
+        let mut handler = new_audio_handler(10000, 850, now);
+        handler.play(vec![0.; 50]);
+        assert_eq!(handler.sync_delay, MAX_SYNC_DELAY);
+
+        // behind the video, skipped to it down to the minimum depth
+        let mut handler = new_audio_handler(1000, 1000, now);
+        handler.play(vec![0.; 100]);
+        assert_eq!(handler.sync_delay, 0);
+        assert_eq!(handler.audio_buffer.lock().unwrap().samples.len(), 20);
+        assert_eq!(handler.get_stats().lock().unwrap().depth, 20);
+
+        // no video shown lately, played on its own
+        let shown = now - VIDEO_CLOCK_TIMEOUT * 2;
+        let mut handler = new_audio_handler(1000, 0, shown);
+        handler.play(vec![0.; 50]);
+        assert_eq!(handler.sync_delay, 0);
+        assert_eq!(handler.get_stats().lock().unwrap().av_offset, 0);
+        assert!(!handler.audio_buffer.lock().unwrap().buffering);
+    }
+}
@@ -3,16 +3,17 @@ use hbb_common::{
     allow_err,
     anyhow::bail,
     compress::{compress as compress_func, decompress},
-    config::{Config, COMPRESS_LEVEL, RENDEZVOUS_TIMEOUT},
+    config::{Config, NetworkType, COMPRESS_LEVEL, RENDEZVOUS_TIMEOUT},
     get_version_number, log,
     message_proto::*,
     protobuf::Message as _,
-    protobuf::ProtobufEnum,
+    protobuf::{ProtobufEnum, ProtobufEnumOrUnknown},
     rendezvous_proto::*,
     sleep, socket_client, tokio, ResultType,
 };
 #[cfg(any(target_os = "android", target_os = "ios", feature = "cli"))]
 use hbb_common::{config::RENDEZVOUS_PORT, futures::future::join_all};
+use magnum_opus::{Application::Audio, Channels, Encoder};
 use std::sync::{Arc, Mutex};
 
 pub const CLIPBOARD_NAME: &'static str = "clipboard";
@@ -29,6 +30,43 @@ lazy_static::lazy_static! {
     pub static ref MOBILE_INFO2: Arc<Mutex<String>> = Default::default();
 }
 
+#[inline]
+pub fn codec_format_from_proto(codec: VideoCodec) -> scrap::CodecFormat {
+    match codec {
+        VideoCodec::VP9 => scrap::CodecFormat::VP9,
+        VideoCodec::H264 => scrap::CodecFormat::H264,
+    }
+}
+
+#[inline]
+pub fn codec_format_to_proto(format: scrap::CodecFormat) -> VideoCodec {
+    match format {
+        scrap::CodecFormat::VP9 => VideoCodec::VP9,
+        scrap::CodecFormat::H264 => VideoCodec::H264,
+    }
+}
+
+/// Codecs of this build for LoginRequest and PeerInfo, the best first.
+pub fn supported_codecs() -> Vec<ProtobufEnumOrUnknown<VideoCodec>> {
+    scrap::codec::supported_formats()
+        .drain(..)
+        .map(|x| codec_format_to_proto(x).into())
+        .collect()
+}
+
+/// Codecs announced by the peer, vp9 if it is an old version without codec negotiation.
+pub fn peer_codecs(codecs: &[ProtobufEnumOrUnknown<VideoCodec>]) -> Vec<scrap::CodecFormat> {
+    let mut formats: Vec<scrap::CodecFormat> = codecs
+        .iter()
+        .filter_map(|x| x.enum_value().ok())
+        .map(codec_format_from_proto)
+        .collect();
+    if formats.is_empty() {
+        formats.push(scrap::CodecFormat::VP9);
+    }
+    formats
+}
+
 #[inline]
 pub fn valid_for_numlock(evt: &KeyEvent) -> bool {
     if let Some(key_event::Union::control_key(ck)) = evt.union {
@@ -98,6 +136,33 @@ pub fn update_clipboard(clipboard: Clipboard, old: Option<&Arc<Mutex<String>>>)
     }
 }
 
+/// The opus sample rate closest below, it must be one of 8000, 12000, 16000, 24000, or 48000.
+pub fn get_opus_sample_rate(sample_rate: u32) -> u32 {
+    if sample_rate < 12000 {
+        8000
+    } else if sample_rate < 16000 {
+        12000
+    } else if sample_rate < 24000 {
+        16000
+    } else if sample_rate < 48000 {
+        24000
+    } else {
+        48000
+    }
+}
+
+/// The expected packet loss in percent, the opus encoder adds in-band fec for it.
+pub const OPUS_PACKET_LOSS_PERC: i32 = 10;
+
+/// The opus encoder with in-band fec, so that the decoder recovers a lost frame from the next one.
+/// The restricted low delay mode is celt only which has no fec.
+pub fn new_opus_encoder(sample_rate: u32, channels: Channels) -> ResultType<Encoder> {
+    let mut encoder = Encoder::new(sample_rate, channels, Audio)?;
+    encoder.set_inband_fec(true)?;
+    encoder.set_packet_loss_perc(OPUS_PACKET_LOSS_PERC)?;
+    Ok(encoder)
+}
+
 #[cfg(feature = "use_rubato")]
 pub fn resample_channels(
     data: &[f32],
@@ -234,23 +299,23 @@ pub fn test_nat_type() {
 #[tokio::main(flavor = "current_thread")]
 async fn test_nat_type_() -> ResultType<bool> {
     log::info!("Testing nat ...");
-    let is_direct = crate::ipc::get_socks_async(1_000).await.is_none(); // sync socks BTW
-    if !is_direct {
+    crate::ipc::get_socks_async(1_000).await; // sync socks BTW
+    if Config::get_network_type() != NetworkType::Direct {
         Config::set_nat_type(NatType::SYMMETRIC as _);
         return Ok(true);
     }
     let start = std::time::Instant::now();
     let rendezvous_server = get_rendezvous_server(1_000).await;
     let server1 = rendezvous_server;
-    let tmp: Vec<&str> = server1.split(":").collect();
-    if tmp.len() != 2 {
-        bail!("Invalid server address: {}", server1);
-    }
-    let port: u16 = tmp[1].parse()?;
+    let (host, port) = match split_host_port(&server1) {
+        Some(x) => x,
+        None => bail!("Invalid server address: {}", server1),
+    };
+    let port: u16 = port.parse()?;
     if port == 0 {
         bail!("Invalid server address: {}", server1);
     }
-    let server2 = format!("{}:{}", tmp[0], port - 1);
+    let server2 = format!("{}:{}", host, port - 1);
     let mut msg_out = RendezvousMessage::new();
     let serial = Config::get_serial();
     msg_out.set_test_nat_request(TestNatRequest {
@@ -261,7 +326,7 @@ async fn test_nat_type_() -> ResultType<bool> {
     let mut port2 = 0;
     let server1 = socket_client::get_target_addr(&server1)?;
     let server2 = socket_client::get_target_addr(&server2)?;
-    let mut addr = Config::get_any_listen_addr();
+    let mut addr = Config::get_any_listen_addr(socket_client::is_ipv4(&server1));
     for i in 0..2 {
         let mut socket = socket_client::connect_tcp(
             if i == 0 {
@@ -340,7 +405,7 @@ async fn test_rendezvous_server_() {
             let tm = std::time::Instant::now();
             if socket_client::connect_tcp(
                 crate::check_port(&host, RENDEZVOUS_PORT),
-                Config::get_any_listen_addr(),
+                Config::get_any_listen_addr(true),
                 RENDEZVOUS_TIMEOUT,
             )
             .await
@@ -385,12 +450,26 @@ pub fn username() -> String {
 #[inline]
 pub fn check_port<T: std::string::ToString>(host: T, port: i32) -> String {
     let host = host.to_string();
-    if !host.contains(":") {
+    if split_host_port(&host).is_none() {
+        if host.parse::<std::net::Ipv6Addr>().is_ok() {
+            return format!("[{}]:{}", host, port);
+        }
         return format!("{}:{}", host, port);
     }
     return host;
 }
 
+/// The host and the port of "host:port", the host of ipv6 in brackets.
+pub fn split_host_port(addr: &str) -> Option<(&str, &str)> {
+    let i = addr.rfind(':')?;
+    let host = &addr[..i];
+    // the colons of a bare ipv6 address
+    if host.contains(':') && !(host.starts_with('[') && host.ends_with(']')) {
+        return None;
+    }
+    Some((host, &addr[i + 1..]))
+}
+
 pub const POSTFIX_SERVICE: &'static str = "_service";
 
 #[inline]
@@ -428,8 +507,11 @@ async fn _check_software_update() -> hbb_common::ResultType<()> {
     sleep(3.).await;
 
     let rendezvous_server = socket_client::get_target_addr(&get_rendezvous_server(1_000).await)?;
-    let mut socket =
-        socket_client::new_udp(Config::get_any_listen_addr(), RENDEZVOUS_TIMEOUT).await?;
+    let mut socket = socket_client::new_udp(
+        Config::get_any_listen_addr(socket_client::is_ipv4(&rendezvous_server)),
+        RENDEZVOUS_TIMEOUT,
+    )
+    .await?;
 
     let mut msg_out = RendezvousMessage::new();
     msg_out.set_software_update(SoftwareUpdate {
@@ -464,4 +546,5 @@ pub fn is_ip(id: &str) -> bool {
     hbb_common::regex::Regex::new(r"^\d+\.\d+\.\d+\.\d+(:\d+)?$")
         .unwrap()
         .is_match(id)
+        || check_port(id, 0).parse::<std::net::SocketAddrV6>().is_ok()
 }
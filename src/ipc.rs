@@ -19,12 +19,27 @@ use std::collections::HashMap;
 #[cfg(not(windows))]
 use std::{fs::File, io::prelude::*};
 
+// FileEntry sent to the cm
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct FileInfo {
+    pub name: String,
+    pub entry_type: i32,
+    pub modified_time: u64,
+    pub size: u64,
+    pub digest: Vec<u8>,
+    pub link_target: String,
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 #[serde(tag = "t", content = "c")]
 pub enum FS {
     ReadDir {
         dir: String,
         include_hidden: bool,
+        id: i32,
     },
     RemoveDir {
         path: String,
@@ -43,7 +58,12 @@ pub enum FS {
     NewWrite {
         path: String,
         id: i32,
-        files: Vec<(String, u64)>,
+        files: Vec<FileInfo>,
+        policy: i32,
+        // the peer waits for the resume offsets
+        resume: bool,
+        per_file_done: bool,
+        preserve_owner: bool,
     },
     CancelWrite {
         id: i32,
@@ -53,10 +73,12 @@ pub enum FS {
         file_num: i32,
         data: Vec<u8>,
         compressed: bool,
+        offset: u64,
     },
     WriteDone {
         id: i32,
         file_num: i32,
+        digest: Vec<u8>,
     },
 }
 
@@ -74,7 +96,9 @@ pub enum Data {
         clipboard: bool,
         audio: bool,
         file: bool,
+        microphone: bool,
         file_transfer_enabled: bool,
+        recording: bool,
     },
     ChatMessage {
         text: String,
@@ -107,6 +131,8 @@ pub enum Data {
     SyncConfigToUserResp(bool),
     ClipbaordFile(ClipbaordFile),
     ClipboardFileEnabled(bool),
+    // a serialized audio frame or audio format of the peer, played by the cm on Linux
+    Microphone(Vec<u8>),
 }
 
 #[tokio::main(flavor = "current_thread")]
@@ -266,5 +266,37 @@ pub static ref T: std::collections::HashMap<&'static str, &'static str> =
         ("android_version_audio_tip", "当前安卓版本不支持音频录制，请升级至安卓10或更高。"),
         ("android_start_service_tip", "点击 [启动服务] 或打开 [屏幕录制] 权限开启手机屏幕共享服务。"),
         ("Account", "账号"),
+        ("Key", "密钥"),
+        ("Require Encryption", "强制加密"),
+        ("Overwrite", "覆盖"),
+        ("Skip if same size and time", "大小和时间相同则跳过"),
+        ("Skip if same content", "内容相同则跳过"),
+        ("Ask", "询问"),
+        ("Confirm Overwrite", "确认覆盖"),
+        ("overwrite_files_tip", "目标位置已存在以下文件，是否覆盖？"),
+        ("File Transfer Speed Limit", "文件传输限速"),
+        ("speed_limit_tip", "单位为KB/s，留空表示不限速"),
+        ("peer_speed_limit_tip", "单位为KB/s，仅用于此设备，留空表示使用全局设置"),
+        ("Lossless text mode", "无损文本模式"),
+        ("Show all displays", "显示所有屏幕"),
+        ("Start recording", "开始录屏"),
+        ("Stop recording", "停止录屏"),
+        ("Recording saved", "录屏已保存"),
+        ("Session Recording", "会话录制"),
+        ("Record all the controlled sessions", "录制所有被控会话"),
+        ("Directory", "目录"),
+        ("Keep days", "保留天数"),
+        ("Max size", "最大容量"),
+        ("session_recording_tip", "本次会话正在被本机录制"),
+        ("session_recording_option_tip", "每个远程控制会话的画面和声音会连同对方的ID、IP和权限一起保存，超出限制时删除最早的录制。"),
+        ("Fit remote resolution to window", "远程分辨率适应窗口"),
+        ("Key frame interval", "关键帧间隔"),
+        ("Seconds", "秒"),
+        ("key_frame_interval_tip", "定期发送关键帧，使中继路上损坏的画面自动恢复，0为关闭。新的连接生效。"),
+        ("Allow hearing the microphone of the peer", "允许听到对方的麦克风"),
+        ("Enable Microphone", "启用麦克风"),
+        ("Start microphone", "开启麦克风"),
+        ("Stop microphone", "关闭麦克风"),
+        ("Microphone", "麦克风"),
     ].iter().cloned().collect();
 }
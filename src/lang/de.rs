@@ -266,5 +266,37 @@ pub static ref T: std::collections::HashMap<&'static str, &'static str> =
         ("android_version_audio_tip", "Die aktuelle Android-Version unterstützt keine Audioaufnahme, bitte aktualisieren Sie auf Android 10 oder höher."),
         ("android_start_service_tip", "Tippen Sie auf [Dienst starten] oder ÖFFNEN Sie die Berechtigung [Bildschirmaufnahme], um den Bildschirmfreigabedienst zu starten."),
         ("Account", "Konto"),
+        ("Key", "Schlüssel"),
+        ("Require Encryption", "Verschlüsselung erzwingen"),
+        ("Overwrite", "Überschreiben"),
+        ("Skip if same size and time", "Überspringen bei gleicher Größe und Zeit"),
+        ("Skip if same content", "Überspringen bei gleichem Inhalt"),
+        ("Ask", "Nachfragen"),
+        ("Confirm Overwrite", "Überschreiben bestätigen"),
+        ("overwrite_files_tip", "Diese Dateien existieren bereits am Ziel, überschreiben?"),
+        ("File Transfer Speed Limit", "Dateiübertragungs-Geschwindigkeitslimit"),
+        ("speed_limit_tip", "In KB/s, leer für unbegrenzt"),
+        ("peer_speed_limit_tip", "In KB/s, nur für dieses Gerät, leer für die globale Einstellung"),
+        ("Lossless text mode", "Verlustfreier Textmodus"),
+        ("Show all displays", "Alle Bildschirme anzeigen"),
+        ("Start recording", "Aufnahme starten"),
+        ("Stop recording", "Aufnahme beenden"),
+        ("Recording saved", "Aufnahme gespeichert"),
+        ("Session Recording", "Sitzungsaufzeichnung"),
+        ("Record all the controlled sessions", "Alle gesteuerten Sitzungen aufzeichnen"),
+        ("Directory", "Verzeichnis"),
+        ("Keep days", "Aufbewahrungstage"),
+        ("Max size", "Maximale Größe"),
+        ("session_recording_tip", "Diese Sitzung wird von diesem Computer aufgezeichnet"),
+        ("session_recording_option_tip", "Bild und Ton jeder Fernsteuerungssitzung werden mit der ID, der IP und den Berechtigungen der Gegenstelle gespeichert. Die ältesten Aufzeichnungen werden über den Grenzwerten entfernt."),
+        ("Fit remote resolution to window", "Entfernte Auflösung an Fenster anpassen"),
+        ("Key frame interval", "Schlüsselbild-Intervall"),
+        ("Seconds", "Sekunden"),
+        ("key_frame_interval_tip", "Regelmäßige Schlüsselbilder reparieren ein unterwegs beschädigtes Bild automatisch, 0 schaltet sie ab. Gilt für neue Verbindungen."),
+        ("Allow hearing the microphone of the peer", "Mikrofon der Gegenstelle hören erlauben"),
+        ("Enable Microphone", "Mikrofon aktivieren"),
+        ("Start microphone", "Mikrofon einschalten"),
+        ("Stop microphone", "Mikrofon ausschalten"),
+        ("Microphone", "Mikrofon"),
     ].iter().cloned().collect();
 }
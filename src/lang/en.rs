@@ -24,5 +24,11 @@ pub static ref T: std::collections::HashMap<&'static str, &'static str> =
         ("android_stop_service_tip", "Closing the service will automatically close all established connections."),
         ("android_version_audio_tip", "The current Android version does not support audio capture, please upgrade to Android 10 or higher."),
         ("android_start_service_tip", "Tap [Start Service] or OPEN [Screen Capture] permission to start the screen sharing service."),
+        ("overwrite_files_tip", "These files already exist in the destination, overwrite them?"),
+        ("speed_limit_tip", "In KB/s, empty for unlimited"),
+        ("peer_speed_limit_tip", "In KB/s, for this peer only, empty for the global setting"),
+        ("session_recording_tip", "This session is recorded by this computer"),
+        ("session_recording_option_tip", "The screen and the sound of every remote control session are saved with the ID, the IP and the permissions of the peer. The oldest recordings are removed beyond the limits."),
+        ("key_frame_interval_tip", "Periodic key frames heal the image corrupted on the way automatically, 0 turns them off. Applies to the new connections."),
     ].iter().cloned().collect();
 }
@@ -266,5 +266,37 @@ pub static ref T: std::collections::HashMap<&'static str, &'static str> =
         ("android_version_audio_tip", ""),
         ("android_start_service_tip", ""),
         ("Account", ""),
+        ("Key", "Ŝlosilo"),
+        ("Require Encryption", "Postuli ĉifradon"),
+        ("Overwrite", "Anstataŭigi"),
+        ("Skip if same size and time", "Preterlasi se sama grandeco kaj tempo"),
+        ("Skip if same content", "Preterlasi se sama enhavo"),
+        ("Ask", "Demandi"),
+        ("Confirm Overwrite", "Konfirmi anstataŭigon"),
+        ("overwrite_files_tip", "Tiuj dosieroj jam ekzistas en la celo, ĉu anstataŭigi ilin?"),
+        ("File Transfer Speed Limit", "Rapidlimo de dosiertransigo"),
+        ("speed_limit_tip", "En KB/s, malplena por senlima"),
+        ("peer_speed_limit_tip", "En KB/s, nur por ĉi tiu samtavolano, malplena por la ĝenerala agordo"),
+        ("Lossless text mode", "Senperda teksta reĝimo"),
+        ("Show all displays", "Montri ĉiujn ekranojn"),
+        ("Start recording", "Komenci registradon"),
+        ("Stop recording", "Ĉesi registradon"),
+        ("Recording saved", "Registrado konservita"),
+        ("Session Recording", "Registrado de seancoj"),
+        ("Record all the controlled sessions", "Registri ĉiujn regatajn seancojn"),
+        ("Directory", "Dosierujo"),
+        ("Keep days", "Tagoj por konservi"),
+        ("Max size", "Maksimuma grandeco"),
+        ("session_recording_tip", "Ĉi tiu seanco estas registrata de ĉi tiu komputilo"),
+        ("session_recording_option_tip", "La ekrano kaj la sono de ĉiu fora seanco estas konservataj kun la ID, la IP kaj la permesoj de la kunulo. La plej malnovaj registraĵoj estas forigataj preter la limoj."),
+        ("Fit remote resolution to window", "Adapti la foran distingivon al la fenestro"),
+        ("Key frame interval", "Intervalo de ŝlosilaj kadroj"),
+        ("Seconds", "Sekundoj"),
+        ("key_frame_interval_tip", "Regulaj ŝlosilaj kadroj aŭtomate riparas bildon difektitan survoje, 0 malŝaltas ilin. Validas por novaj konektoj."),
+        ("Allow hearing the microphone of the peer", "Permesi aŭdi la mikrofonon de la samtavolano"),
+        ("Enable Microphone", "Ebligi mikrofonon"),
+        ("Start microphone", "Ŝalti mikrofonon"),
+        ("Stop microphone", "Malŝalti mikrofonon"),
+        ("Microphone", "Mikrofono"),
     ].iter().cloned().collect();
 }
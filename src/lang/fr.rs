@@ -266,5 +266,37 @@ pub static ref T: std::collections::HashMap<&'static str, &'static str> =
         ("android_version_audio_tip", "La version actuelle d'Android ne prend pas en charge la capture audio, veuillez passer à Android 10 ou supérieur."),
         ("android_start_service_tip", "Appuyez sur [Démarrer le service] ou sur l'autorisation OUVRIR [Capture d'écran] pour démarrer le service de partage d'écran."),
         ("Account", "Compte"),
+        ("Key", "Clé"),
+        ("Require Encryption", "Exiger le chiffrement"),
+        ("Overwrite", "Écraser"),
+        ("Skip if same size and time", "Ignorer si même taille et date"),
+        ("Skip if same content", "Ignorer si même contenu"),
+        ("Ask", "Demander"),
+        ("Confirm Overwrite", "Confirmer l'écrasement"),
+        ("overwrite_files_tip", "Ces fichiers existent déjà dans la destination, les écraser ?"),
+        ("File Transfer Speed Limit", "Limite de vitesse du transfert de fichiers"),
+        ("speed_limit_tip", "En Ko/s, vide pour illimité"),
+        ("peer_speed_limit_tip", "En Ko/s, pour cet appareil uniquement, vide pour le réglage global"),
+        ("Lossless text mode", "Mode texte sans perte"),
+        ("Show all displays", "Afficher tous les écrans"),
+        ("Start recording", "Démarrer l'enregistrement"),
+        ("Stop recording", "Arrêter l'enregistrement"),
+        ("Recording saved", "Enregistrement sauvegardé"),
+        ("Session Recording", "Enregistrement des sessions"),
+        ("Record all the controlled sessions", "Enregistrer toutes les sessions contrôlées"),
+        ("Directory", "Répertoire"),
+        ("Keep days", "Jours de conservation"),
+        ("Max size", "Taille maximale"),
+        ("session_recording_tip", "Cette session est enregistrée par cet ordinateur"),
+        ("session_recording_option_tip", "L'écran et le son de chaque session de contrôle à distance sont enregistrés avec l'ID, l'IP et les permissions du pair. Les enregistrements les plus anciens sont supprimés au-delà des limites."),
+        ("Fit remote resolution to window", "Adapter la résolution distante à la fenêtre"),
+        ("Key frame interval", "Intervalle des images clés"),
+        ("Seconds", "Secondes"),
+        ("key_frame_interval_tip", "Des images clés régulières réparent automatiquement une image abîmée en route, 0 les désactive. S'applique aux nouvelles connexions."),
+        ("Allow hearing the microphone of the peer", "Autoriser l'écoute du microphone du pair"),
+        ("Enable Microphone", "Activer le microphone"),
+        ("Start microphone", "Allumer le microphone"),
+        ("Stop microphone", "Couper le microphone"),
+        ("Microphone", "Microphone"),
     ].iter().cloned().collect();
 }
@@ -266,5 +266,37 @@ pub static ref T: std::collections::HashMap<&'static str, &'static str> =
         ("android_version_audio_tip", "L'attuale versione di Android non supporta l'acquisizione audio, esegui l'upgrade ad Android 10 o versioni successive."),
         ("android_start_service_tip", "Toccare [Avvia servizio] o APRI l'autorizzazione [Cattura schermo] per avviare il servizio di condivisione dello schermo."),
         ("Account", "Account"),
+        ("Key", "Chiave"),
+        ("Require Encryption", "Richiedi crittografia"),
+        ("Overwrite", "Sovrascrivi"),
+        ("Skip if same size and time", "Salta se stessa dimensione e data"),
+        ("Skip if same content", "Salta se stesso contenuto"),
+        ("Ask", "Chiedi"),
+        ("Confirm Overwrite", "Conferma sovrascrittura"),
+        ("overwrite_files_tip", "Questi file esistono già nella destinazione, sovrascriverli?"),
+        ("File Transfer Speed Limit", "Limite velocità trasferimento file"),
+        ("speed_limit_tip", "In KB/s, vuoto per illimitato"),
+        ("peer_speed_limit_tip", "In KB/s, solo per questo dispositivo, vuoto per l'impostazione globale"),
+        ("Lossless text mode", "Modalità testo senza perdita"),
+        ("Show all displays", "Mostra tutti gli schermi"),
+        ("Start recording", "Avvia registrazione"),
+        ("Stop recording", "Ferma registrazione"),
+        ("Recording saved", "Registrazione salvata"),
+        ("Session Recording", "Registrazione sessioni"),
+        ("Record all the controlled sessions", "Registra tutte le sessioni controllate"),
+        ("Directory", "Cartella"),
+        ("Keep days", "Giorni di conservazione"),
+        ("Max size", "Dimensione massima"),
+        ("session_recording_tip", "Questa sessione è registrata da questo computer"),
+        ("session_recording_option_tip", "Lo schermo e l'audio di ogni sessione di controllo remoto vengono salvati con l'ID, l'IP e i permessi del peer. Le registrazioni più vecchie vengono rimosse oltre i limiti."),
+        ("Fit remote resolution to window", "Adatta la risoluzione remota alla finestra"),
+        ("Key frame interval", "Intervallo dei fotogrammi chiave"),
+        ("Seconds", "Secondi"),
+        ("key_frame_interval_tip", "Fotogrammi chiave periodici riparano automaticamente le immagini danneggiate lungo il percorso, 0 li disattiva. Vale per le nuove connessioni."),
+        ("Allow hearing the microphone of the peer", "Consenti di ascoltare il microfono del peer"),
+        ("Enable Microphone", "Abilita microfono"),
+        ("Start microphone", "Accendi il microfono"),
+        ("Stop microphone", "Spegni il microfono"),
+        ("Microphone", "Microfono"),
     ].iter().cloned().collect();
 }
@@ -266,5 +266,37 @@ pub static ref T: std::collections::HashMap<&'static str, &'static str> =
         ("android_version_audio_tip", "A versão atual do Android não suporta captura de áudio, por favor atualize para o Android 10 ou maior."),
         ("android_start_service_tip", "Toque [Iniciar Serviço] ou ABRA a permissão [Captura de Tela] para iniciar o serviço de compartilhamento de tela."),
         ("Account", "Conta"),
+        ("Key", "Chave"),
+        ("Require Encryption", "Exigir criptografia"),
+        ("Overwrite", "Sobrescrever"),
+        ("Skip if same size and time", "Pular se mesmo tamanho e data"),
+        ("Skip if same content", "Pular se mesmo conteúdo"),
+        ("Ask", "Perguntar"),
+        ("Confirm Overwrite", "Confirmar substituição"),
+        ("overwrite_files_tip", "Estes arquivos já existem no destino, sobrescrevê-los?"),
+        ("File Transfer Speed Limit", "Limite de velocidade da transferência de arquivos"),
+        ("speed_limit_tip", "Em KB/s, vazio para ilimitado"),
+        ("peer_speed_limit_tip", "Em KB/s, apenas para este dispositivo, vazio para a configuração global"),
+        ("Lossless text mode", "Modo texto sem perdas"),
+        ("Show all displays", "Mostrar todas as telas"),
+        ("Start recording", "Iniciar gravação"),
+        ("Stop recording", "Parar gravação"),
+        ("Recording saved", "Gravação salva"),
+        ("Session Recording", "Gravação de sessões"),
+        ("Record all the controlled sessions", "Gravar todas as sessões controladas"),
+        ("Directory", "Diretório"),
+        ("Keep days", "Dias de retenção"),
+        ("Max size", "Tamanho máximo"),
+        ("session_recording_tip", "Esta sessão está sendo gravada por este computador"),
+        ("session_recording_option_tip", "A tela e o som de cada sessão de controle remoto são salvos com o ID, o IP e as permissões do parceiro. As gravações mais antigas são removidas além dos limites."),
+        ("Fit remote resolution to window", "Ajustar a resolução remota à janela"),
+        ("Key frame interval", "Intervalo de quadros-chave"),
+        ("Seconds", "Segundos"),
+        ("key_frame_interval_tip", "Quadros-chave periódicos recuperam automaticamente a imagem danificada no caminho, 0 os desativa. Vale para novas conexões."),
+        ("Allow hearing the microphone of the peer", "Permitir ouvir o microfone do parceiro"),
+        ("Enable Microphone", "Habilitar microfone"),
+        ("Start microphone", "Ligar o microfone"),
+        ("Stop microphone", "Desligar o microfone"),
+        ("Microphone", "Microfone"),
     ].iter().cloned().collect();
 }
@@ -266,5 +266,37 @@ pub static ref T: std::collections::HashMap<&'static str, &'static str> =
         ("android_version_audio_tip", "Текущая версия Android не поддерживает захват звука, обновите ее до Android 10 или выше."),
         ("android_start_service_tip", "Коснитесь [Запуск связанного сервера] или ОТКРЫТЬ разрешение [Скриншот], чтобы запустить службу демонстрации экрана."),
         ("Account", "Аккаунт"),
+        ("Key", "Ключ"),
+        ("Require Encryption", "Требовать шифрование"),
+        ("Overwrite", "Перезаписать"),
+        ("Skip if same size and time", "Пропускать при совпадении размера и времени"),
+        ("Skip if same content", "Пропускать при совпадении содержимого"),
+        ("Ask", "Спрашивать"),
+        ("Confirm Overwrite", "Подтвердите перезапись"),
+        ("overwrite_files_tip", "Эти файлы уже существуют в месте назначения, перезаписать их?"),
+        ("File Transfer Speed Limit", "Ограничение скорости передачи файлов"),
+        ("speed_limit_tip", "В КБ/с, пусто — без ограничения"),
+        ("peer_speed_limit_tip", "В КБ/с, только для этого устройства, пусто — глобальная настройка"),
+        ("Lossless text mode", "Текстовый режим без потерь"),
+        ("Show all displays", "Показать все экраны"),
+        ("Start recording", "Начать запись"),
+        ("Stop recording", "Остановить запись"),
+        ("Recording saved", "Запись сохранена"),
+        ("Session Recording", "Запись сеансов"),
+        ("Record all the controlled sessions", "Записывать все управляемые сеансы"),
+        ("Directory", "Каталог"),
+        ("Keep days", "Хранить дней"),
+        ("Max size", "Максимальный размер"),
+        ("session_recording_tip", "Этот сеанс записывается этим компьютером"),
+        ("session_recording_option_tip", "Изображение и звук каждого сеанса удалённого управления сохраняются вместе с ID, IP и разрешениями партнёра. Самые старые записи удаляются при превышении ограничений."),
+        ("Fit remote resolution to window", "Подогнать удалённое разрешение под окно"),
+        ("Key frame interval", "Интервал ключевых кадров"),
+        ("Seconds", "Секунды"),
+        ("key_frame_interval_tip", "Периодические ключевые кадры автоматически восстанавливают изображение, повреждённое в пути, 0 отключает их. Действует для новых подключений."),
+        ("Allow hearing the microphone of the peer", "Разрешить слышать микрофон партнёра"),
+        ("Enable Microphone", "Включить микрофон"),
+        ("Start microphone", "Включить микрофон"),
+        ("Stop microphone", "Выключить микрофон"),
+        ("Microphone", "Микрофон"),
     ].iter().cloned().collect();
 }
@@ -266,5 +266,37 @@ pub static ref T: std::collections::HashMap<&'static str, &'static str> =
         ("android_version_audio_tip", ""),
         ("android_start_service_tip", ""),
         ("Account", ""),
+        ("Key", ""),
+        ("Require Encryption", ""),
+        ("Overwrite", ""),
+        ("Skip if same size and time", ""),
+        ("Skip if same content", ""),
+        ("Ask", ""),
+        ("Confirm Overwrite", ""),
+        ("overwrite_files_tip", ""),
+        ("File Transfer Speed Limit", ""),
+        ("speed_limit_tip", ""),
+        ("peer_speed_limit_tip", ""),
+        ("Lossless text mode", ""),
+        ("Show all displays", ""),
+        ("Start recording", ""),
+        ("Stop recording", ""),
+        ("Recording saved", ""),
+        ("Session Recording", ""),
+        ("Record all the controlled sessions", ""),
+        ("Directory", ""),
+        ("Keep days", ""),
+        ("Max size", ""),
+        ("session_recording_tip", ""),
+        ("session_recording_option_tip", ""),
+        ("Fit remote resolution to window", ""),
+        ("Key frame interval", ""),
+        ("Seconds", ""),
+        ("key_frame_interval_tip", ""),
+        ("Allow hearing the microphone of the peer", ""),
+        ("Enable Microphone", ""),
+        ("Start microphone", ""),
+        ("Stop microphone", ""),
+        ("Microphone", ""),
     ].iter().cloned().collect();
 }
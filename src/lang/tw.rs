@@ -266,5 +266,37 @@ pub static ref T: std::collections::HashMap<&'static str, &'static str> =
         ("android_version_audio_tip", "當前安卓版本不支持音頻錄製，請升級至安卓10或更高。"),
         ("android_start_service_tip", "點擊 [啟動服務] 或打開 [屏幕錄製] 權限開啟手機屏幕共享服務。"),
         ("Account", "帳戶"),
+        ("Key", "金鑰"),
+        ("Require Encryption", "強制加密"),
+        ("Overwrite", "覆蓋"),
+        ("Skip if same size and time", "大小和時間相同則略過"),
+        ("Skip if same content", "內容相同則略過"),
+        ("Ask", "詢問"),
+        ("Confirm Overwrite", "確認覆蓋"),
+        ("overwrite_files_tip", "目標位置已存在以下檔案，是否覆蓋？"),
+        ("File Transfer Speed Limit", "檔案傳輸限速"),
+        ("speed_limit_tip", "單位為KB/s，留空表示不限速"),
+        ("peer_speed_limit_tip", "單位為KB/s，僅用於此設備，留空表示使用全域設定"),
+        ("Lossless text mode", "無損文字模式"),
+        ("Show all displays", "顯示所有螢幕"),
+        ("Start recording", "開始錄影"),
+        ("Stop recording", "停止錄影"),
+        ("Recording saved", "錄影已儲存"),
+        ("Session Recording", "工作階段錄影"),
+        ("Record all the controlled sessions", "錄製所有被控工作階段"),
+        ("Directory", "目錄"),
+        ("Keep days", "保留天數"),
+        ("Max size", "最大容量"),
+        ("session_recording_tip", "本次工作階段正在被本機錄製"),
+        ("session_recording_option_tip", "每個遠端控制工作階段的畫面和聲音會連同對方的 ID、IP 和權限一起儲存，超出限制時刪除最早的錄影。"),
+        ("Fit remote resolution to window", "遠端解析度適應視窗"),
+        ("Key frame interval", "關鍵幀間隔"),
+        ("Seconds", "秒"),
+        ("key_frame_interval_tip", "定期傳送關鍵幀，使中繼途中損壞的畫面自動恢復，0為關閉。新的連線生效。"),
+        ("Allow hearing the microphone of the peer", "允許聽到對方的麥克風"),
+        ("Enable Microphone", "啟用麥克風"),
+        ("Start microphone", "開啟麥克風"),
+        ("Stop microphone", "關閉麥克風"),
+        ("Microphone", "麥克風"),
     ].iter().cloned().collect();
 }
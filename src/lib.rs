@@ -7,6 +7,7 @@ mod server;
 #[cfg(not(any(target_os = "android", target_os = "ios")))]
 pub use self::server::*;
 mod client;
+pub mod recorder;
 #[cfg(not(any(target_os = "android", target_os = "ios")))]
 mod rendezvous_mediator;
 #[cfg(not(any(target_os = "android", target_os = "ios")))]
@@ -101,6 +101,11 @@ fn main() {
                 ipc::set_password(args[1].to_owned()).unwrap();
             }
             return;
+        } else if args[0] == "--key" {
+            if args.len() == 2 {
+                ipc::set_option("key", &args[1]);
+            }
+            return;
         }
     }
     ui::start(&mut args[..]);
@@ -111,16 +116,114 @@ fn main() {
     use clap::App;
     let args = format!(
         "-p, --port-forward=[PORT-FORWARD-OPTIONS] 'Format: remote-id:local-port:remote-port[:remote-host]'
+       -k, --key=[KEY] 'Public key of the ID server, comma separated if more than one, host=key for a given server'
        -s, --server... 'Start server'",
     );
+    const EXIT_CODES: &str =
+        "Exit code: 1 the operation failed, 2 wrong usage, 3 connection or login failed";
+    let password =
+        "--password=[PASSWORD] 'Password of the peer, $RUSTDESK_PASSWORD or the stored one if not set'";
     let matches = App::new("rustdesk")
         .version(crate::VERSION)
         .author("CarrieZ Studio<info@rustdesk.com>")
         .about("RustDesk command line tool")
         .args_from_usage(&args)
+        .subcommand(
+            App::new("cp")
+                .about("Copy files from or to a peer")
+                .after_help(EXIT_CODES)
+                .args_from_usage(&format!(
+                    "-r, --recursive 'Copy directories recursively'\n-p, --preserve 'Preserve the owners of the files, if written as root'\n{}\n<SRC> 'Source, [id:]path'\n<DST> 'Destination, [id:]path'",
+                    password
+                )),
+        )
+        .subcommand(
+            App::new("ls")
+                .about("List a directory on a peer as json")
+                .after_help(EXIT_CODES)
+                .args_from_usage(&format!("{}\n<TARGET> 'id:path'", password)),
+        )
+        .subcommand(
+            App::new("mkdir")
+                .about("Create a directory on a peer")
+                .after_help(EXIT_CODES)
+                .args_from_usage(&format!("{}\n<TARGET> 'id:path'", password)),
+        )
+        .subcommand(
+            App::new("rm")
+                .about("Remove a file or a directory on a peer")
+                .after_help(EXIT_CODES)
+                .args_from_usage(&format!(
+                    "-r, --recursive 'Remove directories and their contents'\n{}\n<TARGET> 'id:path'",
+                    password
+                )),
+        )
+        .subcommand(
+            App::new("stat")
+                .about("Show the type, size and time of a file on a peer as json")
+                .after_help(EXIT_CODES)
+                .args_from_usage(&format!("{}\n<TARGET> 'id:path'", password)),
+        )
+        .subcommand(
+            App::new("record")
+                .about("Record the screen of a peer into a WebM file until Ctrl-C")
+                .after_help(EXIT_CODES)
+                .args_from_usage(&format!(
+                    "-d, --duration=[SECONDS] 'Stop after the duration'\n{}\n<ID> 'ID of the peer'\n<FILE> 'WebM file'",
+                    password
+                )),
+        )
         .get_matches();
     use hbb_common::env_logger::*;
     init_from_env(Env::default().filter_or(DEFAULT_FILTER_ENV, "info"));
+    if let Some(k) = matches.value_of("key") {
+        hbb_common::config::Config::set_option("key".to_owned(), k.to_owned());
+    }
+    let get_password = |m: &clap::ArgMatches| {
+        m.value_of("password")
+            .map(|x| x.to_owned())
+            .or(std::env::var("RUSTDESK_PASSWORD").ok())
+    };
+    let get_target = |m: &clap::ArgMatches| m.value_of("TARGET").unwrap_or_default().to_owned();
+    match matches.subcommand() {
+        Some(("cp", m)) => {
+            std::process::exit(cli::copy(
+                m.value_of("SRC").unwrap_or_default().to_owned(),
+                m.value_of("DST").unwrap_or_default().to_owned(),
+                m.is_present("recursive"),
+                m.is_present("preserve"),
+                get_password(m),
+            ));
+        }
+        Some(("record", m)) => {
+            let duration = match m.value_of("duration").map(|x| x.parse::<u64>()) {
+                Some(Ok(x)) => Some(x),
+                Some(Err(_)) => {
+                    log::error!("Wrong duration");
+                    std::process::exit(cli::EXIT_USAGE);
+                }
+                None => None,
+            };
+            std::process::exit(cli::record(
+                m.value_of("ID").unwrap_or_default().to_owned(),
+                m.value_of("FILE").unwrap_or_default().to_owned(),
+                duration,
+                get_password(m),
+            ));
+        }
+        Some((name, m)) => {
+            let cmd = match name {
+                "ls" => cli::FileCommand::List,
+                "mkdir" => cli::FileCommand::MakeDir,
+                "rm" => cli::FileCommand::Remove {
+                    recursive: m.is_present("recursive"),
+                },
+                _ => cli::FileCommand::Stat,
+            };
+            std::process::exit(cli::file_command(cmd, get_target(m), get_password(m)));
+        }
+        None => {}
+    }
     if let Some(p) = matches.value_of("port-forward") {
         let options: Vec<String> = p.split(":").map(|x| x.to_owned()).collect();
         if options.len() < 3 {
@@ -226,8 +226,8 @@ pub fn start_os_service() {
             start_new = true;
         }
         if start_new {
-            match crate::run_me(vec!["--server"]) {
-                Ok(ps) => server = Some(ps),
+            match start_server(&uid) {
+                Ok(ps) => server = ps,
                 Err(err) => {
                     log::error!("Failed to start server: {}", err);
                 }
@@ -243,6 +243,33 @@ pub fn start_os_service() {
     log::info!("Exit");
 }
 
+fn start_server(uid: &str) -> ResultType<Option<std::process::Child>> {
+    #[cfg(feature = "wayland")]
+    if get_display_server() == "wayland" {
+        // the screen cast portal is only reachable on the session bus of the user,
+        // so the server runs as the user on wayland
+        let mut bus = get_env("DBUS_SESSION_BUS_ADDRESS", uid);
+        if bus.is_empty() {
+            bus = format!("unix:path=/run/user/{}/bus", uid);
+        }
+        let mut display = get_env("WAYLAND_DISPLAY", uid);
+        if display.is_empty() {
+            display = "wayland-0".to_owned();
+        }
+        log::info!("Start server on wayland, WAYLAND_DISPLAY: {}", display);
+        return run_as_user_with_env(
+            "--server",
+            vec![
+                format!("DBUS_SESSION_BUS_ADDRESS={}", bus),
+                format!("WAYLAND_DISPLAY={}", display),
+                "XDG_SESSION_TYPE=wayland".to_owned(),
+            ],
+        );
+    }
+    let _ = uid;
+    Ok(Some(crate::run_me(vec!["--server"])?))
+}
+
 pub fn get_active_userid() -> String {
     get_value_of_seat0(1)
 }
@@ -531,11 +558,19 @@ pub fn is_root() -> bool {
 }
 
 pub fn run_as_user(arg: &str) -> ResultType<Option<std::process::Child>> {
+    run_as_user_with_env(arg, Vec::new())
+}
+
+fn run_as_user_with_env(
+    arg: &str,
+    mut envs: Vec<String>,
+) -> ResultType<Option<std::process::Child>> {
     let uid = get_active_userid();
     let cmd = std::env::current_exe()?;
+    envs.insert(0, format!("XDG_RUNTIME_DIR=/run/user/{}", uid));
     let task = std::process::Command::new("sudo")
+        .args(&envs)
         .args(vec![
-            &format!("XDG_RUNTIME_DIR=/run/user/{}", uid) as &str,
             "-u",
             &get_active_username(),
             cmd.to_str().unwrap_or(""),
@@ -640,3 +675,155 @@ fn get_env(name: &str, uid: &str) -> String {
         "".to_owned()
     }
 }
+
+fn parse_size(s: &str) -> Option<(usize, usize)> {
+    let mut it = s.trim_end_matches('i').splitn(2, 'x');
+    Some((it.next()?.parse().ok()?, it.next()?.parse().ok()?))
+}
+
+// the name, the current size and the modes of the xrandr output at `origin`
+fn get_xrandr_output(
+    origin: (i32, i32),
+) -> ResultType<(String, (usize, usize), Vec<(usize, usize)>)> {
+    let output = std::process::Command::new("xrandr")
+        .arg("--current")
+        .output()?;
+    if !output.status.success() {
+        bail!(
+            "Failed to run xrandr: {}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+    match parse_xrandr_output(&String::from_utf8_lossy(&output.stdout), origin) {
+        Some(x) => Ok(x),
+        None => bail!("No xrandr output at {:?}", origin),
+    }
+}
+
+fn parse_xrandr_output(
+    stdout: &str,
+    origin: (i32, i32),
+) -> Option<(String, (usize, usize), Vec<(usize, usize)>)> {
+    let mut found: Option<(String, (usize, usize), Vec<(usize, usize)>)> = None;
+    for line in stdout.lines() {
+        if !line.starts_with(char::is_whitespace) {
+            if found.is_some() {
+                break;
+            }
+            // e.g. "HDMI-1 connected primary 1920x1080+0+0 (normal left inverted right) 0mm x 0mm"
+            let tokens: Vec<&str> = line.split_whitespace().collect();
+            if tokens.get(1) != Some(&"connected") {
+                continue;
+            }
+            for token in tokens.iter().skip(2) {
+                let geometry: Vec<&str> = token.split('+').collect();
+                if geometry.len() != 3 {
+                    continue;
+                }
+                if let (Some(size), Ok(x), Ok(y)) = (
+                    parse_size(geometry[0]),
+                    geometry[1].parse::<i32>(),
+                    geometry[2].parse::<i32>(),
+                ) {
+                    if (x, y) == origin {
+                        found = Some((tokens[0].to_owned(), size, Vec::new()));
+                    }
+                    break;
+                }
+            }
+        } else if let Some((_, current, modes)) = found.as_mut() {
+            // e.g. "   1920x1080     60.00*+  59.94"
+            if let Some(size) = line.split_whitespace().next().and_then(parse_size) {
+                if line.contains('*') {
+                    *current = size;
+                }
+                if !modes.contains(&size) {
+                    modes.push(size);
+                }
+            }
+        }
+    }
+    found
+}
+
+pub fn set_resolution(name: &str, width: usize, height: usize) -> ResultType<()> {
+    let output = std::process::Command::new("xrandr")
+        .arg("--output")
+        .arg(name)
+        .arg("--mode")
+        .arg(format!("{}x{}", width, height))
+        .output()?;
+    if !output.status.success() {
+        bail!(
+            "Failed to change the resolution of {}: {}",
+            name,
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+    Ok(())
+}
+
+/// Switch the output of the display at `origin` to the largest mode fitting in the size,
+/// the smallest mode if none fits. Returns the name of the output and the size to restore.
+pub fn change_resolution(
+    origin: (i32, i32),
+    width: usize,
+    height: usize,
+) -> ResultType<(String, usize, usize)> {
+    let (name, current, modes) = get_xrandr_output(origin)?;
+    let mode = modes
+        .iter()
+        .filter(|(w, h)| *w <= width && *h <= height)
+        .max_by_key(|(w, h)| w * h)
+        .or_else(|| modes.iter().min_by_key(|(w, h)| w * h));
+    let mode = match mode {
+        Some(mode) => *mode,
+        None => bail!("No modes of {}", name),
+    };
+    if mode != current {
+        log::info!("Changing the resolution of {} to {:?}", name, mode);
+        set_resolution(&name, mode.0, mode.1)?;
+    }
+    Ok((name, current.0, current.1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("1920x1080"), Some((1920, 1080)));
+        // interlaced
+        assert_eq!(parse_size("1920x1080i"), Some((1920, 1080)));
+        assert_eq!(parse_size("1920x"), None);
+        assert_eq!(parse_size("1920"), None);
+        assert_eq!(parse_size("axb"), None);
+        assert_eq!(parse_size(""), None);
+    }
+
+    #[test]
+    fn test_parse_xrandr_output() {
+        let stdout = "\
+Screen 0: minimum 320 x 200, current 3200 x 1080, maximum 16384 x 16384
+HDMI-1 connected primary 1920x1080+0+0 (normal left inverted right x axis y axis) 531mm x 299mm
+   1920x1080     60.00 +  50.00    59.94
+   1920x1080i    60.00*   50.00    59.94
+   1280x720      60.00    50.00    59.94
+HDMI-2 disconnected (normal left inverted right x axis y axis)
+DP-1 connected 1280x1024+1920+0 (normal left inverted right x axis y axis) 338mm x 270mm
+   1280x1024     60.02*+  75.02
+   1024x768      75.03    60.00
+";
+        let (name, current, modes) = parse_xrandr_output(stdout, (0, 0)).unwrap();
+        assert_eq!(name, "HDMI-1");
+        // the interlaced mode in use, of the same size as the progressive one
+        assert_eq!(current, (1920, 1080));
+        assert_eq!(modes, vec![(1920, 1080), (1280, 720)]);
+        let (name, current, modes) = parse_xrandr_output(stdout, (1920, 0)).unwrap();
+        assert_eq!(name, "DP-1");
+        assert_eq!(current, (1280, 1024));
+        assert_eq!(modes, vec![(1280, 1024), (1024, 768)]);
+        assert!(parse_xrandr_output(stdout, (0, 1080)).is_none());
+    }
+}
@@ -26,7 +26,7 @@ pub async fn listen(
     interface: impl Interface,
     ui_receiver: mpsc::UnboundedReceiver<Data>,
 ) -> ResultType<()> {
-    let listener = tcp::new_listener(format!("0.0.0.0:{}", port), true).await?;
+    let listener = tcp::listen_any(port as _, true).await?;
     let addr = listener.local_addr()?;
     log::info!("listening on port {:?}", addr);
     let is_rdp = port == 0;
@@ -84,7 +84,7 @@ async fn connect_and_login(
     } else {
         ConnType::PORT_FORWARD
     };
-    let (mut stream, _) = Client::start(id, conn_type).await?;
+    let (mut stream, _, _) = Client::start(id, conn_type).await?;
     let mut interface = interface;
     let mut buffer = Vec::new();
     loop {
@@ -0,0 +1,398 @@
+// Session recording, the VP9 and Opus streams are muxed into WebM as received, without
+// re-encoding. WebM can not change the video size, a new file is started with a "-1", "-2" ...
+// suffix when the size of the key frames changes, e.g. after switching the display, or when the
+// file is rolled over.
+
+use hbb_common::{
+    bail,
+    config::Config,
+    log,
+    message_proto::{video_frame, AudioFormat, AudioFrame, EncodedVideoFrame, VideoFrame},
+    ResultType,
+};
+use std::{
+    fs::File,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+use webm::mux::{self, Track};
+
+type Writer = mux::Writer<File>;
+
+const AUDIO_TRACK: i32 = 2;
+// pre-skip of the opus decoder in samples at 48kHz
+const OPUS_PRE_SKIP: u16 = 3840;
+// the peer stopped sending audio for longer, the next frame goes back to the wall clock, ns
+const MAX_AUDIO_GAP: u64 = 1_000_000_000;
+
+struct Segment {
+    path: PathBuf,
+    webm: mux::Segment<Writer>,
+    video: mux::VideoTrack,
+    audio: Option<mux::AudioTrack>,
+    width: u32,
+    height: u32,
+    start: Instant,
+    // pts of the first frame, ms
+    first_pts: i64,
+    // shift of the pts after the peer restarted its encoder, ms
+    offset: i64,
+    last_ts: i64,
+    // timestamp of the next audio frame, after the samples written so far, ns
+    next_audio_ts: Option<u64>,
+}
+
+impl Segment {
+    fn new(path: &Path, width: u32, height: u32, audio: Option<&AudioFormat>) -> ResultType<Self> {
+        let file = File::create(path)?;
+        let mut webm = match mux::Segment::new(mux::Writer::new(file)) {
+            Some(x) => x,
+            None => bail!("Failed to create the webm muxer"),
+        };
+        let video = webm.add_video_track(width, height, None, mux::VideoCodecId::VP9);
+        let audio = audio.map(|f| {
+            let track = webm.add_audio_track(
+                f.sample_rate as _,
+                f.channels as _,
+                Some(AUDIO_TRACK),
+                mux::AudioCodecId::Opus,
+            );
+            webm.set_codec_private(AUDIO_TRACK as _, &opus_head(f));
+            track
+        });
+        log::info!("Recording to {} ({}x{})", path.display(), width, height);
+        Ok(Self {
+            path: path.to_owned(),
+            webm,
+            video,
+            audio,
+            width,
+            height,
+            start: Instant::now(),
+            first_pts: 0,
+            offset: 0,
+            last_ts: -1,
+            next_audio_ts: None,
+        })
+    }
+
+    fn write_video(&mut self, frame: &EncodedVideoFrame) {
+        if self.last_ts < 0 {
+            self.first_pts = frame.pts;
+        }
+        let mut ts = frame.pts - self.first_pts + self.offset;
+        if ts <= self.last_ts {
+            // the pts restarts with a new encoder of the peer
+            self.offset += self.last_ts + 1 - ts;
+            ts = self.last_ts + 1;
+        }
+        self.last_ts = ts;
+        self.video
+            .add_frame(&frame.data, ts as u64 * 1_000_000, frame.key);
+    }
+
+    fn write_audio(&mut self, frame: &AudioFrame) {
+        if let Some(audio) = self.audio.as_mut() {
+            let elapsed = self.start.elapsed().as_nanos() as u64;
+            let ts = audio_timestamp(&mut self.next_audio_ts, elapsed, &frame.data);
+            audio.add_frame(&frame.data, ts, true);
+        }
+    }
+
+    fn finalize(self) {
+        if self.webm.finalize(None).is_err() {
+            log::error!("Failed to finalize the recording");
+        }
+    }
+}
+
+// the frames arrive in bursts, so the timestamps follow the samples rather than the arrival
+fn audio_timestamp(next: &mut Option<u64>, elapsed: u64, data: &[u8]) -> u64 {
+    let ts = match *next {
+        Some(ts) if elapsed < ts + MAX_AUDIO_GAP => ts,
+        _ => elapsed,
+    };
+    *next = opus_packet_samples(data).map(|n| ts + n * 1_000_000_000 / 48_000);
+    ts
+}
+
+// https://datatracker.ietf.org/doc/html/rfc6716#section-3.1, the samples at 48kHz of a packet
+fn opus_packet_samples(data: &[u8]) -> Option<u64> {
+    let toc = *data.first()?;
+    let config = toc >> 3;
+    let frame = match config {
+        // silk, 10, 20, 40, 60ms
+        0..=11 => [480, 960, 1920, 2880][config as usize % 4],
+        // hybrid, 10, 20ms
+        12..=15 => [480, 960][config as usize % 2],
+        // celt, 2.5, 5, 10, 20ms
+        _ => [120, 240, 480, 960][config as usize % 4],
+    };
+    let frames = match toc & 3 {
+        0 => 1,
+        1 | 2 => 2,
+        _ => (*data.get(1)? & 0x3f) as u64,
+    };
+    Some(frame * frames)
+}
+
+// the CodecPrivate of an opus track, https://datatracker.ietf.org/doc/html/rfc7845#section-5.1
+fn opus_head(f: &AudioFormat) -> Vec<u8> {
+    let mut head = b"OpusHead".to_vec();
+    head.push(1);
+    head.push(f.channels as _);
+    head.extend_from_slice(&OPUS_PRE_SKIP.to_le_bytes());
+    head.extend_from_slice(&f.sample_rate.to_le_bytes());
+    head.extend_from_slice(&0i16.to_le_bytes());
+    head.push(0);
+    head
+}
+
+/// Records the video frames into WebM files, only vp9 can be recorded.
+/// The frames must be of one display, the caller skips the others.
+pub struct Recorder {
+    path: PathBuf,
+    index: usize,
+    audio_format: Option<AudioFormat>,
+    segment: Option<Segment>,
+    warned: bool,
+}
+
+impl Recorder {
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            index: 0,
+            audio_format: None,
+            segment: None,
+            warned: false,
+        }
+    }
+
+    /// A file in the Videos directory of the user named after the peer and the time.
+    pub fn default_path(id: &str) -> PathBuf {
+        let mut dir = Config::get_home();
+        dir.push("Videos");
+        if !dir.is_dir() {
+            dir.pop();
+        }
+        dir.join(format!("{}_{}.webm", id, crate::get_time()))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn set_audio_format(&mut self, f: AudioFormat) {
+        self.audio_format = Some(f);
+    }
+
+    /// The file being written, none before the first key frame or after [`Recorder::roll`].
+    pub fn current_path(&self) -> Option<&Path> {
+        self.segment.as_ref().map(|x| x.path.as_path())
+    }
+
+    /// How long the current file has been written.
+    pub fn age(&self) -> Option<Duration> {
+        self.segment.as_ref().map(|x| x.start.elapsed())
+    }
+
+    /// Finish the current file, the next key frame starts a new one.
+    pub fn roll(&mut self) {
+        if let Some(segment) = self.segment.take() {
+            segment.finalize();
+        }
+    }
+
+    pub fn write_video(&mut self, vf: &VideoFrame) -> ResultType<()> {
+        let frames = match &vf.union {
+            Some(video_frame::Union::vp9s(vp9s)) => &vp9s.frames,
+            _ => {
+                if !self.warned {
+                    self.warned = true;
+                    log::warn!("Only vp9 can be recorded, waiting for the peer to switch");
+                }
+                return Ok(());
+            }
+        };
+        for frame in frames.iter() {
+            if frame.key {
+                if let Some((width, height)) = vp9_key_frame_size(&frame.data) {
+                    self.check_size(width, height)?;
+                }
+            }
+            // the file starts with a key frame
+            if let Some(segment) = self.segment.as_mut() {
+                segment.write_video(frame);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn write_audio(&mut self, frame: &AudioFrame) {
+        if let Some(segment) = self.segment.as_mut() {
+            segment.write_audio(frame);
+        }
+    }
+
+    // a new file if the size is changed
+    fn check_size(&mut self, width: u32, height: u32) -> ResultType<()> {
+        if let Some(segment) = self.segment.as_ref() {
+            if segment.width == width && segment.height == height {
+                return Ok(());
+            }
+        }
+        if let Some(segment) = self.segment.take() {
+            segment.finalize();
+        }
+        let path = if self.index == 0 {
+            self.path.clone()
+        } else {
+            let stem = self
+                .path
+                .file_stem()
+                .map(|x| x.to_string_lossy().to_string())
+                .unwrap_or_default();
+            self.path
+                .with_file_name(format!("{}-{}.webm", stem, self.index))
+        };
+        self.index += 1;
+        self.segment = Some(Segment::new(
+            &path,
+            width,
+            height,
+            self.audio_format.as_ref(),
+        )?);
+        Ok(())
+    }
+}
+
+impl Drop for Recorder {
+    fn drop(&mut self) {
+        if let Some(segment) = self.segment.take() {
+            segment.finalize();
+        }
+    }
+}
+
+// https://storage.googleapis.com/downloads.webmproject.org/docs/vp9/vp9-bitstream-specification-v0.6-20160331-draft.pdf
+// 6.2 uncompressed header, the size follows the sync code and the color config
+fn vp9_key_frame_size(data: &[u8]) -> Option<(u32, u32)> {
+    let mut r = BitReader { data, pos: 0 };
+    if r.read(2)? != 2 {
+        return None;
+    }
+    let profile = r.read(1)? | (r.read(1)? << 1);
+    if profile == 3 {
+        r.read(1)?;
+    }
+    // show_existing_frame
+    if r.read(1)? == 1 {
+        return None;
+    }
+    // frame_type, 0 is a key frame
+    if r.read(1)? != 0 {
+        return None;
+    }
+    // show_frame, error_resilient_mode
+    r.read(2)?;
+    if r.read(24)? != 0x498342 {
+        return None;
+    }
+    if profile >= 2 {
+        // ten_or_twelve_bit
+        r.read(1)?;
+    }
+    const CS_RGB: u32 = 7;
+    if r.read(3)? != CS_RGB {
+        // color_range
+        r.read(1)?;
+        if profile == 1 || profile == 3 {
+            // subsampling_x, subsampling_y, reserved_zero
+            r.read(3)?;
+        }
+    } else if profile == 1 || profile == 3 {
+        r.read(1)?;
+    }
+    let width = r.read(16)? + 1;
+    let height = r.read(16)? + 1;
+    Some((width, height))
+}
+
+struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> BitReader<'a> {
+    fn read(&mut self, n: usize) -> Option<u32> {
+        let mut v = 0;
+        for _ in 0..n {
+            let byte = *self.data.get(self.pos / 8)?;
+            v = (v << 1) | ((byte >> (7 - self.pos % 8)) & 1) as u32;
+            self.pos += 1;
+        }
+        Some(v)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_vp9_key_frame_size() {
+        // as written by libvpx, profile 0, 4:2:0 8-bit, color space unknown
+        let p0 = [0x82, 0x49, 0x83, 0x42, 0x00, 0x27, 0xf0, 0x1d, 0xf0, 0x00];
+        assert_eq!(vp9_key_frame_size(&p0), Some((640, 480)));
+        // profile 1, 4:4:4 8-bit BT.601, with the subsampling bits
+        let p1 = [0xa2, 0x49, 0x83, 0x42, 0x20, 0x0e, 0xfe, 0x08, 0x6e];
+        assert_eq!(vp9_key_frame_size(&p1), Some((1920, 1080)));
+        // profile 2, 4:2:0 10-bit BT.709
+        let p2 = [0x92, 0x49, 0x83, 0x42, 0x20, 0x27, 0xf8, 0x16, 0x78];
+        assert_eq!(vp9_key_frame_size(&p2), Some((1280, 720)));
+        // profile 3, 12-bit RGB, the reserved bit after the profile shifts the sync code
+        let p3 = [0xb1, 0x24, 0xc1, 0xa1, 0x78, 0x3b, 0xfc, 0x21, 0xbc];
+        assert_eq!(vp9_key_frame_size(&p3), Some((3840, 2160)));
+        // inter frame
+        assert_eq!(vp9_key_frame_size(&[0x86, 0x00, 0x40, 0x92]), None);
+        // show_existing_frame
+        assert_eq!(vp9_key_frame_size(&[0x88]), None);
+        // wrong sync code
+        let mut bad = p0;
+        bad[2] = 0x84;
+        assert_eq!(vp9_key_frame_size(&bad), None);
+        // truncated
+        assert_eq!(vp9_key_frame_size(&p0[..6]), None);
+        assert_eq!(vp9_key_frame_size(&[]), None);
+    }
+
+    #[test]
+    fn test_audio_timestamp() {
+        // celt 20ms, one frame
+        let p20 = [0xf8, 0xff];
+        assert_eq!(opus_packet_samples(&p20), Some(960));
+        // silk 60ms, two frames of the same size
+        assert_eq!(opus_packet_samples(&[0x19]), Some(5760));
+        // hybrid 10ms, two frames of different sizes
+        assert_eq!(opus_packet_samples(&[0x62]), Some(960));
+        // celt 2.5ms, three frames in code 3
+        assert_eq!(opus_packet_samples(&[0x83, 0x03]), Some(360));
+        assert_eq!(opus_packet_samples(&[0x83]), None);
+        assert_eq!(opus_packet_samples(&[]), None);
+
+        let ms = 1_000_000;
+        let mut next = None;
+        // the first frame starts at the wall clock, a burst follows the samples
+        assert_eq!(audio_timestamp(&mut next, 500 * ms, &p20), 500 * ms);
+        assert_eq!(audio_timestamp(&mut next, 501 * ms, &p20), 520 * ms);
+        assert_eq!(audio_timestamp(&mut next, 502 * ms, &p20), 540 * ms);
+        // late frames too
+        assert_eq!(audio_timestamp(&mut next, 700 * ms, &p20), 560 * ms);
+        // after a pause of the audio
+        assert_eq!(audio_timestamp(&mut next, 3000 * ms, &p20), 3000 * ms);
+        // a packet of unknown duration, the next one is at the wall clock
+        assert_eq!(audio_timestamp(&mut next, 3001 * ms, &[]), 3020 * ms);
+        assert_eq!(audio_timestamp(&mut next, 3002 * ms, &p20), 3002 * ms);
+    }
+}
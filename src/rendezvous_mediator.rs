@@ -2,12 +2,14 @@ use crate::server::{check_zombie, new as new_server, ServerPtr};
 use hbb_common::{
     allow_err,
     anyhow::bail,
-    config::{self, Config, REG_INTERVAL, RENDEZVOUS_PORT, RENDEZVOUS_TIMEOUT},
+    config::{self, Config, NetworkType, REG_INTERVAL, RENDEZVOUS_PORT, RENDEZVOUS_TIMEOUT},
     futures::future::join_all,
     log,
     protobuf::Message as _,
     rendezvous_proto::*,
-    sleep, socket_client,
+    sleep,
+    socket2::{Domain, Socket, Type},
+    socket_client,
     tokio::{
         self, select,
         time::{interval, Duration},
@@ -16,7 +18,7 @@ use hbb_common::{
     AddrMangle, IntoTargetAddr, ResultType, TargetAddr,
 };
 use std::{
-    net::SocketAddr,
+    net::{Ipv6Addr, SocketAddr, UdpSocket},
     sync::{
         atomic::{AtomicBool, Ordering},
         Arc, Mutex,
@@ -101,14 +103,18 @@ impl RendezvousMediator {
             })
             .unwrap_or(host.to_owned());
         let mut rz = Self {
-            addr: Config::get_any_listen_addr().into_target_addr()?,
+            addr: Config::get_any_listen_addr(true).into_target_addr()?,
             host: host.clone(),
             host_prefix,
             last_id_pk_registry: "".to_owned(),
         };
 
         rz.addr = socket_client::get_target_addr(&crate::check_port(&host, RENDEZVOUS_PORT))?;
-        let any_addr = Config::get_any_listen_addr();
+        let any_addr = Config::get_any_listen_addr(socket_client::is_ipv4(&rz.addr));
+        // only the outgoing connections go through an http proxy, the udp below goes around it
+        if Config::get_network_type() == NetworkType::HttpConnect {
+            log::warn!("The id is registered over udp outside the http proxy, peers may not reach this host");
+        }
         let mut socket = socket_client::new_udp(any_addr, RENDEZVOUS_TIMEOUT).await?;
 
         const TIMER_OUT: Duration = Duration::from_secs(1);
@@ -244,6 +250,7 @@ impl RendezvousMediator {
                                     rz.addr = socket_client::get_target_addr(&crate::check_port(&host, RENDEZVOUS_PORT))?;
                                     // in some case of network reconnect (dial IP network),
                                     // old UDP socket not work any more after network recover
+                                    let any_addr = Config::get_any_listen_addr(socket_client::is_ipv4(&rz.addr));
                                     if let Some(s) = socket_client::rebind_udp(any_addr).await? {
                                         socket = s;
                                     }
@@ -293,7 +300,7 @@ impl RendezvousMediator {
 
         let mut socket = socket_client::connect_tcp(
             self.addr.to_owned(),
-            Config::get_any_listen_addr(),
+            Config::get_any_listen_addr(true),
             RENDEZVOUS_TIMEOUT,
         )
         .await?;
@@ -320,13 +327,11 @@ impl RendezvousMediator {
         log::debug!("Handle intranet from {:?}", peer_addr);
         let mut socket = socket_client::connect_tcp(
             self.addr.to_owned(),
-            Config::get_any_listen_addr(),
+            Config::get_any_listen_addr(true),
             RENDEZVOUS_TIMEOUT,
         )
         .await?;
         let local_addr = socket.local_addr();
-        let local_addr: SocketAddr =
-            format!("{}:{}", local_addr.ip(), local_addr.port()).parse()?;
         let mut msg_out = Message::new();
         let relay_server = self.get_relay_server(fla.relay_server);
         msg_out.set_local_addr(LocalAddr {
@@ -335,6 +340,7 @@ impl RendezvousMediator {
             local_addr: AddrMangle::encode(local_addr),
             relay_server,
             version: crate::VERSION.to_owned(),
+            quic: socket_client::is_quic_available(),
             ..Default::default()
         });
         let bytes = msg_out.write_to_bytes()?;
@@ -358,7 +364,7 @@ impl RendezvousMediator {
         let mut socket = {
             let socket = socket_client::connect_tcp(
                 self.addr.to_owned(),
-                Config::get_any_listen_addr(),
+                Config::get_any_listen_addr(true),
                 RENDEZVOUS_TIMEOUT,
             )
             .await?;
@@ -375,6 +381,7 @@ impl RendezvousMediator {
             relay_server,
             nat_type: nat_type.into(),
             version: crate::VERSION.to_owned(),
+            quic: socket_client::is_quic_available(),
             ..Default::default()
         });
         let bytes = msg_out.write_to_bytes()?;
@@ -456,12 +463,9 @@ impl RendezvousMediator {
             relay_server = provided_by_rendzvous_server;
         }
         if relay_server.is_empty() {
-            if self.host.contains(":") {
-                let tmp: Vec<&str> = self.host.split(":").collect();
-                if tmp.len() == 2 {
-                    let port: u16 = tmp[1].parse().unwrap_or(0);
-                    relay_server = format!("{}:{}", tmp[0], port + 1);
-                }
+            if let Some((host, port)) = crate::split_host_port(&self.host) {
+                let port: u16 = port.parse().unwrap_or(0);
+                relay_server = format!("{}:{}", host, port + 1);
             } else {
                 relay_server = self.host.clone();
             }
@@ -487,8 +491,7 @@ async fn direct_server(server: ServerPtr) {
         let disabled = Config::get_option("direct-server").is_empty();
         if !disabled && listener.is_none() {
             port = get_direct_port();
-            let addr = format!("0.0.0.0:{}", port);
-            match hbb_common::tcp::new_listener(&addr, false).await {
+            match hbb_common::tcp::listen_any(port as _, false).await {
                 Ok(l) => {
                     listener = Some(l);
                     log::info!(
@@ -499,8 +502,8 @@ async fn direct_server(server: ServerPtr) {
                 Err(err) => {
                     // to-do: pass to ui
                     log::error!(
-                        "Failed to start direct server on : {}, error: {}",
-                        addr,
+                        "Failed to start direct server on port: {}, error: {}",
+                        port,
                         err
                     );
                     loop {
@@ -520,8 +523,11 @@ async fn direct_server(server: ServerPtr) {
             }
             if let Ok(Ok((stream, addr))) = hbb_common::timeout(1000, l.accept()).await {
                 stream.set_nodelay(true).ok();
+                let addr = hbb_common::to_canonical(addr);
                 log::info!("direct access from {}", addr);
-                let local_addr = stream.local_addr().unwrap_or(Config::get_any_listen_addr());
+                let local_addr = stream
+                    .local_addr()
+                    .unwrap_or(Config::get_any_listen_addr(addr.is_ipv4()));
                 let server = server.clone();
                 tokio::spawn(async move {
                     allow_err!(
@@ -529,7 +535,8 @@ async fn direct_server(server: ServerPtr) {
                             server,
                             hbb_common::Stream::from(stream, local_addr),
                             addr,
-                            false,
+                            true,
+                            true,
                         )
                         .await
                     );
@@ -556,11 +563,42 @@ pub fn get_mac() -> String {
     }
 }
 
+// ipv6 has no broadcast, the pings are sent to this group of the link-local scope
+const DISCOVERY_GROUP_V6: Ipv6Addr = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0x5244, 0x5344);
+
+fn new_discovery_socket_v6(port: u16) -> ResultType<UdpSocket> {
+    let socket = Socket::new(Domain::ipv6(), Type::dgram(), None)?;
+    // not taking the ipv4 port of the broadcast
+    socket.set_only_v6(true)?;
+    socket.bind(&SocketAddr::from((Ipv6Addr::UNSPECIFIED, port)).into())?;
+    Ok(socket.into_udp_socket())
+}
+
 fn lan_discovery() -> ResultType<()> {
+    match new_discovery_socket_v6(get_broadcast_port()) {
+        // e.g. no ipv6 route for multicast, the ipv4 broadcast still answered
+        Ok(socket) => match socket.join_multicast_v6(&DISCOVERY_GROUP_V6, 0) {
+            Ok(_) => {
+                std::thread::spawn(move || allow_err!(answer_discovery(socket)));
+            }
+            Err(err) => {
+                log::info!("No ipv6 lan discovery: {}", err);
+            }
+        },
+        Err(err) => {
+            log::info!("No ipv6 lan discovery: {}", err);
+        }
+    }
     let addr = SocketAddr::from(([0, 0, 0, 0], get_broadcast_port()));
-    let socket = std::net::UdpSocket::bind(addr)?;
+    answer_discovery(UdpSocket::bind(addr)?)
+}
+
+fn answer_discovery(socket: UdpSocket) -> ResultType<()> {
     socket.set_read_timeout(Some(std::time::Duration::from_millis(1000)))?;
-    log::info!("lan discovery listener started");
+    log::info!(
+        "lan discovery listener started on {:?}",
+        socket.local_addr()
+    );
     loop {
         let mut buf = [0; 2048];
         if let Ok((len, addr)) = socket.recv_from(&mut buf) {
@@ -591,7 +629,7 @@ fn lan_discovery() -> ResultType<()> {
 
 pub fn discover() -> ResultType<()> {
     let addr = SocketAddr::from(([0, 0, 0, 0], 0));
-    let socket = std::net::UdpSocket::bind(addr)?;
+    let socket = UdpSocket::bind(addr)?;
     socket.set_broadcast(true)?;
     let mut msg_out = Message::new();
     let peer = PeerDiscovery {
@@ -599,8 +637,20 @@ pub fn discover() -> ResultType<()> {
         ..Default::default()
     };
     msg_out.set_peer_discovery(peer);
+    let ping = msg_out.write_to_bytes()?;
     let maddr = SocketAddr::from(([255, 255, 255, 255], get_broadcast_port()));
-    socket.send_to(&msg_out.write_to_bytes()?, maddr)?;
+    socket.send_to(&ping, maddr)?;
+    let mut sockets = vec![socket];
+    match new_discovery_socket_v6(0) {
+        Ok(socket) => {
+            let maddr = SocketAddr::from((DISCOVERY_GROUP_V6, get_broadcast_port()));
+            allow_err!(socket.send_to(&ping, maddr));
+            sockets.push(socket);
+        }
+        Err(err) => {
+            log::info!("No ipv6 lan discovery: {}", err);
+        }
+    }
     log::info!("discover ping sent");
     let mut last_recv_time = Instant::now();
     let mut last_write_time = Instant::now();
@@ -608,21 +658,25 @@ pub fn discover() -> ResultType<()> {
     // to-do: load saved peers, and update incrementally (then we can see offline)
     let mut peers = Vec::new();
     let mac = get_mac();
-    socket.set_read_timeout(Some(std::time::Duration::from_millis(10)))?;
+    for socket in sockets.iter() {
+        socket.set_read_timeout(Some(std::time::Duration::from_millis(10)))?;
+    }
     loop {
-        let mut buf = [0; 2048];
-        if let Ok((len, _)) = socket.recv_from(&mut buf) {
-            if let Ok(msg_in) = Message::parse_from_bytes(&buf[0..len]) {
-                match msg_in.union {
-                    Some(rendezvous_message::Union::peer_discovery(p)) => {
-                        last_recv_time = Instant::now();
-                        if p.cmd == "pong" {
-                            if p.mac != mac {
+        for socket in sockets.iter() {
+            let mut buf = [0; 2048];
+            if let Ok((len, _)) = socket.recv_from(&mut buf) {
+                if let Ok(msg_in) = Message::parse_from_bytes(&buf[0..len]) {
+                    match msg_in.union {
+                        Some(rendezvous_message::Union::peer_discovery(p)) => {
+                            last_recv_time = Instant::now();
+                            // answering on both ipv4 and ipv6
+                            let known = peers.iter().any(|x| x.0 == p.id);
+                            if p.cmd == "pong" && p.mac != mac && !known {
                                 peers.push((p.id, p.username, p.hostname, p.platform));
                             }
                         }
+                        _ => {}
                     }
-                    _ => {}
                 }
             }
         }
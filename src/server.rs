@@ -10,6 +10,7 @@ use hbb_common::{
     log,
     message_proto::*,
     protobuf::{Message as _, ProtobufEnum},
+    quic,
     rendezvous_proto::*,
     sleep, socket_client,
     sodiumoxide::crypto::{box_, secretbox, sign},
@@ -27,11 +28,18 @@ use std::{
     time::Duration,
 };
 
+// an old client neither expects nor answers the signed id on a direct-IP connection,
+// it waits for the login, so the connection goes on without encryption after this
+const DIRECT_HANDSHAKE_TIMEOUT: u64 = 3_000;
+
 pub mod audio_service;
 mod clipboard_service;
 mod connection;
 pub mod input_service;
 mod service;
+mod session_record;
+mod text_mode;
+mod video_qos;
 mod video_service;
 
 use hbb_common::tcp::new_listener;
@@ -41,6 +49,7 @@ type ConnMap = HashMap<i32, ConnInner>;
 
 lazy_static::lazy_static! {
     pub static ref CHILD_PROCESS: Childs = Default::default();
+    static ref MEDIA_START: std::time::Instant = std::time::Instant::now();
 }
 
 pub struct Server {
@@ -66,7 +75,12 @@ pub fn new() -> ServerPtr {
     Arc::new(RwLock::new(server))
 }
 
-async fn accept_connection_(server: ServerPtr, socket: Stream, secure: bool) -> ResultType<()> {
+async fn accept_connection_(
+    server: ServerPtr,
+    socket: Stream,
+    peer_addr: SocketAddr,
+    secure: bool,
+) -> ResultType<()> {
     let local_addr = socket.local_addr();
     drop(socket);
     // even we drop socket, below still may fail if not use reuse_addr,
@@ -74,10 +88,26 @@ async fn accept_connection_(server: ServerPtr, socket: Stream, secure: bool) ->
     // see “Only one usage of each socket address is normally permitted” on windows sometimes,
     let listener = new_listener(local_addr, true).await?;
     log::info!("Server listening on: {}", &listener.local_addr()?);
-    if let Ok((stream, addr)) = timeout(CONNECT_TIMEOUT, listener.accept()).await? {
-        stream.set_nodelay(true).ok();
-        let stream_addr = stream.local_addr()?;
-        create_tcp_connection(server, Stream::from(stream, stream_addr), addr, secure).await?;
+    // the peer tries QUIC on the same port first, and TCP if it fails
+    let quic = async {
+        if !socket_client::is_quic_available() {
+            bail!("QUIC is not available through the proxy");
+        }
+        quic::accept(peer_addr, local_addr, CONNECT_TIMEOUT).await
+    };
+    tokio::select! {
+        res = timeout(CONNECT_TIMEOUT, listener.accept()) => {
+            if let Ok((stream, addr)) = res? {
+                stream.set_nodelay(true).ok();
+                let stream_addr = stream.local_addr()?;
+                let stream = Stream::from(stream, stream_addr);
+                create_tcp_connection(server, stream, addr, secure, false).await?;
+            }
+        }
+        Ok((stream, addr)) = quic => {
+            log::info!("QUIC connection from {}", addr);
+            create_tcp_connection(server, stream, addr, secure, false).await?;
+        }
     }
     Ok(())
 }
@@ -87,6 +117,7 @@ pub async fn create_tcp_connection(
     stream: Stream,
     addr: SocketAddr,
     secure: bool,
+    direct: bool,
 ) -> ResultType<()> {
     let mut stream = stream;
     let id = {
@@ -95,6 +126,7 @@ pub async fn create_tcp_connection(
         w.id_count
     };
     let (sk, pk) = Config::get_key_pair();
+    let mut encrypted = false;
     if secure && pk.len() == sign::PUBLICKEYBYTES && sk.len() == sign::SECRETKEYBYTES {
         let mut sk_ = [0u8; sign::SECRETKEYBYTES];
         sk_[..].copy_from_slice(&sk);
@@ -112,48 +144,70 @@ pub async fn create_tcp_connection(
                 .unwrap_or_default(),
                 &sk,
             ),
+            pk,
             ..Default::default()
         });
         timeout(CONNECT_TIMEOUT, stream.send(&msg_out)).await??;
-        match timeout(CONNECT_TIMEOUT, stream.next()).await? {
-            Some(res) => {
-                let bytes = res?;
-                if let Ok(msg_in) = Message::parse_from_bytes(&bytes) {
-                    if let Some(message::Union::public_key(pk)) = msg_in.union {
-                        if pk.asymmetric_value.len() == box_::PUBLICKEYBYTES {
-                            let nonce = box_::Nonce([0u8; box_::NONCEBYTES]);
-                            let mut pk_ = [0u8; box_::PUBLICKEYBYTES];
-                            pk_[..].copy_from_slice(&pk.asymmetric_value);
-                            let their_pk_b = box_::PublicKey(pk_);
-                            let symmetric_key =
-                                box_::open(&pk.symmetric_value, &nonce, &their_pk_b, &our_sk_b)
-                                    .map_err(|_| {
-                                        anyhow!("Handshake failed: box decryption failure")
-                                    })?;
-                            if symmetric_key.len() != secretbox::KEYBYTES {
-                                bail!("Handshake failed: invalid secret key length from peer");
+        let wait = if direct {
+            DIRECT_HANDSHAKE_TIMEOUT
+        } else {
+            CONNECT_TIMEOUT
+        };
+        match timeout(wait, stream.next()).await {
+            Err(_) if direct => {
+                log::info!(
+                    "No handshake from {}, an old client, fall back to non-secure",
+                    addr
+                );
+            }
+            res => match res? {
+                Some(res) => {
+                    let bytes = res?;
+                    if let Ok(msg_in) = Message::parse_from_bytes(&bytes) {
+                        if let Some(message::Union::public_key(pk)) = msg_in.union {
+                            if pk.asymmetric_value.len() == box_::PUBLICKEYBYTES {
+                                let nonce = box_::Nonce([0u8; box_::NONCEBYTES]);
+                                let mut pk_ = [0u8; box_::PUBLICKEYBYTES];
+                                pk_[..].copy_from_slice(&pk.asymmetric_value);
+                                let their_pk_b = box_::PublicKey(pk_);
+                                let symmetric_key =
+                                    box_::open(&pk.symmetric_value, &nonce, &their_pk_b, &our_sk_b)
+                                        .map_err(|_| {
+                                            anyhow!("Handshake failed: box decryption failure")
+                                        })?;
+                                if symmetric_key.len() != secretbox::KEYBYTES {
+                                    bail!("Handshake failed: invalid secret key length from peer");
+                                }
+                                let mut key = [0u8; secretbox::KEYBYTES];
+                                key[..].copy_from_slice(&symmetric_key);
+                                stream.set_key(secretbox::Key(key));
+                                encrypted = true;
+                            } else if pk.asymmetric_value.is_empty() {
+                                // our key is not the one the rendezvous server signed for the
+                                // client, no such key on a direct-IP connection
+                                if !direct {
+                                    Config::set_key_confirmed(false);
+                                    log::info!("Force to update pk");
+                                }
+                            } else {
+                                bail!("Handshake failed: invalid public sign key length from peer");
                             }
-                            let mut key = [0u8; secretbox::KEYBYTES];
-                            key[..].copy_from_slice(&symmetric_key);
-                            stream.set_key(secretbox::Key(key));
-                        } else if pk.asymmetric_value.is_empty() {
-                            Config::set_key_confirmed(false);
-                            log::info!("Force to update pk");
                         } else {
-                            bail!("Handshake failed: invalid public sign key length from peer");
+                            log::error!("Handshake failed: invalid message type");
                         }
                     } else {
-                        log::error!("Handshake failed: invalid message type");
+                        bail!("Handshake failed: invalid message format");
                     }
-                } else {
-                    bail!("Handshake failed: invalid message format");
                 }
-            }
-            None => {
-                bail!("Failed to receive public key");
-            }
+                None => {
+                    bail!("Failed to receive public key");
+                }
+            },
         }
     }
+    if !encrypted && !Config::get_option("require-encryption").is_empty() {
+        bail!("Handshake failed: encryption is required");
+    }
 
     Connection::start(addr, stream, id, Arc::downgrade(&server)).await;
     Ok(())
@@ -165,7 +219,7 @@ pub async fn accept_connection(
     peer_addr: SocketAddr,
     secure: bool,
 ) {
-    if let Err(err) = accept_connection_(server, socket, secure).await {
+    if let Err(err) = accept_connection_(server, socket, peer_addr, secure).await {
         log::error!("Failed to accept connection from {}: {}", peer_addr, err);
     }
 }
@@ -198,7 +252,7 @@ async fn create_relay_connection_(
 ) -> ResultType<()> {
     let mut stream = socket_client::connect_tcp(
         crate::check_port(relay_server, RELAY_PORT),
-        Config::get_any_listen_addr(),
+        Config::get_any_listen_addr(true),
         CONNECT_TIMEOUT,
     )
     .await?;
@@ -208,14 +262,15 @@ async fn create_relay_connection_(
         ..Default::default()
     });
     stream.send(&msg_out).await?;
-    create_tcp_connection(server, stream, peer_addr, secure).await?;
+    create_tcp_connection(server, stream, peer_addr, secure, false).await?;
     Ok(())
 }
 
 impl Server {
     pub fn add_connection(&mut self, conn: ConnInner, noperms: &Vec<&'static str>) {
         for s in self.services.values() {
-            if !noperms.contains(&s.name()) {
+            // the extra displays are subscribed by capture_displays
+            if !noperms.contains(&s.name()) && !video_service::is_display_service(s.name()) {
                 s.on_subscribe(conn.clone());
             }
         }
@@ -234,6 +289,22 @@ impl Server {
         self.services.insert(name, service);
     }
 
+    /// Stream `displays` to `conn` besides the current display,
+    /// the services of the extra displays are created on demand.
+    pub fn capture_displays(&mut self, conn: ConnInner, displays: &[usize]) {
+        let n = video_service::get_display_num();
+        let displays: Vec<usize> = displays.iter().filter(|d| **d < n).cloned().collect();
+        for display in displays.iter() {
+            let name = video_service::get_display_service_name(*display);
+            if !self.services.contains_key(name) {
+                self.add_service(Box::new(video_service::new_display(*display)));
+            }
+        }
+        for (display, name) in video_service::get_display_service_names() {
+            self.subscribe(name, conn.clone(), displays.contains(&display));
+        }
+    }
+
     pub fn subscribe(&mut self, name: &str, conn: ConnInner, sub: bool) {
         if let Some(s) = self.services.get(&name) {
             if s.is_subed(conn.id()) == sub {
@@ -256,6 +327,12 @@ impl Drop for Server {
     }
 }
 
+/// Milliseconds on the clock shared by the video pts and the audio timestamps,
+/// so that the peers can play them in sync.
+pub fn get_media_time() -> i64 {
+    MEDIA_START.elapsed().as_millis() as _
+}
+
 pub fn check_zombie() {
     std::thread::spawn(|| loop {
         let mut lock = CHILD_PROCESS.lock().unwrap();
@@ -13,7 +13,7 @@
 // https://github.com/krruzic/pulsectl
 
 use super::*;
-use magnum_opus::{Application::*, Channels::*, Encoder};
+use magnum_opus::{Channels::*, Encoder};
 use std::sync::atomic::{AtomicBool, Ordering};
 
 pub const NAME: &'static str = "audio";
@@ -52,8 +52,10 @@ mod pa_impl {
         let mut stream = crate::ipc::connect(1000, "_pa").await?;
         unsafe {
             AUDIO_ZERO_COUNT = 0;
+            AUDIO_CLOCK = (0, 0);
         }
-        let mut encoder = Encoder::new(crate::platform::linux::PA_SAMPLE_RATE, Stereo, LowDelay)?;
+        let mut encoder =
+            crate::common::new_opus_encoder(crate::platform::linux::PA_SAMPLE_RATE, Stereo)?;
         allow_err!(
             stream
                 .send(&crate::ipc::Data::Config((
@@ -70,7 +72,13 @@ mod pa_impl {
             })?;
             if let Ok(data) = stream.next_raw().await {
                 if data.len() == 0 {
-                    send_f32(&zero_audio_frame, &mut encoder, &sp);
+                    send_f32(
+                        &zero_audio_frame,
+                        crate::platform::linux::PA_SAMPLE_RATE,
+                        2,
+                        &mut encoder,
+                        &sp,
+                    );
                     continue;
                 }
                 if data.len() != AUDIO_DATA_SIZE_U8 {
@@ -79,7 +87,13 @@ mod pa_impl {
                 let data = unsafe {
                     std::slice::from_raw_parts::<f32>(data.as_ptr() as _, data.len() / 4)
                 };
-                send_f32(data, &mut encoder, &sp);
+                send_f32(
+                    data,
+                    crate::platform::linux::PA_SAMPLE_RATE,
+                    2,
+                    &mut encoder,
+                    &sp,
+                );
             }
         }
         Ok(())
@@ -140,7 +154,7 @@ mod cpal_impl {
         } else {
             data
         };
-        send_f32(data, encoder, sp);
+        send_f32(data, sample_rate, channels, encoder, sp);
     }
 
     #[cfg(windows)]
@@ -206,27 +220,16 @@ mod cpal_impl {
             // too many UnknownErrno, will improve later
             log::trace!("an error occurred on stream: {}", err);
         };
-        // Sample rate must be one of 8000, 12000, 16000, 24000, or 48000.
         let sample_rate_0 = config.sample_rate().0;
-        let sample_rate = if sample_rate_0 < 12000 {
-            8000
-        } else if sample_rate_0 < 16000 {
-            12000
-        } else if sample_rate_0 < 24000 {
-            16000
-        } else if sample_rate_0 < 48000 {
-            24000
-        } else {
-            48000
-        };
+        let sample_rate = crate::common::get_opus_sample_rate(sample_rate_0);
         log::debug!("Audio sample rate : {}", sample_rate);
         unsafe {
             AUDIO_ZERO_COUNT = 0;
+            AUDIO_CLOCK = (0, 0);
         }
-        let mut encoder = Encoder::new(
+        let mut encoder = crate::common::new_opus_encoder(
             sample_rate,
             if config.channels() > 1 { Stereo } else { Mono },
-            LowDelay,
         )?;
         let channels = config.channels();
         let stream = match config.sample_format() {
@@ -289,6 +292,7 @@ fn create_format_msg(sample_rate: u32, channels: u16) -> Message {
         channels: channels as _,
         ..Default::default()
     };
+    super::session_record::set_audio_format(format.clone());
     let mut misc = Misc::new();
     misc.set_audio_format(format);
     let mut msg = Message::new();
@@ -302,7 +306,33 @@ fn create_format_msg(sample_rate: u32, channels: u16) -> Message {
 const MAX_AUDIO_ZERO_COUNT: u16 = 800;
 static mut AUDIO_ZERO_COUNT: u16 = 0;
 
-fn send_f32(data: &[f32], encoder: &mut Encoder, sp: &GenericService) {
+// the timestamps count the samples so that the peers can tell the lost frames from the jitter,
+// starting on the media clock and re-anchored if drifting more than MAX_AUDIO_CLOCK_DRIFT ms away
+const MAX_AUDIO_CLOCK_DRIFT: i64 = 100;
+static mut AUDIO_CLOCK: (i64, u64) = (0, 0);
+
+fn get_timestamp(samples: usize, sample_rate: u32) -> i64 {
+    let now = super::get_media_time().max(1); // 0 is unknown
+    unsafe {
+        let mut timestamp = AUDIO_CLOCK.0 + (AUDIO_CLOCK.1 * 1000 / sample_rate as u64) as i64;
+        if AUDIO_CLOCK.0 == 0 || (timestamp - now).abs() > MAX_AUDIO_CLOCK_DRIFT {
+            AUDIO_CLOCK = (now, 0);
+            timestamp = now;
+        }
+        AUDIO_CLOCK.1 += samples as u64;
+        timestamp
+    }
+}
+
+fn send_f32(
+    data: &[f32],
+    sample_rate: u32,
+    channels: u16,
+    encoder: &mut Encoder,
+    sp: &GenericService,
+) {
+    // the silent frames dropped below still move the clock
+    let timestamp = get_timestamp(data.len() / channels.max(1) as usize, sample_rate);
     if data.iter().filter(|x| **x != 0.).next().is_some() {
         unsafe {
             AUDIO_ZERO_COUNT = 0;
@@ -321,11 +351,14 @@ fn send_f32(data: &[f32], encoder: &mut Encoder, sp: &GenericService) {
     }
     match encoder.encode_vec_float(data, data.len() * 6) {
         Ok(data) => {
-            let mut msg_out = Message::new();
-            msg_out.set_audio_frame(AudioFrame {
+            let frame = AudioFrame {
                 data,
+                timestamp,
                 ..Default::default()
-            });
+            };
+            super::session_record::write_audio(&frame);
+            let mut msg_out = Message::new();
+            msg_out.set_audio_frame(frame);
             sp.send(msg_out);
         }
         Err(_) => {}
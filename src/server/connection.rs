@@ -40,14 +40,25 @@ enum MessageInput {
     PrivacyOff,
 }
 
+// hashed in a blocking thread for the file job of the id
+enum JobHashed {
+    Digests(i32, Vec<Vec<u8>>),
+    Prefixes(i32, fs::PrefixHashers),
+}
+
 pub struct Connection {
     inner: ConnInner,
     stream: super::Stream,
     server: super::ServerPtrWeak,
     hash: Hash,
     read_jobs: Vec<fs::TransferJob>,
+    // waiting for the confirm or the hashing
+    pending_read_jobs: Vec<fs::TransferJob>,
+    tx_job_hashed: mpsc::UnboundedSender<JobHashed>,
+    job_scheduler: fs::JobScheduler,
     timer: Interval,
     file_transfer: Option<(String, bool)>,
+    file_transfer_features: Vec<FileTransferFeature>,
     port_forward_socket: Option<Framed<TcpStream, BytesCodec>>,
     port_forward_address: String,
     tx_to_cm: mpsc::UnboundedSender<ipc::Data>,
@@ -56,12 +67,20 @@ pub struct Connection {
     clipboard: bool,
     audio: bool,
     file: bool,
+    microphone: bool,
+    // the microphone of the peer, played once the format is known
+    #[cfg(not(target_os = "linux"))]
+    microphone_format: Option<AudioFormat>,
+    #[cfg(not(target_os = "linux"))]
+    microphone_player: Option<crate::client::MediaSender>,
     last_test_delay: i64,
     image_quality: i32,
     lock_after_session_end: bool,
     show_remote_cursor: bool, // by peer
     privacy_mode: bool,
     ip: String,
+    // id and name of the peer for the session recording
+    peer: (String, String),
     disable_clipboard: bool,                  // by peer
     disable_audio: bool,                      // by peer
     enable_file_transfer: bool,               // by peer
@@ -117,6 +136,7 @@ impl Connection {
         let (tx_to_cm, rx_to_cm) = mpsc::unbounded_channel::<ipc::Data>();
         let (tx, mut rx) = mpsc::unbounded_channel::<(Instant, Arc<Message>)>();
         let (tx_video, mut rx_video) = mpsc::unbounded_channel::<(Instant, Arc<Message>)>();
+        let (tx_job_hashed, mut rx_job_hashed) = mpsc::unbounded_channel();
         let (tx_input, rx_input) = std_mpsc::channel();
 
         let tx_cloned = tx.clone();
@@ -130,8 +150,12 @@ impl Connection {
             server,
             hash,
             read_jobs: Vec::new(),
+            pending_read_jobs: Vec::new(),
+            tx_job_hashed,
+            job_scheduler: Default::default(),
             timer: time::interval(SEC30),
             file_transfer: None,
+            file_transfer_features: Vec::new(),
             port_forward_socket: None,
             port_forward_address: "".to_owned(),
             tx_to_cm,
@@ -139,6 +163,11 @@ impl Connection {
             keyboard: Config::get_option("enable-keyboard").is_empty(),
             clipboard: Config::get_option("enable-clipboard").is_empty(),
             audio: Config::get_option("enable-audio").is_empty(),
+            microphone: Config::get_option("enable-microphone") == "Y",
+            #[cfg(not(target_os = "linux"))]
+            microphone_format: None,
+            #[cfg(not(target_os = "linux"))]
+            microphone_player: None,
             file: Config::get_option("enable-file-transfer").is_empty(),
             last_test_delay: 0,
             image_quality: ImageQuality::Balanced.value(),
@@ -146,6 +175,7 @@ impl Connection {
             show_remote_cursor: false,
             privacy_mode: false,
             ip: "".to_owned(),
+            peer: Default::default(),
             disable_audio: false,
             enable_file_transfer: false,
             disable_clipboard: false,
@@ -172,6 +202,9 @@ impl Connection {
         if !conn.file {
             conn.send_permission(Permission::File, false).await;
         }
+        if !conn.microphone {
+            conn.send_permission(Permission::Microphone, false).await;
+        }
         let mut test_delay_timer =
             time::interval_at(Instant::now() + TEST_DELAY_TIMEOUT, TEST_DELAY_TIMEOUT);
         let mut last_recv_time = Instant::now();
@@ -219,6 +252,7 @@ impl Connection {
                         }
                         ipc::Data::SwitchPermission{name, enabled} => {
                             log::info!("Change permission {} -> {}", name, enabled);
+                            super::session_record::update_permission(id, &name, enabled);
                             if &name == "keyboard" {
                                 conn.keyboard = enabled;
                                 conn.send_permission(Permission::Keyboard, enabled).await;
@@ -247,6 +281,13 @@ impl Connection {
                                 conn.file = enabled;
                                 conn.send_permission(Permission::File, enabled).await;
                                 conn.send_to_cm(ipc::Data::ClipboardFileEnabled(conn.file_transfer_enabled()));
+                            } else if &name == "microphone" {
+                                conn.microphone = enabled;
+                                conn.send_permission(Permission::Microphone, enabled).await;
+                                #[cfg(not(target_os = "linux"))]
+                                if !enabled {
+                                    conn.microphone_player = None;
+                                }
                             }
                         }
                         ipc::Data::RawMessage(bytes) => {
@@ -284,7 +325,7 @@ impl Connection {
                 },
                 _ = conn.timer.tick() => {
                     if !conn.read_jobs.is_empty() {
-                        if let Err(err) = fs::handle_read_jobs(&mut conn.read_jobs, &mut conn.stream).await {
+                        if let Err(err) = fs::handle_read_jobs(&mut conn.read_jobs, &mut conn.stream, &mut conn.job_scheduler).await {
                             conn.on_close(&err.to_string(), false);
                             break;
                         }
@@ -292,9 +333,16 @@ impl Connection {
                         conn.timer = time::interval_at(Instant::now() + SEC30, SEC30);
                     }
                 },
+                Some(hashed) = rx_job_hashed.recv() => {
+                    conn.on_job_hashed(hashed).await;
+                },
                 Some((instant, value)) = rx_video.recv() => {
                     if !conn.video_ack_required {
-                        video_service::notify_video_frame_feched(id, Some(instant.into()));
+                        let display = match &value.union {
+                            Some(message::Union::video_frame(vf)) => Some(vf.display as usize),
+                            _ => None,
+                        };
+                        video_service::notify_video_frame_feched(id, display, Some(instant.into()));
                     }
                     if let Err(err) = conn.stream.send(&value as &Message).await {
                         conn.on_close(&err.to_string(), false);
@@ -338,9 +386,14 @@ impl Connection {
             }
         }
 
-        video_service::notify_video_frame_feched(id, None);
+        video_service::notify_video_frame_feched(id, None, None);
+        super::session_record::stop(id);
         super::video_service::update_test_latency(id, 0);
         super::video_service::update_image_quality(id, None);
+        super::video_service::update_peer_decodings(id, None);
+        super::video_service::update_viewport(id, None);
+        super::video_service::restore_resolution(id);
+        super::video_service::clear_key_frame_requests(id);
         if let Err(err) = conn.try_port_forward_loop(&mut rx_from_cm).await {
             conn.on_close(&err.to_string(), false);
         }
@@ -505,7 +558,7 @@ impl Connection {
         if !whitelist.is_empty()
             && whitelist
                 .iter()
-                .filter(|x| x == &"0.0.0.0")
+                .filter(|x| x == &"0.0.0.0" || x == &"::")
                 .next()
                 .is_none()
             && whitelist
@@ -549,10 +602,16 @@ impl Connection {
         #[cfg(target_os = "linux")]
         if !self.file_transfer.is_some() && !self.port_forward_socket.is_some() {
             let dtype = crate::platform::linux::get_display_server();
-            if dtype != "x11" {
+            let supported = dtype == "x11" || (cfg!(feature = "wayland") && dtype == "wayland");
+            if !supported {
+                let expected = if cfg!(feature = "wayland") {
+                    "x11 or wayland"
+                } else {
+                    "x11"
+                };
                 res.set_error(format!(
-                    "Unsupported display server type {}, x11 expected",
-                    dtype
+                    "Unsupported display server type {}, {} expected",
+                    dtype, expected
                 ));
                 let mut msg_out = Message::new();
                 msg_out.set_login_response(res);
@@ -571,6 +630,30 @@ impl Connection {
                 username = "".to_owned();
             }
         }
+        // before the video service starts so that the encoder is created for it,
+        // no unrecorded session if required
+        if self.file_transfer.is_none() {
+            if let Err(err) = super::session_record::start(
+                self.inner.id(),
+                self.peer.0.clone(),
+                self.peer.1.clone(),
+                self.ip.clone(),
+                &[
+                    ("keyboard", self.keyboard),
+                    ("clipboard", self.clipboard),
+                    ("audio", self.audio),
+                    ("file", self.file),
+                    ("microphone", self.microphone),
+                ],
+            ) {
+                log::error!("Failed to start the session recording: {}", err);
+                res.set_error("The session recording required by the host failed".to_owned());
+                let mut msg_out = Message::new();
+                msg_out.set_login_response(res);
+                self.send(msg_out).await;
+                return;
+            }
+        }
         self.authorized = true;
         let mut pi = PeerInfo {
             hostname: whoami::hostname(),
@@ -578,6 +661,7 @@ impl Connection {
             platform: whoami::platform().to_string(),
             version: crate::VERSION.to_owned(),
             sas_enabled,
+            file_transfer_features: fs::features().into(),
             ..Default::default()
         };
         let mut sub_service = false;
@@ -592,6 +676,7 @@ impl Connection {
                 Ok((current, displays)) => {
                     pi.displays = displays.into();
                     pi.current_display = current as _;
+                    pi.supported_encodings = crate::common::supported_codecs().into();
                     res.set_peer_info(pi);
                     sub_service = true;
                 }
@@ -606,7 +691,7 @@ impl Connection {
             } else {
                 ""
             };
-            self.read_dir(dir, show_hidden);
+            self.read_dir(dir, show_hidden, 0);
         } else if sub_service {
             if let Some(s) = self.server.upgrade() {
                 let mut noperms = Vec::new();
@@ -641,6 +726,47 @@ impl Connection {
         self.file && self.enable_file_transfer
     }
 
+    // the default sink of the host plays the microphone of the peer if permitted
+    #[cfg(not(target_os = "linux"))]
+    fn play_microphone(&mut self, frame: AudioFrame) {
+        if !self.microphone {
+            return;
+        }
+        if self.microphone_player.is_none() {
+            if let Some(format) = self.microphone_format.clone() {
+                let (player, _) = crate::client::start_audio_thread(None);
+                player
+                    .send(crate::client::MediaData::AudioFormat(format))
+                    .ok();
+                self.microphone_player = Some(player);
+            }
+        }
+        if let Some(player) = self.microphone_player.as_ref() {
+            player
+                .send(crate::client::MediaData::AudioFrame(frame))
+                .ok();
+        }
+    }
+
+    // root can not reach the pulseaudio of the user, so the cm plays it instead
+    #[cfg(target_os = "linux")]
+    fn play_microphone(&mut self, frame: AudioFrame) {
+        if !self.microphone {
+            return;
+        }
+        let mut msg = Message::new();
+        msg.set_audio_frame(frame);
+        self.send_microphone(msg);
+    }
+
+    #[cfg(target_os = "linux")]
+    fn send_microphone(&mut self, msg: Message) {
+        match msg.write_to_bytes() {
+            Ok(bytes) => self.send_to_cm(ipc::Data::Microphone(bytes)),
+            err => allow_err!(err),
+        }
+    }
+
     async fn try_start_cm(&mut self, peer_id: String, name: String, authorized: bool) {
         self.send_to_cm(ipc::Data::Login {
             id: self.inner.id(),
@@ -653,7 +779,11 @@ impl Connection {
             clipboard: self.clipboard,
             audio: self.audio,
             file: self.file,
+            microphone: self.microphone,
             file_transfer_enabled: self.file_transfer_enabled(),
+            recording: self.file_transfer.is_none()
+                && self.port_forward_socket.is_none()
+                && super::session_record::enabled(),
         });
     }
 
@@ -702,9 +832,16 @@ impl Connection {
                 self.update_option(o).await;
             }
             self.video_ack_required = lr.video_ack_required;
+            if lr.union.is_none() {
+                super::video_service::update_peer_decodings(
+                    self.inner.id(),
+                    Some(crate::common::peer_codecs(&lr.supported_decodings)),
+                );
+            }
             if self.authorized {
                 return true;
             }
+            self.peer = (lr.my_id.clone(), lr.my_name.clone());
             match lr.union {
                 Some(login_request::Union::file_transfer(ft)) => {
                     if !Config::get_option("enable-file-transfer").is_empty() {
@@ -713,7 +850,17 @@ impl Connection {
                         sleep(1.).await;
                         return false;
                     }
+                    // the lower one of ours and the peer's, 0 for unlimited
+                    let limit = Config::get_option("file-transfer-speed-limit")
+                        .parse()
+                        .unwrap_or(0);
+                    let limit = match (limit, ft.speed_limit) {
+                        (0, x) | (x, 0) => x,
+                        (a, b) => std::cmp::min(a, b),
+                    };
+                    self.job_scheduler.set_limit(limit);
                     self.file_transfer = Some((ft.dir, ft.show_hidden));
+                    self.file_transfer_features = fs::peer_features(&lr.file_transfer_features);
                 }
                 Some(login_request::Union::port_forward(mut pf)) => {
                     if !Config::get_option("enable-tunnel").is_empty() {
@@ -730,7 +877,7 @@ impl Connection {
                     if pf.host.is_empty() {
                         pf.host = "localhost".to_owned();
                     }
-                    let mut addr = format!("{}:{}", pf.host, pf.port);
+                    let mut addr = crate::check_port(&pf.host, pf.port);
                     self.port_forward_address = addr.clone();
                     match timeout(3000, TcpStream::connect(&addr)).await {
                         Ok(Ok(sock)) => {
@@ -860,7 +1007,7 @@ impl Connection {
                     if self.file_transfer.is_some() {
                         match fa.union {
                             Some(file_action::Union::read_dir(rd)) => {
-                                self.read_dir(&rd.path, rd.include_hidden);
+                                self.read_dir(&rd.path, rd.include_hidden, rd.id);
                             }
                             Some(file_action::Union::all_files(f)) => {
                                 match fs::get_recursive_files(&f.path, f.include_hidden) {
@@ -878,10 +1025,26 @@ impl Connection {
                                     Err(err) => {
                                         self.send(fs::new_error(id, err, 0)).await;
                                     }
-                                    Ok(job) => {
-                                        self.send(fs::new_dir(id, job.files().to_vec())).await;
-                                        self.read_jobs.push(job);
-                                        self.timer = time::interval(MILLI1);
+                                    Ok(mut job) => {
+                                        let policy = s.policy.enum_value_or_default();
+                                        job.set_policy(policy);
+                                        job.set_per_file_done(
+                                            self.file_transfer_features
+                                                .contains(&FileTransferFeature::PerFileDone),
+                                        );
+                                        job.set_download_offsets(&s.files);
+                                        if policy == FileOverwritePolicy::SkipSameContent {
+                                            // replied once hashed
+                                            self.hash_files(job);
+                                        } else {
+                                            self.send(fs::new_dir(id, job.files().to_vec())).await;
+                                            if policy == FileOverwritePolicy::Overwrite {
+                                                self.start_read_job(job);
+                                            } else {
+                                                // wait for the receiver to apply the policy
+                                                self.pending_read_jobs.push(job);
+                                            }
+                                        }
                                     }
                                 }
                             }
@@ -893,8 +1056,26 @@ impl Connection {
                                         .files
                                         .to_vec()
                                         .drain(..)
-                                        .map(|f| (f.name, f.modified_time))
+                                        .map(|f| ipc::FileInfo {
+                                            name: f.name,
+                                            entry_type: f.entry_type.value(),
+                                            modified_time: f.modified_time,
+                                            size: f.size,
+                                            digest: f.digest,
+                                            link_target: f.link_target,
+                                            mode: f.mode,
+                                            uid: f.uid,
+                                            gid: f.gid,
+                                        })
                                         .collect(),
+                                    policy: r.policy.value(),
+                                    resume: self
+                                        .file_transfer_features
+                                        .contains(&FileTransferFeature::Resume),
+                                    per_file_done: self
+                                        .file_transfer_features
+                                        .contains(&FileTransferFeature::PerFileDone),
+                                    preserve_owner: r.preserve_owner,
                                 });
                             }
                             Some(file_action::Union::remove_dir(d)) => {
@@ -917,9 +1098,19 @@ impl Connection {
                                     id: c.id,
                                 });
                             }
+                            Some(file_action::Union::send_confirm(c)) => {
+                                if let Some(i) =
+                                    self.pending_read_jobs.iter().position(|x| x.id() == c.id)
+                                {
+                                    let mut job = self.pending_read_jobs.remove(i);
+                                    job.set_offsets(&c.files);
+                                    self.start_read_job(job);
+                                }
+                            }
                             Some(file_action::Union::cancel(c)) => {
                                 self.send_fs(ipc::FS::CancelWrite { id: c.id });
                                 fs::remove_job(c.id, &mut self.read_jobs);
+                                fs::remove_job(c.id, &mut self.pending_read_jobs);
                             }
                             _ => {}
                         }
@@ -932,17 +1123,38 @@ impl Connection {
                             file_num: block.file_num,
                             data: block.data,
                             compressed: block.compressed,
+                            offset: block.offset,
                         });
                     }
                     Some(file_response::Union::done(d)) => {
                         self.send_fs(ipc::FS::WriteDone {
                             id: d.id,
                             file_num: d.file_num,
+                            digest: d.digest,
                         });
                     }
                     _ => {}
                 },
+                Some(message::Union::audio_frame(frame)) => {
+                    self.play_microphone(frame);
+                }
                 Some(message::Union::misc(misc)) => match misc.union {
+                    Some(misc::Union::audio_format(format)) => {
+                        // the player is restarted with the new format
+                        #[cfg(target_os = "linux")]
+                        {
+                            let mut misc = Misc::new();
+                            misc.set_audio_format(format);
+                            let mut msg = Message::new();
+                            msg.set_misc(misc);
+                            self.send_microphone(msg);
+                        }
+                        #[cfg(not(target_os = "linux"))]
+                        {
+                            self.microphone_format = Some(format);
+                            self.microphone_player = None;
+                        }
+                    }
                     Some(misc::Union::switch_display(s)) => {
                         super::video_service::switch_display(s.display);
                     }
@@ -958,11 +1170,56 @@ impl Connection {
                         }
                     }
                     Some(misc::Union::video_received(_)) => {
+                        // the clients requiring acks do not capture extra displays
                         video_service::notify_video_frame_feched(
                             self.inner.id,
+                            None,
                             Some(Instant::now().into()),
                         );
                     }
+                    Some(misc::Union::capture_displays(c)) => {
+                        if self.file_transfer.is_none() && self.port_forward_socket.is_none() {
+                            if let Some(s) = self.server.upgrade() {
+                                let displays: Vec<usize> =
+                                    c.displays.iter().map(|d| *d as usize).collect();
+                                s.write()
+                                    .unwrap()
+                                    .capture_displays(self.inner.clone(), &displays);
+                            }
+                        }
+                    }
+                    Some(misc::Union::key_frame_request(r)) => {
+                        super::video_service::request_key_frame(
+                            self.inner.id(),
+                            r.display as usize,
+                        );
+                    }
+                    Some(misc::Union::viewport(r)) => {
+                        let viewport = if r.width > 0 && r.height > 0 {
+                            Some((r.width as usize, r.height as usize))
+                        } else {
+                            None
+                        };
+                        super::video_service::update_viewport(self.inner.id(), viewport);
+                    }
+                    Some(misc::Union::change_resolution(r)) => {
+                        if self.keyboard && r.width > 0 && r.height > 0 {
+                            if let Err(err) = super::video_service::change_resolution(
+                                self.inner.id(),
+                                r.width as _,
+                                r.height as _,
+                            ) {
+                                log::error!("Failed to change the resolution: {}", err);
+                                let mut res = OptionResponse::new();
+                                res.error = err.to_string();
+                                let mut misc = Misc::new();
+                                misc.set_option_response(res);
+                                let mut msg_out = Message::new();
+                                msg_out.set_misc(misc);
+                                self.send(msg_out).await;
+                            }
+                        }
+                    }
                     _ => {}
                 },
                 _ => {}
@@ -982,6 +1239,12 @@ impl Connection {
             self.image_quality = q;
             super::video_service::update_image_quality(self.inner.id(), Some(q));
         }
+        if !o.supported_decodings.is_empty() {
+            super::video_service::update_peer_decodings(
+                self.inner.id(),
+                Some(crate::common::peer_codecs(&o.supported_decodings)),
+            );
+        }
         if let Ok(q) = o.lock_after_session_end.enum_value() {
             if q != BoolOption::NotSet {
                 self.lock_after_session_end = q == BoolOption::Yes;
@@ -1078,11 +1341,65 @@ impl Connection {
         self.port_forward_socket.take();
     }
 
-    fn read_dir(&mut self, dir: &str, include_hidden: bool) {
+    // hash the resume prefixes in a blocking thread, not to stall the connection,
+    // the job is read once they are back
+    fn start_read_job(&mut self, job: fs::TransferJob) {
+        let id = job.id();
+        let hash_prefixes = job.hash_prefixes();
+        self.pending_read_jobs.push(job);
+        let tx = self.tx_job_hashed.clone();
+        tokio::spawn(async move {
+            if let Ok(hashers) = tokio::task::spawn_blocking(hash_prefixes).await {
+                tx.send(JobHashed::Prefixes(id, hashers)).ok();
+            }
+        });
+    }
+
+    // the digests for SkipSameContent, the files are replied once they are back
+    fn hash_files(&mut self, job: fs::TransferJob) {
+        let id = job.id();
+        let hash_files = job.hash_files();
+        self.pending_read_jobs.push(job);
+        let tx = self.tx_job_hashed.clone();
+        tokio::spawn(async move {
+            if let Ok(digests) = tokio::task::spawn_blocking(hash_files).await {
+                tx.send(JobHashed::Digests(id, digests)).ok();
+            }
+        });
+    }
+
+    async fn on_job_hashed(&mut self, hashed: JobHashed) {
+        let id = match &hashed {
+            JobHashed::Digests(id, _) | JobHashed::Prefixes(id, _) => *id,
+        };
+        // not found if cancelled meanwhile
+        let i = match self.pending_read_jobs.iter().position(|x| x.id() == id) {
+            Some(i) => i,
+            None => return,
+        };
+        match hashed {
+            JobHashed::Digests(_, digests) => {
+                // wait for the receiver to apply the policy
+                let job = &mut self.pending_read_jobs[i];
+                job.set_digests(digests);
+                let files = job.files().to_vec();
+                self.send(fs::new_dir(id, files)).await;
+            }
+            JobHashed::Prefixes(_, hashers) => {
+                let mut job = self.pending_read_jobs.remove(i);
+                job.set_prefix_hashers(hashers);
+                self.read_jobs.push(job);
+                self.timer = time::interval(MILLI1);
+            }
+        }
+    }
+
+    fn read_dir(&mut self, dir: &str, include_hidden: bool, id: i32) {
         let dir = dir.to_string();
         self.send_fs(ipc::FS::ReadDir {
             dir,
             include_hidden,
+            id,
         });
     }
 
@@ -1160,8 +1477,9 @@ async fn start_ipc(
                         if let Data::FS(ipc::FS::WriteBlock{id,
                             file_num,
                             data,
-                            compressed}) = data {
-                                stream.send(&Data::FS(ipc::FS::WriteBlock{id, file_num, data: Vec::new(), compressed})).await?;
+                            compressed,
+                            offset}) = data {
+                                stream.send(&Data::FS(ipc::FS::WriteBlock{id, file_num, data: Vec::new(), compressed, offset})).await?;
                                 stream.send_raw(data).await?;
                         } else {
                             stream.send(&data).await?;
@@ -354,6 +354,11 @@ fn handle_mouse_(evt: &MouseEvent, conn: i32) {
         let time = crate::get_time();
         *LATEST_INPUT.lock().unwrap() = Input { time, conn };
     }
+    #[cfg(all(target_os = "linux", feature = "wayland"))]
+    if scrap::is_wayland() {
+        wayland::handle_mouse(evt, evt_type, buttons);
+        return;
+    }
     let mut en = ENIGO.lock().unwrap();
     #[cfg(not(target_os = "macos"))]
     let mut to_release = Vec::new();
@@ -551,6 +556,11 @@ fn handle_key_(evt: &KeyEvent) {
     }
     #[cfg(windows)]
     crate::platform::windows::try_change_desktop();
+    #[cfg(all(target_os = "linux", feature = "wayland"))]
+    if scrap::is_wayland() {
+        wayland::handle_key(evt);
+        return;
+    }
     let mut en = ENIGO.lock().unwrap();
     // disable numlock if press home etc when numlock is on,
     // because we will get numpad value (7,8,9 etc) if not
@@ -686,6 +696,185 @@ fn handle_key_(evt: &KeyEvent) {
     }
 }
 
+// input through the RemoteDesktop portal, X11 input is not reachable on wayland
+#[cfg(all(target_os = "linux", feature = "wayland"))]
+mod wayland {
+    use super::*;
+    use scrap::wayland::pipewire;
+
+    // linux/input-event-codes.h
+    const BTN_LEFT: i32 = 0x110;
+    const BTN_RIGHT: i32 = 0x111;
+    const BTN_MIDDLE: i32 = 0x112;
+
+    lazy_static::lazy_static! {
+        static ref KEYSYM_MAP: HashMap<i32, i32> =
+        [
+            (ControlKey::Alt, 0xffe9),
+            (ControlKey::Backspace, 0xff08),
+            (ControlKey::CapsLock, 0xffe5),
+            (ControlKey::Control, 0xffe3),
+            (ControlKey::Delete, 0xffff),
+            (ControlKey::DownArrow, 0xff54),
+            (ControlKey::End, 0xff57),
+            (ControlKey::Escape, 0xff1b),
+            (ControlKey::F1, 0xffbe),
+            (ControlKey::F2, 0xffbf),
+            (ControlKey::F3, 0xffc0),
+            (ControlKey::F4, 0xffc1),
+            (ControlKey::F5, 0xffc2),
+            (ControlKey::F6, 0xffc3),
+            (ControlKey::F7, 0xffc4),
+            (ControlKey::F8, 0xffc5),
+            (ControlKey::F9, 0xffc6),
+            (ControlKey::F10, 0xffc7),
+            (ControlKey::F11, 0xffc8),
+            (ControlKey::F12, 0xffc9),
+            (ControlKey::Home, 0xff50),
+            (ControlKey::LeftArrow, 0xff51),
+            (ControlKey::Meta, 0xffeb),
+            (ControlKey::PageDown, 0xff56),
+            (ControlKey::PageUp, 0xff55),
+            (ControlKey::Return, 0xff0d),
+            (ControlKey::RightArrow, 0xff53),
+            (ControlKey::Shift, 0xffe1),
+            (ControlKey::Space, 0x20),
+            (ControlKey::Tab, 0xff09),
+            (ControlKey::UpArrow, 0xff52),
+            (ControlKey::Numpad0, 0xffb0),
+            (ControlKey::Numpad1, 0xffb1),
+            (ControlKey::Numpad2, 0xffb2),
+            (ControlKey::Numpad3, 0xffb3),
+            (ControlKey::Numpad4, 0xffb4),
+            (ControlKey::Numpad5, 0xffb5),
+            (ControlKey::Numpad6, 0xffb6),
+            (ControlKey::Numpad7, 0xffb7),
+            (ControlKey::Numpad8, 0xffb8),
+            (ControlKey::Numpad9, 0xffb9),
+            (ControlKey::Cancel, 0xff69),
+            (ControlKey::Clear, 0xff0b),
+            (ControlKey::Menu, 0xffe9),
+            (ControlKey::Pause, 0xff13),
+            (ControlKey::Select, 0xff60),
+            (ControlKey::Print, 0xff61),
+            (ControlKey::Execute, 0xff62),
+            (ControlKey::Snapshot, 0xff61),
+            (ControlKey::Insert, 0xff63),
+            (ControlKey::Help, 0xff6a),
+            (ControlKey::Separator, 0xffac),
+            (ControlKey::Scroll, 0xff14),
+            (ControlKey::NumLock, 0xff7f),
+            (ControlKey::Apps, 0xff67),
+            (ControlKey::Multiply, 0xffaa),
+            (ControlKey::Add, 0xffab),
+            (ControlKey::Subtract, 0xffad),
+            (ControlKey::Decimal, 0xffae),
+            (ControlKey::Divide, 0xffaf),
+            (ControlKey::Equals, 0xffbd),
+            (ControlKey::NumpadEnter, 0xff8d),
+            (ControlKey::RAlt, 0xffea),
+            (ControlKey::RWin, 0xffec),
+            (ControlKey::RControl, 0xffe4),
+            (ControlKey::RShift, 0xffe2),
+        ].iter().map(|(a, b)| (a.value(), *b)).collect();
+    }
+
+    fn char_keysym(chr: u32) -> i32 {
+        if chr < 0x100 {
+            chr as _
+        } else {
+            (0x01000000 | chr) as _
+        }
+    }
+
+    fn key(keysym: i32, down: bool) {
+        if let Err(err) = pipewire::key_keysym(keysym, down) {
+            log::error!("Failed to send key through portal: {}", err);
+        }
+    }
+
+    fn with_modifiers(modifiers: &[ProtobufEnumOrUnknown<ControlKey>], f: impl FnOnce()) {
+        let modifiers: Vec<i32> = modifiers
+            .iter()
+            .filter(|ck| {
+                ck.value() != ControlKey::CapsLock.value()
+                    && ck.value() != ControlKey::NumLock.value()
+            })
+            .filter_map(|ck| KEYSYM_MAP.get(&ck.value()).cloned())
+            .collect();
+        for keysym in modifiers.iter() {
+            key(*keysym, true);
+        }
+        f();
+        for keysym in modifiers.iter().rev() {
+            key(*keysym, false);
+        }
+    }
+
+    pub fn handle_mouse(evt: &MouseEvent, evt_type: i32, buttons: i32) {
+        let button = match buttons {
+            1 => BTN_LEFT,
+            2 => BTN_RIGHT,
+            4 => BTN_MIDDLE,
+            _ => 0,
+        };
+        let res = match evt_type {
+            0 => pipewire::mouse_move_to(evt.x, evt.y),
+            1 | 2 if button != 0 => {
+                let mut res = Ok(());
+                with_modifiers(&evt.modifiers[..], || {
+                    res = pipewire::mouse_button(button, evt_type == 1);
+                });
+                res
+            }
+            3 => {
+                let mut res = Ok(());
+                if evt.x != 0 {
+                    res = pipewire::mouse_scroll(1, -evt.x);
+                }
+                if evt.y != 0 && res.is_ok() {
+                    res = pipewire::mouse_scroll(0, -evt.y);
+                }
+                res
+            }
+            _ => Ok(()),
+        };
+        if let Err(err) = res {
+            log::error!("Failed to send mouse event through portal: {}", err);
+        }
+    }
+
+    pub fn handle_key(evt: &KeyEvent) {
+        let modifiers: &[_] = if evt.down { &evt.modifiers[..] } else { &[] };
+        match evt.union {
+            Some(key_event::Union::control_key(ck)) => {
+                if let Some(keysym) = KEYSYM_MAP.get(&ck.value()) {
+                    with_modifiers(modifiers, || key(*keysym, evt.down));
+                } else if ck.value() == ControlKey::LockScreen.value() {
+                    crate::platform::lock_screen();
+                    super::super::video_service::switch_to_primary();
+                }
+            }
+            Some(key_event::Union::chr(chr)) => {
+                with_modifiers(modifiers, || key(char_keysym(chr), evt.down));
+            }
+            Some(key_event::Union::unicode(chr)) => {
+                let keysym = char_keysym(chr);
+                key(keysym, true);
+                key(keysym, false);
+            }
+            Some(key_event::Union::seq(ref seq)) => {
+                for chr in seq.chars() {
+                    let keysym = char_keysym(chr as _);
+                    key(keysym, true);
+                    key(keysym, false);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
 #[tokio::main(flavor = "current_thread")]
 async fn send_sas() -> ResultType<()> {
     let mut stream = crate::ipc::connect(1000, crate::POSTFIX_SERVICE).await?;
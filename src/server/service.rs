@@ -114,6 +114,10 @@ impl<T: Subscriber + From<ConnInner>> ServiceTmpl<T> {
         self.0.read().unwrap().has_subscribes()
     }
 
+    pub fn subscriber_ids(&self) -> HashSet<i32> {
+        self.0.read().unwrap().subscribes.keys().cloned().collect()
+    }
+
     #[inline]
     pub fn ok(&self) -> bool {
         let lock = self.0.read().unwrap();
@@ -150,20 +154,6 @@ impl<T: Subscriber + From<ConnInner>> ServiceTmpl<T> {
         }
     }
 
-    pub fn send_video_frame(&self, msg: Message) -> HashSet<i32> {
-        self.send_video_frame_shared(Arc::new(msg))
-    }
-
-    pub fn send_video_frame_shared(&self, msg: Arc<Message>) -> HashSet<i32> {
-        let mut conn_ids = HashSet::new();
-        let mut lock = self.0.write().unwrap();
-        for s in lock.subscribes.values_mut() {
-            s.send(msg.clone());
-            conn_ids.insert(s.id());
-        }
-        conn_ids
-    }
-
     pub fn send_without(&self, msg: Message, sub: i32) {
         let mut lock = self.0.write().unwrap();
         let msg = Arc::new(msg);
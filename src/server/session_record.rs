@@ -0,0 +1,408 @@
+// Mandatory recording of the controlled sessions for compliance, the "record-session" option.
+// The video sent to every remote control connection is teed into WebM files together with the
+// audio of the host, one per display at its native size, the metadata of the session is written
+// as JSON next to them.
+// The archive is pruned by age and total size when a recording starts and periodically while
+// recording, the files being written are rolled over to new ones if still too big or too old.
+// The login is refused if the recording can not be started.
+
+use super::*;
+use crate::recorder::Recorder;
+use serde_derive::Serialize;
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+    sync::mpsc,
+    time::{Instant, SystemTime},
+};
+
+const DEFAULT_MAX_DAYS: u64 = 30;
+const DEFAULT_MAX_SIZE_MB: u64 = 10240;
+const CHECK_INTERVAL: Duration = Duration::from_secs(600);
+
+lazy_static::lazy_static! {
+    static ref RECORDS: Arc<Mutex<HashMap<i32, SessionRecord>>> = Default::default();
+    static ref AUDIO_FORMAT: Arc<Mutex<Option<AudioFormat>>> = Default::default();
+    // the WebM files being written, not pruned
+    static ref WRITING: Arc<Mutex<HashSet<PathBuf>>> = Default::default();
+}
+
+#[derive(Serialize)]
+struct PermissionChange {
+    time: i64,
+    name: String,
+    enabled: bool,
+}
+
+#[derive(Serialize)]
+struct Meta {
+    peer_id: String,
+    name: String,
+    ip: String,
+    // at the start of the session
+    permissions: HashMap<String, bool>,
+    start: i64,
+    end: i64,
+    permission_changes: Vec<PermissionChange>,
+}
+
+enum Data {
+    Video(VideoFrame),
+    Audio(AudioFrame),
+    AudioFormat(AudioFormat),
+}
+
+struct SessionRecord {
+    meta: Meta,
+    meta_path: PathBuf,
+    // the WebM is written in a thread of its own, not under RECORDS in the capturers,
+    // finalized once the sender is dropped
+    tx: mpsc::Sender<Data>,
+}
+
+impl SessionRecord {
+    fn save_meta(&self) -> ResultType<()> {
+        std::fs::write(&self.meta_path, serde_json::to_string_pretty(&self.meta)?)?;
+        Ok(())
+    }
+}
+
+// one file per display, the frames of the extra display services included
+struct Writer {
+    id: i32,
+    dir: PathBuf,
+    base: PathBuf,
+    audio_format: Option<AudioFormat>,
+    recorders: HashMap<usize, Recorder>,
+}
+
+impl Writer {
+    fn write_video(&mut self, vf: &VideoFrame) {
+        let display = vf.display as usize;
+        let (base, audio_format) = (&self.base, &self.audio_format);
+        let recorder = self.recorders.entry(display).or_insert_with(|| {
+            let mut name = base.file_name().unwrap_or_default().to_owned();
+            name.push(format!("-d{}.webm", display));
+            let mut recorder = Recorder::new(base.with_file_name(name));
+            if let Some(f) = audio_format.clone() {
+                recorder.set_audio_format(f);
+            }
+            recorder
+        });
+        let old = recorder.current_path().map(|x| x.to_owned());
+        if let Err(err) = recorder.write_video(vf) {
+            log::error!("#{} Failed to record the session: {}", self.id, err);
+        }
+        let new = recorder.current_path();
+        if old.as_deref() != new {
+            let mut writing = WRITING.lock().unwrap();
+            if let Some(old) = old {
+                writing.remove(&old);
+            }
+            if let Some(new) = new {
+                writing.insert(new.to_owned());
+            }
+        }
+    }
+
+    fn write_audio(&mut self, frame: &AudioFrame) {
+        for recorder in self.recorders.values_mut() {
+            recorder.write_audio(frame);
+        }
+    }
+
+    fn set_audio_format(&mut self, f: AudioFormat) {
+        for recorder in self.recorders.values_mut() {
+            recorder.set_audio_format(f.clone());
+        }
+        self.audio_format = Some(f);
+    }
+
+    // the files being written are finished if the archive is still too big or they are too old,
+    // so that the next check prunes them
+    fn check(&mut self) {
+        let over_size = prune(&self.dir);
+        let max_age = get_max_age();
+        for (display, recorder) in self.recorders.iter_mut() {
+            if !over_size && recorder.age().map(|x| x < max_age).unwrap_or(true) {
+                continue;
+            }
+            if let Some(path) = recorder.current_path() {
+                log::info!("#{} Rolling over the recording {}", self.id, path.display());
+                WRITING.lock().unwrap().remove(path);
+            }
+            recorder.roll();
+            super::video_service::request_key_frame(self.id, *display);
+        }
+    }
+}
+
+impl Drop for Writer {
+    fn drop(&mut self) {
+        for (_, recorder) in self.recorders.drain() {
+            let path = recorder.current_path().map(|x| x.to_owned());
+            // finalized
+            drop(recorder);
+            if let Some(path) = path {
+                WRITING.lock().unwrap().remove(&path);
+            }
+        }
+    }
+}
+
+fn start_writer(mut writer: Writer) -> mpsc::Sender<Data> {
+    let (tx, rx) = mpsc::channel();
+    std::thread::spawn(move || {
+        let mut last_check = Instant::now();
+        loop {
+            match rx.recv_timeout(CHECK_INTERVAL) {
+                Ok(Data::Video(vf)) => writer.write_video(&vf),
+                Ok(Data::Audio(frame)) => writer.write_audio(&frame),
+                Ok(Data::AudioFormat(f)) => writer.set_audio_format(f),
+                Err(mpsc::RecvTimeoutError::Timeout) => {}
+                Err(mpsc::RecvTimeoutError::Disconnected) => break,
+            }
+            if last_check.elapsed() >= CHECK_INTERVAL {
+                last_check = Instant::now();
+                writer.check();
+            }
+        }
+        log::info!("#{} Session recording finished", writer.id);
+    });
+    tx
+}
+
+pub fn enabled() -> bool {
+    Config::get_option("record-session") == "Y"
+}
+
+fn get_dir() -> PathBuf {
+    let dir = Config::get_option("record-session-dir");
+    if dir.is_empty() {
+        Config::path("recordings")
+    } else {
+        dir.into()
+    }
+}
+
+fn get_limit(name: &str, default: u64) -> u64 {
+    Config::get_option(name).parse().unwrap_or(default)
+}
+
+fn get_max_age() -> Duration {
+    Duration::from_secs(get_limit("record-session-max-days", DEFAULT_MAX_DAYS) * 24 * 3600)
+}
+
+/// Start the recording of a connection if the option is enabled,
+/// the connection must not go on unrecorded if failed.
+pub fn start(
+    id: i32,
+    peer_id: String,
+    name: String,
+    ip: String,
+    permissions: &[(&str, bool)],
+) -> ResultType<()> {
+    if !enabled() {
+        return Ok(());
+    }
+    let dir = get_dir();
+    if let Err(err) = std::fs::create_dir_all(&dir) {
+        bail!("Failed to create {}: {}", dir.display(), err);
+    }
+    prune(&dir);
+    let meta = Meta {
+        peer_id,
+        name,
+        ip,
+        permissions: permissions
+            .iter()
+            .map(|(k, v)| (k.to_string(), *v))
+            .collect(),
+        start: crate::get_time(),
+        end: 0,
+        permission_changes: Vec::new(),
+    };
+    let base = dir.join(format!(
+        "{}_{}_{}",
+        meta.peer_id.replace(|c: char| !c.is_alphanumeric(), "_"),
+        meta.start,
+        id
+    ));
+    let meta_path = base.with_extension("json");
+    // the directory is writable
+    if let Err(err) = std::fs::write(&meta_path, serde_json::to_string_pretty(&meta)?) {
+        bail!("Failed to write {}: {}", meta_path.display(), err);
+    }
+    let writer = Writer {
+        id,
+        dir,
+        base: base.clone(),
+        audio_format: AUDIO_FORMAT.lock().unwrap().clone(),
+        recorders: HashMap::new(),
+    };
+    let record = SessionRecord {
+        meta,
+        meta_path,
+        tx: start_writer(writer),
+    };
+    log::info!("#{} Recording the session to {}", id, base.display());
+    RECORDS.lock().unwrap().insert(id, record);
+    Ok(())
+}
+
+pub fn stop(id: i32) {
+    if let Some(mut record) = RECORDS.lock().unwrap().remove(&id) {
+        record.meta.end = crate::get_time();
+        allow_err!(record.save_meta());
+    }
+}
+
+#[inline]
+pub fn is_recording(id: i32) -> bool {
+    RECORDS.lock().unwrap().contains_key(&id)
+}
+
+pub fn update_permission(id: i32, name: &str, enabled: bool) {
+    if let Some(record) = RECORDS.lock().unwrap().get_mut(&id) {
+        record.meta.permission_changes.push(PermissionChange {
+            time: crate::get_time(),
+            name: name.to_owned(),
+            enabled,
+        });
+        allow_err!(record.save_meta());
+    }
+}
+
+pub fn write_video(id: i32, vf: &VideoFrame) {
+    if let Some(record) = RECORDS.lock().unwrap().get(&id) {
+        record.tx.send(Data::Video(vf.clone())).ok();
+    }
+}
+
+pub fn write_audio(frame: &AudioFrame) {
+    for record in RECORDS.lock().unwrap().values() {
+        record.tx.send(Data::Audio(frame.clone())).ok();
+    }
+}
+
+// the audio track is added to the files started afterwards
+pub fn set_audio_format(f: AudioFormat) {
+    for record in RECORDS.lock().unwrap().values() {
+        record.tx.send(Data::AudioFormat(f.clone())).ok();
+    }
+    *AUDIO_FORMAT.lock().unwrap() = Some(f);
+}
+
+// "<peer>_<time>_<id>" of the files of the recordings, the video files have the "-d<display>"
+// and the "-<n>" suffixes of `Writer` and `Recorder`
+fn get_record_base(path: &Path) -> Option<&str> {
+    fn strip_number<'a>(s: &'a str, prefix: &str) -> Option<&'a str> {
+        let (head, tail) = s.split_at(s.rfind(prefix)?);
+        let n = &tail[prefix.len()..];
+        if !n.is_empty() && n.bytes().all(|x| x.is_ascii_digit()) {
+            Some(head)
+        } else {
+            None
+        }
+    }
+    let stem = path.file_stem()?.to_str()?;
+    let base = match path.extension()?.to_str()? {
+        "json" => stem,
+        "webm" => strip_number(strip_number(stem, "-").unwrap_or(stem), "-d")?,
+        _ => return None,
+    };
+    let mut parts = base.rsplitn(3, '_');
+    let id = parts.next()?;
+    let time = parts.next()?;
+    let peer = parts.next()?;
+    let is_number = |x: &str| !x.is_empty() && x.bytes().all(|x| x.is_ascii_digit());
+    if is_number(id)
+        && is_number(time)
+        && !peer.is_empty()
+        && peer.chars().all(|x| x.is_alphanumeric() || x == '_')
+    {
+        Some(base)
+    } else {
+        None
+    }
+}
+
+// remove the recordings older than "record-session-max-days", then the oldest ones until the
+// archive is smaller than "record-session-max-size" in MB, the other files in the directory and
+// the files of the sessions being written are kept, returns true if still too big
+fn prune(dir: &Path) -> bool {
+    let max_age = get_max_age();
+    let max_size = get_limit("record-session-max-size", DEFAULT_MAX_SIZE_MB) * 1024 * 1024;
+    let mut kept: HashSet<PathBuf> = WRITING.lock().unwrap().clone();
+    kept.extend(
+        RECORDS
+            .lock()
+            .unwrap()
+            .values()
+            .map(|x| x.meta_path.clone()),
+    );
+    let mut files = Vec::new();
+    let mut total: u64 = 0;
+    if let Ok(entries) = std::fs::read_dir(dir) {
+        for entry in entries.filter_map(|x| x.ok()) {
+            let path = entry.path();
+            if get_record_base(&path).is_none() {
+                continue;
+            }
+            if let Ok(meta) = entry.metadata() {
+                if meta.is_file() {
+                    total += meta.len();
+                    if !kept.contains(&path) {
+                        let modified = meta.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+                        files.push((modified, meta.len(), path));
+                    }
+                }
+            }
+        }
+    }
+    files.sort_by(|a, b| a.0.cmp(&b.0));
+    let now = SystemTime::now();
+    for (modified, size, path) in files {
+        let expired = now.duration_since(modified).unwrap_or_default() > max_age;
+        if !expired && total <= max_size {
+            break;
+        }
+        match std::fs::remove_file(&path) {
+            Ok(()) => {
+                log::info!("Removed the session recording {}", path.display());
+                total -= size;
+            }
+            Err(err) => log::error!("Failed to remove {}: {}", path.display(), err),
+        }
+    }
+    total > max_size
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record_base() {
+        let base = |x: &str| get_record_base(Path::new(x));
+        assert_eq!(
+            base("/r/123_456_1640000000000_7.json"),
+            Some("123_456_1640000000000_7")
+        );
+        assert_eq!(
+            base("/r/123456_1640000000000_7-d0.webm"),
+            Some("123456_1640000000000_7")
+        );
+        assert_eq!(
+            base("/r/123456_1640000000000_7-d1-2.webm"),
+            Some("123456_1640000000000_7")
+        );
+        // not of a recording
+        assert_eq!(base("/r/123456_1640000000000_7.webm"), None);
+        assert_eq!(base("/r/123456_1640000000000_7-d1.mp4"), None);
+        assert_eq!(base("/r/holiday-2021_12_24.json"), None);
+        assert_eq!(base("/r/1640000000000_7.json"), None);
+        assert_eq!(base("/r/notes.txt"), None);
+        assert_eq!(base("/r/a_b_c.json"), None);
+    }
+}
@@ -0,0 +1,162 @@
+// Lossless "text mode" of the video service.
+// VP9 4:2:0 smears coloured text, so when all the viewers choose ImageQuality::Lossless
+// the changed tiles of the BGRA frame are sent zstd-compressed instead of encoded.
+// During heavy motion (scrolling, videos) the tiles are too expensive and the frames
+// fall back to the video encoder, the screen is refreshed losslessly once it is still.
+
+use super::*;
+use hbb_common::config::COMPRESS_LEVEL;
+
+const TILE_SIZE: usize = 64;
+// ratio of the changed tiles
+const MOTION_RATIO: f32 = 0.3;
+const STILL_RATIO: f32 = 0.1;
+// consecutive frames above MOTION_RATIO to switch to the encoder
+const MOTION_FRAMES: usize = 3;
+// consecutive frames below STILL_RATIO to switch back to lossless
+const STILL_FRAMES: usize = 30;
+
+pub enum Update {
+    Tiles(RGB),
+    Unchanged,
+    // encode the frame, true if the encoder was not used for the previous frame
+    Motion(bool),
+}
+
+pub struct TextMode {
+    width: usize,
+    height: usize,
+    last: Vec<u8>,
+    motion: bool,
+    counter: usize,
+}
+
+impl TextMode {
+    pub fn new(width: usize, height: usize) -> Self {
+        Self {
+            width,
+            height,
+            last: Vec::new(),
+            motion: false,
+            counter: 0,
+        }
+    }
+
+    pub fn update(&mut self, frame: &[u8]) -> Update {
+        let stride = frame.len() / self.height;
+        let refresh = self.last.len() != frame.len();
+        let mut changed = Vec::new();
+        let mut total = 0;
+        for y in (0..self.height).step_by(TILE_SIZE) {
+            let h = TILE_SIZE.min(self.height - y);
+            for x in (0..self.width).step_by(TILE_SIZE) {
+                let w = TILE_SIZE.min(self.width - x);
+                total += 1;
+                if refresh || self.tile_changed(frame, stride, x, y, w, h) {
+                    changed.push((x, y, w, h));
+                }
+            }
+        }
+        let ratio = changed.len() as f32 / total.max(1) as f32;
+        let update = if self.motion {
+            if ratio < STILL_RATIO {
+                self.counter += 1;
+            } else {
+                self.counter = 0;
+            }
+            if self.counter >= STILL_FRAMES {
+                log::debug!("text mode: still, back to lossless");
+                self.motion = false;
+                self.counter = 0;
+                // the encoded frames are lossy, refresh the whole screen
+                Update::Tiles(self.create_rgb(frame, stride, &self.all_tiles()))
+            } else {
+                Update::Motion(false)
+            }
+        } else if changed.is_empty() {
+            Update::Unchanged
+        } else {
+            if ratio > MOTION_RATIO && !refresh {
+                self.counter += 1;
+            } else {
+                self.counter = 0;
+            }
+            if self.counter >= MOTION_FRAMES {
+                log::debug!("text mode: heavy motion, switch to the encoder");
+                self.motion = true;
+                self.counter = 0;
+                Update::Motion(true)
+            } else {
+                Update::Tiles(self.create_rgb(frame, stride, &changed))
+            }
+        };
+        self.last.clear();
+        self.last.extend_from_slice(frame);
+        update
+    }
+
+    fn tile_changed(
+        &self,
+        frame: &[u8],
+        stride: usize,
+        x: usize,
+        y: usize,
+        w: usize,
+        h: usize,
+    ) -> bool {
+        (y..y + h).any(|row| {
+            let start = row * stride + x * 4;
+            let end = start + w * 4;
+            frame[start..end] != self.last[start..end]
+        })
+    }
+
+    fn all_tiles(&self) -> Vec<(usize, usize, usize, usize)> {
+        let mut tiles = Vec::new();
+        for y in (0..self.height).step_by(TILE_SIZE) {
+            for x in (0..self.width).step_by(TILE_SIZE) {
+                tiles.push((
+                    x,
+                    y,
+                    TILE_SIZE.min(self.width - x),
+                    TILE_SIZE.min(self.height - y),
+                ));
+            }
+        }
+        tiles
+    }
+
+    fn create_rgb(
+        &self,
+        frame: &[u8],
+        stride: usize,
+        tiles: &[(usize, usize, usize, usize)],
+    ) -> RGB {
+        let mut buf = Vec::with_capacity(TILE_SIZE * TILE_SIZE * 4);
+        let tiles = tiles
+            .iter()
+            .map(|&(x, y, w, h)| {
+                buf.clear();
+                for row in y..y + h {
+                    let start = row * stride + x * 4;
+                    buf.extend_from_slice(&frame[start..start + w * 4]);
+                }
+                RGBTile {
+                    x: x as _,
+                    y: y as _,
+                    width: w as _,
+                    height: h as _,
+                    data: hbb_common::compress::compress(&buf, COMPRESS_LEVEL),
+                    ..Default::default()
+                }
+            })
+            .collect::<Vec<_>>();
+        RGB {
+            compress: true,
+            width: self.width as _,
+            height: self.height as _,
+            tiles: tiles.into(),
+            ..Default::default()
+        }
+    }
+}
@@ -0,0 +1,186 @@
+// Congestion control of the video service, one instance per viewer encoder.
+// The fps, the target bitrate and the quantizer range follow the viewer's link,
+// measured by the rtt of test_delay, the delay until a frame is fetched (or acked
+// with video_ack_required) and the number of frames queued but not fetched yet.
+
+use super::*;
+use std::{
+    collections::HashSet,
+    time::{Duration, Instant},
+};
+
+pub const MAX_FPS: u32 = 30;
+pub const MIN_FPS: u32 = 5;
+const MIN_RATIO: f32 = 0.2;
+const ADJUST_INTERVAL: Duration = Duration::from_secs(1);
+// ack delays above it reduce the quality, below the quarter of it raise the quality
+const DELAY_THRESHOLD: u32 = 200;
+const MAX_QUANTIZER: u32 = 56;
+
+#[derive(Default, Debug)]
+struct Viewer {
+    // smoothed delay of the frame fetch, in ms
+    delay: u32,
+    queued: usize,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Target {
+    pub fps: u32,
+    pub bitrate: u32,
+    pub rc_min_quantizer: u32,
+    pub rc_max_quantizer: u32,
+}
+
+pub struct VideoQoS {
+    // from the image quality chosen by the viewers
+    base: Target,
+    fps: u32,
+    ratio: f32,
+    viewers: HashMap<i32, Viewer>,
+    last_adjust: Instant,
+    current: Target,
+}
+
+impl VideoQoS {
+    pub fn new(bitrate: u32, rc_min_quantizer: u32, rc_max_quantizer: u32) -> Self {
+        let base = Target {
+            fps: MAX_FPS,
+            bitrate,
+            rc_min_quantizer,
+            rc_max_quantizer,
+        };
+        Self {
+            base,
+            fps: MAX_FPS,
+            ratio: 1.,
+            viewers: Default::default(),
+            last_adjust: Instant::now(),
+            current: base,
+        }
+    }
+
+    #[inline]
+    pub fn target(&self) -> Target {
+        self.current
+    }
+
+    #[inline]
+    pub fn spf(&self) -> Duration {
+        Duration::from_secs_f32(1. / (self.current.fps as f32))
+    }
+
+    pub fn on_sent(&mut self, conn_ids: &HashSet<i32>) {
+        // the viewers gone do not receive frames any more
+        self.viewers.retain(|id, _| conn_ids.contains(id));
+        for id in conn_ids {
+            self.viewers.entry(*id).or_default().queued += 1;
+        }
+    }
+
+    pub fn on_fetched(&mut self, id: i32, delay: Duration) {
+        let v = self.viewers.entry(id).or_default();
+        v.queued = v.queued.saturating_sub(1);
+        let delay = delay.as_millis() as u32;
+        v.delay = if v.delay == 0 {
+            delay
+        } else {
+            (v.delay * 7 + delay) / 8
+        };
+    }
+
+    /// Adjust once per ADJUST_INTERVAL, returns the new target if it is changed.
+    pub fn check(&mut self, rtts: &HashMap<i32, i64>) -> Option<Target> {
+        if self.last_adjust.elapsed() < ADJUST_INTERVAL {
+            return None;
+        }
+        self.last_adjust = Instant::now();
+        let delay = self.viewers.values().map(|v| v.delay).max().unwrap_or(0);
+        let queued = self.viewers.values().map(|v| v.queued).max().unwrap_or(0);
+        // rtt of test_delay includes the time the peer takes to answer
+        let rtt = rtts.values().cloned().max().unwrap_or(0) as u32;
+        let threshold = DELAY_THRESHOLD.max(rtt);
+        if queued > 2 || delay > threshold * 2 {
+            self.ratio *= 0.7;
+            self.fps = self.fps * 2 / 3;
+        } else if queued > 1 || delay > threshold {
+            self.ratio *= 0.9;
+            self.fps = self.fps.saturating_sub(2);
+        } else if delay < threshold / 4 {
+            self.ratio *= 1.1;
+            self.fps += 2;
+        }
+        self.ratio = self.ratio.max(MIN_RATIO).min(1.);
+        self.fps = self.fps.max(MIN_FPS).min(MAX_FPS);
+        let target = self.compute();
+        if target != self.current {
+            log::debug!(
+                "video qos: delay={}ms, queued={}, rtt={}ms, {:?}",
+                delay,
+                queued,
+                rtt,
+                target
+            );
+            self.current = target;
+            Some(target)
+        } else {
+            None
+        }
+    }
+
+    fn compute(&self) -> Target {
+        let base = &self.base;
+        // fewer bits per frame, the quantizers go up to keep the frame size small
+        let extra = ((1. - self.ratio) * 24.) as u32;
+        let rc_max_quantizer = base.rc_max_quantizer.max(MAX_QUANTIZER);
+        Target {
+            fps: self.fps,
+            bitrate: ((base.bitrate as f32 * self.ratio) as u32).max(1),
+            rc_min_quantizer: (base.rc_min_quantizer + extra).min(rc_max_quantizer),
+            rc_max_quantizer,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check(qos: &mut VideoQoS) -> Option<Target> {
+        qos.last_adjust -= ADJUST_INTERVAL;
+        qos.check(&Default::default())
+    }
+
+    #[test]
+    fn test_congestion() {
+        let mut qos = VideoQoS::new(1000, 12, 56);
+        let ids: HashSet<i32> = [1].iter().cloned().collect();
+        qos.on_sent(&ids);
+        qos.on_fetched(1, Duration::from_millis(1000));
+        let t = check(&mut qos).unwrap();
+        assert!(t.fps < MAX_FPS);
+        assert!(t.bitrate < 1000);
+        assert!(t.rc_min_quantizer > 12);
+        for _ in 0..100 {
+            qos.on_sent(&ids);
+            qos.on_fetched(1, Duration::from_millis(10));
+            check(&mut qos);
+        }
+        assert_eq!(qos.target(), qos.base);
+    }
+
+    #[test]
+    fn test_queued() {
+        let mut qos = VideoQoS::new(1000, 12, 56);
+        let ids: HashSet<i32> = [1, 2].iter().cloned().collect();
+        for _ in 0..4 {
+            qos.on_sent(&ids);
+        }
+        for _ in 0..100 {
+            check(&mut qos);
+        }
+        let t = qos.target();
+        assert_eq!(t.fps, MIN_FPS);
+        assert_eq!(t.bitrate, 200);
+    }
+}
@@ -18,7 +18,11 @@
 // to-do:
 // https://slhck.info/video/2017/03/01/rate-control.html
 
-use super::*;
+use super::{
+    text_mode::{TextMode, Update},
+    video_qos::VideoQoS,
+    *,
+};
 use hbb_common::tokio::{
     runtime::Runtime,
     sync::{
@@ -26,15 +30,23 @@ use hbb_common::tokio::{
         Mutex as TokioMutex,
     },
 };
-use scrap::{Capturer, Config, Display, EncodeFrame, Encoder, VideoCodecId, STRIDE_ALIGN};
+use scrap::{
+    codec::{EncodedFrame, EncoderApi},
+    Capturer, CodecFormat, Config, DirtyRect, Display, VideoCodecId,
+};
 use std::{
-    collections::HashSet,
+    collections::{HashSet, VecDeque},
     io::ErrorKind::WouldBlock,
     time::{self, Duration, Instant},
 };
 use virtual_display;
 
 const WAIT_BASE: i32 = 17;
+// frames sent but not fetched, more are not encoded for the viewer until QUEUED_TIMEOUT
+const MAX_QUEUED: usize = 2;
+const QUEUED_TIMEOUT: Duration = Duration::from_secs(3);
+// the key frames requested by a viewer are not encoded more often
+const MIN_KEY_FRAME_INTERVAL: Duration = Duration::from_secs(1);
 pub const NAME: &'static str = "video";
 
 lazy_static::lazy_static! {
@@ -43,28 +55,66 @@ lazy_static::lazy_static! {
     static ref SWITCH: Arc<Mutex<bool>> = Default::default();
     static ref TEST_LATENCIES: Arc<Mutex<HashMap<i32, i64>>> = Default::default();
     static ref IMAGE_QUALITIES: Arc<Mutex<HashMap<i32, i32>>> = Default::default();
-    static ref FRAME_FETCHED_NOTIFIER: (UnboundedSender<(i32, Option<Instant>)>, Arc<TokioMutex<UnboundedReceiver<(i32, Option<Instant>)>>>) = {
-        let (tx, rx) = unbounded_channel();
-        (tx, Arc::new(TokioMutex::new(rx)))
-    };
+    static ref PEER_DECODINGS: Arc<Mutex<HashMap<i32, Vec<CodecFormat>>>> = Default::default();
+    static ref LOSSLESS: Arc<Mutex<HashSet<i32>>> = Default::default();
+    // the size the viewers show the video at, the frames are scaled down to it before encoding
+    static ref VIEWPORTS: Arc<Mutex<HashMap<i32, (usize, usize)>>> = Default::default();
+    // the connections and the displays the decoders of which need a key frame
+    static ref KEY_FRAME_REQUESTS: Arc<Mutex<HashSet<(i32, usize)>>> = Default::default();
+    // the connection which changed the resolution, with the output and the size to restore
+    #[cfg(target_os = "linux")]
+    static ref CHANGED_RESOLUTION: Arc<Mutex<Option<(i32, String, usize, usize)>>> = Default::default();
+    // per display, the main service and the service of the same display never run together
+    static ref FRAME_FETCHED_NOTIFIERS: Arc<Mutex<HashMap<usize, FrameFetchedNotifier>>> = Default::default();
+    static ref DISPLAY_SERVICE_NAMES: Arc<Mutex<HashMap<usize, &'static str>>> = Default::default();
 }
 
-pub fn notify_video_frame_feched(conn_id: i32, frame_tm: Option<Instant>) {
-    FRAME_FETCHED_NOTIFIER.0.send((conn_id, frame_tm)).unwrap()
+type FrameFetchedNotifier = (
+    UnboundedSender<(i32, Option<Instant>)>,
+    Arc<TokioMutex<UnboundedReceiver<(i32, Option<Instant>)>>>,
+);
+
+fn get_frame_fetched_notifier(display: usize) -> FrameFetchedNotifier {
+    FRAME_FETCHED_NOTIFIERS
+        .lock()
+        .unwrap()
+        .entry(display)
+        .or_insert_with(|| {
+            let (tx, rx) = unbounded_channel();
+            (tx, Arc::new(TokioMutex::new(rx)))
+        })
+        .clone()
+}
+
+/// Notify the service of `display` that the connection fetched a frame, all the services if None.
+pub fn notify_video_frame_feched(conn_id: i32, display: Option<usize>, frame_tm: Option<Instant>) {
+    match display {
+        Some(display) => {
+            get_frame_fetched_notifier(display)
+                .0
+                .send((conn_id, frame_tm))
+                .ok();
+        }
+        None => {
+            for (tx, _) in FRAME_FETCHED_NOTIFIERS.lock().unwrap().values() {
+                tx.send((conn_id, frame_tm)).ok();
+            }
+        }
+    }
 }
 
 struct VideoFrameController {
-    cur: Instant,
     send_conn_ids: HashSet<i32>,
     rt: Runtime,
+    notifier: FrameFetchedNotifier,
 }
 
 impl VideoFrameController {
-    fn new() -> Self {
+    fn new(display: usize) -> Self {
         Self {
-            cur: Instant::now(),
             send_conn_ids: HashSet::new(),
             rt: Runtime::new().unwrap(),
+            notifier: get_frame_fetched_notifier(display),
         }
     }
 
@@ -72,41 +122,44 @@ impl VideoFrameController {
         self.send_conn_ids.clear();
     }
 
-    fn set_send(&mut self, tm: Instant, conn_ids: HashSet<i32>) {
-        if !conn_ids.is_empty() {
-            self.cur = tm;
-            self.send_conn_ids = conn_ids;
-        }
+    fn set_send(&mut self, conn_ids: HashSet<i32>) {
+        self.send_conn_ids = conn_ids;
     }
 
-    fn blocking_wait_next(&mut self, timeout_millis: u128) {
-        if self.send_conn_ids.is_empty() {
-            return;
-        }
-
+    // returns when the connections fetched the frames sent to them, including the earlier ones,
+    // stops waiting once all the connections of the current frame fetched it
+    fn blocking_wait_next(&mut self, timeout: Duration) -> Vec<(i32, Instant)> {
         let send_conn_ids = self.send_conn_ids.clone();
+        // only takes the notifications already received if nothing is sent
+        let timeout = if send_conn_ids.is_empty() {
+            Duration::ZERO
+        } else {
+            timeout
+        };
+        let rx = self.notifier.1.clone();
         self.rt.block_on(async move {
+            let mut fetched = Vec::new();
             let mut fetched_conn_ids = HashSet::new();
             let begin = Instant::now();
-            while begin.elapsed().as_millis() < timeout_millis {
-                let timeout_dur =
-                    Duration::from_millis((timeout_millis - begin.elapsed().as_millis()) as u64);
-                match tokio::time::timeout(
-                    timeout_dur,
-                    FRAME_FETCHED_NOTIFIER.1.lock().await.recv(),
-                )
-                .await
-                {
+            loop {
+                let timeout_dur = timeout.saturating_sub(begin.elapsed());
+                match tokio::time::timeout(timeout_dur, rx.lock().await.recv()).await {
                     Err(_) => {
                         // break if timeout
-                        // log::error!("blocking wait frame receiving timeout {}", timeout_millis);
                         break;
                     }
                     Ok(Some((id, instant))) => {
-                        if let Some(tm) = instant {
-                            log::trace!("Channel recv latency: {}", tm.elapsed().as_secs_f32());
+                        match instant {
+                            Some(tm) => {
+                                log::trace!("Channel recv latency: {}", tm.elapsed().as_secs_f32());
+                            }
+                            // the connection is closed
+                            None => continue,
+                        }
+                        fetched.push((id, Instant::now()));
+                        if send_conn_ids.contains(&id) {
+                            fetched_conn_ids.insert(id);
                         }
-                        fetched_conn_ids.insert(id);
 
                         // break if all connections have received current frame
                         if fetched_conn_ids.len() >= send_conn_ids.len() {
@@ -115,19 +168,386 @@ impl VideoFrameController {
                     }
                     Ok(None) => {
                         // this branch would nerver be reached
+                        break;
                     }
                 }
             }
-        });
+            fetched
+        })
     }
 }
 
+// The frame captured once for all the viewers, converted to I420 and scaled to each size at most once.
+struct FrameData<'a> {
+    data: &'a [u8],
+    bgra: bool,
+    width: usize,
+    height: usize,
+    yuv: &'a mut Vec<u8>,
+    converted: bool,
+    // the buffers are kept across the frames, `done` are the sizes of the current frame
+    scaled: &'a mut HashMap<(usize, usize), Vec<u8>>,
+    done: HashSet<(usize, usize)>,
+}
+
+impl<'a> FrameData<'a> {
+    fn i420(&mut self) -> &[u8] {
+        if !self.bgra {
+            return self.data;
+        }
+        if !self.converted {
+            scrap::bgra_to_i420(self.width, self.height, self.data, self.yuv);
+            self.converted = true;
+        }
+        &self.yuv[..]
+    }
+
+    fn i420_scaled(&mut self, width: usize, height: usize) -> &[u8] {
+        if width == self.width && height == self.height {
+            return self.i420();
+        }
+        let size = (width, height);
+        if !self.done.contains(&size) {
+            self.i420();
+            let src = if self.bgra { &self.yuv[..] } else { self.data };
+            let dst = self.scaled.entry(size).or_default();
+            scrap::i420_scale(self.width, self.height, src, width, height, dst);
+            self.done.insert(size);
+        }
+        &self.scaled[&size][..]
+    }
+}
+
+// The encoder of one connection, with the codec, the quality and the congestion of its own.
+struct Viewer {
+    id: i32,
+    quality: i32,
+    format: CodecFormat,
+    // the size encoded, smaller than the display if the viewer scales it down
+    size: (usize, usize),
+    display_size: (usize, usize),
+    cfg: Config,
+    encoder: Box<dyn EncoderApi>,
+    qos: VideoQoS,
+    text_mode: Option<TextMode>,
+    // the send time of the frames not fetched yet
+    sent: VecDeque<Instant>,
+    last_sent: Option<Instant>,
+    // frames are captured but not encoded, the dirty rects of the next frame are incomplete
+    skipped: bool,
+    // the sequence number of the next encoded frame
+    seq: i64,
+    last_key_frame: Instant,
+    key_frame_interval: Option<Duration>,
+}
+
+impl Viewer {
+    fn new(id: i32, width: usize, height: usize) -> ResultType<Self> {
+        let quality = get_image_quality(id);
+        let size = get_scaled_size(id, width, height);
+        let (bitrate, rc_min_quantizer, rc_max_quantizer, speed) =
+            get_quality(size.0, size.1, quality);
+        let cfg = Config {
+            width: size.0 as _,
+            height: size.1 as _,
+            timebase: [1, 1000], // Output timestamp precision
+            bitrate,
+            codec: VideoCodecId::VP9,
+            rc_min_quantizer,
+            rc_max_quantizer,
+            speed,
+        };
+        let format = get_codec_format(id);
+        let encoder = create_encoder(format, &cfg)?;
+        let text_mode = if is_lossless(id) {
+            Some(TextMode::new(width, height))
+        } else {
+            None
+        };
+        log::info!(
+            "#{} codec: {:?}, size={:?}, bitrate={}, rc_min_quantizer={}, lossless={}",
+            id,
+            format,
+            size,
+            bitrate,
+            rc_min_quantizer,
+            text_mode.is_some()
+        );
+        Ok(Self {
+            id,
+            quality,
+            format,
+            size,
+            display_size: (width, height),
+            cfg,
+            encoder,
+            qos: VideoQoS::new(bitrate, rc_min_quantizer, rc_max_quantizer),
+            text_mode,
+            sent: Default::default(),
+            last_sent: None,
+            skipped: false,
+            seq: 1,
+            last_key_frame: Instant::now(),
+            key_frame_interval: get_key_frame_interval(),
+        })
+    }
+
+    // the viewer chose another quality, codec or viewport
+    fn changed(&self) -> bool {
+        get_image_quality(self.id) != self.quality
+            || get_codec_format(self.id) != self.format
+            || is_lossless(self.id) != self.text_mode.is_some()
+            || get_scaled_size(self.id, self.display_size.0, self.display_size.1) != self.size
+    }
+
+    // requested by the viewer or periodic, rate limited
+    fn key_frame_due(&self, display: usize) -> bool {
+        let elapsed = self.last_key_frame.elapsed();
+        if elapsed < MIN_KEY_FRAME_INTERVAL {
+            return false;
+        }
+        if KEY_FRAME_REQUESTS
+            .lock()
+            .unwrap()
+            .remove(&(self.id, display))
+        {
+            log::info!("#{} Key frame requested for display {}", self.id, display);
+            return true;
+        }
+        self.key_frame_interval
+            .map(|x| elapsed >= x)
+            .unwrap_or(false)
+    }
+
+    // nothing is captured on a static screen, e.g. with XDamage, the last frame is encoded
+    // again for the viewers with nothing to decode yet or waiting for a key frame
+    fn needs_frame(&self, display: usize) -> bool {
+        self.last_sent.is_none()
+            || (self.text_mode.is_none()
+                && self.last_key_frame.elapsed() >= MIN_KEY_FRAME_INTERVAL
+                && KEY_FRAME_REQUESTS
+                    .lock()
+                    .unwrap()
+                    .contains(&(self.id, display)))
+    }
+
+    fn header(&self, display: usize) -> VideoFrame {
+        let mut vf = VideoFrame::new();
+        vf.display = display as _;
+        if self.size != self.display_size {
+            vf.scaling = Some(Scaling {
+                width: self.size.0 as _,
+                height: self.size.1 as _,
+                display_width: self.display_size.0 as _,
+                display_height: self.display_size.1 as _,
+                ..Default::default()
+            })
+            .into();
+        }
+        vf
+    }
+
+    // not encoded if the viewer did not fetch the last frames or the fps of it is lower
+    fn due(&self) -> bool {
+        let last_sent = match self.last_sent {
+            Some(tm) => tm,
+            None => return true,
+        };
+        if self.sent.len() > MAX_QUEUED && last_sent.elapsed() < QUEUED_TIMEOUT {
+            return false;
+        }
+        let spf = self.qos.spf();
+        last_sent.elapsed() + spf / 10 >= spf
+    }
+
+    fn handle_frame(
+        &mut self,
+        sp: &GenericService,
+        display: usize,
+        frame: &mut FrameData,
+        dirty_rects: &[DirtyRect],
+        ms: i64,
+        crc: &mut (u32, u32),
+    ) -> ResultType<bool> {
+        let mut restart = false;
+        if let Some(text_mode) = self.text_mode.as_mut() {
+            match text_mode.update(frame.data) {
+                Update::Tiles(rgb) => {
+                    send_rgb_frame(sp, self.id, display, rgb);
+                    return Ok(true);
+                }
+                Update::Unchanged => return Ok(false),
+                Update::Motion(x) => restart = x,
+            }
+        }
+        let key_frame = restart || self.key_frame_due(display);
+        if self.text_mode.is_none() {
+            // only the changed areas are encoded
+            let rects = if self.skipped || key_frame {
+                Vec::new()
+            } else {
+                scale_rects(dirty_rects, self.display_size, self.size)
+            };
+            self.encoder
+                .set_dirty_rects(&rects)
+                .with_context(|| "Failed to set dirty rects")?;
+        }
+        if key_frame {
+            self.last_key_frame = Instant::now();
+            KEY_FRAME_REQUESTS
+                .lock()
+                .unwrap()
+                .remove(&(self.id, display));
+        }
+        if restart {
+            // the viewer needs a key frame to decode from
+            self.encoder = create_encoder(self.format, &self.cfg)?;
+            let target = self.qos.target();
+            self.encoder
+                .set_quality(
+                    target.bitrate,
+                    target.rc_min_quantizer,
+                    target.rc_max_quantizer,
+                )
+                .with_context(|| "Failed to change the encoder quality")?;
+        } else if key_frame {
+            self.encoder
+                .force_key_frame()
+                .with_context(|| "Failed to force a key frame")?;
+        }
+        self.skipped = false;
+        handle_one_frame(
+            sp,
+            self.id,
+            self.header(display),
+            frame.i420_scaled(self.size.0, self.size.1),
+            ms,
+            &mut self.seq,
+            crc,
+            self.encoder.as_mut(),
+        )
+    }
+
+    fn on_sent(&mut self, tm: Instant) {
+        self.sent.push_back(tm);
+        self.last_sent = Some(tm);
+        self.qos.on_sent(&std::iter::once(self.id).collect());
+    }
+
+    fn on_fetched(&mut self, tm: Instant) {
+        if let Some(sent) = self.sent.pop_front() {
+            self.qos
+                .on_fetched(self.id, tm.saturating_duration_since(sent));
+        }
+    }
+
+    fn check_qos(&mut self, sp: &GenericService, latencies: &HashMap<i32, i64>) -> ResultType<()> {
+        let rtts = latencies
+            .get(&self.id)
+            .map(|x| (self.id, *x))
+            .into_iter()
+            .collect();
+        if let Some(target) = self.qos.check(&rtts) {
+            self.encoder
+                .set_quality(
+                    target.bitrate,
+                    target.rc_min_quantizer,
+                    target.rc_max_quantizer,
+                )
+                .with_context(|| "Failed to change the encoder quality")?;
+            sp.send_to(create_quality_msg(&self.qos), self.id);
+        }
+        Ok(())
+    }
+}
+
+// the rects in the scaled frame, grown by a pixel for the filtering
+fn scale_rects(rects: &[DirtyRect], from: (usize, usize), to: (usize, usize)) -> Vec<DirtyRect> {
+    if from == to {
+        return rects.to_vec();
+    }
+    rects
+        .iter()
+        .map(|r| {
+            let x = (r.x * to.0 / from.0).saturating_sub(1);
+            let y = (r.y * to.1 / from.1).saturating_sub(1);
+            let right = ((r.x + r.w) * to.0 / from.0 + 2).min(to.0);
+            let bottom = ((r.y + r.h) * to.1 / from.1 + 2).min(to.1);
+            DirtyRect {
+                x,
+                y,
+                w: right.saturating_sub(x),
+                h: bottom.saturating_sub(y),
+            }
+        })
+        .collect()
+}
+
+fn create_encoder(format: CodecFormat, cfg: &Config) -> ResultType<Box<dyn EncoderApi>> {
+    match scrap::codec::new_encoder(format, cfg, (num_cpus::get() / 2) as _) {
+        Ok(x) => Ok(x),
+        Err(err) => bail!("Failed to create encoder: {}", err),
+    }
+}
+
+// the new viewers get their own encoders, the others are not interrupted
+fn update_viewers(
+    sp: &GenericService,
+    viewers: &mut HashMap<i32, Viewer>,
+    width: usize,
+    height: usize,
+) -> ResultType<()> {
+    sp.snapshot(|_| Ok(()))?;
+    let ids = sp.subscriber_ids();
+    viewers.retain(|id, _| ids.contains(id));
+    for id in ids {
+        if viewers.get(&id).map(|v| v.changed()).unwrap_or(true) {
+            let viewer = Viewer::new(id, width, height)?;
+            sp.send_to(create_quality_msg(&viewer.qos), id);
+            viewers.insert(id, viewer);
+        }
+    }
+    Ok(())
+}
+
 pub fn new() -> GenericService {
     let sp = GenericService::new(NAME, true);
-    sp.run(run);
+    sp.run(|sp| run(sp, None));
+    sp
+}
+
+/// The service streaming `display` besides the current one, subscribed with capture_displays.
+pub fn new_display(display: usize) -> GenericService {
+    let sp = GenericService::new(get_display_service_name(display), true);
+    sp.run(move |sp| run(sp, Some(display)));
     sp
 }
 
+pub fn get_display_service_name(display: usize) -> &'static str {
+    DISPLAY_SERVICE_NAMES
+        .lock()
+        .unwrap()
+        .entry(display)
+        .or_insert_with(|| Box::leak(format!("{}{}", NAME, display).into_boxed_str()))
+}
+
+pub fn get_display_service_names() -> Vec<(usize, &'static str)> {
+    DISPLAY_SERVICE_NAMES
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(display, name)| (*display, *name))
+        .collect()
+}
+
+pub fn is_display_service(name: &str) -> bool {
+    DISPLAY_SERVICE_NAMES
+        .lock()
+        .unwrap()
+        .values()
+        .any(|x| *x == name)
+}
+
 fn check_display_changed(
     last_n: usize,
     last_current: usize,
@@ -158,9 +578,12 @@ fn check_display_changed(
     return false;
 }
 
-fn run(sp: GenericService) -> ResultType<()> {
+// `fixed` is the display of an extra display service, None for the current display
+fn run(sp: GenericService, fixed: Option<usize>) -> ResultType<()> {
     let num_displays = Display::all()?.len();
-    if num_displays == 0 {
+    if fixed.is_some() {
+        // the virtual display is managed by the main service
+    } else if num_displays == 0 {
         // Device may sometimes be uninstalled by user in "Device Manager" Window.
         // Closing device will clear the instance data.
         virtual_display::close_device();
@@ -171,9 +594,21 @@ fn run(sp: GenericService) -> ResultType<()> {
         }
     }
 
-    let fps = 30;
-    let spf = time::Duration::from_secs_f32(1. / (fps as f32));
-    let (ndisplay, current, display) = get_current_display()?;
+    let (ndisplay, current, display) = match fixed {
+        Some(i) => {
+            let (ndisplay, current, _) = get_current_display()?;
+            if i == current {
+                // streamed by the main service
+                std::thread::sleep(Duration::from_millis(300));
+                return Ok(());
+            }
+            match try_get_displays()?.into_iter().nth(i) {
+                Some(display) => (ndisplay, i, display),
+                None => bail!("No display {}", i),
+            }
+        }
+        None => get_current_display()?,
+    };
     let (origin, width, height) = (display.origin(), display.width(), display.height());
     log::debug!(
         "#displays={}, current={}, origin: {:?}, width={}, height={}",
@@ -183,30 +618,13 @@ fn run(sp: GenericService) -> ResultType<()> {
         width,
         height
     );
+    // the lossless viewers compare the BGRA frames, they are converted for the others
+    let bgra = has_lossless_viewer(&sp);
     // Capturer object is expensive, avoiding to create it frequently.
-    let mut c = Capturer::new(display, true).with_context(|| "Failed to create capturer")?;
-
-    let q = get_image_quality();
-    let (bitrate, rc_min_quantizer, rc_max_quantizer, speed) = get_quality(width, height, q);
-    log::info!("bitrate={}, rc_min_quantizer={}", bitrate, rc_min_quantizer);
+    let mut c = Capturer::new(display, !bgra).with_context(|| "Failed to create capturer")?;
     let mut wait = WAIT_BASE;
-    let cfg = Config {
-        width: width as _,
-        height: height as _,
-        timebase: [1, 1000], // Output timestamp precision
-        bitrate,
-        codec: VideoCodecId::VP9,
-        rc_min_quantizer,
-        rc_max_quantizer,
-        speed,
-    };
-    let mut vpx;
-    match Encoder::new(&cfg, (num_cpus::get() / 2) as _) {
-        Ok(x) => vpx = x,
-        Err(err) => bail!("Failed to create encoder: {}", err),
-    }
 
-    if *SWITCH.lock().unwrap() {
+    if fixed.is_none() && *SWITCH.lock().unwrap() {
         log::debug!("Broadcasting display switch");
         let mut misc = Misc::new();
         misc.set_switch_display(SwitchDisplay {
@@ -223,24 +641,38 @@ fn run(sp: GenericService) -> ResultType<()> {
         sp.send(msg_out);
     }
 
-    let mut frame_controller = VideoFrameController::new();
+    let mut viewers: HashMap<i32, Viewer> = HashMap::new();
+    let mut frame_controller = VideoFrameController::new(current);
+    let mut yuv = Vec::new();
+    let mut scaled = HashMap::new();
+    // the last frame captured, for the viewers in need of a frame while nothing changes
+    let mut last_frame = Vec::new();
 
     let mut crc = (0, 0);
-    let start = time::Instant::now();
     let mut last_check_displays = time::Instant::now();
     #[cfg(windows)]
     let mut try_gdi = 1;
     #[cfg(windows)]
     log::info!("gdi: {}", c.is_gdi());
     while sp.ok() {
-        if *SWITCH.lock().unwrap() {
-            bail!("SWITCH");
-        }
-        if current != *CURRENT_DISPLAY.lock().unwrap() {
-            *SWITCH.lock().unwrap() = true;
-            bail!("SWITCH");
+        if fixed.is_some() {
+            if current == *CURRENT_DISPLAY.lock().unwrap() {
+                log::debug!(
+                    "Display {} is current, streamed by the main service",
+                    current
+                );
+                break;
+            }
+        } else {
+            if *SWITCH.lock().unwrap() {
+                bail!("SWITCH");
+            }
+            if current != *CURRENT_DISPLAY.lock().unwrap() {
+                *SWITCH.lock().unwrap() = true;
+                bail!("SWITCH");
+            }
         }
-        if get_image_quality() != q {
+        if has_lossless_viewer(&sp) != bgra {
             bail!("SWITCH");
         }
         #[cfg(windows)]
@@ -254,44 +686,101 @@ fn run(sp: GenericService) -> ResultType<()> {
             last_check_displays = now;
             if ndisplay != get_display_num() {
                 log::info!("Displays changed");
-                *SWITCH.lock().unwrap() = true;
+                if fixed.is_none() {
+                    *SWITCH.lock().unwrap() = true;
+                }
                 bail!("SWITCH");
             }
         }
         *LAST_ACTIVE.lock().unwrap() = now;
 
         frame_controller.reset();
+        update_viewers(&sp, &mut viewers, width, height)?;
 
         match c.frame(wait as _) {
             Ok(frame) => {
-                let time = now - start;
-                let ms = (time.as_secs() * 1000 + time.subsec_millis() as u64) as i64;
-                let send_conn_ids = handle_one_frame(&sp, &frame, ms, &mut crc, &mut vpx)?;
-                frame_controller.set_send(now, send_conn_ids);
+                let ms = super::get_media_time();
+                #[cfg(target_os = "linux")]
+                let dirty_rects = frame.dirty_rects();
+                #[cfg(not(target_os = "linux"))]
+                let dirty_rects: &[DirtyRect] = &[];
+                last_frame.clear();
+                last_frame.extend_from_slice(&frame);
+                let mut frame = FrameData {
+                    data: &frame,
+                    bgra,
+                    width,
+                    height,
+                    yuv: &mut yuv,
+                    converted: false,
+                    scaled: &mut scaled,
+                    done: HashSet::new(),
+                };
+                let mut send_conn_ids = HashSet::new();
+                for viewer in viewers.values_mut() {
+                    if !viewer.due() {
+                        viewer.skipped = true;
+                        continue;
+                    }
+                    if viewer.handle_frame(&sp, current, &mut frame, dirty_rects, ms, &mut crc)? {
+                        viewer.on_sent(now);
+                        send_conn_ids.insert(viewer.id);
+                    }
+                }
+                frame_controller.set_send(send_conn_ids);
                 #[cfg(windows)]
                 {
                     try_gdi = 0;
                 }
             }
             Err(ref e) if e.kind() == WouldBlock => {
-                // https://github.com/NVIDIA/video-sdk-samples/tree/master/nvEncDXGIOutputDuplicationSample
-                wait = WAIT_BASE - now.elapsed().as_millis() as i32;
-                if wait < 0 {
-                    wait = 0
-                }
-                #[cfg(windows)]
-                if try_gdi > 0 && !c.is_gdi() {
-                    if try_gdi > 3 {
-                        c.set_gdi();
-                        try_gdi = 0;
-                        log::info!("No image, fall back to gdi");
+                if !last_frame.is_empty()
+                    && viewers.values().any(|v| v.due() && v.needs_frame(current))
+                {
+                    let ms = super::get_media_time();
+                    let mut frame = FrameData {
+                        data: &last_frame,
+                        bgra,
+                        width,
+                        height,
+                        yuv: &mut yuv,
+                        converted: false,
+                        scaled: &mut scaled,
+                        done: HashSet::new(),
+                    };
+                    let mut send_conn_ids = HashSet::new();
+                    for viewer in viewers.values_mut() {
+                        if !viewer.due() || !viewer.needs_frame(current) {
+                            continue;
+                        }
+                        // the whole frame, the dirty rects are of the frames already sent
+                        viewer.skipped = true;
+                        if viewer.handle_frame(&sp, current, &mut frame, &[], ms, &mut crc)? {
+                            viewer.on_sent(now);
+                            send_conn_ids.insert(viewer.id);
+                        }
+                    }
+                    frame_controller.set_send(send_conn_ids);
+                } else {
+                    // https://github.com/NVIDIA/video-sdk-samples/tree/master/nvEncDXGIOutputDuplicationSample
+                    wait = WAIT_BASE - now.elapsed().as_millis() as i32;
+                    if wait < 0 {
+                        wait = 0
                     }
-                    try_gdi += 1;
+                    #[cfg(windows)]
+                    if try_gdi > 0 && !c.is_gdi() {
+                        if try_gdi > 3 {
+                            c.set_gdi();
+                            try_gdi = 0;
+                            log::info!("No image, fall back to gdi");
+                        }
+                        try_gdi += 1;
+                    }
+                    continue;
                 }
-                continue;
             }
             Err(err) => {
-                if check_display_changed(ndisplay, current, width, height) {
+                if fixed.is_none() && check_display_changed(ndisplay, current, width, height) {
                     log::info!("Displays changed");
                     *SWITCH.lock().unwrap() = true;
                     bail!("SWITCH");
@@ -301,8 +790,21 @@ fn run(sp: GenericService) -> ResultType<()> {
             }
         }
 
-        // i love 3, 6, 8
-        frame_controller.blocking_wait_next(3_000);
+        // paced by the fastest viewer, the slower ones skip frames
+        let spf = viewers
+            .values()
+            .map(|v| v.qos.spf())
+            .min()
+            .unwrap_or(Duration::from_millis(WAIT_BASE as _));
+        for (id, tm) in frame_controller.blocking_wait_next(spf.saturating_sub(now.elapsed())) {
+            if let Some(viewer) = viewers.get_mut(&id) {
+                viewer.on_fetched(tm);
+            }
+        }
+        let latencies = TEST_LATENCIES.lock().unwrap().clone();
+        for viewer in viewers.values_mut() {
+            viewer.check_qos(&sp, &latencies)?;
+        }
 
         let elapsed = now.elapsed();
         // may need to enable frame(timeout)
@@ -316,21 +818,48 @@ fn run(sp: GenericService) -> ResultType<()> {
 }
 
 #[inline]
-fn create_msg(vp9s: Vec<VP9>) -> Message {
+fn create_msg(mut vf: VideoFrame, format: CodecFormat, frames: Vec<EncodedVideoFrame>) -> Message {
     let mut msg_out = Message::new();
-    let mut vf = VideoFrame::new();
-    vf.set_vp9s(VP9s {
-        frames: vp9s.into(),
+    let frames = EncodedVideoFrames {
+        frames: frames.into(),
         ..Default::default()
-    });
+    };
+    match format {
+        CodecFormat::VP9 => vf.set_vp9s(frames),
+        CodecFormat::H264 => vf.set_h264s(frames),
+    }
     msg_out.set_video_frame(vf);
     msg_out
 }
 
+fn create_quality_msg(qos: &VideoQoS) -> Message {
+    let target = qos.target();
+    let mut misc = Misc::new();
+    misc.set_video_quality(VideoQuality {
+        fps: target.fps,
+        bitrate: target.bitrate,
+        rc_min_quantizer: target.rc_min_quantizer,
+        rc_max_quantizer: target.rc_max_quantizer,
+        ..Default::default()
+    });
+    let mut msg_out = Message::new();
+    msg_out.set_misc(misc);
+    msg_out
+}
+
+fn send_rgb_frame(sp: &GenericService, id: i32, display: usize, rgb: RGB) {
+    let mut vf = VideoFrame::new();
+    vf.display = display as _;
+    vf.set_rgb(rgb);
+    let mut msg_out = Message::new();
+    msg_out.set_video_frame(vf);
+    sp.send_to(msg_out, id);
+}
+
 #[inline]
-fn create_frame(frame: &EncodeFrame) -> VP9 {
-    VP9 {
-        data: frame.data.to_vec(),
+fn create_frame(frame: EncodedFrame) -> EncodedVideoFrame {
+    EncodedVideoFrame {
+        data: frame.data,
         key: frame.key,
         pts: frame.pts,
         ..Default::default()
@@ -338,21 +867,17 @@ fn create_frame(frame: &EncodeFrame) -> VP9 {
 }
 
 #[inline]
+// returns true if the frame is sent to the viewer
 fn handle_one_frame(
     sp: &GenericService,
+    id: i32,
+    header: VideoFrame,
     frame: &[u8],
     ms: i64,
+    seq: &mut i64,
     _crc: &mut (u32, u32),
-    vpx: &mut Encoder,
-) -> ResultType<HashSet<i32>> {
-    sp.snapshot(|sps| {
-        // so that new sub and old sub share the same encoder after switch
-        if sps.has_subscribes() {
-            bail!("SWITCH");
-        }
-        Ok(())
-    })?;
-
+    encoder: &mut dyn EncoderApi,
+) -> ResultType<bool> {
     /*
     // crc runs faster on my i7-4790, around 0.5ms for 720p picture,
     // but it is super slow on my Linux (in virtualbox) on the same machine, 720ms consumed.
@@ -371,28 +896,35 @@ fn handle_one_frame(
     */
     let encode = true;
 
-    let mut send_conn_ids: HashSet<i32> = Default::default();
+    let mut sent = false;
     if encode {
-        let mut frames = Vec::new();
-        for ref frame in vpx
-            .encode(ms, frame, STRIDE_ALIGN)
+        let frames: Vec<EncodedVideoFrame> = encoder
+            .encode(ms, frame)
             .with_context(|| "Failed to encode")?
-        {
-            frames.push(create_frame(frame));
-        }
-        for ref frame in vpx.flush().with_context(|| "Failed to flush")? {
-            frames.push(create_frame(frame));
-        }
+            .drain(..)
+            .map(|frame| {
+                let mut frame = create_frame(frame);
+                frame.seq = *seq;
+                *seq += 1;
+                frame
+            })
+            .collect();
 
         // to-do: flush periodically, e.g. 1 second
         if frames.len() > 0 {
-            send_conn_ids = sp.send_video_frame(create_msg(frames));
+            let msg = create_msg(header, encoder.format(), frames);
+            // every display streamed is recorded, each to a file of its own
+            if let Some(message::Union::video_frame(vf)) = &msg.union {
+                super::session_record::write_video(id, vf);
+            }
+            sp.send_to(msg, id);
+            sent = true;
         }
     }
-    Ok(send_conn_ids)
+    Ok(sent)
 }
 
-fn get_display_num() -> usize {
+pub fn get_display_num() -> usize {
     if let Ok(d) = try_get_displays() {
         d.len()
     } else {
@@ -525,7 +1057,7 @@ fn convert_quality(q: i32) -> i32 {
             (100 * 2 / 3, 12)
         } else if q == ImageQuality::Low.value() {
             (100 / 2, 18)
-        } else if q == ImageQuality::Best.value() {
+        } else if q == ImageQuality::Best.value() || q == ImageQuality::Lossless.value() {
             (100, 12)
         } else {
             let bitrate = q >> 8 & 0xFF;
@@ -543,6 +1075,11 @@ fn convert_quality(q: i32) -> i32 {
 pub fn update_image_quality(id: i32, q: Option<i32>) {
     match q {
         Some(q) => {
+            if q == ImageQuality::Lossless.value() {
+                LOSSLESS.lock().unwrap().insert(id);
+            } else {
+                LOSSLESS.lock().unwrap().remove(&id);
+            }
             let q = convert_quality(q);
             if q > 0 {
                 IMAGE_QUALITIES.lock().unwrap().insert(id, q);
@@ -552,18 +1089,52 @@ pub fn update_image_quality(id: i32, q: Option<i32>) {
         }
         None => {
             IMAGE_QUALITIES.lock().unwrap().remove(&id);
+            LOSSLESS.lock().unwrap().remove(&id);
+        }
+    }
+}
+
+pub fn update_peer_decodings(id: i32, decodings: Option<Vec<CodecFormat>>) {
+    match decodings {
+        Some(decodings) => {
+            PEER_DECODINGS.lock().unwrap().insert(id, decodings);
         }
+        None => {
+            PEER_DECODINGS.lock().unwrap().remove(&id);
+        }
+    }
+}
+
+// the best codec the viewer can decode
+fn get_codec_format(id: i32) -> CodecFormat {
+    // only vp9 can be recorded
+    if super::session_record::is_recording(id) {
+        return CodecFormat::VP9;
     }
+    let decodings = PEER_DECODINGS
+        .lock()
+        .unwrap()
+        .get(&id)
+        .cloned()
+        .unwrap_or_default();
+    scrap::codec::negotiate_format(&scrap::codec::supported_formats(), &decodings)
+}
+
+fn is_lossless(id: i32) -> bool {
+    LOSSLESS.lock().unwrap().contains(&id) && !super::session_record::is_recording(id)
+}
+
+fn has_lossless_viewer(sp: &GenericService) -> bool {
+    sp.subscriber_ids().iter().any(|id| is_lossless(*id))
 }
 
-fn get_image_quality() -> i32 {
+fn get_image_quality(id: i32) -> i32 {
     IMAGE_QUALITIES
         .lock()
         .unwrap()
-        .values()
-        .min()
-        .unwrap_or(&convert_quality(ImageQuality::Balanced.value()))
-        .clone()
+        .get(&id)
+        .cloned()
+        .unwrap_or(convert_quality(ImageQuality::Balanced.value()))
 }
 
 #[inline]
@@ -574,3 +1145,161 @@ fn get_quality(w: usize, h: usize, q: i32) -> (u32, u32, u32, i32) {
     let b = ((w * h) / 1000) as u32;
     (bitrate as u32 * b / 100, quantizer as _, 56, 7)
 }
+
+/// The decoder of the viewer failed, the next frame of the display is encoded as a key frame.
+pub fn request_key_frame(id: i32, display: usize) {
+    KEY_FRAME_REQUESTS.lock().unwrap().insert((id, display));
+}
+
+pub fn clear_key_frame_requests(id: i32) {
+    KEY_FRAME_REQUESTS.lock().unwrap().retain(|x| x.0 != id);
+}
+
+// periodic key frames heal the streams corrupted on the way, the "key-frame-interval" option in seconds
+fn get_key_frame_interval() -> Option<Duration> {
+    let secs: u64 = hbb_common::config::Config::get_option("key-frame-interval")
+        .parse()
+        .unwrap_or(0);
+    if secs > 0 {
+        Some(Duration::from_secs(secs))
+    } else {
+        None
+    }
+}
+
+pub fn update_viewport(id: i32, viewport: Option<(usize, usize)>) {
+    match viewport {
+        Some(viewport) => {
+            VIEWPORTS.lock().unwrap().insert(id, viewport);
+        }
+        None => {
+            VIEWPORTS.lock().unwrap().remove(&id);
+        }
+    }
+}
+
+// the size encoded for the viewer, the display fit in the viewport keeping the aspect ratio,
+// never scaled up, the lossless viewers and the recorded sessions get the native size
+fn get_scaled_size(id: i32, width: usize, height: usize) -> (usize, usize) {
+    if is_lossless(id) || super::session_record::is_recording(id) {
+        return (width, height);
+    }
+    match VIEWPORTS.lock().unwrap().get(&id) {
+        Some((w, h)) if *w < width || *h < height => {
+            let ratio = (*w as f64 / width as f64).min(*h as f64 / height as f64);
+            // multiples of 8 avoid new encoders on every pixel of a window resize
+            let w = ((width as f64 * ratio) as usize & !7).max(8);
+            let h = ((height as f64 * ratio) as usize & !7).max(8);
+            (w, h)
+        }
+        _ => (width, height),
+    }
+}
+
+/// Change the resolution of the current display, restored when the connection closes.
+pub fn change_resolution(id: i32, width: usize, height: usize) -> ResultType<()> {
+    #[cfg(target_os = "linux")]
+    {
+        let (_, _, display) = get_current_display()?;
+        let (name, w, h) =
+            crate::platform::linux::change_resolution(display.origin(), width, height)?;
+        let mut changed = CHANGED_RESOLUTION.lock().unwrap();
+        match changed.as_mut() {
+            // the size before the first change is restored
+            Some(x) if x.1 == name => x.0 = id,
+            _ => *changed = Some((id, name, w, h)),
+        }
+        refresh();
+        Ok(())
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        let _ = (id, width, height);
+        bail!("Changing the resolution is not supported on this platform");
+    }
+}
+
+pub fn restore_resolution(id: i32) {
+    #[cfg(target_os = "linux")]
+    {
+        let mut changed = CHANGED_RESOLUTION.lock().unwrap();
+        if changed.as_ref().map(|x| x.0) != Some(id) {
+            return;
+        }
+        if let Some((_, name, w, h)) = changed.take() {
+            log::info!("Restoring the resolution of {} to {}x{}", name, w, h);
+            allow_err!(crate::platform::linux::set_resolution(&name, w, h));
+            refresh();
+        }
+    }
+    #[cfg(not(target_os = "linux"))]
+    let _ = id;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rect(x: usize, y: usize, w: usize, h: usize) -> DirtyRect {
+        DirtyRect { x, y, w, h }
+    }
+
+    #[test]
+    fn test_scale_rects() {
+        let (from, to) = ((1920, 1080), (1280, 720));
+        let scaled = |r: DirtyRect| {
+            let v = scale_rects(&[r], from, to);
+            (v[0].x, v[0].y, v[0].w, v[0].h)
+        };
+        assert_eq!(scaled(rect(0, 0, 1920, 1080)), (0, 0, 1280, 720));
+        // grown by a pixel, clipped at the edges
+        assert_eq!(scaled(rect(0, 0, 3, 3)), (0, 0, 4, 4));
+        assert_eq!(scaled(rect(1900, 1060, 20, 20)), (1265, 705, 15, 15));
+        // the scaled rects cover the fractional pixels and stay in the frame
+        let (from, to) = ((1366, 768), (683, 384));
+        for r in [
+            rect(1365, 767, 1, 1),
+            rect(1, 1, 1, 1),
+            rect(7, 9, 333, 101),
+        ] {
+            let (x, y, r2) = (r.x, r.y, scale_rects(&[r], from, to)[0]);
+            assert!(r2.x <= x * to.0 / from.0 && r2.y <= y * to.1 / from.1);
+            assert!((r2.x + r2.w) * from.0 >= (r.x + r.w) * to.0);
+            assert!((r2.y + r2.h) * from.1 >= (r.y + r.h) * to.1);
+            assert!(r2.x + r2.w <= to.0 && r2.y + r2.h <= to.1);
+        }
+        assert_eq!(scale_rects(&[rect(1, 2, 3, 4)], from, from)[0].w, 3);
+    }
+
+    #[test]
+    fn test_key_frame_request_on_idle_display() {
+        let (id, display, width, height) = (1, 0, 64, 64);
+        let mut viewer = Viewer::new(id, width, height).unwrap();
+        // a new viewer has nothing to decode yet
+        assert!(viewer.needs_frame(display));
+        viewer.last_sent = Some(Instant::now());
+        viewer.last_key_frame = Instant::now() - MIN_KEY_FRAME_INTERVAL;
+        assert!(!viewer.needs_frame(display));
+        request_key_frame(id, display);
+        assert!(viewer.needs_frame(display));
+        // the last frame encoded again, as run() does while nothing is captured
+        let sp = GenericService::new(NAME, true);
+        let last_frame = vec![0u8; width * height * 3 / 2];
+        let (mut yuv, mut scaled) = (Vec::new(), HashMap::new());
+        let mut frame = FrameData {
+            data: &last_frame,
+            bgra: false,
+            width,
+            height,
+            yuv: &mut yuv,
+            converted: false,
+            scaled: &mut scaled,
+            done: HashSet::new(),
+        };
+        viewer
+            .handle_frame(&sp, display, &mut frame, &[], 0, &mut (0, 0))
+            .unwrap();
+        assert!(viewer.last_key_frame.elapsed() < MIN_KEY_FRAME_INTERVAL);
+        assert!(!viewer.needs_frame(display));
+    }
+}
@@ -8,7 +8,7 @@ use hbb_common::{
     config::{Config, ICON},
     fs, log,
     message_proto::*,
-    protobuf::Message as _,
+    protobuf::{Message as _, ProtobufEnum},
     tokio::{self, sync::mpsc, task::spawn_blocking},
 };
 use sciter::{make_args, Element, Value, HELEMENT};
@@ -81,6 +81,8 @@ impl ConnectionManager {
         clipboard: bool,
         audio: bool,
         file: bool,
+        microphone: bool,
+        recording: bool,
         tx: mpsc::UnboundedSender<Data>,
     ) {
         self.call(
@@ -95,7 +97,9 @@ impl ConnectionManager {
                 keyboard,
                 clipboard,
                 audio,
-                file
+                file,
+                microphone,
+                recording
             ),
         );
         self.write().unwrap().senders.insert(id, tx);
@@ -112,6 +116,7 @@ impl ConnectionManager {
         data: Data,
         _tx_clip_file: &mpsc::UnboundedSender<ClipboardFileData>,
         write_jobs: &mut Vec<fs::TransferJob>,
+        tx_resume: &mpsc::UnboundedSender<(i32, Vec<FileEntry>, Vec<usize>)>,
         conn: &mut Connection,
     ) {
         match data {
@@ -122,8 +127,9 @@ impl ConnectionManager {
                 ipc::FS::ReadDir {
                     dir,
                     include_hidden,
+                    id,
                 } => {
-                    Self::read_dir(&dir, include_hidden, conn).await;
+                    Self::read_dir(&dir, include_hidden, id, conn).await;
                 }
                 ipc::FS::RemoveDir {
                     path,
@@ -142,19 +148,51 @@ impl ConnectionManager {
                     path,
                     id,
                     mut files,
+                    policy,
+                    resume,
+                    per_file_done,
+                    preserve_owner,
                 } => {
-                    write_jobs.push(fs::TransferJob::new_write(
+                    let mut job = fs::TransferJob::new_write(
                         id,
                         path,
                         files
                             .drain(..)
                             .map(|f| FileEntry {
-                                name: f.0,
-                                modified_time: f.1,
+                                name: f.name,
+                                entry_type: FileType::from_i32(f.entry_type)
+                                    .unwrap_or(FileType::File)
+                                    .into(),
+                                modified_time: f.modified_time,
+                                size: f.size,
+                                digest: f.digest,
+                                link_target: f.link_target,
+                                mode: f.mode,
+                                uid: f.uid,
+                                gid: f.gid,
                                 ..Default::default()
                             })
                             .collect(),
-                    ));
+                    );
+                    job.set_policy(FileOverwritePolicy::from_i32(policy).unwrap_or_default());
+                    job.set_per_file_done(per_file_done);
+                    job.set_preserve_owner(preserve_owner);
+                    // report the existing .download sizes and the skipped files,
+                    // the peer starts reading after that, an old one does not wait,
+                    // hashed in a blocking thread not to stall this loop
+                    if resume {
+                        let download_offsets = job.download_offsets();
+                        let same_contents = job.same_contents();
+                        let tx_resume = tx_resume.clone();
+                        tokio::spawn(async move {
+                            let res =
+                                spawn_blocking(move || (download_offsets(), same_contents())).await;
+                            if let Ok((offsets, same_contents)) = res {
+                                tx_resume.send((id, offsets, same_contents)).ok();
+                            }
+                        });
+                    }
+                    write_jobs.push(job);
                 }
                 ipc::FS::CancelWrite { id } => {
                     if let Some(job) = fs::get_job(id, write_jobs) {
@@ -162,11 +200,23 @@ impl ConnectionManager {
                         fs::remove_job(id, write_jobs);
                     }
                 }
-                ipc::FS::WriteDone { id, file_num } => {
+                ipc::FS::WriteDone {
+                    id,
+                    file_num,
+                    digest,
+                } => {
                     if let Some(job) = fs::get_job(id, write_jobs) {
-                        job.modify_time();
-                        Self::send(fs::new_done(id, file_num), conn).await;
-                        fs::remove_job(id, write_jobs);
+                        if (file_num as usize) < job.files().len() {
+                            if let Err(err) = job.modify_time(file_num, &digest).await {
+                                Self::send(fs::new_error(id, err, file_num), conn).await;
+                            }
+                        } else {
+                            if let Err(err) = job.finish().await {
+                                Self::send(fs::new_error(id, err, file_num), conn).await;
+                            }
+                            Self::send(fs::new_done(id, file_num), conn).await;
+                            fs::remove_job(id, write_jobs);
+                        }
                     }
                 }
                 ipc::FS::WriteBlock {
@@ -174,6 +224,7 @@ impl ConnectionManager {
                     file_num,
                     data,
                     compressed,
+                    offset,
                 } => {
                     let raw = if let Ok(bytes) = conn.next_raw().await {
                         Some(bytes)
@@ -188,6 +239,7 @@ impl ConnectionManager {
                                     file_num,
                                     data,
                                     compressed,
+                                    offset,
                                     ..Default::default()
                                 },
                                 raw.as_ref().map(|x| &x[..]),
@@ -215,7 +267,7 @@ impl ConnectionManager {
         }
     }
 
-    async fn read_dir(dir: &str, include_hidden: bool, conn: &mut Connection) {
+    async fn read_dir(dir: &str, include_hidden: bool, id: i32, conn: &mut Connection) {
         let path = {
             if dir.is_empty() {
                 Config::get_home()
@@ -223,12 +275,19 @@ impl ConnectionManager {
                 fs::get_path(dir)
             }
         };
-        if let Ok(Ok(fd)) = spawn_blocking(move || fs::read_dir(&path, include_hidden)).await {
-            let mut msg_out = Message::new();
-            let mut file_response = FileResponse::new();
-            file_response.set_dir(fd);
-            msg_out.set_file_response(file_response);
-            Self::send(msg_out, conn).await;
+        match spawn_blocking(move || fs::read_dir(&path, include_hidden)).await {
+            Ok(Ok(mut fd)) => {
+                fd.id = id;
+                let mut msg_out = Message::new();
+                let mut file_response = FileResponse::new();
+                file_response.set_dir(fd);
+                msg_out.set_file_response(file_response);
+                Self::send(msg_out, conn).await;
+            }
+            Ok(Err(err)) if id != 0 => {
+                Self::send(fs::new_error(id, err, -1), conn).await;
+            }
+            _ => {}
         }
     }
 
@@ -375,6 +434,9 @@ async fn start_ipc(cm: ConnectionManager) {
                             let mut conn_id: i32 = 0;
                             let (tx, mut rx) = mpsc::unbounded_channel::<Data>();
                             let mut write_jobs: Vec<fs::TransferJob> = Vec::new();
+                            let (tx_resume, mut rx_resume) = mpsc::unbounded_channel();
+                            #[cfg(target_os = "linux")]
+                            let mut microphone_player = None;
                             loop {
                                 tokio::select! {
                                     res = stream.next() => {
@@ -385,18 +447,22 @@ async fn start_ipc(cm: ConnectionManager) {
                                             }
                                             Ok(Some(data)) => {
                                                 match data {
-                                                    Data::Login{id, is_file_transfer, port_forward, peer_id, name, authorized, keyboard, clipboard, audio, file, file_transfer_enabled} => {
+                                                    Data::Login{id, is_file_transfer, port_forward, peer_id, name, authorized, keyboard, clipboard, audio, file, microphone, file_transfer_enabled, recording} => {
                                                         conn_id = id;
                                                         tx_file.send(ClipboardFileData::Enable((id, file_transfer_enabled))).ok();
-                                                        cm.add_connection(id, is_file_transfer, port_forward, peer_id, name, authorized, keyboard, clipboard, audio, file, tx.clone());
+                                                        cm.add_connection(id, is_file_transfer, port_forward, peer_id, name, authorized, keyboard, clipboard, audio, file, microphone, recording, tx.clone());
                                                     }
                                                     Data::Close => {
                                                         tx_file.send(ClipboardFileData::Enable((conn_id, false))).ok();
                                                         log::info!("cm ipc connection closed from connection request");
                                                         break;
                                                     }
+                                                    #[cfg(target_os = "linux")]
+                                                    Data::Microphone(bytes) => {
+                                                        play_microphone(&bytes, &mut microphone_player);
+                                                    }
                                                     _ => {
-                                                        cm.handle_data(conn_id, data, &tx_file, &mut write_jobs, &mut stream).await;
+                                                        cm.handle_data(conn_id, data, &tx_file, &mut write_jobs, &tx_resume, &mut stream).await;
                                                     }
                                                 }
                                             }
@@ -406,6 +472,14 @@ async fn start_ipc(cm: ConnectionManager) {
                                     Some(data) = rx.recv() => {
                                         allow_err!(stream.send(&data).await);
                                     }
+                                    Some((id, offsets, same_contents)) = rx_resume.recv() => {
+                                        // not found if cancelled meanwhile
+                                        if let Some(job) = fs::get_job(id, &mut write_jobs) {
+                                            job.set_download_offsets(&offsets);
+                                            let files = job.apply_policy(&same_contents);
+                                            ConnectionManager::send(fs::new_dir(id, files), &mut stream).await;
+                                        }
+                                    }
                                 }
                             }
                             cm.remove_connection(conn_id);
@@ -424,6 +498,35 @@ async fn start_ipc(cm: ConnectionManager) {
     std::process::exit(-1);
 }
 
+// the connection runs as root on Linux and can not reach the pulseaudio of the user
+#[cfg(target_os = "linux")]
+fn play_microphone(bytes: &[u8], player: &mut Option<crate::client::MediaSender>) {
+    use crate::client::{start_audio_thread, MediaData};
+    let msg = match Message::parse_from_bytes(bytes) {
+        Ok(msg) => msg,
+        Err(err) => {
+            log::error!("Failed to parse microphone data: {}", err);
+            return;
+        }
+    };
+    match msg.union {
+        Some(message::Union::audio_frame(frame)) => {
+            if let Some(player) = player.as_ref() {
+                player.send(MediaData::AudioFrame(frame)).ok();
+            }
+        }
+        Some(message::Union::misc(misc)) => {
+            if let Some(misc::Union::audio_format(format)) = misc.union {
+                // the player is restarted with the new format
+                let (p, _) = start_audio_thread(None);
+                p.send(MediaData::AudioFormat(format)).ok();
+                *player = Some(p);
+            }
+        }
+        _ => {}
+    }
+}
+
 #[cfg(target_os = "linux")]
 #[tokio::main(flavor = "current_thread")]
 async fn start_pa() {
@@ -3,6 +3,7 @@ use crate::clipboard_file::*;
 use crate::{
     client::*,
     common::{self, check_clipboard, update_clipboard, ClipboardContext, CLIPBOARD_INTERVAL},
+    recorder::Recorder,
 };
 #[cfg(windows)]
 use clipboard::{
@@ -45,6 +46,83 @@ type Video = AssetPtr<video_destination>;
 lazy_static::lazy_static! {
     static ref ENIGO: Arc<Mutex<Enigo>> = Arc::new(Mutex::new(Enigo::new()));
     static ref VIDEO: Arc<Mutex<Option<Video>>> = Default::default();
+    static ref DISPLAY_VIEW: Arc<Mutex<DisplayView>> = Default::default();
+}
+
+// The displays shown in the video element, the current one or all of them stitched together.
+#[derive(Default)]
+struct DisplayView {
+    displays: Vec<DisplayInfo>,
+    current: usize,
+    all: bool,
+    // the frames are tagged with their display since the extra displays are captured,
+    // the frames of old peers are always for the current display
+    captured: bool,
+    // the bounding box of all the displays
+    rect: (i32, i32, usize, usize),
+    rgb: Vec<u8>,
+}
+
+impl DisplayView {
+    fn set_all(&mut self, all: bool) {
+        self.all = all;
+        if all {
+            self.captured = true;
+            let x0 = self.displays.iter().map(|d| d.x).min().unwrap_or(0);
+            let y0 = self.displays.iter().map(|d| d.y).min().unwrap_or(0);
+            let x1 = self
+                .displays
+                .iter()
+                .map(|d| d.x + d.width)
+                .max()
+                .unwrap_or(0);
+            let y1 = self
+                .displays
+                .iter()
+                .map(|d| d.y + d.height)
+                .max()
+                .unwrap_or(0);
+            let (w, h) = ((x1 - x0) as usize, (y1 - y0) as usize);
+            self.rect = (x0, y0, w, h);
+            self.rgb = vec![0; w * h * 4];
+        } else {
+            self.rgb = Vec::new();
+        }
+    }
+
+    // x, y, width and height of the shown area on the remote desktop
+    fn rect(&self) -> Option<(i32, i32, i32, i32)> {
+        if self.all {
+            let (x, y, w, h) = self.rect;
+            Some((x, y, w as _, h as _))
+        } else {
+            self.displays
+                .get(self.current)
+                .map(|d| (d.x, d.y, d.width, d.height))
+        }
+    }
+
+    // the image to render after the frame of `display` is decoded
+    fn draw<'a>(&'a mut self, display: usize, data: &'a [u8]) -> Option<&'a [u8]> {
+        if !self.all {
+            if display == self.current || !self.captured {
+                return Some(data);
+            }
+            return None;
+        }
+        let d = self.displays.get(display)?;
+        let (x0, y0, w, _) = self.rect;
+        let (dw, dh) = (d.width as usize, d.height as usize);
+        if data.len() < dw * dh * 4 {
+            return None;
+        }
+        let (dx, dy) = ((d.x - x0) as usize, (d.y - y0) as usize);
+        for row in 0..dh {
+            let dst = ((dy + row) * w + dx) * 4;
+            self.rgb[dst..dst + dw * 4].copy_from_slice(&data[row * dw * 4..(row + 1) * dw * 4]);
+        }
+        Some(&self.rgb)
+    }
 }
 
 fn get_key_state(key: enigo::Key) -> bool {
@@ -185,9 +263,14 @@ impl sciter::EventHandler for Handler {
         fn read_remote_dir(String, bool);
         fn send_chat(String);
         fn switch_display(i32);
+        fn is_all_displays();
+        fn toggle_all_displays();
         fn remove_dir_all(i32, String, bool);
         fn confirm_delete_files(i32, i32);
         fn set_no_confirm(i32);
+        fn confirm_overwrite(i32, bool);
+        fn get_peer_speed_limit();
+        fn save_peer_speed_limit(i32);
         fn cancel_job(i32);
         fn send_files(i32, String, String, bool, bool);
         fn get_platform(bool);
@@ -207,6 +290,10 @@ impl sciter::EventHandler for Handler {
         fn save_image_quality(String);
         fn save_custom_image_quality(i32, i32);
         fn refresh_video();
+        fn toggle_recording();
+        fn toggle_microphone();
+        fn set_viewport(i32, i32);
+        fn change_resolution(i32, i32);
         fn get_toggle_option(String);
         fn toggle_option(String);
         fn get_remember();
@@ -505,6 +592,45 @@ impl Handler {
         self.send(Data::Message(LoginConfigHandler::refresh()));
     }
 
+    fn toggle_recording(&mut self) {
+        self.send(Data::ToggleRecording);
+    }
+
+    fn toggle_microphone(&mut self) {
+        self.send(Data::ToggleMicrophone);
+    }
+
+    // the size the display is shown at, 0 if not scaled down, the peer encodes the frames at it
+    fn set_viewport(&mut self, width: i32, height: i32) {
+        let (width, height) = if DISPLAY_VIEW.lock().unwrap().all {
+            // the displays are stitched at their native sizes
+            (0, 0)
+        } else {
+            (width.max(0), height.max(0))
+        };
+        let mut misc = Misc::new();
+        misc.set_viewport(Resolution {
+            width,
+            height,
+            ..Default::default()
+        });
+        let mut msg_out = Message::new();
+        msg_out.set_misc(misc);
+        self.send(Data::Message(msg_out));
+    }
+
+    fn change_resolution(&mut self, width: i32, height: i32) {
+        let mut misc = Misc::new();
+        misc.set_change_resolution(Resolution {
+            width,
+            height,
+            ..Default::default()
+        });
+        let mut msg_out = Message::new();
+        msg_out.set_misc(misc);
+        self.send(Data::Message(msg_out));
+    }
+
     fn save_custom_image_quality(&mut self, bitrate: i32, quantizer: i32) {
         let msg = self
             .lc
@@ -723,6 +849,40 @@ impl Handler {
         self.send(Data::Message(msg_out));
     }
 
+    fn is_all_displays(&mut self) -> bool {
+        DISPLAY_VIEW.lock().unwrap().all
+    }
+
+    // stream all the displays and show them stitched together, or the current one only
+    fn toggle_all_displays(&mut self) {
+        let mut view = DISPLAY_VIEW.lock().unwrap();
+        let all = !view.all;
+        view.set_all(all);
+        let displays: Vec<i32> = if all {
+            (0..view.displays.len() as i32).collect()
+        } else {
+            Vec::new()
+        };
+        if let Some((x, y, w, h)) = view.rect() {
+            VIDEO.lock().unwrap().as_mut().map(|v| {
+                v.stop_streaming().ok();
+                let ok = v.start_streaming((w, h), COLOR_SPACE::Rgb32, None);
+                log::info!("[video] reinitialized: {:?}", ok);
+            });
+            self.set_display(x, y, w, h);
+        }
+        drop(view);
+        let mut misc = Misc::new();
+        // the current display is streamed by the main service anyway
+        misc.set_capture_displays(CaptureDisplays {
+            displays: displays.into(),
+            ..Default::default()
+        });
+        let mut msg_out = Message::new();
+        msg_out.set_misc(misc);
+        self.send(Data::Message(msg_out));
+    }
+
     fn remove_file(&mut self, id: i32, path: String, file_num: i32, is_remote: bool) {
         self.send(Data::RemoveFile((id, path, file_num, is_remote)));
     }
@@ -739,6 +899,33 @@ impl Handler {
         self.send(Data::SetNoConfirm(id));
     }
 
+    fn confirm_overwrite(&mut self, id: i32, overwrite: bool) {
+        self.send(Data::ConfirmOverwrite((id, overwrite)));
+    }
+
+    fn get_peer_speed_limit(&self) -> i32 {
+        self.lc.read().unwrap().get_peer_speed_limit() as _
+    }
+
+    fn save_peer_speed_limit(&mut self, value: i32) {
+        self.lc
+            .write()
+            .unwrap()
+            .save_peer_speed_limit(std::cmp::max(value, 0) as _);
+        self.send(Data::UpdateSpeedLimit);
+    }
+
+    fn get_overwrite_policy(&self) -> FileOverwritePolicy {
+        let policy = self.lc.read().unwrap().get_option("overwrite-policy");
+        match policy.as_ref() {
+            "skip-same-size-time" => FileOverwritePolicy::SkipSameSizeTime,
+            "skip-same-content" => FileOverwritePolicy::SkipSameContent,
+            "rename" => FileOverwritePolicy::Rename,
+            "ask" => FileOverwritePolicy::Ask,
+            _ => FileOverwritePolicy::Overwrite,
+        }
+    }
+
     fn remove_dir(&mut self, id: i32, path: String, is_remote: bool) {
         if is_remote {
             self.send(Data::RemoveDir((id, path)));
@@ -1263,27 +1450,47 @@ async fn io_loop(handler: Handler) {
         }
         return;
     }
-    let (video_sender, audio_sender) = start_video_audio_threads(|data: &[u8]| {
-        VIDEO
-            .lock()
-            .unwrap()
-            .as_mut()
-            .map(|v| v.render_frame(data).ok());
-    });
-
+    let key_frame_sender = sender.clone();
+    let (video_sender, audio_sender, audio_stats) = start_video_audio_threads(
+        |display, data: &[u8]| {
+            let mut view = DISPLAY_VIEW.lock().unwrap();
+            if let Some(data) = view.draw(display, data) {
+                VIDEO
+                    .lock()
+                    .unwrap()
+                    .as_mut()
+                    .map(|v| v.render_frame(data).ok());
+            }
+        },
+        move |display| {
+            key_frame_sender
+                .send(Data::Message(LoginConfigHandler::request_key_frame(
+                    display,
+                )))
+                .ok();
+        },
+    );
+
+    let speed_limit = handler.lc.read().unwrap().get_speed_limit();
     let mut remote = Remote {
         handler,
         video_sender,
         audio_sender,
+        audio_stats,
         receiver,
         sender,
         old_clipboard: Default::default(),
         read_jobs: Vec::new(),
+        pending_read_jobs: Vec::new(),
         write_jobs: Vec::new(),
         remove_jobs: Default::default(),
+        job_scheduler: fs::JobScheduler::new(speed_limit),
         timer: time::interval(SEC30),
         last_update_jobs_status: (Instant::now(), Default::default()),
         first_frame: false,
+        recorder: None,
+        audio_format: None,
+        microphone: None,
         #[cfg(windows)]
         clipboard_file_context: None,
     };
@@ -1316,15 +1523,22 @@ struct Remote {
     handler: Handler,
     video_sender: MediaSender,
     audio_sender: MediaSender,
+    audio_stats: Arc<Mutex<AudioStats>>,
     receiver: mpsc::UnboundedReceiver<Data>,
     sender: mpsc::UnboundedSender<Data>,
     old_clipboard: Arc<Mutex<String>>,
     read_jobs: Vec<fs::TransferJob>,
+    // waiting for the resume offsets from the remote, or their prefixes hashed
+    pending_read_jobs: Vec<fs::TransferJob>,
     write_jobs: Vec<fs::TransferJob>,
     remove_jobs: HashMap<i32, RemoveJob>,
+    job_scheduler: fs::JobScheduler,
     timer: Interval,
     last_update_jobs_status: (Instant, HashMap<i32, u64>),
     first_frame: bool,
+    recorder: Option<Recorder>,
+    audio_format: Option<AudioFormat>,
+    microphone: Option<Microphone>,
     #[cfg(windows)]
     clipboard_file_context: Option<Box<CliprdrClientContext>>,
 }
@@ -1339,14 +1553,16 @@ impl Remote {
             ConnType::default()
         };
         match Client::start(&self.handler.id, conn_type).await {
-            Ok((mut peer, direct)) => {
+            Ok((mut peer, direct, quic)) => {
                 unsafe {
                     SERVER_KEYBOARD_ENABLED = true;
                     SERVER_CLIPBOARD_ENABLED = true;
                     SERVER_FILE_TRANSFER_ENABLED = true;
                 }
-                self.handler
-                    .call("setConnectionType", &make_args!(peer.is_secured(), direct));
+                self.handler.call(
+                    "setConnectionType",
+                    &make_args!(peer.is_secured(), direct, quic),
+                );
 
                 // just build for now
                 #[cfg(not(windows))]
@@ -1401,7 +1617,7 @@ impl Remote {
                                 break;
                             }
                             if !self.read_jobs.is_empty() {
-                                if let Err(err) = fs::handle_read_jobs(&mut self.read_jobs, &mut peer).await {
+                                if let Err(err) = fs::handle_read_jobs(&mut self.read_jobs, &mut peer, &mut self.job_scheduler).await {
                                     self.handler.msgbox("error", "Connection Error", &err.to_string());
                                     break;
                                 }
@@ -1509,21 +1725,58 @@ impl Remote {
             Data::ToggleClipboardFile => {
                 self.check_clipboard_file_context();
             }
+            Data::ToggleRecording => {
+                self.toggle_recording(peer).await;
+            }
+            Data::ToggleMicrophone => {
+                self.toggle_microphone(peer).await;
+            }
             Data::Message(msg) => {
                 allow_err!(peer.send(&msg).await);
             }
             Data::SendFiles((id, path, to, include_hidden, is_remote)) => {
+                let features = self
+                    .handler
+                    .lc
+                    .read()
+                    .unwrap()
+                    .file_transfer_features
+                    .clone();
+                // an old peer neither applies nor confirms the policies
+                let policy = if features.contains(&FileTransferFeature::OverwritePolicy) {
+                    self.handler.get_overwrite_policy()
+                } else {
+                    FileOverwritePolicy::Overwrite
+                };
+                let resume = features.contains(&FileTransferFeature::Resume);
+                let per_file_done = features.contains(&FileTransferFeature::PerFileDone);
                 if is_remote {
                     log::debug!("New job {}, write to {} from remote {}", id, to, path);
-                    self.write_jobs
-                        .push(fs::TransferJob::new_write(id, to, Vec::new()));
-                    allow_err!(peer.send(&fs::new_send(id, path, include_hidden)).await);
+                    let mut job = fs::TransferJob::new_write(id, to.clone(), Vec::new());
+                    job.set_policy(policy);
+                    job.set_per_file_done(per_file_done);
+                    self.write_jobs.push(job);
+                    if resume {
+                        // requested once hashed
+                        let sender = self.sender.clone();
+                        tokio::spawn(async move {
+                            let offsets = fs::get_download_offsets(to).await;
+                            sender
+                                .send(Data::DownloadOffsets((id, path, include_hidden, offsets)))
+                                .ok();
+                        });
+                    } else {
+                        allow_err!(
+                            peer.send(&fs::new_send(id, path, include_hidden, Vec::new(), policy))
+                                .await
+                        );
+                    }
                 } else {
                     match fs::TransferJob::new_read(id, path.clone(), include_hidden) {
                         Err(err) => {
                             self.handle_job_status(id, -1, Some(err.to_string()));
                         }
-                        Ok(job) => {
+                        Ok(mut job) => {
                             log::debug!(
                                 "New job {}, read {} to remote {}, {} files",
                                 id,
@@ -1533,19 +1786,114 @@ impl Remote {
                             );
                             let m = make_fd(job.id(), job.files(), true);
                             self.handler.call("updateFolderFiles", &make_args!(m));
-                            let files = job.files().clone();
-                            self.read_jobs.push(job);
-                            self.timer = time::interval(MILLI1);
-                            allow_err!(peer.send(&fs::new_receive(id, to, files)).await);
+                            job.set_policy(policy);
+                            job.set_per_file_done(per_file_done);
+                            if policy == FileOverwritePolicy::SkipSameContent {
+                                // requested once hashed
+                                let hash_files = job.hash_files();
+                                self.pending_read_jobs.push(job);
+                                self.spawn_hashing(hash_files, move |digests| {
+                                    Data::FileDigests((id, to, digests))
+                                });
+                            } else {
+                                let files = job.files().clone();
+                                if resume {
+                                    // wait for the resume offsets from the remote
+                                    self.pending_read_jobs.push(job);
+                                } else {
+                                    self.read_jobs.push(job);
+                                    self.timer = time::interval(MILLI1);
+                                }
+                                allow_err!(
+                                    peer.send(&fs::new_receive(id, to, files, policy, false))
+                                        .await
+                                );
+                            }
                         }
                     }
                 }
             }
+            Data::DownloadOffsets((id, path, include_hidden, offsets)) => {
+                // not found if cancelled meanwhile
+                if let Some(job) = fs::get_job(id, &mut self.write_jobs) {
+                    let policy = job.policy();
+                    allow_err!(
+                        peer.send(&fs::new_send(id, path, include_hidden, offsets, policy))
+                            .await
+                    );
+                }
+            }
+            Data::FileDigests((id, to, digests)) => {
+                if let Some(i) = self.pending_read_jobs.iter().position(|x| x.id() == id) {
+                    let job = &mut self.pending_read_jobs[i];
+                    job.set_digests(digests);
+                    let files = job.files().clone();
+                    let policy = job.policy();
+                    let resume = self
+                        .handler
+                        .lc
+                        .read()
+                        .unwrap()
+                        .file_transfer_features
+                        .contains(&FileTransferFeature::Resume);
+                    allow_err!(
+                        peer.send(&fs::new_receive(id, to, files, policy, false))
+                            .await
+                    );
+                    // otherwise wait for the resume offsets from the remote
+                    if !resume {
+                        self.start_pending_read_job(i);
+                    }
+                }
+            }
+            Data::SameContents((id, same_contents)) => {
+                if let Some(job) = fs::get_job(id, &mut self.write_jobs) {
+                    let policy = job.policy();
+                    let files = job.apply_policy(&same_contents);
+                    let n = files.iter().filter(|x| x.skip).count();
+                    if policy == FileOverwritePolicy::Ask && n > 0 {
+                        self.handler
+                            .call("confirmOverwriteFiles", &make_args!(id, n as i32));
+                    } else {
+                        allow_err!(peer.send(&fs::new_send_confirm(id, files)).await);
+                    }
+                    Self::update_job_files(&mut self.handler, job);
+                }
+            }
+            Data::PrefixHashed((id, hashers)) => {
+                // not found if cancelled meanwhile
+                if let Some(i) = self.pending_read_jobs.iter().position(|x| x.id() == id) {
+                    let mut job = self.pending_read_jobs.remove(i);
+                    job.set_prefix_hashers(hashers);
+                    self.read_jobs.push(job);
+                    self.timer = time::interval(MILLI1);
+                }
+            }
             Data::SetNoConfirm(id) => {
                 if let Some(job) = self.remove_jobs.get_mut(&id) {
                     job.no_confirm = true;
                 }
             }
+            Data::UpdateSpeedLimit => {
+                // only our uploads, the peer keeps the limit of the login until reconnected
+                let limit = self.handler.lc.read().unwrap().get_speed_limit();
+                self.job_scheduler.set_limit(limit);
+            }
+            Data::ConfirmOverwrite((id, overwrite)) => {
+                if let Some(job) = fs::get_job(id, &mut self.write_jobs) {
+                    if overwrite {
+                        job.overwrite_skipped();
+                    }
+                    let files = job.reply_files();
+                    Self::update_job_files(&mut self.handler, job);
+                    allow_err!(peer.send(&fs::new_send_confirm(id, files)).await);
+                } else if let Some(i) = self.pending_read_jobs.iter().position(|x| x.id() == id) {
+                    if overwrite {
+                        self.pending_read_jobs[i].overwrite_skipped();
+                    }
+                    self.start_pending_read_job(i);
+                }
+            }
             Data::ConfirmDeleteFiles((id, file_num)) => {
                 if let Some(job) = self.remove_jobs.get_mut(&id) {
                     let i = file_num as usize;
@@ -1600,6 +1948,7 @@ impl Remote {
                     fs::remove_job(id, &mut self.write_jobs);
                 }
                 fs::remove_job(id, &mut self.read_jobs);
+                fs::remove_job(id, &mut self.pending_read_jobs);
                 self.remove_jobs.remove(&id);
             }
             Data::RemoveDir((id, path)) => {
@@ -1664,6 +2013,38 @@ impl Remote {
         true
     }
 
+    // the job is read once the resume prefixes are hashed
+    fn start_pending_read_job(&mut self, i: usize) {
+        let job = &self.pending_read_jobs[i];
+        Self::update_job_files(&mut self.handler, job);
+        let id = job.id();
+        let hash_prefixes = job.hash_prefixes();
+        self.spawn_hashing(hash_prefixes, move |hashers| {
+            Data::PrefixHashed((id, hashers))
+        });
+    }
+
+    // hash in a blocking thread not to stall the session, the result is fed back to io_loop
+    fn spawn_hashing<T: Send + 'static>(
+        &self,
+        hash: impl FnOnce() -> T + Send + 'static,
+        data: impl FnOnce(T) -> Data + Send + 'static,
+    ) {
+        let sender = self.sender.clone();
+        tokio::spawn(async move {
+            if let Ok(res) = tokio::task::spawn_blocking(hash).await {
+                sender.send(data(res)).ok();
+            }
+        });
+    }
+
+    // the files to transfer, without the skipped ones
+    fn update_job_files(handler: &mut Handler, job: &fs::TransferJob) {
+        let files = job.files().iter().filter(|x| !x.skip).cloned().collect();
+        let m = make_fd(job.id(), &files, true);
+        handler.call("updateFolderFiles", &make_args!(m));
+    }
+
     #[inline]
     fn update_job_status(
         job: &fs::TransferJob,
@@ -1714,6 +2095,59 @@ impl Remote {
         }
     }
 
+    // the peer is asked for vp9 and a refresh which starts the recording with a key frame
+    async fn toggle_recording(&mut self, peer: &mut Stream) {
+        let decodings = if let Some(recorder) = self.recorder.take() {
+            let path = recorder.path().to_string_lossy().to_string();
+            drop(recorder);
+            self.handler.call("updateRecording", &make_args!(false));
+            self.handler
+                .msgbox("custom-nocancel", "Recording saved", &path);
+            scrap::codec::supported_formats()
+        } else {
+            let mut recorder = Recorder::new(Recorder::default_path(&self.handler.id));
+            if let Some(f) = self.audio_format.clone() {
+                recorder.set_audio_format(f);
+            }
+            self.recorder = Some(recorder);
+            self.handler.call("updateRecording", &make_args!(true));
+            vec![scrap::CodecFormat::VP9]
+        };
+        let msg = LoginConfigHandler::set_decodings(decodings);
+        allow_err!(peer.send(&msg).await);
+        allow_err!(peer.send(&LoginConfigHandler::refresh()).await);
+    }
+
+    // the encoded frames are sent to the peer through the channel of the ui
+    async fn toggle_microphone(&mut self, peer: &mut Stream) {
+        if self.microphone.take().is_some() {
+            self.handler.call("updateMicrophone", &make_args!(false));
+            return;
+        }
+        let sender = self.sender.clone();
+        let res = Microphone::new(move |frame| {
+            let mut msg_out = Message::new();
+            msg_out.set_audio_frame(frame);
+            sender.send(Data::Message(msg_out)).ok();
+        });
+        match res {
+            Ok((microphone, format)) => {
+                let mut misc = Misc::new();
+                misc.set_audio_format(format);
+                let mut msg_out = Message::new();
+                msg_out.set_misc(misc);
+                allow_err!(peer.send(&msg_out).await);
+                self.microphone = Some(microphone);
+                self.handler.call("updateMicrophone", &make_args!(true));
+            }
+            Err(err) => {
+                log::error!("Failed to open the microphone: {}", err);
+                self.handler
+                    .msgbox("custom-error", "Microphone", &err.to_string());
+            }
+        }
+    }
+
     async fn handle_msg_from_peer(&mut self, data: &[u8], peer: &mut Stream) -> bool {
         if let Ok(msg_in) = Message::parse_from_bytes(&data) {
             match msg_in.union {
@@ -1723,6 +2157,21 @@ impl Remote {
                         self.handler.call2("closeSuccess", &make_args!());
                         self.handler.call("adaptSize", &make_args!());
                     }
+                    let recorded = {
+                        let view = DISPLAY_VIEW.lock().unwrap();
+                        !view.all || vf.display as usize == view.current
+                    };
+                    if let (Some(recorder), true) = (self.recorder.as_mut(), recorded) {
+                        if let Err(err) = recorder.write_video(&vf) {
+                            log::error!("Failed to record: {}", err);
+                            self.recorder = None;
+                            self.handler.call("updateRecording", &make_args!(false));
+                            let msg = LoginConfigHandler::set_decodings(
+                                scrap::codec::supported_formats(),
+                            );
+                            allow_err!(peer.send(&msg).await);
+                        }
+                    }
                     self.video_sender.send(MediaData::VideoFrame(vf)).ok();
                 }
                 Some(message::Union::hash(hash)) => {
@@ -1735,6 +2184,11 @@ impl Remote {
                         }
                     }
                     Some(login_response::Union::peer_info(pi)) => {
+                        for (i, d) in pi.displays.iter().enumerate() {
+                            self.video_sender
+                                .send(MediaData::DisplaySize(i, d.width as _, d.height as _))
+                                .ok();
+                        }
                         self.handler.handle_peer_info(pi);
                         self.check_clipboard_file_context();
                         if !(self.handler.is_file_transfer()
@@ -1784,15 +2238,38 @@ impl Remote {
                 Some(message::Union::file_response(fr)) => match fr.union {
                     Some(file_response::Union::dir(fd)) => {
                         let entries = fd.entries.to_vec();
-                        let mut m = make_fd(fd.id, &entries, fd.id > 0);
-                        if fd.id <= 0 {
-                            m.set_item("path", fd.path);
-                        }
-                        self.handler.call("updateFolderFiles", &make_args!(m));
-                        if let Some(job) = fs::get_job(fd.id, &mut self.write_jobs) {
+                        if let Some(i) = self.pending_read_jobs.iter().position(|x| x.id() == fd.id)
+                        {
+                            let job = &mut self.pending_read_jobs[i];
+                            job.set_offsets(&entries);
+                            let n = job.files().iter().filter(|x| x.skip).count();
+                            if job.policy() == FileOverwritePolicy::Ask && n > 0 {
+                                self.handler
+                                    .call("confirmOverwriteFiles", &make_args!(fd.id, n as i32));
+                            } else {
+                                self.start_pending_read_job(i);
+                            }
+                        } else if let Some(job) = fs::get_job(fd.id, &mut self.write_jobs) {
                             job.set_files(entries);
-                        } else if let Some(job) = self.remove_jobs.get_mut(&fd.id) {
-                            job.files = entries;
+                            if job.policy() != FileOverwritePolicy::Overwrite {
+                                // the remote waits for the files to skip, replied once hashed
+                                let same_contents = job.same_contents();
+                                let id = fd.id;
+                                self.spawn_hashing(same_contents, move |same_contents| {
+                                    Data::SameContents((id, same_contents))
+                                });
+                            } else {
+                                Self::update_job_files(&mut self.handler, job);
+                            }
+                        } else {
+                            let mut m = make_fd(fd.id, &entries, fd.id > 0);
+                            if fd.id <= 0 {
+                                m.set_item("path", fd.path);
+                            }
+                            self.handler.call("updateFolderFiles", &make_args!(m));
+                            if let Some(job) = self.remove_jobs.get_mut(&fd.id) {
+                                job.files = entries;
+                            }
                         }
                     }
                     Some(file_response::Union::block(block)) => {
@@ -1805,7 +2282,16 @@ impl Remote {
                     }
                     Some(file_response::Union::done(d)) => {
                         if let Some(job) = fs::get_job(d.id, &mut self.write_jobs) {
-                            job.modify_time();
+                            if (d.file_num as usize) < job.files().len() {
+                                if let Err(err) = job.modify_time(d.file_num, &d.digest).await {
+                                    let err = Some(err.to_string());
+                                    self.handle_job_status(d.id, d.file_num, err);
+                                }
+                                return true;
+                            }
+                            if let Err(err) = job.finish().await {
+                                self.handle_job_status(d.id, d.file_num, Some(err.to_string()));
+                            }
                             fs::remove_job(d.id, &mut self.write_jobs);
                         }
                         self.handle_job_status(d.id, d.file_num, None);
@@ -1817,6 +2303,10 @@ impl Remote {
                 },
                 Some(message::Union::misc(misc)) => match misc.union {
                     Some(misc::Union::audio_format(f)) => {
+                        self.audio_format = Some(f.clone());
+                        if let Some(recorder) = self.recorder.as_mut() {
+                            recorder.set_audio_format(f.clone());
+                        }
                         self.audio_sender.send(MediaData::AudioFormat(f)).ok();
                     }
                     Some(misc::Union::chat_message(c)) => {
@@ -1854,12 +2344,36 @@ impl Remote {
                                 self.handler
                                     .call2("setPermission", &make_args!("file", p.enabled));
                             }
+                            Permission::Microphone => {
+                                if !p.enabled && self.microphone.take().is_some() {
+                                    self.handler.call("updateMicrophone", &make_args!(false));
+                                }
+                                self.handler
+                                    .call2("setPermission", &make_args!("microphone", p.enabled));
+                            }
                         }
                     }
                     Some(misc::Union::switch_display(s)) => {
                         self.handler.call("switchDisplay", &make_args!(s.display));
-                        self.video_sender.send(MediaData::Reset).ok();
                         if s.width > 0 && s.height > 0 {
+                            self.video_sender
+                                .send(MediaData::DisplaySize(
+                                    s.display as _,
+                                    s.width as _,
+                                    s.height as _,
+                                ))
+                                .ok();
+                        }
+                        self.video_sender
+                            .send(MediaData::Reset(s.display as _))
+                            .ok();
+                        let all = {
+                            let mut view = DISPLAY_VIEW.lock().unwrap();
+                            view.current = s.display as _;
+                            view.all
+                        };
+                        // the stitched view keeps its size
+                        if s.width > 0 && s.height > 0 && !all {
                             VIDEO.lock().unwrap().as_mut().map(|v| {
                                 v.stop_streaming().ok();
                                 let ok = v.start_streaming(
@@ -1880,12 +2394,23 @@ impl Remote {
                         self.handler
                             .msgbox("custom-error", "Option Error", &resp.error);
                     }
+                    Some(misc::Union::video_quality(q)) => {
+                        log::debug!("Video quality: {:?}", q);
+                        self.handler.call(
+                            "updateVideoQuality",
+                            &make_args!(q.fps as i32, q.bitrate as i32),
+                        );
+                    }
                     _ => {}
                 },
                 Some(message::Union::test_delay(t)) => {
                     self.handler.handle_test_delay(t, peer).await;
+                    self.update_audio_stats();
                 }
                 Some(message::Union::audio_frame(frame)) => {
+                    if let Some(recorder) = self.recorder.as_mut() {
+                        recorder.write_audio(&frame);
+                    }
                     if !self.handler.lc.read().unwrap().disable_audio {
                         self.audio_sender.send(MediaData::AudioFrame(frame)).ok();
                     }
@@ -1896,6 +2421,26 @@ impl Remote {
         true
     }
 
+    // along with the test delays, every few seconds
+    fn update_audio_stats(&self) {
+        let stats = *self.audio_stats.lock().unwrap();
+        if stats.target == 0 || self.handler.lc.read().unwrap().disable_audio {
+            return;
+        }
+        log::debug!("Audio stats: {:?}", stats);
+        self.handler.call(
+            "updateAudioStats",
+            &make_args!(
+                stats.depth as i32,
+                stats.target as i32,
+                stats.jitter as i32,
+                stats.underruns as i32,
+                stats.concealed as i32,
+                stats.av_offset as i32
+            ),
+        );
+    }
+
     fn check_clipboard_file_context(&mut self) {
         #[cfg(windows)]
         {
@@ -1996,6 +2541,12 @@ impl Interface for Handler {
                 current = 0;
             }
             pi_sciter.set_item("current_display", current as i32);
+            {
+                let mut view = DISPLAY_VIEW.lock().unwrap();
+                view.displays = pi.displays.to_vec();
+                view.current = current;
+                view.set_all(false);
+            }
             let current = &pi.displays[current];
             self.set_display(current.x, current.y, current.width, current.height);
             // https://sciter.com/forums/topic/color_spaceiyuv-crash